@@ -0,0 +1,22 @@
+ALTER TABLE products ADD COLUMN shipping_dimensions VARCHAR(255) NOT NULL DEFAULT '';
+
+UPDATE products
+SET shipping_dimensions = shipping_length::text || 'x' || shipping_width::text || 'x' || shipping_height::text;
+
+ALTER TABLE products
+    ALTER COLUMN shipping_dimensions DROP DEFAULT,
+    DROP COLUMN shipping_length,
+    DROP COLUMN shipping_width,
+    DROP COLUMN shipping_height,
+    DROP CONSTRAINT products_allow_backorders_check,
+    ALTER COLUMN price DROP DEFAULT,
+    ALTER COLUMN price TYPE TEXT USING price::text,
+    ALTER COLUMN stock DROP DEFAULT,
+    ALTER COLUMN stock TYPE TEXT USING stock::text,
+    ALTER COLUMN allow_backorders DROP DEFAULT,
+    ALTER COLUMN allow_backorders TYPE VARCHAR(8),
+    ALTER COLUMN low_stock_threshold DROP DEFAULT,
+    -- wider than the VARCHAR(3) and VARCHAR(8) they were, the numbers can have more digits now
+    ALTER COLUMN low_stock_threshold TYPE TEXT USING low_stock_threshold::text,
+    ALTER COLUMN shipping_weight DROP DEFAULT,
+    ALTER COLUMN shipping_weight TYPE TEXT USING shipping_weight::text;
@@ -0,0 +1,38 @@
+-- Store product numbers as real numeric types so they can be sorted, summed and validated.
+-- Existing text values that don't parse fall back to 0 / 'no', ones too large for the new columns
+-- are clamped to the largest value they hold.
+
+ALTER TABLE products
+    ALTER COLUMN price TYPE NUMERIC(12, 2)
+        USING (CASE WHEN btrim(price) ~ '^\d+(\.\d+)?$' THEN LEAST(round(btrim(price)::numeric, 2), 9999999999.99) ELSE 0 END),
+    ALTER COLUMN price SET DEFAULT 0,
+    ALTER COLUMN stock TYPE INTEGER
+        USING (CASE WHEN btrim(stock) ~ '^-?\d{1,9}$' THEN btrim(stock)::integer ELSE 0 END),
+    ALTER COLUMN stock SET DEFAULT 0,
+    ALTER COLUMN allow_backorders TYPE TEXT
+        USING (CASE WHEN allow_backorders IN ('yes', 'notify') THEN allow_backorders ELSE 'no' END),
+    ALTER COLUMN allow_backorders SET DEFAULT 'no',
+    ADD CONSTRAINT products_allow_backorders_check CHECK (allow_backorders IN ('no', 'notify', 'yes')),
+    ALTER COLUMN low_stock_threshold TYPE INTEGER
+        USING (CASE WHEN btrim(low_stock_threshold) ~ '^\d{1,3}$' THEN btrim(low_stock_threshold)::integer ELSE 0 END),
+    ALTER COLUMN low_stock_threshold SET DEFAULT 0,
+    ALTER COLUMN shipping_weight TYPE NUMERIC(10, 3)
+        USING (CASE WHEN btrim(shipping_weight) ~ '^\d+(\.\d+)?$' THEN LEAST(round(btrim(shipping_weight)::numeric, 3), 9999999.999) ELSE 0 END),
+    ALTER COLUMN shipping_weight SET DEFAULT 0,
+    ADD COLUMN shipping_length NUMERIC(10, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN shipping_width NUMERIC(10, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN shipping_height NUMERIC(10, 2) NOT NULL DEFAULT 0;
+
+-- shipping_dimensions was free text, split anything shaped like "LxWxH" into the new columns
+UPDATE products
+SET shipping_length = LEAST(round(split_part(dims, 'x', 1)::numeric, 2), 99999999.99),
+    shipping_width = LEAST(round(split_part(dims, 'x', 2)::numeric, 2), 99999999.99),
+    shipping_height = LEAST(round(split_part(dims, 'x', 3)::numeric, 2), 99999999.99)
+FROM (
+    SELECT id AS dims_id, regexp_replace(lower(shipping_dimensions), '\s', '', 'g') AS dims
+    FROM products
+) AS parsed
+WHERE products.id = parsed.dims_id
+  AND dims ~ '^\d+(\.\d+)?x\d+(\.\d+)?x\d+(\.\d+)?$';
+
+ALTER TABLE products DROP COLUMN shipping_dimensions;
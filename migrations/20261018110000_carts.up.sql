@@ -0,0 +1,28 @@
+-- carts belong to the anonymous session_id cookie given to every visitor
+CREATE TABLE
+  IF NOT EXISTS carts (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      session_id UUID NOT NULL UNIQUE,
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE TABLE
+  IF NOT EXISTS cart_items (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      cart_id UUID NOT NULL REFERENCES carts(id) ON DELETE CASCADE,
+      product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+      variation TEXT NOT NULL DEFAULT '', -- one of the product's variations, empty when it has none
+      quantity INTEGER NOT NULL CHECK (quantity > 0),
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+      UNIQUE (cart_id, product_id, variation)
+  );
@@ -0,0 +1,47 @@
+-- statuses follow WooCommerce, the allowed transitions are enforced by OrderStatus in model.rs
+CREATE TABLE
+  IF NOT EXISTS orders (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      number BIGINT GENERATED ALWAYS AS IDENTITY (START WITH 1000) UNIQUE, -- shown to customers
+      session_id UUID NOT NULL, -- the visitor who placed the order
+      status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'processing', 'on-hold', 'completed', 'cancelled', 'refunded', 'failed')),
+      billing_name TEXT NOT NULL,
+      billing_email TEXT NOT NULL,
+      billing_phone TEXT NOT NULL DEFAULT '',
+      billing_address TEXT NOT NULL,
+      billing_city TEXT NOT NULL,
+      billing_postcode TEXT NOT NULL DEFAULT '',
+      billing_country TEXT NOT NULL,
+      customer_note TEXT NOT NULL DEFAULT '',
+      subtotal NUMERIC(12, 2) NOT NULL,
+      tax_total NUMERIC(12, 2) NOT NULL,
+      total NUMERIC(12, 2) NOT NULL,
+      stock_reduced BOOLEAN NOT NULL DEFAULT FALSE, -- stock is given back when the order is cancelled, refunded or failed
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE INDEX IF NOT EXISTS orders_status_idx ON orders (status);
+
+-- line items keep a copy of the product as it was when the order was placed
+CREATE TABLE
+  IF NOT EXISTS order_items (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      order_id UUID NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+      product_id UUID REFERENCES products(id) ON DELETE SET NULL,
+      title TEXT NOT NULL,
+      sku TEXT NOT NULL,
+      variation TEXT NOT NULL DEFAULT '',
+      price NUMERIC(12, 2) NOT NULL,
+      quantity INTEGER NOT NULL CHECK (quantity > 0),
+      tax_rate NUMERIC(5, 2) NOT NULL DEFAULT 0, -- percent
+      tax NUMERIC(12, 2) NOT NULL DEFAULT 0,
+      line_total NUMERIC(12, 2) NOT NULL -- price * quantity, without tax
+  );
+
+CREATE INDEX IF NOT EXISTS order_items_order_id_idx ON order_items (order_id);
@@ -0,0 +1,23 @@
+ALTER TABLE orders ADD COLUMN payment_method TEXT NOT NULL DEFAULT ''; -- id of the payment gateway picked at checkout
+
+-- every attempt to pay for an order, transaction_id is the id given by the gateway
+CREATE TABLE
+  IF NOT EXISTS payments (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      order_id UUID NOT NULL REFERENCES orders(id) ON DELETE CASCADE,
+      gateway TEXT NOT NULL,
+      status TEXT NOT NULL
+        CHECK (status IN ('pending', 'requires-action', 'authorized', 'captured', 'declined', 'refunded')),
+      amount NUMERIC(12, 2) NOT NULL,
+      transaction_id TEXT NOT NULL,
+      message TEXT NOT NULL DEFAULT '',
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+      UNIQUE (gateway, transaction_id)
+  );
+
+CREATE INDEX IF NOT EXISTS payments_order_id_idx ON payments (order_id);
@@ -0,0 +1,9 @@
+ALTER TABLE orders DROP COLUMN IF EXISTS customer_id;
+
+DROP TABLE IF EXISTS password_reset_tokens;
+
+DROP TABLE IF EXISTS customer_sessions;
+
+DROP TABLE IF EXISTS customer_addresses;
+
+DROP TABLE IF EXISTS customers;
@@ -0,0 +1,62 @@
+CREATE TABLE
+  IF NOT EXISTS customers (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      email TEXT NOT NULL, -- stored lowercase
+      password_hash TEXT NOT NULL, -- argon2 PHC string
+      first_name TEXT NOT NULL DEFAULT '',
+      last_name TEXT NOT NULL DEFAULT '',
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE UNIQUE INDEX IF NOT EXISTS customers_email_idx ON customers (LOWER(email));
+
+-- saved billing and shipping address of a customer, used to fill in checkout
+CREATE TABLE
+  IF NOT EXISTS customer_addresses (
+      customer_id UUID NOT NULL REFERENCES customers(id) ON DELETE CASCADE,
+      kind TEXT NOT NULL CHECK (kind IN ('billing', 'shipping')),
+      name TEXT NOT NULL DEFAULT '',
+      phone TEXT NOT NULL DEFAULT '',
+      address TEXT NOT NULL DEFAULT '',
+      city TEXT NOT NULL DEFAULT '',
+      postcode TEXT NOT NULL DEFAULT '',
+      country TEXT NOT NULL DEFAULT '',
+      updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+      PRIMARY KEY (customer_id, kind)
+  );
+
+-- logged in sessions, the cookie holds the token and only its sha256 is stored
+CREATE TABLE
+  IF NOT EXISTS customer_sessions (
+      token_hash TEXT PRIMARY KEY NOT NULL,
+      customer_id UUID NOT NULL REFERENCES customers(id) ON DELETE CASCADE,
+      expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE INDEX IF NOT EXISTS customer_sessions_customer_id_idx ON customer_sessions (customer_id);
+
+-- single use password reset links, only the sha256 of the token is stored
+CREATE TABLE
+  IF NOT EXISTS password_reset_tokens (
+      token_hash TEXT PRIMARY KEY NOT NULL,
+      customer_id UUID NOT NULL REFERENCES customers(id) ON DELETE CASCADE,
+      expires_at TIMESTAMP WITH TIME ZONE NOT NULL,
+      used_at TIMESTAMP WITH TIME ZONE,
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+ALTER TABLE orders ADD COLUMN customer_id UUID REFERENCES customers(id) ON DELETE SET NULL; -- NULL for guest checkout
+
+CREATE INDEX IF NOT EXISTS orders_customer_id_idx ON orders (customer_id);
@@ -0,0 +1,2 @@
+-- admins manage the shop and who works on it, shop managers manage products and orders
+ALTER TABLE customers ADD COLUMN role TEXT NOT NULL DEFAULT 'customer' CHECK (role IN ('admin', 'shop-manager', 'customer'));
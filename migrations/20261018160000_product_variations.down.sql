@@ -0,0 +1,21 @@
+ALTER TABLE products
+    ADD COLUMN attributes VARCHAR(255) NOT NULL DEFAULT '',
+    ADD COLUMN variations VARCHAR(255) NOT NULL DEFAULT '';
+
+ALTER TABLE order_items DROP COLUMN IF EXISTS variation_id;
+
+ALTER TABLE cart_items DROP CONSTRAINT IF EXISTS cart_items_cart_id_product_id_variation_id_key;
+ALTER TABLE cart_items DROP COLUMN IF EXISTS variation_id;
+ALTER TABLE cart_items ADD CONSTRAINT cart_items_cart_id_product_id_variation_key UNIQUE (cart_id, product_id, variation);
+
+DROP TABLE IF EXISTS product_variation_terms;
+
+DROP TABLE IF EXISTS product_variations;
+
+DROP TABLE IF EXISTS product_terms_map;
+
+DROP TABLE IF EXISTS product_attributes_map;
+
+ALTER TABLE product_terms DROP CONSTRAINT IF EXISTS product_terms_attribute_term_key;
+ALTER TABLE product_terms DROP CONSTRAINT IF EXISTS product_terms_id_key;
+ALTER TABLE product_terms DROP COLUMN IF EXISTS id;
@@ -0,0 +1,62 @@
+-- terms get their own id so products and variations can point at them
+ALTER TABLE product_terms ADD COLUMN id UUID NOT NULL DEFAULT (uuid_generate_v4());
+ALTER TABLE product_terms ADD CONSTRAINT product_terms_id_key UNIQUE (id);
+-- lets product_variation_terms check the term belongs to the attribute
+ALTER TABLE product_terms ADD CONSTRAINT product_terms_attribute_term_key UNIQUE (product_id, id);
+
+-- attributes picked for a product, in the order they are shown
+CREATE TABLE
+  IF NOT EXISTS product_attributes_map (
+      product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+      attribute_id UUID NOT NULL REFERENCES product_attributes(id) ON DELETE CASCADE,
+      position INTEGER NOT NULL DEFAULT 0,
+      PRIMARY KEY (product_id, attribute_id)
+  );
+
+-- terms of those attributes the product comes in, variations are generated from these
+CREATE TABLE
+  IF NOT EXISTS product_terms_map (
+      product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+      term_id UUID NOT NULL REFERENCES product_terms(id) ON DELETE CASCADE,
+      PRIMARY KEY (product_id, term_id)
+  );
+
+CREATE TABLE
+  IF NOT EXISTS product_variations (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+      sku TEXT NOT NULL DEFAULT '',
+      price NUMERIC(12, 2) NOT NULL DEFAULT 0 CHECK (price >= 0),
+      stock INTEGER NOT NULL DEFAULT 0,
+      shipping_weight NUMERIC(10, 3) NOT NULL DEFAULT 0 CHECK (shipping_weight >= 0), -- kg
+      image VARCHAR(255) NOT NULL DEFAULT '', -- empty uses the product image
+      enabled BOOLEAN NOT NULL DEFAULT TRUE, -- disabled variations can't be bought
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE INDEX IF NOT EXISTS product_variations_product_id_idx ON product_variations (product_id);
+
+-- the term of each attribute a variation is made of
+CREATE TABLE
+  IF NOT EXISTS product_variation_terms (
+      variation_id UUID NOT NULL REFERENCES product_variations(id) ON DELETE CASCADE,
+      attribute_id UUID NOT NULL REFERENCES product_attributes(id) ON DELETE CASCADE,
+      term_id UUID NOT NULL,
+      PRIMARY KEY (variation_id, attribute_id),
+      FOREIGN KEY (attribute_id, term_id) REFERENCES product_terms(product_id, id) ON DELETE CASCADE
+  );
+
+-- cart items point at the variation, variation stays as its label for display
+ALTER TABLE cart_items ADD COLUMN variation_id UUID REFERENCES product_variations(id) ON DELETE CASCADE;
+ALTER TABLE cart_items DROP CONSTRAINT cart_items_cart_id_product_id_variation_key;
+ALTER TABLE cart_items ADD CONSTRAINT cart_items_cart_id_product_id_variation_id_key UNIQUE NULLS NOT DISTINCT (cart_id, product_id, variation_id);
+
+ALTER TABLE order_items ADD COLUMN variation_id UUID REFERENCES product_variations(id) ON DELETE SET NULL;
+
+-- replaced by the tables above
+ALTER TABLE products DROP COLUMN attributes, DROP COLUMN variations;
@@ -0,0 +1,6 @@
+ALTER TABLE product_terms DROP CONSTRAINT product_terms_attribute_slug_key;
+ALTER TABLE product_terms DROP COLUMN created_at;
+ALTER TABLE product_terms DROP COLUMN position;
+
+ALTER TABLE product_attributes DROP CONSTRAINT product_attributes_order_by_check;
+ALTER TABLE product_attributes ALTER COLUMN order_by DROP DEFAULT;
@@ -0,0 +1,16 @@
+-- how the terms of an attribute are listed, menu_order is the drag and drop order kept in product_terms.position
+UPDATE product_attributes SET order_by = 'menu_order' WHERE order_by NOT IN ('menu_order', 'name', 'name_num', 'id');
+ALTER TABLE product_attributes ALTER COLUMN order_by SET DEFAULT 'menu_order';
+ALTER TABLE product_attributes ADD CONSTRAINT product_attributes_order_by_check CHECK (order_by IN ('menu_order', 'name', 'name_num', 'id'));
+
+ALTER TABLE product_terms ADD COLUMN position INTEGER NOT NULL DEFAULT 0;
+-- order_by id lists terms in the order they were added
+ALTER TABLE product_terms ADD COLUMN created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW();
+ALTER TABLE product_terms ADD CONSTRAINT product_terms_attribute_slug_key UNIQUE (product_id, slug);
+
+-- existing terms keep their alphabetical order as the custom order
+UPDATE product_terms t SET position = ordered.position
+FROM (
+    SELECT id, ROW_NUMBER() OVER (PARTITION BY product_id ORDER BY name)::INTEGER - 1 AS position FROM product_terms
+) ordered
+WHERE t.id = ordered.id;
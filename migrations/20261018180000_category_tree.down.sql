@@ -0,0 +1,55 @@
+ALTER TABLE product_categories DROP CONSTRAINT product_categories_display_type_check;
+DROP INDEX IF EXISTS product_categories_parent_id_idx;
+ALTER TABLE product_categories DROP CONSTRAINT product_categories_parent_id_check;
+ALTER TABLE product_categories DROP CONSTRAINT product_categories_parent_id_fkey;
+ALTER TABLE product_categories ADD CONSTRAINT product_categories_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES product_categories(id) ON DELETE CASCADE;
+
+ALTER TABLE product_categories ADD COLUMN lvl TEXT NOT NULL DEFAULT '0';
+ALTER TABLE product_categories ADD COLUMN parent TEXT NOT NULL DEFAULT '-1';
+ALTER TABLE product_categories ADD COLUMN child_categories UUID[] DEFAULT '{}';
+
+WITH RECURSIVE tree AS (
+    SELECT id, 0 AS depth FROM product_categories WHERE parent_id IS NULL
+    UNION ALL
+    SELECT c.id, tree.depth + 1 FROM product_categories c JOIN tree ON c.parent_id = tree.id
+)
+UPDATE product_categories c SET lvl = tree.depth::TEXT, parent = COALESCE(c.parent_id::TEXT, '-1')
+FROM tree WHERE tree.id = c.id;
+UPDATE product_categories p SET child_categories = ARRAY(SELECT id FROM product_categories c WHERE c.parent_id = p.id);
+ALTER TABLE product_categories ALTER COLUMN lvl DROP DEFAULT, ALTER COLUMN parent DROP DEFAULT;
+
+CREATE OR REPLACE FUNCTION update_parent_child_categories()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.parent = '-1' THEN
+        RETURN NEW;
+    END IF;
+    UPDATE product_categories
+    SET child_categories = array_append(COALESCE(child_categories, '{}'::uuid[]), NEW.id)
+    WHERE id::text = NEW.parent OR name = NEW.parent;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER after_category_insert
+AFTER INSERT ON product_categories
+FOR EACH ROW
+EXECUTE FUNCTION update_parent_child_categories();
+
+CREATE OR REPLACE FUNCTION remove_from_parent_child_categories()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF OLD.parent = '-1' THEN
+        RETURN OLD;
+    END IF;
+    UPDATE product_categories
+    SET child_categories = array_remove(child_categories, OLD.id)
+    WHERE id::text = OLD.parent OR name = OLD.parent;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER before_category_delete
+BEFORE DELETE ON product_categories
+FOR EACH ROW
+EXECUTE FUNCTION remove_from_parent_child_categories();
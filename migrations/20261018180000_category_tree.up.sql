@@ -0,0 +1,22 @@
+-- the tree is kept in parent_id alone, lvl, parent and child_categories are worked out from it when needed
+DROP TRIGGER IF EXISTS after_category_insert ON product_categories;
+DROP TRIGGER IF EXISTS before_category_delete ON product_categories;
+DROP FUNCTION IF EXISTS update_parent_child_categories();
+DROP FUNCTION IF EXISTS remove_from_parent_child_categories();
+
+-- parent held the parent's id, or its name for older rows
+UPDATE product_categories c SET parent_id = p.id
+FROM product_categories p
+WHERE c.parent_id IS NULL AND c.parent NOT IN ('', '-1') AND p.id <> c.id AND (p.id::TEXT = c.parent OR p.name = c.parent);
+
+ALTER TABLE product_categories DROP COLUMN lvl, DROP COLUMN parent, DROP COLUMN child_categories;
+
+-- deleting a category moves its children up instead of deleting them with it
+ALTER TABLE product_categories DROP CONSTRAINT product_categories_parent_id_fkey;
+ALTER TABLE product_categories ADD CONSTRAINT product_categories_parent_id_fkey FOREIGN KEY (parent_id) REFERENCES product_categories(id);
+ALTER TABLE product_categories ADD CONSTRAINT product_categories_parent_id_check CHECK (parent_id <> id);
+CREATE INDEX IF NOT EXISTS product_categories_parent_id_idx ON product_categories (parent_id);
+
+-- empty is the default display type
+UPDATE product_categories SET display_type = '' WHERE display_type NOT IN ('products', 'subcategories', 'both');
+ALTER TABLE product_categories ADD CONSTRAINT product_categories_display_type_check CHECK (display_type IN ('', 'products', 'subcategories', 'both'));
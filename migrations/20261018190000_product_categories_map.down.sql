@@ -0,0 +1,39 @@
+DROP TRIGGER IF EXISTS refresh_category_counts_on_delete ON product_categories;
+DROP TRIGGER IF EXISTS refresh_category_counts_on_move ON product_categories;
+DROP TRIGGER IF EXISTS refresh_category_counts_on_map ON product_categories_map;
+DROP TRIGGER IF EXISTS refresh_category_counts_on_publish ON products;
+DROP FUNCTION IF EXISTS refresh_parent_category_counts();
+DROP FUNCTION IF EXISTS refresh_map_category_counts();
+DROP FUNCTION IF EXISTS refresh_product_category_counts();
+DROP FUNCTION IF EXISTS refresh_category_counts(UUID[]);
+ALTER TABLE product_categories DROP COLUMN total_count;
+
+-- a product keeps the first of its categories by name
+ALTER TABLE products ADD COLUMN category VARCHAR(100) NOT NULL DEFAULT '';
+UPDATE products p SET category = (
+    SELECT c.name FROM product_categories_map m
+    JOIN product_categories c ON c.id = m.category_id
+    WHERE m.product_id = p.id
+    ORDER BY c.name LIMIT 1
+)
+WHERE EXISTS (SELECT 1 FROM product_categories_map m WHERE m.product_id = p.id);
+ALTER TABLE products ALTER COLUMN category DROP DEFAULT;
+
+DROP TABLE IF EXISTS product_categories_map;
+
+CREATE OR REPLACE FUNCTION update_category_count()
+RETURNS TRIGGER AS $$
+BEGIN
+    -- Increment the count for the category of the new product
+    UPDATE product_categories
+    SET count = count + 1
+    WHERE name = NEW.category;
+
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER after_product_insert
+AFTER INSERT ON products
+FOR EACH ROW
+EXECUTE FUNCTION update_category_count();
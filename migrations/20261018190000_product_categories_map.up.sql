@@ -0,0 +1,131 @@
+-- products belong to any number of categories by id instead of a single category name
+CREATE TABLE IF NOT EXISTS product_categories_map (
+    product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    category_id UUID NOT NULL REFERENCES product_categories(id) ON DELETE CASCADE,
+    PRIMARY KEY (product_id, category_id)
+);
+CREATE INDEX IF NOT EXISTS product_categories_map_category_id_idx ON product_categories_map (category_id);
+
+-- names aren't unique, the oldest category with the name wins, unmatched products go to Uncategorized
+INSERT INTO product_categories_map (product_id, category_id)
+SELECT p.id, COALESCE(
+    (SELECT c.id FROM product_categories c WHERE c.name = p.category ORDER BY c.created_at, c.id LIMIT 1),
+    (SELECT c.id FROM product_categories c WHERE c.slug = 'uncategorized')
+)
+FROM products p
+WHERE EXISTS (SELECT 1 FROM product_categories c WHERE c.name = p.category OR c.slug = 'uncategorized');
+
+DROP TRIGGER IF EXISTS after_product_insert ON products;
+DROP FUNCTION IF EXISTS update_category_count();
+ALTER TABLE products DROP COLUMN category;
+
+-- count is the published products in the category itself,
+-- total_count also counts the ones in its subcategories, once each
+ALTER TABLE product_categories ADD COLUMN total_count INTEGER NOT NULL DEFAULT 0;
+
+-- Works out the counts of the given categories and their ancestors, the only ones a change can affect
+CREATE OR REPLACE FUNCTION refresh_category_counts(category_ids UUID[])
+RETURNS VOID AS $$
+DECLARE
+    affected UUID[];
+BEGIN
+    WITH RECURSIVE ancestors AS (
+        SELECT id, parent_id FROM product_categories WHERE id = ANY(category_ids)
+        UNION
+        SELECT c.id, c.parent_id FROM product_categories c
+        JOIN ancestors a ON c.id = a.parent_id
+    )
+    SELECT ARRAY_AGG(id) INTO affected FROM ancestors;
+    IF affected IS NULL THEN
+        RETURN;
+    END IF;
+
+    -- locked in id order, so refreshes of the same categories wait for each other
+    -- and the counts below are worked out from what the one before committed
+    PERFORM 1 FROM product_categories WHERE id = ANY(affected) ORDER BY id FOR UPDATE;
+
+    WITH RECURSIVE subtree AS (
+        SELECT id AS ancestor_id, id AS category_id FROM product_categories WHERE id = ANY(affected)
+        UNION
+        SELECT s.ancestor_id, c.id FROM subtree s
+        JOIN product_categories c ON c.parent_id = s.category_id
+    ),
+    counts AS (
+        SELECT c.id,
+        (SELECT COUNT(*) FROM product_categories_map m
+            JOIN products p ON p.id = m.product_id
+            WHERE m.category_id = c.id AND p.published)::INTEGER AS count,
+        (SELECT COUNT(DISTINCT m.product_id) FROM subtree s
+            JOIN product_categories_map m ON m.category_id = s.category_id
+            JOIN products p ON p.id = m.product_id
+            WHERE s.ancestor_id = c.id AND p.published)::INTEGER AS total_count
+        FROM product_categories c
+        WHERE c.id = ANY(affected)
+    )
+    UPDATE product_categories c SET count = counts.count, total_count = counts.total_count
+    FROM counts
+    WHERE counts.id = c.id AND (c.count, c.total_count) IS DISTINCT FROM (counts.count, counts.total_count);
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_product_category_counts()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM refresh_category_counts(ARRAY(SELECT category_id FROM product_categories_map WHERE product_id = NEW.id));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_map_category_counts()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM refresh_category_counts(ARRAY[NEW.category_id]);
+    ELSIF TG_OP = 'DELETE' THEN
+        PERFORM refresh_category_counts(ARRAY[OLD.category_id]);
+    ELSE
+        PERFORM refresh_category_counts(ARRAY[OLD.category_id, NEW.category_id]);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- the totals of the parents a category was moved from and to, or deleted from
+CREATE OR REPLACE FUNCTION refresh_parent_category_counts()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' THEN
+        PERFORM refresh_category_counts(ARRAY[OLD.parent_id]);
+    ELSE
+        PERFORM refresh_category_counts(ARRAY[OLD.parent_id, NEW.parent_id]);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- the product forms write published on every save, only a change refreshes the counts.
+-- Inserting or deleting a product changes its map rows, which refresh the counts through the map trigger
+CREATE TRIGGER refresh_category_counts_on_publish
+AFTER UPDATE OF published ON products
+FOR EACH ROW
+WHEN (OLD.published IS DISTINCT FROM NEW.published)
+EXECUTE FUNCTION refresh_product_category_counts();
+
+CREATE TRIGGER refresh_category_counts_on_map
+AFTER INSERT OR UPDATE OR DELETE ON product_categories_map
+FOR EACH ROW
+EXECUTE FUNCTION refresh_map_category_counts();
+
+CREATE TRIGGER refresh_category_counts_on_move
+AFTER UPDATE OF parent_id ON product_categories
+FOR EACH ROW
+WHEN (OLD.parent_id IS DISTINCT FROM NEW.parent_id)
+EXECUTE FUNCTION refresh_parent_category_counts();
+
+CREATE TRIGGER refresh_category_counts_on_delete
+AFTER DELETE ON product_categories
+FOR EACH ROW
+EXECUTE FUNCTION refresh_parent_category_counts();
+
+-- the old counts were never decremented, start from the real ones
+SELECT refresh_category_counts(ARRAY(SELECT id FROM product_categories));
@@ -0,0 +1,7 @@
+DROP TRIGGER IF EXISTS refresh_tag_counts_on_map ON product_tags_map;
+DROP TRIGGER IF EXISTS refresh_tag_counts_on_publish ON products;
+DROP FUNCTION IF EXISTS refresh_map_tag_counts();
+DROP FUNCTION IF EXISTS refresh_product_tag_counts();
+DROP FUNCTION IF EXISTS refresh_tag_counts(UUID[]);
+DROP TABLE IF EXISTS product_tags_map;
+DROP TABLE IF EXISTS product_tags;
@@ -0,0 +1,74 @@
+CREATE TABLE IF NOT EXISTS product_tags (
+    id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+    name VARCHAR(200) NOT NULL,
+    slug VARCHAR(200) NOT NULL UNIQUE,
+    description TEXT NOT NULL DEFAULT '',
+    -- published products with the tag
+    count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW(),
+    updated_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+-- tags typed on the product form are matched by name, whatever the case
+CREATE UNIQUE INDEX IF NOT EXISTS product_tags_name_idx ON product_tags (LOWER(name));
+
+CREATE TABLE IF NOT EXISTS product_tags_map (
+    product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    tag_id UUID NOT NULL REFERENCES product_tags(id) ON DELETE CASCADE,
+    PRIMARY KEY (product_id, tag_id)
+);
+CREATE INDEX IF NOT EXISTS product_tags_map_tag_id_idx ON product_tags_map (tag_id);
+
+-- Works out the counts of the given tags
+CREATE OR REPLACE FUNCTION refresh_tag_counts(tag_ids UUID[])
+RETURNS VOID AS $$
+BEGIN
+    -- locked in id order like refresh_category_counts, so the counts are worked out from what was committed before
+    PERFORM 1 FROM product_tags WHERE id = ANY(tag_ids) ORDER BY id FOR UPDATE;
+
+    WITH counts AS (
+        SELECT t.id, (
+            SELECT COUNT(*) FROM product_tags_map m
+            JOIN products p ON p.id = m.product_id
+            WHERE m.tag_id = t.id AND p.published
+        )::INTEGER AS count
+        FROM product_tags t
+        WHERE t.id = ANY(tag_ids)
+    )
+    UPDATE product_tags t SET count = counts.count
+    FROM counts
+    WHERE counts.id = t.id AND t.count <> counts.count;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_product_tag_counts()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM refresh_tag_counts(ARRAY(SELECT tag_id FROM product_tags_map WHERE product_id = NEW.id));
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_map_tag_counts()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        PERFORM refresh_tag_counts(ARRAY[NEW.tag_id]);
+    ELSIF TG_OP = 'DELETE' THEN
+        PERFORM refresh_tag_counts(ARRAY[OLD.tag_id]);
+    ELSE
+        PERFORM refresh_tag_counts(ARRAY[OLD.tag_id, NEW.tag_id]);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER refresh_tag_counts_on_publish
+AFTER UPDATE OF published ON products
+FOR EACH ROW
+WHEN (OLD.published IS DISTINCT FROM NEW.published)
+EXECUTE FUNCTION refresh_product_tag_counts();
+
+CREATE TRIGGER refresh_tag_counts_on_map
+AFTER INSERT OR UPDATE OR DELETE ON product_tags_map
+FOR EACH ROW
+EXECUTE FUNCTION refresh_map_tag_counts();
@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS refresh_product_rating_on_review ON product_reviews;
+DROP FUNCTION IF EXISTS refresh_product_rating_trigger();
+DROP FUNCTION IF EXISTS refresh_product_rating(UUID);
+ALTER TABLE products DROP COLUMN average_rating, DROP COLUMN rating_count;
+DROP TABLE IF EXISTS product_reviews;
@@ -0,0 +1,67 @@
+-- statuses follow WordPress comments, new reviews wait in pending until they are approved
+CREATE TABLE
+  IF NOT EXISTS product_reviews (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+      -- replies from the shop have the review they answer as parent and no rating
+      parent_id UUID REFERENCES product_reviews(id) ON DELETE CASCADE,
+      customer_id UUID REFERENCES customers(id) ON DELETE SET NULL, -- NULL for guests
+      author_name TEXT NOT NULL,
+      author_email TEXT NOT NULL,
+      rating INTEGER CHECK (rating BETWEEN 1 AND 5),
+      content TEXT NOT NULL,
+      status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'approved', 'spam', 'trash')),
+      -- the author had bought the product when the review was posted
+      verified BOOLEAN NOT NULL DEFAULT FALSE,
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+          updated_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW(),
+      CHECK ((parent_id IS NULL) = (rating IS NOT NULL))
+  );
+
+CREATE INDEX IF NOT EXISTS product_reviews_product_id_idx ON product_reviews (product_id, status);
+CREATE INDEX IF NOT EXISTS product_reviews_status_idx ON product_reviews (status);
+CREATE INDEX IF NOT EXISTS product_reviews_parent_id_idx ON product_reviews (parent_id);
+
+-- approved ratings, cached so products can be sorted by them
+ALTER TABLE products
+    ADD COLUMN average_rating NUMERIC(3, 2) NOT NULL DEFAULT 0,
+    ADD COLUMN rating_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE OR REPLACE FUNCTION refresh_product_rating(product UUID)
+RETURNS VOID AS $$
+BEGIN
+    -- the row lock makes a second refresh of the same product wait and see the reviews committed by the first
+    PERFORM 1 FROM products WHERE id = product FOR UPDATE;
+
+    UPDATE products p SET average_rating = r.average, rating_count = r.count
+    FROM (
+        SELECT COALESCE(ROUND(AVG(rating), 2), 0) AS average, COUNT(*)::INTEGER AS count
+        FROM product_reviews
+        WHERE product_id = product AND parent_id IS NULL AND status = 'approved'
+    ) r
+    WHERE p.id = product AND (p.average_rating, p.rating_count) IS DISTINCT FROM (r.average, r.count);
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_product_rating_trigger()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        PERFORM refresh_product_rating(OLD.product_id);
+    END IF;
+    IF TG_OP = 'INSERT' OR (TG_OP = 'UPDATE' AND NEW.product_id <> OLD.product_id) THEN
+        PERFORM refresh_product_rating(NEW.product_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER refresh_product_rating_on_review
+AFTER INSERT OR UPDATE OF product_id, parent_id, rating, status OR DELETE ON product_reviews
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_rating_trigger();
@@ -0,0 +1 @@
+DROP FUNCTION IF EXISTS category_filter_matches(UUID, NUMERIC, NUMERIC, BOOLEAN, UUID[]);
@@ -0,0 +1,35 @@
+-- the published products of a category and its subcategories for the category pages, with the price range and
+-- stock of each (a variable product's come from its enabled variations) and whether it passes each filter.
+-- A product passes the term filter when it has one of the picked terms of every attribute they belong to
+CREATE OR REPLACE FUNCTION category_filter_matches(category UUID, min NUMERIC, max NUMERIC, only_in_stock BOOLEAN, term_ids UUID[])
+RETURNS TABLE (id UUID, min_price NUMERIC, max_price NUMERIC, in_stock BOOLEAN, price_ok BOOLEAN, stock_ok BOOLEAN, terms_ok BOOLEAN) AS $$
+    WITH RECURSIVE tree AS (
+        SELECT id FROM product_categories WHERE id = category
+        UNION ALL
+        SELECT c.id FROM product_categories c JOIN tree ON c.parent_id = tree.id
+    ),
+    facts AS (
+        SELECT p.id,
+            COALESCE(v.min_price, p.price) AS min_price,
+            COALESCE(v.max_price, p.price) AS max_price,
+            COALESCE(v.in_stock, p.stock > 0) AS in_stock
+        FROM products p
+        LEFT JOIN LATERAL (
+            SELECT MIN(price) AS min_price, MAX(price) AS max_price, BOOL_OR(stock > 0) AS in_stock
+            FROM product_variations WHERE product_id = p.id AND enabled
+        ) v ON TRUE
+        WHERE p.published AND EXISTS (
+            SELECT 1 FROM product_categories_map m JOIN tree ON tree.id = m.category_id WHERE m.product_id = p.id
+        )
+    )
+    SELECT f.id, f.min_price, f.max_price, f.in_stock,
+        (min IS NULL OR f.max_price >= min) AND (max IS NULL OR f.min_price <= max),
+        NOT only_in_stock OR f.in_stock,
+        NOT EXISTS (
+            SELECT 1 FROM product_terms ft WHERE ft.id = ANY(term_ids) AND NOT EXISTS (
+                SELECT 1 FROM product_terms_map tm JOIN product_terms t ON t.id = tm.term_id
+                WHERE tm.product_id = f.id AND t.product_id = ft.product_id AND t.id = ANY(term_ids)
+            )
+        )
+    FROM facts f;
+$$ LANGUAGE sql STABLE;
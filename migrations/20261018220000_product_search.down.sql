@@ -0,0 +1,9 @@
+DROP TRIGGER IF EXISTS refresh_product_search_on_tag_rename ON product_tags;
+DROP TRIGGER IF EXISTS refresh_product_search_on_category_rename ON product_categories;
+DROP TRIGGER IF EXISTS refresh_product_search_on_tag_map ON product_tags_map;
+DROP TRIGGER IF EXISTS refresh_product_search_on_category_map ON product_categories_map;
+DROP TRIGGER IF EXISTS refresh_product_search_on_product ON products;
+DROP FUNCTION IF EXISTS refresh_product_search_trigger();
+DROP FUNCTION IF EXISTS refresh_product_search(UUID);
+DROP INDEX IF EXISTS products_title_trgm_idx;
+DROP TABLE IF EXISTS product_search;
@@ -0,0 +1,80 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- full-text search document of each product, kept up to date by the triggers below.
+-- Words are stored stemmed (english) and as typed (simple), so prefixes of a word being typed match too
+CREATE TABLE IF NOT EXISTS product_search (
+    product_id UUID PRIMARY KEY REFERENCES products(id) ON DELETE CASCADE,
+    document TSVECTOR NOT NULL
+);
+CREATE INDEX IF NOT EXISTS product_search_document_idx ON product_search USING GIN (document);
+
+-- typo tolerant matching of titles when the full-text search finds nothing
+CREATE INDEX IF NOT EXISTS products_title_trgm_idx ON products USING GIN (title gin_trgm_ops);
+
+-- title and sku weigh the most, then category and tag names, then the description
+CREATE OR REPLACE FUNCTION refresh_product_search(product UUID)
+RETURNS VOID AS $$
+BEGIN
+    INSERT INTO product_search (product_id, document)
+    SELECT p.id,
+        setweight(to_tsvector('english', p.title) || to_tsvector('simple', p.title || ' ' || p.sku), 'A') ||
+        setweight(to_tsvector('english', names.names) || to_tsvector('simple', names.names), 'B') ||
+        setweight(to_tsvector('english', p.description) || to_tsvector('simple', p.description), 'C')
+    FROM products p, LATERAL (
+        SELECT CONCAT_WS(' ',
+            (SELECT STRING_AGG(c.name, ' ') FROM product_categories_map m
+             JOIN product_categories c ON c.id = m.category_id WHERE m.product_id = p.id),
+            (SELECT STRING_AGG(t.name, ' ') FROM product_tags_map m
+             JOIN product_tags t ON t.id = m.tag_id WHERE m.product_id = p.id)
+        ) AS names
+    ) names
+    WHERE p.id = product
+    ON CONFLICT (product_id) DO UPDATE SET document = EXCLUDED.document;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_product_search_trigger()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'products' THEN
+        PERFORM refresh_product_search(NEW.id);
+    ELSIF TG_TABLE_NAME = 'product_categories' THEN
+        PERFORM refresh_product_search(m.product_id) FROM product_categories_map m WHERE m.category_id = NEW.id;
+    ELSIF TG_TABLE_NAME = 'product_tags' THEN
+        PERFORM refresh_product_search(m.product_id) FROM product_tags_map m WHERE m.tag_id = NEW.id;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- also runs when the product itself is being deleted, it is gone by then and nothing is written
+        PERFORM refresh_product_search(OLD.product_id);
+    ELSE
+        PERFORM refresh_product_search(NEW.product_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER refresh_product_search_on_product
+AFTER INSERT OR UPDATE OF title, description, sku ON products
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_search_trigger();
+
+CREATE TRIGGER refresh_product_search_on_category_map
+AFTER INSERT OR DELETE ON product_categories_map
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_search_trigger();
+
+CREATE TRIGGER refresh_product_search_on_tag_map
+AFTER INSERT OR DELETE ON product_tags_map
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_search_trigger();
+
+CREATE TRIGGER refresh_product_search_on_category_rename
+AFTER UPDATE OF name ON product_categories
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_search_trigger();
+
+CREATE TRIGGER refresh_product_search_on_tag_rename
+AFTER UPDATE OF name ON product_tags
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_search_trigger();
+
+SELECT refresh_product_search(id) FROM products;
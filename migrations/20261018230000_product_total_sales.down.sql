@@ -0,0 +1,5 @@
+DROP TRIGGER IF EXISTS refresh_product_sales_on_item ON order_items;
+DROP TRIGGER IF EXISTS refresh_product_sales_on_status ON orders;
+DROP FUNCTION IF EXISTS refresh_product_sales_trigger();
+DROP FUNCTION IF EXISTS refresh_product_sales(UUID);
+ALTER TABLE products DROP COLUMN total_sales;
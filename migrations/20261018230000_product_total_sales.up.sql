@@ -0,0 +1,51 @@
+-- items sold in orders that are paid for or waiting on payment by bank transfer, for sorting by popularity
+ALTER TABLE products ADD COLUMN total_sales INTEGER NOT NULL DEFAULT 0;
+
+CREATE OR REPLACE FUNCTION refresh_product_sales(product UUID)
+RETURNS VOID AS $$
+BEGIN
+    -- the row lock makes a second refresh of the same product wait, like refresh_product_rating
+    PERFORM 1 FROM products WHERE id = product FOR UPDATE;
+
+    UPDATE products p SET total_sales = s.total
+    FROM (
+        SELECT COALESCE(SUM(i.quantity), 0)::INTEGER AS total
+        FROM order_items i JOIN orders o ON o.id = i.order_id
+        WHERE i.product_id = product AND o.status IN ('processing', 'on-hold', 'completed')
+    ) s
+    WHERE p.id = product AND p.total_sales <> s.total;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION refresh_product_sales_trigger()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_TABLE_NAME = 'orders' THEN
+        PERFORM refresh_product_sales(product_id)
+        FROM (SELECT DISTINCT product_id FROM order_items WHERE order_id = NEW.id AND product_id IS NOT NULL) items;
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP <> 'INSERT' AND OLD.product_id IS NOT NULL THEN
+        PERFORM refresh_product_sales(OLD.product_id);
+    END IF;
+    IF TG_OP <> 'DELETE' AND NEW.product_id IS NOT NULL
+        AND (TG_OP = 'INSERT' OR NEW.product_id IS DISTINCT FROM OLD.product_id) THEN
+        PERFORM refresh_product_sales(NEW.product_id);
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER refresh_product_sales_on_status
+AFTER UPDATE OF status ON orders
+FOR EACH ROW
+WHEN (OLD.status IS DISTINCT FROM NEW.status)
+EXECUTE FUNCTION refresh_product_sales_trigger();
+
+CREATE TRIGGER refresh_product_sales_on_item
+AFTER INSERT OR UPDATE OF product_id, quantity, order_id OR DELETE ON order_items
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_sales_trigger();
+
+SELECT refresh_product_sales(id) FROM products;
@@ -0,0 +1,20 @@
+-- consumer key and secret pairs for machine clients of /api/v1, both are only stored as their sha256.
+-- A key acts as the account that made it, so it stops working when the account is no longer a shop manager
+CREATE TABLE
+  IF NOT EXISTS api_keys (
+      id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+      customer_id UUID NOT NULL REFERENCES customers(id) ON DELETE CASCADE,
+      description TEXT NOT NULL,
+      permissions TEXT NOT NULL DEFAULT 'read' CHECK (permissions IN ('read', 'write', 'read-write')),
+      consumer_key_hash TEXT NOT NULL UNIQUE,
+      consumer_secret_hash TEXT NOT NULL,
+      -- last characters of the consumer key, to tell keys apart on the API keys page
+      truncated_key TEXT NOT NULL,
+      last_access TIMESTAMP WITH TIME ZONE,
+      revoked_at TIMESTAMP WITH TIME ZONE,
+      created_at TIMESTAMP
+      WITH
+          TIME ZONE DEFAULT NOW()
+  );
+
+CREATE INDEX IF NOT EXISTS api_keys_customer_id_idx ON api_keys (customer_id);
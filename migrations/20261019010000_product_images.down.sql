@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS refresh_product_gallery_on_image ON product_images;
+DROP FUNCTION IF EXISTS refresh_product_gallery();
+ALTER TABLE products ALTER COLUMN product_gallery DROP DEFAULT;
+DROP TABLE IF EXISTS product_images;
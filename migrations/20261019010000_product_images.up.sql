@@ -0,0 +1,40 @@
+-- every image of a product, in gallery order. The featured one is the main image on the product page
+CREATE TABLE IF NOT EXISTS product_images (
+    id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+    product_id UUID NOT NULL REFERENCES products(id) ON DELETE CASCADE,
+    path VARCHAR(255) NOT NULL,
+    alt_text VARCHAR(255) NOT NULL DEFAULT '',
+    position INTEGER NOT NULL DEFAULT 0,
+    featured BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS product_images_product_id_idx ON product_images (product_id, position);
+CREATE UNIQUE INDEX IF NOT EXISTS product_images_featured_idx ON product_images (product_id) WHERE featured;
+
+INSERT INTO product_images (product_id, path, featured)
+SELECT id, product_gallery, TRUE FROM products WHERE product_gallery <> '';
+
+-- product_gallery stays as a copy of the path of the featured image, or the first one when none is featured,
+-- so the listings, the cart and search don't have to join the images
+ALTER TABLE products ALTER COLUMN product_gallery SET DEFAULT '';
+
+CREATE OR REPLACE FUNCTION refresh_product_gallery()
+RETURNS TRIGGER AS $$
+DECLARE
+    product UUID := CASE WHEN TG_OP = 'DELETE' THEN OLD.product_id ELSE NEW.product_id END;
+BEGIN
+    UPDATE products p SET product_gallery = COALESCE((
+        SELECT i.path FROM product_images i
+        WHERE i.product_id = product
+        ORDER BY i.featured DESC, i.position, i.created_at, i.id
+        LIMIT 1
+    ), '')
+    WHERE p.id = product;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER refresh_product_gallery_on_image
+AFTER INSERT OR UPDATE OF path, position, featured OR DELETE ON product_images
+FOR EACH ROW
+EXECUTE FUNCTION refresh_product_gallery();
@@ -0,0 +1,25 @@
+-- every uploaded image, for the media library. Uploads are named by their content so path is unique,
+-- files stored before the library existed are added by scanning the storage from the library page
+CREATE TABLE IF NOT EXISTS media (
+    id UUID PRIMARY KEY NOT NULL DEFAULT (uuid_generate_v4()),
+    path VARCHAR(255) NOT NULL UNIQUE,
+    file_name VARCHAR(255) NOT NULL DEFAULT '',
+    mime_type VARCHAR(50) NOT NULL,
+    size BIGINT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    -- the widths of the sizes stored next to the original, in ImageSize order (thumbnail, medium, large), and whether
+    -- they have WebP versions, so pages build srcset without looking at the storage. Empty for originals without sizes
+    widths INTEGER[] NOT NULL DEFAULT '{}',
+    webp BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+);
+CREATE INDEX IF NOT EXISTS media_created_at_idx ON media (created_at DESC);
+
+-- where each image is used, media that isn't in here can be deleted
+CREATE OR REPLACE VIEW media_references AS
+SELECT path, product_id, NULL::UUID AS category_id FROM product_images
+UNION
+SELECT image, product_id, NULL FROM product_variations WHERE image <> ''
+UNION
+SELECT thumbnail, NULL, id FROM product_categories;
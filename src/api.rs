@@ -0,0 +1,1530 @@
+//Versioned JSON REST API at /api/v1 for shop managers, shaped like the WooCommerce REST API where it fits the shop:
+//ids are UUIDs, money is sent as strings and lists are paged with ?page=&per_page= and the X-WP-Total,
+//X-WP-TotalPages and Link headers. Writes go through the same validation and database functions as the admin pages
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::JsonRejection, rejection::QueryRejection, Path, Query, State},
+    http::{header::LINK, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    error::{AppError, ErrorBody},
+    images,
+    handler::{
+        check_variation, delete_attribute, delete_order, delete_product, delete_tag, delete_terms, find_attribute, find_category,
+        find_order_items, find_product, find_tag, find_term, find_terms, find_variations, insert_attribute, insert_category, insert_order,
+        insert_product, insert_tag, insert_term, lock_order, move_order, remove_category, site_url, update_attribute, update_category,
+        update_product, update_tag, update_term, NewOrderItem, CATEGORY_PLACEHOLDER,
+    },
+    model::{
+        ApiAttribute, ApiBilling, ApiCategory, ApiImage, ApiOrder, ApiOrderLine, ApiProduct, ApiProductAttribute, ApiProductDimensions,
+        ApiTag, ApiTerm, ApiTermLink, BackorderStatus, OrderItemModel, OrderModel, OrderStatus, ProductAttributes, ProductCategories,
+        ProductModel, ProductTags, ProductTerms, TermOrder,
+    },
+    schema::{
+        rename_errors, slugify, split_name, ApiListOptions, ApiOrderSchema, ApiProductSchema, ApiTermRef, ApiTermSchema, AttributeSchema,
+        CategorySchema, CheckoutSchema, CreateProductSchema, FieldErrors, Pagination, ProductFields, TagSchema, TermSchema, UpdateProductSchema,
+        API_ORDER_FIELDS, API_PRODUCT_FIELDS,
+    },
+    AppState,
+};
+
+type ListQuery = Result<Query<ApiListOptions>, QueryRejection>;
+type ListParams = Query<Vec<(String, String)>>;
+
+//A page of a list with the X-WP-Total and X-WP-TotalPages headers and a Link header to the pages around it.
+//params is the query string of the request, the links keep everything but page
+fn paged_json<T: Serialize>(path: &str, params: &[(String, String)], opts: &ApiListOptions, total: i64, items: Vec<T>) -> Response {
+    let params: Vec<(String, String)> = params.iter().filter(|(name, _)| name != "page").cloned().collect();
+    let pagination = Pagination::new(path, &params, &opts.filter_options(), total);
+    let pages = (total.max(0) as usize).div_ceil(opts.per_page());
+    let links: Vec<String> = [("prev", pagination.prev_url), ("next", pagination.next_url)]
+        .into_iter()
+        .filter_map(|(rel, url)| url.map(|url| format!("<{}{}>; rel=\"{}\"", site_url(), url, rel)))
+        .collect();
+
+    let mut response = Json(items).into_response();
+    let headers = response.headers_mut();
+    headers.insert("x-wp-total", HeaderValue::from(total));
+    headers.insert("x-wp-totalpages", HeaderValue::from(pages));
+    if let Ok(link) = HeaderValue::from_str(&links.join(", ")) {
+        if !links.is_empty() {
+            headers.insert(LINK, link);
+        }
+    }
+    response
+}
+
+//what category, term and tag lists can be sorted by
+trait Listed {
+    fn name(&self) -> &str;
+    fn slug(&self) -> &str;
+    fn count(&self) -> i64;
+}
+
+macro_rules! listed {
+    ($($resource:ty),*) => {
+        $(impl Listed for $resource {
+            fn name(&self) -> &str {
+                &self.name
+            }
+            fn slug(&self) -> &str {
+                &self.slug
+            }
+            fn count(&self) -> i64 {
+                self.count.into()
+            }
+        })*
+    };
+}
+
+listed!(ApiCategory, ApiTerm, ApiTag);
+
+//Sorts a category, tag or term list by orderby, which is name, slug or count. None keeps the order it's in
+fn sort_listed<T: Listed>(items: &mut [T], opts: &ApiListOptions, default: Option<&str>) -> Result<(), AppError> {
+    let orderby = match opts.orderby.as_deref().or(default) {
+        None => return Ok(()),
+        Some(orderby @ ("name" | "slug" | "count")) => orderby,
+        Some(orderby) => return Err(AppError::BadRequest(format!("orderby must be name, slug or count, got \"{}\"", orderby))),
+    };
+    let ascending = opts.ascending(true).map_err(AppError::BadRequest)?;
+    items.sort_by(|a, b| {
+        let ordering = match orderby {
+            "count" => a.count().cmp(&b.count()),
+            "slug" => a.slug().to_lowercase().cmp(&b.slug().to_lowercase()),
+            _ => a.name().to_lowercase().cmp(&b.name().to_lowercase()),
+        };
+        if ascending {
+            ordering
+        } else {
+            ordering.reverse()
+        }
+    });
+    Ok(())
+}
+
+fn page_of<T>(items: Vec<T>, opts: &ApiListOptions) -> Vec<T> {
+    items.into_iter().skip(opts.offset()).take(opts.per_page()).collect()
+}
+
+//only the part of the name or description that matches search is needed
+fn matches_search(opts: &ApiListOptions, texts: &[&str]) -> bool {
+    match opts.search.as_deref().map(str::trim).filter(|search| !search.is_empty()) {
+        None => true,
+        Some(search) => {
+            let search = search.to_lowercase();
+            texts.iter().any(|text| text.to_lowercase().contains(&search))
+        }
+    }
+}
+
+//products
+
+//categories, tags, attributes and variations of the products on a page, fetched for all of them at once
+#[derive(Default)]
+struct ProductLinks {
+    categories: HashMap<Uuid, Vec<ApiTermLink>>,
+    tags: HashMap<Uuid, Vec<ApiTermLink>>,
+    attributes: HashMap<Uuid, Vec<ApiProductAttribute>>,
+    //id, stock and enabled of every variation
+    variations: HashMap<Uuid, Vec<(Uuid, i32, bool)>>,
+    //the featured image first, then the rest of the gallery
+    images: HashMap<Uuid, Vec<ApiImage>>,
+}
+
+async fn product_links(data: &AppState, ids: &[Uuid]) -> Result<ProductLinks, AppError> {
+    let mut links = ProductLinks::default();
+
+    let rows = sqlx::query!(
+        "SELECT m.product_id, c.id, c.name, c.slug FROM product_categories_map m
+        JOIN product_categories c ON c.id = m.category_id
+        WHERE m.product_id = ANY($1)
+        ORDER BY c.name, c.id",
+        ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        links
+            .categories
+            .entry(row.product_id)
+            .or_default()
+            .push(ApiTermLink { id: row.id, name: row.name, slug: row.slug });
+    }
+
+    let rows = sqlx::query!(
+        "SELECT m.product_id, t.id, t.name, t.slug FROM product_tags_map m
+        JOIN product_tags t ON t.id = m.tag_id
+        WHERE m.product_id = ANY($1)
+        ORDER BY LOWER(t.name), t.id",
+        ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        links
+            .tags
+            .entry(row.product_id)
+            .or_default()
+            .push(ApiTermLink { id: row.id, name: row.name, slug: row.slug });
+    }
+
+    //the attributes picked for each product with the names of the terms it comes in
+    let rows = sqlx::query!(
+        r#"SELECT am.product_id, am.position, a.id, a.name, a.slug,
+            COALESCE(ARRAY_AGG(t.name ORDER BY t.position, t.name) FILTER (WHERE t.id IS NOT NULL), '{}') AS "options!"
+        FROM product_attributes_map am
+        JOIN product_attributes a ON a.id = am.attribute_id
+        LEFT JOIN product_terms_map tm ON tm.product_id = am.product_id
+        LEFT JOIN product_terms t ON t.id = tm.term_id AND t.product_id = a.id
+        WHERE am.product_id = ANY($1)
+        GROUP BY am.product_id, am.position, a.id, a.name, a.slug
+        ORDER BY am.position, a.name"#,
+        ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        links.attributes.entry(row.product_id).or_default().push(ApiProductAttribute {
+            id: row.id,
+            name: row.name,
+            slug: row.slug,
+            position: row.position,
+            variation: false,
+            options: row.options,
+        });
+    }
+
+    let rows = sqlx::query!(
+        "SELECT product_id, id, stock, enabled FROM product_variations WHERE product_id = ANY($1) ORDER BY created_at, id",
+        ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        links.variations.entry(row.product_id).or_default().push((row.id, row.stock, row.enabled));
+    }
+
+    let rows = sqlx::query!(
+        "SELECT product_id, path, alt_text FROM product_images WHERE product_id = ANY($1)
+        ORDER BY featured DESC, position, created_at, id",
+        ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        links.images.entry(row.product_id).or_default().push(ApiImage {
+            src: images::absolute_url(&row.path),
+            alt: row.alt_text,
+        });
+    }
+
+    Ok(links)
+}
+
+//instock, outofstock or onbackorder, variable products are in stock when one of their enabled variations is
+fn stock_status(product: &ProductModel, variations: &[(Uuid, i32, bool)]) -> &'static str {
+    let enabled: Vec<i32> = variations.iter().filter(|variation| variation.2).map(|variation| variation.1).collect();
+    let in_stock = if enabled.is_empty() {
+        product.stock > 0
+    } else {
+        enabled.iter().any(|stock| *stock > 0)
+    };
+    if in_stock {
+        "instock"
+    } else if product.allow_backorders != BackorderStatus::No {
+        "onbackorder"
+    } else {
+        "outofstock"
+    }
+}
+
+fn product_json(product: &ProductModel, links: &ProductLinks) -> ApiProduct {
+    let variations = links.variations.get(&product.id).map_or(&[][..], |variations| variations.as_slice());
+    let has_variations = !variations.is_empty();
+
+    ApiProduct {
+        id: product.id,
+        name: product.title.clone(),
+        permalink: format!("{}/product/{}", site_url(), product.id),
+        date_created: product.created_at,
+        date_modified: product.updated_at,
+        product_type: if has_variations { "variable" } else { "simple" }.to_string(),
+        status: if product.published.unwrap_or(false) { "publish" } else { "draft" }.to_string(),
+        description: product.description.clone(),
+        sku: product.sku.clone(),
+        price: product.price,
+        regular_price: product.price,
+        is_virtual: product.product_type == "digital",
+        total_sales: product.total_sales,
+        tax_status: product.tax_status.clone(),
+        tax_class: product.tax_class.clone(),
+        manage_stock: true,
+        stock_quantity: product.stock,
+        stock_status: stock_status(product, variations).to_string(),
+        backorders: product.allow_backorders,
+        low_stock_amount: product.low_stock_threshold,
+        weight: product.shipping_weight,
+        dimensions: ApiProductDimensions {
+            length: product.shipping_length,
+            width: product.shipping_width,
+            height: product.shipping_height,
+        },
+        shipping_class: product.shipping_class.clone(),
+        average_rating: product.average_rating,
+        rating_count: product.rating_count,
+        categories: links.categories.get(&product.id).cloned().unwrap_or_default(),
+        tags: links.tags.get(&product.id).cloned().unwrap_or_default(),
+        images: links.images.get(&product.id).cloned().unwrap_or_default(),
+        attributes: links
+            .attributes
+            .get(&product.id)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|attribute| ApiProductAttribute {
+                variation: has_variations,
+                ..attribute
+            })
+            .collect(),
+        variations: variations.iter().map(|variation| variation.0).collect(),
+    }
+}
+
+async fn single_product_json(data: &AppState, product: &ProductModel) -> Result<ApiProduct, AppError> {
+    let links = product_links(data, &[product.id]).await?;
+    Ok(product_json(product, &links))
+}
+
+//The tags sent with a product as the comma separated names the product form sends, tags sent by id have to exist
+async fn tag_names(data: &AppState, tags: &[ApiTermRef]) -> Result<String, AppError> {
+    let mut names = Vec::new();
+    for tag in tags {
+        match (&tag.id, &tag.name) {
+            (Some(id), _) => names.push(find_tag(&data.db, *id).await?.name),
+            (None, Some(name)) => names.push(name.clone()),
+            (None, None) => {
+                return Err(AppError::Validation(FieldErrors::from([("tags", "need an id or a name each".to_string())])));
+            }
+        }
+    }
+    Ok(names.join(", "))
+}
+
+//The product form fields of a product sent to the API
+async fn product_fields(
+    data: &AppState,
+    body: &ApiProductSchema,
+) -> Result<ProductFields, AppError> {
+    let (mut fields, category_ids) = body.fields().map_err(AppError::Validation)?;
+    if let Some(tags) = &body.tags {
+        fields.insert("tags".to_string(), tag_names(data, tags).await?);
+    }
+    Ok((fields, category_ids))
+}
+
+//GET /api/v1/products?search=&status=&category=&tag=&sku=&orderby=&order=
+#[utoipa::path(
+    get,
+    path = "/api/v1/products",
+    tag = "products",
+    summary = "List products",
+    params(ApiListOptions),
+    responses(
+        (status = 200, description = "A page of products", body = [ApiProduct],
+            headers(
+            ("X-WP-Total" = i64, description = "Items in the whole list"),
+            ("X-WP-TotalPages" = i64, description = "Pages in the whole list"),
+            ("Link" = String, description = "The prev and next pages"),
+        )),
+        (status = 400, description = "Invalid query string", body = ErrorBody),
+    )
+)]
+pub async fn list_products_handler(
+    opts: ListQuery,
+    Query(params): ListParams,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Query(opts) = opts?;
+    let published = match opts.status.as_deref() {
+        None | Some("") | Some("any") => None,
+        Some("publish") => Some(true),
+        Some("draft" | "pending" | "private") => Some(false),
+        Some(status) => return Err(AppError::BadRequest(format!("status must be any, publish or draft, got \"{}\"", status))),
+    };
+    let orderby = match opts.orderby.as_deref() {
+        None | Some("") => "date",
+        Some(orderby @ ("date" | "title" | "price" | "popularity" | "rating")) => orderby,
+        Some(orderby) => {
+            return Err(AppError::BadRequest(format!(
+                "orderby must be date, title, price, popularity or rating, got \"{}\"",
+                orderby
+            )))
+        }
+    };
+    let ascending = opts.ascending(false).map_err(AppError::BadRequest)?;
+    let search = opts.search_pattern();
+
+    let products = sqlx::query_as!(
+        ProductModel,
+        "SELECT * FROM products p
+        WHERE ($1::TEXT IS NULL OR p.title ILIKE $1 OR p.sku ILIKE $1)
+        AND ($2::BOOLEAN IS NULL OR COALESCE(p.published, FALSE) = $2)
+        AND ($3::UUID IS NULL OR EXISTS(SELECT 1 FROM product_categories_map m WHERE m.product_id = p.id AND m.category_id = $3))
+        AND ($4::UUID IS NULL OR EXISTS(SELECT 1 FROM product_tags_map m WHERE m.product_id = p.id AND m.tag_id = $4))
+        AND ($5::TEXT IS NULL OR p.sku = $5)
+        ORDER BY
+            CASE WHEN $6 = 'title' AND $7 THEN LOWER(p.title) END ASC,
+            CASE WHEN $6 = 'title' AND NOT $7 THEN LOWER(p.title) END DESC,
+            CASE WHEN $7 THEN CASE $6 WHEN 'price' THEN p.price WHEN 'popularity' THEN p.total_sales WHEN 'rating' THEN p.average_rating END END ASC,
+            CASE WHEN NOT $7 THEN CASE $6 WHEN 'price' THEN p.price WHEN 'popularity' THEN p.total_sales WHEN 'rating' THEN p.average_rating END END DESC,
+            CASE WHEN $7 THEN p.created_at END ASC,
+            p.created_at DESC, p.id
+        LIMIT $8 OFFSET $9",
+        search,
+        published,
+        opts.category,
+        opts.tag,
+        opts.sku,
+        orderby,
+        ascending,
+        opts.per_page() as i64,
+        opts.offset() as i64,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM products p
+        WHERE ($1::TEXT IS NULL OR p.title ILIKE $1 OR p.sku ILIKE $1)
+        AND ($2::BOOLEAN IS NULL OR COALESCE(p.published, FALSE) = $2)
+        AND ($3::UUID IS NULL OR EXISTS(SELECT 1 FROM product_categories_map m WHERE m.product_id = p.id AND m.category_id = $3))
+        AND ($4::UUID IS NULL OR EXISTS(SELECT 1 FROM product_tags_map m WHERE m.product_id = p.id AND m.tag_id = $4))
+        AND ($5::TEXT IS NULL OR p.sku = $5)"#,
+        search,
+        published,
+        opts.category,
+        opts.tag,
+        opts.sku,
+    )
+    .fetch_one(&data.db)
+    .await?;
+
+    let ids: Vec<Uuid> = products.iter().map(|product| product.id).collect();
+    let links = product_links(&data, &ids).await?;
+    let items = products.iter().map(|product| product_json(product, &links)).collect();
+    Ok(paged_json("/api/v1/products", &params, &opts, total, items))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/{id}",
+    tag = "products",
+    summary = "Get a product",
+    params(("id" = Uuid, Path, description = "Product id")),
+    responses(
+        (status = 200, body = ApiProduct),
+        (status = 404, description = "No such product", body = ErrorBody),
+    )
+)]
+pub async fn get_product_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiProduct>, AppError> {
+    let product = find_product(&data.db, id).await?;
+    Ok(Json(single_product_json(&data, &product).await?))
+}
+
+//New products are published unless status says otherwise, like WooCommerce does
+#[utoipa::path(
+    post,
+    path = "/api/v1/products",
+    tag = "products",
+    summary = "Create a product, published unless status says otherwise",
+    request_body = ApiProductSchema,
+    responses(
+        (status = 201, body = ApiProduct),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such category or tag", body = ErrorBody),
+        (status = 409, description = "The sku or a category is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_product_handler(
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiProductSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    let (mut fields, category_ids) = product_fields(&data, &body).await?;
+    fields.entry("published".to_string()).or_insert_with(|| "true".to_string());
+
+    let product = CreateProductSchema::from_fields(&fields, &category_ids.unwrap_or_default())
+        .map_err(|errors| AppError::Validation(rename_errors(errors, &API_PRODUCT_FIELDS)))?;
+    let (product, _, _) = insert_product(&data.db, &product).await?;
+
+    Ok((StatusCode::CREATED, Json(single_product_json(&data, &product).await?)))
+}
+
+//PUT and PATCH, only the fields that are sent are changed
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/products/{id}",
+    tag = "products",
+    summary = "Change the fields of a product that are sent",
+    params(("id" = Uuid, Path, description = "Product id")),
+    request_body = ApiProductSchema,
+    responses(
+        (status = 200, body = ApiProduct),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such product", body = ErrorBody),
+    )
+)]
+pub async fn update_product_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiProductSchema>, JsonRejection>,
+) -> Result<Json<ApiProduct>, AppError> {
+    let Json(body) = body?;
+    let (fields, category_ids) = product_fields(&data, &body).await?;
+
+    let changes = UpdateProductSchema::from_fields(&fields, category_ids.as_deref())
+        .map_err(|errors| AppError::Validation(rename_errors(errors, &API_PRODUCT_FIELDS)))?;
+    let product = update_product(&data.db, id, &changes).await?;
+
+    Ok(Json(single_product_json(&data, &product).await?))
+}
+
+//Deletes the product for good and answers with what it was, there is no trash
+#[utoipa::path(
+    delete,
+    path = "/api/v1/products/{id}",
+    tag = "products",
+    summary = "Delete a product for good",
+    params(("id" = Uuid, Path, description = "Product id")),
+    responses(
+        (status = 200, description = "The deleted product", body = ApiProduct),
+        (status = 404, description = "No such product", body = ErrorBody),
+    )
+)]
+pub async fn delete_product_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiProduct>, AppError> {
+    let product = find_product(&data.db, id).await?;
+    let product = single_product_json(&data, &product).await?;
+    delete_product(&data, id).await?;
+    Ok(Json(product))
+}
+
+//categories
+
+fn category_json(category: &ProductCategories) -> ApiCategory {
+    ApiCategory {
+        id: category.id,
+        name: category.name.clone(),
+        slug: category.slug.clone(),
+        parent: category.parent_id,
+        description: category.description.clone(),
+        display: if category.display_type.is_empty() { "default" } else { &category.display_type }.to_string(),
+        image: (!category.thumbnail.is_empty()).then(|| ApiImage {
+            src: images::absolute_url(&category.thumbnail),
+            alt: String::new(),
+        }),
+        count: category.count,
+    }
+}
+
+//The category form fields of a category sent to the API, over the category's current values when it's updated
+fn category_schema(body: ApiTermSchema, current: Option<&ProductCategories>) -> Result<CategorySchema, AppError> {
+    let name = body.name.or_else(|| current.map(|category| category.name.clone())).unwrap_or_default();
+    let slug = body
+        .slug
+        .or_else(|| current.map(|category| category.slug.clone()))
+        .unwrap_or_else(|| slugify(&name));
+    let parent = match body.parent {
+        Some(parent) if parent == "0" || parent.is_empty() => "-1".to_string(),
+        Some(parent) => parent,
+        None => current.and_then(|category| category.parent_id).map_or("-1".to_string(), |id| id.to_string()),
+    };
+    let display_type = match body.display.as_deref() {
+        Some("default") => String::new(),
+        Some(display) => display.to_string(),
+        None => current.map(|category| category.display_type.clone()).unwrap_or_default(),
+    };
+    let description = body
+        .description
+        .or_else(|| current.map(|category| category.description.clone()))
+        .unwrap_or_default();
+
+    let fields = HashMap::from([
+        ("name".to_string(), name),
+        ("slug".to_string(), slug),
+        ("parent".to_string(), parent),
+        ("display_type".to_string(), display_type),
+        ("description".to_string(), description),
+    ]);
+    CategorySchema::from_fields(&fields).map_err(|errors| AppError::Validation(rename_errors(errors, &[("display_type", "display")])))
+}
+
+//GET /api/v1/products/categories?search=&parent=&hide_empty=&orderby=&order=
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/categories",
+    tag = "categories",
+    summary = "List categories",
+    params(ApiListOptions),
+    responses(
+        (status = 200, description = "A page of categories", body = [ApiCategory],
+            headers(
+            ("X-WP-Total" = i64, description = "Items in the whole list"),
+            ("X-WP-TotalPages" = i64, description = "Pages in the whole list"),
+            ("Link" = String, description = "The prev and next pages"),
+        )),
+        (status = 400, description = "Invalid query string", body = ErrorBody),
+    )
+)]
+pub async fn list_categories_handler(
+    opts: ListQuery,
+    Query(params): ListParams,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Query(opts) = opts?;
+    let parent = match opts.parent.as_deref() {
+        None => None,
+        Some("0" | "") => Some(None),
+        Some(parent) => Some(Some(
+            Uuid::parse_str(parent).map_err(|_| AppError::BadRequest(format!("{} isn't a category", parent)))?,
+        )),
+    };
+
+    let categories = sqlx::query_as!(ProductCategories, "SELECT * FROM product_categories ORDER BY name, id")
+        .fetch_all(&data.db)
+        .await?;
+    let mut items: Vec<ApiCategory> = categories
+        .iter()
+        .filter(|category| parent.is_none_or(|parent| category.parent_id == parent))
+        .filter(|category| !opts.hide_empty.unwrap_or(false) || category.count > 0)
+        .filter(|category| matches_search(&opts, &[&category.name, &category.slug, &category.description]))
+        .map(category_json)
+        .collect();
+    sort_listed(&mut items, &opts, Some("name"))?;
+
+    let total = items.len() as i64;
+    Ok(paged_json("/api/v1/products/categories", &params, &opts, total, page_of(items, &opts)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/categories/{id}",
+    tag = "categories",
+    summary = "Get a category",
+    params(("id" = Uuid, Path, description = "Category id")),
+    responses(
+        (status = 200, body = ApiCategory),
+        (status = 404, description = "No such category", body = ErrorBody),
+    )
+)]
+pub async fn get_category_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiCategory>, AppError> {
+    Ok(Json(category_json(&find_category(&data.db, id).await?)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/products/categories",
+    tag = "categories",
+    summary = "Create a category",
+    request_body = ApiTermSchema,
+    responses(
+        (status = 201, body = ApiCategory),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_category_handler(
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    let category = category_schema(body, None)?;
+    let category = insert_category(&data.db, &category, CATEGORY_PLACEHOLDER).await?;
+    Ok((StatusCode::CREATED, Json(category_json(&category))))
+}
+
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/products/categories/{id}",
+    tag = "categories",
+    summary = "Change the fields of a category that are sent",
+    params(("id" = Uuid, Path, description = "Category id")),
+    request_body = ApiTermSchema,
+    responses(
+        (status = 200, body = ApiCategory),
+        (status = 400, description = "Invalid fields, or a parent under the category itself", body = ErrorBody),
+        (status = 404, description = "No such category", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn update_category_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<Json<ApiCategory>, AppError> {
+    let Json(body) = body?;
+    let current = find_category(&data.db, id).await?;
+    let changes = category_schema(body, Some(&current))?;
+    let category = update_category(&data.db, id, &changes, None).await?;
+    Ok(Json(category_json(&category)))
+}
+
+//Subcategories and products that were only in it move to its parent, like on the categories page
+#[utoipa::path(
+    delete,
+    path = "/api/v1/products/categories/{id}",
+    tag = "categories",
+    summary = "Delete a category, its subcategories and products move to its parent",
+    params(("id" = Uuid, Path, description = "Category id")),
+    responses(
+        (status = 200, description = "The deleted category", body = ApiCategory),
+        (status = 404, description = "No such category", body = ErrorBody),
+        (status = 400, description = "Uncategorized can't be deleted", body = ErrorBody),
+    )
+)]
+pub async fn delete_category_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiCategory>, AppError> {
+    let category = remove_category(&data.db, id).await?;
+    Ok(Json(category_json(&category)))
+}
+
+//attributes and their terms
+
+fn attribute_json(attribute: &ProductAttributes) -> ApiAttribute {
+    ApiAttribute {
+        id: attribute.id,
+        name: attribute.name.clone(),
+        slug: attribute.slug.clone(),
+        attribute_type: "select".to_string(),
+        order_by: attribute.order_by,
+        has_archives: false,
+    }
+}
+
+fn attribute_schema(body: ApiTermSchema, current: Option<&ProductAttributes>) -> Result<(AttributeSchema, TermOrder), AppError> {
+    let attribute_name = body.name.or_else(|| current.map(|attribute| attribute.name.clone())).unwrap_or_default();
+    let mut attribute = AttributeSchema {
+        attribute_slug: body
+            .slug
+            .or_else(|| current.map(|attribute| attribute.slug.clone()))
+            .unwrap_or_else(|| slugify(&attribute_name)),
+        attribute_name,
+        order_by: body
+            .order_by
+            .or_else(|| current.map(|attribute| attribute.order_by.as_str().to_string()))
+            .unwrap_or_else(|| TermOrder::default().as_str().to_string()),
+    };
+
+    let mut errors = attribute.validate();
+    let order_by = attribute.order_by.parse::<TermOrder>().unwrap_or_else(|e| {
+        errors.insert("order_by", e);
+        TermOrder::default()
+    });
+    if !errors.is_empty() {
+        return Err(AppError::Validation(rename_errors(errors, &[("attribute_name", "name"), ("attribute_slug", "slug")])));
+    }
+    Ok((attribute, order_by))
+}
+
+//Every attribute, WooCommerce doesn't page this list either
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/attributes",
+    tag = "attributes",
+    summary = "List every attribute",
+    responses(
+        (status = 200, body = [ApiAttribute]),
+    )
+)]
+pub async fn list_attributes_handler(State(data): State<Arc<AppState>>) -> Result<Json<Vec<ApiAttribute>>, AppError> {
+    let attributes = sqlx::query_as!(ProductAttributes, "SELECT * FROM product_attributes ORDER BY name, id")
+        .fetch_all(&data.db)
+        .await?;
+    Ok(Json(attributes.iter().map(attribute_json).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/attributes/{id}",
+    tag = "attributes",
+    summary = "Get an attribute",
+    params(("id" = Uuid, Path, description = "Attribute id")),
+    responses(
+        (status = 200, body = ApiAttribute),
+        (status = 404, description = "No such attribute", body = ErrorBody),
+    )
+)]
+pub async fn get_attribute_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiAttribute>, AppError> {
+    Ok(Json(attribute_json(&find_attribute(&data.db, id).await?)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/products/attributes",
+    tag = "attributes",
+    summary = "Create an attribute",
+    request_body = ApiTermSchema,
+    responses(
+        (status = 201, body = ApiAttribute),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_attribute_handler(
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    let (attribute, order_by) = attribute_schema(body, None)?;
+    let attribute = insert_attribute(&data.db, &attribute, order_by).await?;
+    Ok((StatusCode::CREATED, Json(attribute_json(&attribute))))
+}
+
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/products/attributes/{id}",
+    tag = "attributes",
+    summary = "Change the fields of an attribute that are sent",
+    params(("id" = Uuid, Path, description = "Attribute id")),
+    request_body = ApiTermSchema,
+    responses(
+        (status = 200, body = ApiAttribute),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such attribute", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn update_attribute_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<Json<ApiAttribute>, AppError> {
+    let Json(body) = body?;
+    let current = find_attribute(&data.db, id).await?;
+    let (changes, order_by) = attribute_schema(body, Some(&current))?;
+    let attribute = update_attribute(&data.db, id, &changes, order_by).await?;
+    Ok(Json(attribute_json(&attribute)))
+}
+
+//Its terms go with it and the variations made with it are disabled
+#[utoipa::path(
+    delete,
+    path = "/api/v1/products/attributes/{id}",
+    tag = "attributes",
+    summary = "Delete an attribute and its terms, the variations made with it are disabled",
+    params(("id" = Uuid, Path, description = "Attribute id")),
+    responses(
+        (status = 200, description = "The deleted attribute", body = ApiAttribute),
+        (status = 404, description = "No such attribute", body = ErrorBody),
+    )
+)]
+pub async fn delete_attribute_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiAttribute>, AppError> {
+    let attribute = delete_attribute(&data.db, id).await?;
+    Ok(Json(attribute_json(&attribute)))
+}
+
+//count is how many products come in the term
+fn term_json(term: &ProductTerms, count: i64) -> ApiTerm {
+    ApiTerm {
+        id: term.id,
+        name: term.name.clone(),
+        slug: term.slug.clone(),
+        description: term.description.clone().unwrap_or_default(),
+        menu_order: term.position,
+        count,
+    }
+}
+
+async fn term_counts(data: &AppState, term_ids: &[Uuid]) -> Result<HashMap<Uuid, i64>, AppError> {
+    let counts = sqlx::query!(
+        r#"SELECT term_id, COUNT(*) AS "count!" FROM product_terms_map WHERE term_id = ANY($1) GROUP BY term_id"#,
+        term_ids,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .map(|row| (row.term_id, row.count))
+    .collect();
+    Ok(counts)
+}
+
+async fn single_term_json(data: &AppState, term: &ProductTerms) -> Result<ApiTerm, AppError> {
+    let counts = term_counts(data, &[term.id]).await?;
+    Ok(term_json(term, counts.get(&term.id).copied().unwrap_or(0)))
+}
+
+fn term_schema(body: ApiTermSchema, current: Option<&ProductTerms>) -> Result<TermSchema, AppError> {
+    let term_name = body.name.or_else(|| current.map(|term| term.name.clone())).unwrap_or_default();
+    let mut term = TermSchema {
+        term_slug: body
+            .slug
+            .or_else(|| current.map(|term| term.slug.clone()))
+            .unwrap_or_else(|| slugify(&term_name)),
+        term_name,
+        description: body
+            .description
+            .or_else(|| current.and_then(|term| term.description.clone()))
+            .unwrap_or_default(),
+    };
+    let errors = term.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(rename_errors(errors, &[("term_name", "name"), ("term_slug", "slug")])));
+    }
+    Ok(term)
+}
+
+//GET /api/v1/products/attributes/:id/terms?search=&hide_empty=&orderby=&order=, in the attribute's order unless orderby is given
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/attributes/{id}/terms",
+    tag = "terms",
+    summary = "List the terms of an attribute, in its order unless orderby is given",
+    params(("id" = Uuid, Path, description = "Attribute id"), ApiListOptions),
+    responses(
+        (status = 200, description = "A page of terms", body = [ApiTerm],
+            headers(
+            ("X-WP-Total" = i64, description = "Items in the whole list"),
+            ("X-WP-TotalPages" = i64, description = "Pages in the whole list"),
+            ("Link" = String, description = "The prev and next pages"),
+        )),
+        (status = 400, description = "Invalid query string", body = ErrorBody),
+        (status = 404, description = "No such attribute", body = ErrorBody),
+    )
+)]
+pub async fn list_terms_handler(
+    Path(id): Path<Uuid>,
+    opts: ListQuery,
+    Query(params): ListParams,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Query(opts) = opts?;
+    let attribute = find_attribute(&data.db, id).await?;
+    let terms = find_terms(&data.db, &attribute).await?;
+    let term_ids: Vec<Uuid> = terms.iter().map(|term| term.id).collect();
+    let counts = term_counts(&data, &term_ids).await?;
+
+    let mut items: Vec<ApiTerm> = terms
+        .iter()
+        .map(|term| (term, counts.get(&term.id).copied().unwrap_or(0)))
+        .filter(|(_, count)| !opts.hide_empty.unwrap_or(false) || *count > 0)
+        .filter(|(term, _)| matches_search(&opts, &[&term.name, &term.slug, term.description.as_deref().unwrap_or_default()]))
+        .map(|(term, count)| term_json(term, count))
+        .collect();
+    sort_listed(&mut items, &opts, None)?;
+
+    let total = items.len() as i64;
+    let path = format!("/api/v1/products/attributes/{}/terms", id);
+    Ok(paged_json(&path, &params, &opts, total, page_of(items, &opts)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/attributes/{id}/terms/{term_id}",
+    tag = "terms",
+    summary = "Get a term",
+    params(("id" = Uuid, Path, description = "Attribute id"), ("term_id" = Uuid, Path, description = "Term id")),
+    responses(
+        (status = 200, body = ApiTerm),
+        (status = 404, description = "No such term", body = ErrorBody),
+    )
+)]
+pub async fn get_term_handler(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiTerm>, AppError> {
+    let term = find_term(&data.db, id, term_id).await?;
+    Ok(Json(single_term_json(&data, &term).await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/products/attributes/{id}/terms",
+    tag = "terms",
+    summary = "Add a term to an attribute",
+    params(("id" = Uuid, Path, description = "Attribute id")),
+    request_body = ApiTermSchema,
+    responses(
+        (status = 201, body = ApiTerm),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such attribute", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_term_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    find_attribute(&data.db, id).await?;
+    let term = term_schema(body, None)?;
+    let term = insert_term(&data.db, id, &term).await?;
+    Ok((StatusCode::CREATED, Json(term_json(&term, 0))))
+}
+
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/products/attributes/{id}/terms/{term_id}",
+    tag = "terms",
+    summary = "Change the fields of a term that are sent",
+    params(("id" = Uuid, Path, description = "Attribute id"), ("term_id" = Uuid, Path, description = "Term id")),
+    request_body = ApiTermSchema,
+    responses(
+        (status = 200, body = ApiTerm),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such term", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn update_term_handler(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<Json<ApiTerm>, AppError> {
+    let Json(body) = body?;
+    let current = find_term(&data.db, id, term_id).await?;
+    let changes = term_schema(body, Some(&current))?;
+    let term = update_term(&data.db, id, term_id, &changes).await?;
+    Ok(Json(single_term_json(&data, &term).await?))
+}
+
+//The term is taken off every product and the variations made with it are disabled
+#[utoipa::path(
+    delete,
+    path = "/api/v1/products/attributes/{id}/terms/{term_id}",
+    tag = "terms",
+    summary = "Delete a term, the variations made with it are disabled",
+    params(("id" = Uuid, Path, description = "Attribute id"), ("term_id" = Uuid, Path, description = "Term id")),
+    responses(
+        (status = 200, description = "The deleted term", body = ApiTerm),
+        (status = 404, description = "No such term", body = ErrorBody),
+    )
+)]
+pub async fn delete_term_handler(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiTerm>, AppError> {
+    let term = find_term(&data.db, id, term_id).await?;
+    let term = single_term_json(&data, &term).await?;
+    delete_terms(&data.db, id, &[term_id]).await?;
+    Ok(Json(term))
+}
+
+//tags
+
+fn tag_json(tag: &ProductTags) -> ApiTag {
+    ApiTag {
+        id: tag.id,
+        name: tag.name.clone(),
+        slug: tag.slug.clone(),
+        description: tag.description.clone(),
+        count: tag.count,
+    }
+}
+
+//an empty slug is made from the name by TagSchema::validate
+fn tag_schema(body: ApiTermSchema, current: Option<&ProductTags>) -> Result<TagSchema, AppError> {
+    let mut tag = TagSchema {
+        name: body.name.or_else(|| current.map(|tag| tag.name.clone())).unwrap_or_default(),
+        slug: body.slug.or_else(|| current.map(|tag| tag.slug.clone())).unwrap_or_default(),
+        description: body.description.or_else(|| current.map(|tag| tag.description.clone())).unwrap_or_default(),
+    };
+    let errors = tag.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    Ok(tag)
+}
+
+//GET /api/v1/products/tags?search=&hide_empty=&orderby=&order=
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/tags",
+    tag = "tags",
+    summary = "List tags",
+    params(ApiListOptions),
+    responses(
+        (status = 200, description = "A page of tags", body = [ApiTag],
+            headers(
+            ("X-WP-Total" = i64, description = "Items in the whole list"),
+            ("X-WP-TotalPages" = i64, description = "Pages in the whole list"),
+            ("Link" = String, description = "The prev and next pages"),
+        )),
+        (status = 400, description = "Invalid query string", body = ErrorBody),
+    )
+)]
+pub async fn list_tags_handler(
+    opts: ListQuery,
+    Query(params): ListParams,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Query(opts) = opts?;
+    let tags = sqlx::query_as!(ProductTags, "SELECT * FROM product_tags ORDER BY LOWER(name), id")
+        .fetch_all(&data.db)
+        .await?;
+    let mut items: Vec<ApiTag> = tags
+        .iter()
+        .filter(|tag| !opts.hide_empty.unwrap_or(false) || tag.count > 0)
+        .filter(|tag| matches_search(&opts, &[&tag.name, &tag.slug, &tag.description]))
+        .map(tag_json)
+        .collect();
+    sort_listed(&mut items, &opts, Some("name"))?;
+
+    let total = items.len() as i64;
+    Ok(paged_json("/api/v1/products/tags", &params, &opts, total, page_of(items, &opts)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/products/tags/{id}",
+    tag = "tags",
+    summary = "Get a tag",
+    params(("id" = Uuid, Path, description = "Tag id")),
+    responses(
+        (status = 200, body = ApiTag),
+        (status = 404, description = "No such tag", body = ErrorBody),
+    )
+)]
+pub async fn get_tag_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiTag>, AppError> {
+    Ok(Json(tag_json(&find_tag(&data.db, id).await?)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/products/tags",
+    tag = "tags",
+    summary = "Create a tag",
+    request_body = ApiTermSchema,
+    responses(
+        (status = 201, body = ApiTag),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn create_tag_handler(
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    let tag = tag_schema(body, None)?;
+    let tag = insert_tag(&data.db, &tag).await?;
+    Ok((StatusCode::CREATED, Json(tag_json(&tag))))
+}
+
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/products/tags/{id}",
+    tag = "tags",
+    summary = "Change the fields of a tag that are sent",
+    params(("id" = Uuid, Path, description = "Tag id")),
+    request_body = ApiTermSchema,
+    responses(
+        (status = 200, body = ApiTag),
+        (status = 400, description = "Invalid fields or a body that isn't JSON", body = ErrorBody),
+        (status = 404, description = "No such tag", body = ErrorBody),
+        (status = 409, description = "The name or slug is taken", body = ErrorBody),
+    )
+)]
+pub async fn update_tag_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiTermSchema>, JsonRejection>,
+) -> Result<Json<ApiTag>, AppError> {
+    let Json(body) = body?;
+    let current = find_tag(&data.db, id).await?;
+    let changes = tag_schema(body, Some(&current))?;
+    let tag = update_tag(&data.db, id, &changes).await?;
+    Ok(Json(tag_json(&tag)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/products/tags/{id}",
+    tag = "tags",
+    summary = "Delete a tag",
+    params(("id" = Uuid, Path, description = "Tag id")),
+    responses(
+        (status = 200, description = "The deleted tag", body = ApiTag),
+        (status = 404, description = "No such tag", body = ErrorBody),
+    )
+)]
+pub async fn delete_tag_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiTag>, AppError> {
+    let tag = delete_tag(&data.db, id).await?;
+    Ok(Json(tag_json(&tag)))
+}
+
+//orders
+
+fn order_json(data: &AppState, order: &OrderModel, items: &[OrderItemModel]) -> ApiOrder {
+    let (first_name, last_name) = split_name(&order.billing_name);
+    let line_items = items
+        .iter()
+        .map(|item| ApiOrderLine {
+            id: item.id,
+            name: item.title.clone(),
+            product_id: item.product_id,
+            variation_id: item.variation_id,
+            variation: item.variation.clone(),
+            quantity: item.quantity,
+            sku: item.sku.clone(),
+            price: item.price,
+            subtotal: item.line_total,
+            total: item.line_total,
+            total_tax: item.tax,
+            tax_rate: item.tax_rate,
+        })
+        .collect();
+
+    ApiOrder {
+        id: order.id,
+        number: order.number.to_string(),
+        status: order.status,
+        date_created: order.created_at,
+        date_modified: order.updated_at,
+        customer_id: order.customer_id,
+        customer_note: order.customer_note.clone(),
+        billing: ApiBilling {
+            first_name: first_name.to_string(),
+            last_name: last_name.to_string(),
+            email: order.billing_email.clone(),
+            phone: order.billing_phone.clone(),
+            address_1: order.billing_address.clone(),
+            city: order.billing_city.clone(),
+            postcode: order.billing_postcode.clone(),
+            country: order.billing_country.clone(),
+        },
+        payment_method: order.payment_method.clone(),
+        payment_method_title: data.payments.get(&order.payment_method).map_or("", |gateway| gateway.title()).to_string(),
+        total: order.total,
+        total_tax: order.tax_total,
+        line_items,
+    }
+}
+
+async fn find_order(data: &AppState, id: Uuid) -> Result<OrderModel, AppError> {
+    sqlx::query_as!(OrderModel, "SELECT * FROM orders WHERE id = $1", id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Order with ID: {} not found", id)))
+}
+
+async fn single_order_json(data: &AppState, order: &OrderModel) -> Result<ApiOrder, AppError> {
+    let items = find_order_items(&data.db, order.id).await?;
+    Ok(order_json(data, order, &items))
+}
+
+//The billing details and status of an order sent to the API, checked like the checkout form checks them.
+//payment_method can be left empty for orders that are paid outside the shop
+fn check_order(data: &AppState, body: &ApiOrderSchema, checkout: &mut CheckoutSchema) -> Result<Option<OrderStatus>, AppError> {
+    body.apply_to(checkout);
+    let mut errors = rename_errors(checkout.validate(), &API_ORDER_FIELDS);
+    if !checkout.payment_method.is_empty() && data.payments.get(&checkout.payment_method).is_none() {
+        errors.insert("payment_method", "isn't one of the shop's payment methods".to_string());
+    }
+    let status = match body.status.as_deref() {
+        None => None,
+        Some(status) => match status.parse::<OrderStatus>() {
+            Ok(status) => Some(status),
+            Err(e) => {
+                errors.insert("status", e);
+                None
+            }
+        },
+    };
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    Ok(status)
+}
+
+//GET /api/v1/orders?status=&customer=&search=&order=, newest first
+#[utoipa::path(
+    get,
+    path = "/api/v1/orders",
+    tag = "orders",
+    summary = "List orders, newest first",
+    params(ApiListOptions),
+    responses(
+        (status = 200, description = "A page of orders", body = [ApiOrder],
+            headers(
+            ("X-WP-Total" = i64, description = "Items in the whole list"),
+            ("X-WP-TotalPages" = i64, description = "Pages in the whole list"),
+            ("Link" = String, description = "The prev and next pages"),
+        )),
+        (status = 400, description = "Invalid query string", body = ErrorBody),
+    )
+)]
+pub async fn list_orders_handler(
+    opts: ListQuery,
+    Query(params): ListParams,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Query(opts) = opts?;
+    let status = match opts.status.as_deref() {
+        None | Some("") | Some("any") => None,
+        Some(status) => Some(status.parse::<OrderStatus>().map_err(AppError::BadRequest)?),
+    };
+    match opts.orderby.as_deref() {
+        None | Some("" | "date" | "id") => {}
+        Some(orderby) => return Err(AppError::BadRequest(format!("orderby must be date or id, got \"{}\"", orderby))),
+    }
+    let ascending = opts.ascending(false).map_err(AppError::BadRequest)?;
+    let search = opts.search_pattern();
+
+    let orders = sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders
+        WHERE ($1::TEXT IS NULL OR status = $1)
+        AND ($2::UUID IS NULL OR customer_id = $2)
+        AND ($3::TEXT IS NULL OR billing_name ILIKE $3 OR billing_email ILIKE $3 OR number::TEXT ILIKE $3)
+        ORDER BY CASE WHEN $4 THEN number END ASC, number DESC
+        LIMIT $5 OFFSET $6",
+        status.map(|status| status.as_str()),
+        opts.customer,
+        search,
+        ascending,
+        opts.per_page() as i64,
+        opts.offset() as i64,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM orders
+        WHERE ($1::TEXT IS NULL OR status = $1)
+        AND ($2::UUID IS NULL OR customer_id = $2)
+        AND ($3::TEXT IS NULL OR billing_name ILIKE $3 OR billing_email ILIKE $3 OR number::TEXT ILIKE $3)"#,
+        status.map(|status| status.as_str()),
+        opts.customer,
+        search,
+    )
+    .fetch_one(&data.db)
+    .await?;
+
+    let order_ids: Vec<Uuid> = orders.iter().map(|order| order.id).collect();
+    let mut items: HashMap<Uuid, Vec<OrderItemModel>> = HashMap::new();
+    let rows = sqlx::query_as!(
+        OrderItemModel,
+        "SELECT * FROM order_items WHERE order_id = ANY($1) ORDER BY title, variation",
+        &order_ids,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for item in rows {
+        items.entry(item.order_id).or_default().push(item);
+    }
+
+    let orders = orders
+        .iter()
+        .map(|order| order_json(&data, order, items.get(&order.id).map_or(&[][..], |items| items.as_slice())))
+        .collect();
+    Ok(paged_json("/api/v1/orders", &params, &opts, total, orders))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/orders/{id}",
+    tag = "orders",
+    summary = "Get an order",
+    params(("id" = Uuid, Path, description = "Order id")),
+    responses(
+        (status = 200, body = ApiOrder),
+        (status = 404, description = "No such order", body = ErrorBody),
+    )
+)]
+pub async fn get_order_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiOrder>, AppError> {
+    let order = find_order(&data, id).await?;
+    Ok(Json(single_order_json(&data, &order).await?))
+}
+
+//Places an order for the line items at their current prices and takes them out of stock. It starts as pending
+//and is then moved to status if one is sent, so it can only be one a pending order can move to
+#[utoipa::path(
+    post,
+    path = "/api/v1/orders",
+    tag = "orders",
+    summary = "Place an order at the current prices, taking the line items out of stock",
+    request_body = ApiOrderSchema,
+    responses(
+        (status = 201, body = ApiOrder),
+        (status = 400, description = "Invalid fields, no line items or a missing variation", body = ErrorBody),
+        (status = 404, description = "No such product or variation", body = ErrorBody),
+        (status = 409, description = "Not enough in stock, or a status a new order can't have", body = ErrorBody),
+    )
+)]
+pub async fn create_order_handler(
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiOrderSchema>, JsonRejection>,
+) -> Result<impl IntoResponse, AppError> {
+    let Json(body) = body?;
+    let mut checkout = CheckoutSchema::default();
+    let status = check_order(&data, &body, &mut checkout)?;
+    if body.line_items.is_empty() {
+        return Err(AppError::BadRequest("An order needs at least one line item".to_string()));
+    }
+    if let Some(status) = status.filter(|status| *status != OrderStatus::Pending) {
+        if !OrderStatus::Pending.can_transition_to(status) {
+            return Err(AppError::Conflict(format!("A new order can't be {}", status.as_str())));
+        }
+    }
+
+    let mut items = Vec::new();
+    for line in &body.line_items {
+        if line.quantity < 1 {
+            return Err(AppError::BadRequest("Quantity must be at least 1".to_string()));
+        }
+        let product = find_product(&data.db, line.product_id).await?;
+        let variations = find_variations(&data.db, product.id).await?;
+        let variation = check_variation(&product, &variations, line.variation_id)?;
+        items.push(NewOrderItem {
+            product_id: product.id,
+            variation_id: line.variation_id,
+            variation: variation.map_or(String::new(), |variation| variation.label.clone()),
+            quantity: line.quantity,
+        });
+    }
+
+    let mut tx = data.db.begin().await?;
+    //a session nobody has, the order is seen by shop managers and the customer it's for
+    let order = insert_order(&mut tx, Uuid::new_v4(), body.customer_id, &checkout, &items).await?;
+    //the order isn't placed when it can't move to status
+    let order = match status {
+        Some(status) if status != OrderStatus::Pending => move_order(&mut tx, &order, status).await?,
+        _ => order,
+    };
+    tx.commit().await?;
+    Ok((StatusCode::CREATED, Json(single_order_json(&data, &order).await?)))
+}
+
+//PUT and PATCH change the billing details, customer note, payment method and customer of an order,
+//and move it to status if the order status transitions allow it. Line items can't be changed
+#[utoipa::path(
+    method(put, patch),
+    path = "/api/v1/orders/{id}",
+    tag = "orders",
+    summary = "Change the billing details, note, payment method, customer or status of an order",
+    params(("id" = Uuid, Path, description = "Order id")),
+    request_body = ApiOrderSchema,
+    responses(
+        (status = 200, body = ApiOrder),
+        (status = 400, description = "Invalid fields or line items were sent", body = ErrorBody),
+        (status = 404, description = "No such order", body = ErrorBody),
+        (status = 409, description = "The order can't move to that status", body = ErrorBody),
+    )
+)]
+pub async fn update_order_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    body: Result<Json<ApiOrderSchema>, JsonRejection>,
+) -> Result<Json<ApiOrder>, AppError> {
+    let Json(body) = body?;
+    if !body.line_items.is_empty() {
+        return Err(AppError::BadRequest("The line items of an order can't be changed once it's placed".to_string()));
+    }
+    let order = find_order(&data, id).await?;
+    let mut checkout = CheckoutSchema {
+        billing_name: order.billing_name.clone(),
+        billing_email: order.billing_email.clone(),
+        billing_phone: order.billing_phone.clone(),
+        billing_address: order.billing_address.clone(),
+        billing_city: order.billing_city.clone(),
+        billing_postcode: order.billing_postcode.clone(),
+        billing_country: order.billing_country.clone(),
+        customer_note: order.customer_note.clone(),
+        payment_method: order.payment_method.clone(),
+        card_number: String::new(),
+    };
+    let status = check_order(&data, &body, &mut checkout)?;
+
+    //nothing is saved when the order can't move to status
+    let mut tx = data.db.begin().await?;
+    let order = lock_order(&mut tx, order.id).await?;
+    let order = sqlx::query_as!(
+        OrderModel,
+        "UPDATE orders SET
+        billing_name = $1,
+        billing_email = $2,
+        billing_phone = $3,
+        billing_address = $4,
+        billing_city = $5,
+        billing_postcode = $6,
+        billing_country = $7,
+        customer_note = $8,
+        payment_method = $9,
+        customer_id = COALESCE($10, customer_id),
+        updated_at = NOW()
+        WHERE id = $11 RETURNING *",
+        checkout.billing_name,
+        checkout.billing_email,
+        checkout.billing_phone,
+        checkout.billing_address,
+        checkout.billing_city,
+        checkout.billing_postcode,
+        checkout.billing_country,
+        checkout.customer_note,
+        checkout.payment_method,
+        body.customer_id,
+        order.id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let order = match status {
+        Some(status) if status != order.status => move_order(&mut tx, &order, status).await?,
+        _ => order,
+    };
+    tx.commit().await?;
+    Ok(Json(single_order_json(&data, &order).await?))
+}
+
+//Deletes the order for good, the stock it holds goes back
+#[utoipa::path(
+    delete,
+    path = "/api/v1/orders/{id}",
+    tag = "orders",
+    summary = "Delete an order for good, the stock it holds goes back",
+    params(("id" = Uuid, Path, description = "Order id")),
+    responses(
+        (status = 200, description = "The deleted order", body = ApiOrder),
+        (status = 404, description = "No such order", body = ErrorBody),
+    )
+)]
+pub async fn delete_order_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Json<ApiOrder>, AppError> {
+    let order = find_order(&data, id).await?;
+    let order = single_order_json(&data, &order).await?;
+    delete_order(&data.db, id).await?;
+    Ok(Json(order))
+}
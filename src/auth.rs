@@ -0,0 +1,360 @@
+use std::sync::Arc;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::AUTHORIZATION, request::Parts, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    model::{ApiKeyModel, CustomerModel, Role},
+    session::{self, SessionId},
+    AppState,
+};
+
+pub const LOGIN_COOKIE: &str = "login_session";
+//how long a login lasts
+pub const LOGIN_DAYS: i64 = 14;
+//how long a password reset link works
+pub const RESET_HOURS: i64 = 2;
+
+lazy_static! {
+    //checked when the email doesn't exist so logging in takes as long either way
+    static ref DUMMY_HASH: String = argon2_hash("not the password").expect("argon2 works with the default params");
+}
+
+fn argon2_hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+//argon2 is slow on purpose, so hashing and verifying run on the blocking thread pool
+pub async fn hash_password(password: String) -> Result<String, AppError> {
+    tokio::task::spawn_blocking(move || argon2_hash(&password))
+        .await
+        .map_err(std::io::Error::other)?
+        .map_err(|e| AppError::Io(std::io::Error::other(e.to_string())))
+}
+
+//None means there is no customer with the email, the dummy hash is checked anyway
+pub async fn verify_password(password: String, hash: Option<String>) -> bool {
+    tokio::task::spawn_blocking(move || {
+        let found = hash.is_some();
+        let hash = hash.as_deref().unwrap_or(DUMMY_HASH.as_str());
+        let verified = PasswordHash::new(hash)
+            .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+            .unwrap_or(false);
+        found && verified
+    })
+    .await
+    .unwrap_or(false)
+}
+
+//random token for cookies and links, 32 bytes as hex
+pub fn new_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+//tokens are only stored hashed so a database leak can't be used to log in
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+//cookies are Secure everywhere except APP_ENV=development, which runs over plain http
+pub fn secure_cookies() -> bool {
+    std::env::var("APP_ENV").map_or(true, |env| env != "development")
+}
+
+pub fn login_cookie(token: String) -> Cookie<'static> {
+    Cookie::build((LOGIN_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .secure(secure_cookies())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(LOGIN_DAYS))
+        .build()
+}
+
+//Creates a login session for the customer and a new session_id, the returned jar has to be part of the
+//response. The cart of the visitor's old session_id moves to the new one
+pub async fn start_login(
+    db: &Pool<Postgres>,
+    jar: CookieJar,
+    session: SessionId,
+    customer_id: Uuid,
+) -> Result<CookieJar, AppError> {
+    let token = new_token();
+    let (jar, renewed) = session::renew(jar);
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "INSERT INTO customer_sessions (token_hash, customer_id, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(days => $3))",
+        hash_token(&token),
+        customer_id,
+        LOGIN_DAYS as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE carts SET session_id = $1, updated_at = NOW() WHERE session_id = $2",
+        renewed.0,
+        session.0,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(jar.add(login_cookie(token)))
+}
+
+//Deletes the login session in the cookie, if there is one, and removes the cookie. The visitor gets
+//a new session_id too, so whoever uses the browser next doesn't get the cart and orders of the old one
+pub async fn end_login(db: &Pool<Postgres>, jar: CookieJar) -> Result<CookieJar, AppError> {
+    if let Some(cookie) = jar.get(LOGIN_COOKIE) {
+        sqlx::query!("DELETE FROM customer_sessions WHERE token_hash = $1", hash_token(cookie.value()))
+            .execute(db)
+            .await?;
+    }
+    let (jar, _) = session::renew(jar);
+    Ok(jar.remove(Cookie::build(LOGIN_COOKIE).path("/")))
+}
+
+//The logged in customer, None for guests
+pub struct CurrentCustomer(pub Option<CustomerModel>);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for CurrentCustomer {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+        let Some(cookie) = jar.get(LOGIN_COOKIE) else {
+            return Ok(CurrentCustomer(None));
+        };
+
+        let customer = sqlx::query_as!(
+            CustomerModel,
+            "SELECT c.* FROM customers c
+            JOIN customer_sessions s ON s.customer_id = c.id
+            WHERE s.token_hash = $1 AND s.expires_at > NOW()",
+            hash_token(cookie.value()),
+        )
+        .fetch_optional(&state.db)
+        .await?;
+
+        Ok(CurrentCustomer(customer))
+    }
+}
+
+//Middleware for the admin routes, only shop managers and admins get through
+pub async fn require_shop_manager(customer: CurrentCustomer, request: Request, next: Next) -> Response {
+    authorize(customer, request, next, Role::can_manage_shop).await
+}
+
+//Middleware for managing accounts, admins only
+pub async fn require_admin(customer: CurrentCustomer, request: Request, next: Next) -> Response {
+    authorize(customer, request, next, |role| *role == Role::Admin).await
+}
+
+//Middleware for /api/v1, shop managers get in with their login session or with one of their API keys
+//in the Authorization header, which is used instead of the session when both are sent
+pub async fn require_api_access(
+    State(data): State<Arc<AppState>>,
+    customer: CurrentCustomer,
+    request: Request,
+    next: Next,
+) -> Response {
+    let customer = match request.headers().get(AUTHORIZATION) {
+        None => customer,
+        Some(header) => match api_key_customer(&data.db, header, request.method()).await {
+            Ok(customer) => CurrentCustomer(Some(customer)),
+            Err(e) => return e.into_response(),
+        },
+    };
+    authorize(customer, request, next, Role::can_manage_shop).await
+}
+
+//A new consumer key and secret, ck_ and cs_ followed by 40 hex characters like WooCommerce's
+pub fn new_api_credentials() -> (String, String) {
+    (
+        format!("ck_{}", hex::encode(rand::random::<[u8; 20]>())),
+        format!("cs_{}", hex::encode(rand::random::<[u8; 20]>())),
+    )
+}
+
+//The consumer key and secret sent as the username and password of Authorization: Basic
+fn basic_credentials(header: &HeaderValue) -> Option<(String, String)> {
+    let (scheme, encoded) = header.to_str().ok()?.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (key, secret) = decoded.split_once(':')?;
+    Some((key.to_string(), secret.to_string()))
+}
+
+//The account of the API key in the Authorization header. Read keys can only GET and write keys can't,
+//every request that gets through is recorded as the key's last access
+async fn api_key_customer(db: &Pool<Postgres>, header: &HeaderValue, method: &Method) -> Result<CustomerModel, AppError> {
+    let (key, secret) = basic_credentials(header).ok_or_else(|| {
+        AppError::Unauthorized("Send the consumer key and secret as Authorization: Basic".to_string())
+    })?;
+    let api_key = sqlx::query_as!(
+        ApiKeyModel,
+        "SELECT * FROM api_keys WHERE consumer_key_hash = $1 AND revoked_at IS NULL",
+        hash_token(&key),
+    )
+    .fetch_optional(db)
+    .await?
+    .filter(|api_key| api_key.consumer_secret_hash == hash_token(&secret))
+    .ok_or_else(|| AppError::Unauthorized("Invalid consumer key or secret".to_string()))?;
+
+    let reading = matches!(*method, Method::GET | Method::HEAD);
+    if (reading && !api_key.permissions.can_read()) || (!reading && !api_key.permissions.can_write()) {
+        return Err(AppError::Forbidden(format!(
+            "The API key has {} permissions only",
+            api_key.permissions.as_str()
+        )));
+    }
+
+    sqlx::query!("UPDATE api_keys SET last_access = NOW() WHERE id = $1", api_key.id)
+        .execute(db)
+        .await?;
+    let customer = sqlx::query_as!(CustomerModel, "SELECT * FROM customers WHERE id = $1", api_key.customer_id)
+        .fetch_one(db)
+        .await?;
+    Ok(customer)
+}
+
+//Guests opening a page are sent to the login page and come back afterwards,
+//anything else from a guest, /api/ included, is a 401 and a customer without the role gets a 403
+async fn authorize(
+    CurrentCustomer(customer): CurrentCustomer,
+    request: Request,
+    next: Next,
+    allowed: fn(&Role) -> bool,
+) -> Response {
+    let Some(customer) = customer else {
+        if request.method() == Method::GET && !request.uri().path().starts_with("/api/") {
+            let path = request.uri().path_and_query().map_or("/", |path| path.as_str());
+            return Redirect::to(&format!("/login?next={}", urlencoding::encode(path))).into_response();
+        }
+        return AppError::Unauthorized("Log in to do that".to_string()).into_response();
+    };
+    if !allowed(&customer.role) {
+        return AppError::Forbidden("Your account isn't allowed to do that".to_string()).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn basic(credentials: &str) -> HeaderValue {
+        HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode(credentials))).unwrap()
+    }
+
+    #[test]
+    fn basic_credentials_of_the_header() {
+        assert_eq!(basic_credentials(&basic("ck_1:cs_2")), Some(("ck_1".to_string(), "cs_2".to_string())));
+        //the secret is everything after the first colon
+        assert_eq!(basic_credentials(&basic("ck_1:cs:2")), Some(("ck_1".to_string(), "cs:2".to_string())));
+        assert_eq!(basic_credentials(&basic("ck_1:")), Some(("ck_1".to_string(), String::new())));
+        let lowercase = HeaderValue::from_str(&format!("basic  {}", BASE64_STANDARD.encode("ck_1:cs_2"))).unwrap();
+        assert_eq!(basic_credentials(&lowercase), Some(("ck_1".to_string(), "cs_2".to_string())));
+    }
+
+    #[test]
+    fn basic_credentials_rejects_other_headers() {
+        assert_eq!(basic_credentials(&basic("no colon")), None);
+        assert_eq!(basic_credentials(&HeaderValue::from_static("Bearer abc")), None);
+        assert_eq!(basic_credentials(&HeaderValue::from_static("Basic")), None);
+        assert_eq!(basic_credentials(&HeaderValue::from_static("Basic not*base64")), None);
+        //not UTF-8 once decoded
+        let latin1 = HeaderValue::from_str(&format!("Basic {}", BASE64_STANDARD.encode([0xff, b':', b'a']))).unwrap();
+        assert_eq!(basic_credentials(&latin1), None);
+    }
+
+    async fn insert_shop_manager(db: &Pool<Postgres>) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO customers (email, password_hash, first_name, last_name, role)
+            VALUES ('manager@example.com', '', 'A', 'B', 'shop-manager') RETURNING id",
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn insert_api_key(db: &Pool<Postgres>, customer_id: Uuid, key: &str, permissions: &str) {
+        sqlx::query(
+            "INSERT INTO api_keys (customer_id, description, permissions, consumer_key_hash, consumer_secret_hash, truncated_key)
+            VALUES ($1, 'test', $2, $3, $4, '')",
+        )
+        .bind(customer_id)
+        .bind(permissions)
+        .bind(hash_token(&format!("ck_{}", key)))
+        .bind(hash_token(&format!("cs_{}", key)))
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn status_of(db: &Pool<Postgres>, credentials: &str, method: Method) -> StatusCode {
+        match api_key_customer(db, &basic(credentials), &method).await {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.status(),
+        }
+    }
+
+    #[sqlx::test]
+    async fn api_key_scopes(db: Pool<Postgres>) {
+        let customer_id = insert_shop_manager(&db).await;
+        insert_api_key(&db, customer_id, "read", "read").await;
+        insert_api_key(&db, customer_id, "write", "write").await;
+        insert_api_key(&db, customer_id, "both", "read-write").await;
+
+        let customer = api_key_customer(&db, &basic("ck_read:cs_read"), &Method::GET).await.unwrap();
+        assert_eq!(customer.id, customer_id);
+        assert_eq!(status_of(&db, "ck_read:cs_read", Method::HEAD).await, StatusCode::OK);
+        assert_eq!(status_of(&db, "ck_read:cs_read", Method::POST).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_of(&db, "ck_write:cs_write", Method::GET).await, StatusCode::FORBIDDEN);
+        assert_eq!(status_of(&db, "ck_write:cs_write", Method::DELETE).await, StatusCode::OK);
+        assert_eq!(status_of(&db, "ck_both:cs_both", Method::GET).await, StatusCode::OK);
+        assert_eq!(status_of(&db, "ck_both:cs_both", Method::PUT).await, StatusCode::OK);
+
+        let accessed: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM api_keys WHERE last_access IS NOT NULL")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(accessed, 3);
+    }
+
+    #[sqlx::test]
+    async fn api_key_secret_and_revocation(db: Pool<Postgres>) {
+        let customer_id = insert_shop_manager(&db).await;
+        insert_api_key(&db, customer_id, "a", "read-write").await;
+
+        assert_eq!(status_of(&db, "ck_a:cs_a", Method::GET).await, StatusCode::OK);
+        assert_eq!(status_of(&db, "ck_a:cs_b", Method::GET).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status_of(&db, "ck_b:cs_a", Method::GET).await, StatusCode::UNAUTHORIZED);
+        let bearer = api_key_customer(&db, &HeaderValue::from_static("Bearer ck_a"), &Method::GET).await;
+        assert_eq!(bearer.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+
+        sqlx::query("UPDATE api_keys SET revoked_at = NOW()").execute(&db).await.unwrap();
+        assert_eq!(status_of(&db, "ck_a:cs_a", Method::GET).await, StatusCode::UNAUTHORIZED);
+    }
+}
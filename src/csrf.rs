@@ -0,0 +1,279 @@
+use std::sync::Arc;
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart, Request, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Form,
+};
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::{error::render_error_page, error::AppError, session::SessionId, AppState};
+
+//hidden field in every form, pages put it in with {% include "utils/csrf_field.html" %}
+pub const CSRF_FIELD: &str = "csrf_token";
+//scripts send the token in this header instead
+pub const CSRF_HEADER: &str = "x-csrf-token";
+//same as axum's default body limit, which the form handlers are under anyway.
+//Forms with images are multipart and only their first part is read here
+const FORM_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+lazy_static! {
+    //CSRF_SECRET keeps tokens valid across restarts, otherwise pages opened before a restart have to be reloaded
+    static ref SECRET: Vec<u8> = std::env::var("CSRF_SECRET")
+        .map(String::into_bytes)
+        .unwrap_or_else(|_| rand::random::<[u8; 32]>().to_vec());
+}
+
+tokio::task_local! {
+    //token of the request being handled, common_context puts it in every page
+    static CSRF_TOKEN: String;
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+//The token is an HMAC of the session_id cookie, so it can't be guessed from another site
+//and nothing has to be stored for it
+fn session_mac(session: SessionId) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&SECRET).expect("HMAC takes keys of any size");
+    mac.update(session.0.as_bytes());
+    mac
+}
+
+pub fn csrf_token(session: SessionId) -> String {
+    hex::encode(session_mac(session).finalize().into_bytes())
+}
+
+fn verify(session: SessionId, token: &str) -> bool {
+    hex::decode(token).is_ok_and(|bytes| session_mac(session).verify_slice(&bytes).is_ok())
+}
+
+//The token for the forms of the page being rendered, empty outside of a request
+pub fn current_token() -> String {
+    CSRF_TOKEN.try_with(Clone::clone).unwrap_or_default()
+}
+
+fn content_type(headers: &HeaderMap) -> &str {
+    headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("")
+}
+
+//Another site can only make a browser send a POST with a form body without asking first.
+//JSON bodies and the other methods need a CORS preflight, which only allows our own frontend.
+//Webhooks come from the payment gateways and are signed instead
+fn needs_check(request: &Request) -> bool {
+    request.method() == Method::POST
+        && !content_type(request.headers()).starts_with("application/json")
+        && !request.uri().path().starts_with("/payments/webhook/")
+}
+
+//A POST carrying form data for the extractors, which read the query string instead of the body for other methods
+fn probe(content_type: &str, bytes: Bytes) -> Request {
+    Request::builder()
+        .method(Method::POST)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(bytes))
+        .expect("the content type came from a valid header")
+}
+
+fn boundary(content_type: &str) -> Option<&str> {
+    content_type
+        .split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty())
+}
+
+//Reads a multipart body up to the end of its first part, where csrf_field.html puts the token in every form.
+//Returns what was read and the body with it put back, the rest of an upload is left for the handler
+//and the body limit of its route
+async fn first_part(body: Body, boundary: &str) -> Result<(Bytes, Body), AppError> {
+    let delimiter = format!("\r\n--{}", boundary);
+    let mut stream = body.into_data_stream();
+    let mut start = Vec::new();
+    let mut searched = 0;
+    while !start[searched..].windows(delimiter.len()).any(|window| window == delimiter.as_bytes()) {
+        searched = start.len().saturating_sub(delimiter.len() + 1);
+        match stream.next().await {
+            Some(Ok(chunk)) => start.extend_from_slice(&chunk),
+            Some(Err(_)) => return Err(AppError::BadRequest("The form couldn't be read".to_string())),
+            None => break,
+        }
+        if start.len() > FORM_BODY_LIMIT {
+            return Err(AppError::BadRequest("The form is too large".to_string()));
+        }
+    }
+    let start = Bytes::from(start);
+    let read = start.clone();
+    let body = Body::from_stream(stream::once(async move { Ok::<_, axum::Error>(read) }).chain(stream));
+    Ok((start, body))
+}
+
+//Reads the token from the header or the form, what was read of the body is put back for the handler
+async fn check(session: SessionId, request: Request) -> Result<Request, AppError> {
+    let expired = || AppError::Forbidden("This form has expired, go back, reload the page and try again".to_string());
+
+    if let Some(token) = request.headers().get(CSRF_HEADER) {
+        return match token.to_str() {
+            Ok(token) if verify(session, token) => Ok(request),
+            _ => Err(expired()),
+        };
+    }
+
+    let content_type = content_type(request.headers()).to_string();
+    let (parts, body) = request.into_parts();
+    let (token, body) = if content_type.starts_with("application/x-www-form-urlencoded") {
+        let bytes = axum::body::to_bytes(body, FORM_BODY_LIMIT)
+            .await
+            .map_err(|_| AppError::BadRequest("The form is too large".to_string()))?;
+        let token = Form::<CsrfForm>::from_request(probe(&content_type, bytes.clone()), &())
+            .await
+            .ok()
+            .map(|Form(form)| form.csrf_token);
+        (token, Body::from(bytes))
+    } else if content_type.starts_with("multipart/form-data") {
+        let boundary = boundary(&content_type).ok_or_else(expired)?;
+        let (start, body) = first_part(body, boundary).await?;
+        let mut token = None;
+        if let Ok(mut multipart) = Multipart::from_request(probe(&content_type, start), &()).await {
+            if let Ok(Some(field)) = multipart.next_field().await {
+                if field.name() == Some(CSRF_FIELD) {
+                    token = field.text().await.ok();
+                }
+            }
+        }
+        (token, body)
+    } else {
+        (None, body)
+    };
+
+    match token {
+        Some(token) if verify(session, &token) => Ok(Request::from_parts(parts, body)),
+        _ => Err(expired()),
+    }
+}
+
+//Middleware that rejects form posts without the token of the visitor's session,
+//a rejected form shows the error page and anything else gets the JSON error
+pub async fn csrf_protect(
+    State(data): State<Arc<AppState>>,
+    Extension(session): Extension<SessionId>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let request = if needs_check(&request) {
+        let content_type = content_type(request.headers());
+        let from_page = content_type.starts_with("application/x-www-form-urlencoded")
+            || content_type.starts_with("multipart/form-data");
+        match check(session, request).await {
+            Ok(request) => request,
+            Err(e) if from_page => return render_error_page(&data.templates, StatusCode::FORBIDDEN, &e.message()),
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        request
+    };
+    CSRF_TOKEN.scope(csrf_token(session), next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const BOUNDARY: &str = "XyZ";
+
+    fn multipart_body(first_name: &str, first_value: &str) -> Vec<u8> {
+        format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n\
+            --{b}\r\nContent-Disposition: form-data; name=\"title\"\r\n\r\nHat\r\n--{b}--\r\n",
+            first_name,
+            first_value,
+            b = BOUNDARY,
+        )
+        .into_bytes()
+    }
+
+    fn multipart_request(body: Body) -> Request {
+        Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(body)
+            .unwrap()
+    }
+
+    async fn body_of(request: Request) -> Vec<u8> {
+        axum::body::to_bytes(request.into_body(), usize::MAX).await.unwrap().to_vec()
+    }
+
+    #[tokio::test]
+    async fn token_in_the_first_part() {
+        let session = SessionId(Uuid::new_v4());
+        let body = multipart_body(CSRF_FIELD, &csrf_token(session));
+        let request = check(session, multipart_request(Body::from(body.clone()))).await.unwrap();
+        //the handler still gets the whole form
+        assert_eq!(body_of(request).await, body);
+    }
+
+    #[tokio::test]
+    async fn token_split_across_chunks() {
+        let session = SessionId(Uuid::new_v4());
+        let body = multipart_body(CSRF_FIELD, &csrf_token(session));
+        let chunks: Vec<Result<Vec<u8>, axum::Error>> = body.chunks(5).map(|chunk| Ok(chunk.to_vec())).collect();
+        let request = check(session, multipart_request(Body::from_stream(stream::iter(chunks)))).await.unwrap();
+        assert_eq!(body_of(request).await, body);
+    }
+
+    #[tokio::test]
+    async fn missing_or_wrong_token() {
+        let session = SessionId(Uuid::new_v4());
+        let other_session = SessionId(Uuid::new_v4());
+        for body in [
+            multipart_body("title", "Hat"),
+            multipart_body(CSRF_FIELD, ""),
+            multipart_body(CSRF_FIELD, "not hex"),
+            multipart_body(CSRF_FIELD, &csrf_token(other_session)),
+        ] {
+            let error = check(session, multipart_request(Body::from(body))).await.unwrap_err();
+            assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn urlencoded_and_header_tokens() {
+        let session = SessionId(Uuid::new_v4());
+        let form = format!("title=Hat&{}={}", CSRF_FIELD, csrf_token(session));
+        let request = Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form.clone()))
+            .unwrap();
+        assert_eq!(body_of(check(session, request).await.unwrap()).await, form.as_bytes());
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .header(CSRF_HEADER, csrf_token(session))
+            .body(Body::empty())
+            .unwrap();
+        assert!(check(session, request).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_first_part() {
+        let session = SessionId(Uuid::new_v4());
+        let value = "a".repeat(FORM_BODY_LIMIT + 1);
+        let body = multipart_body("description", &value);
+        let chunks: Vec<Result<Vec<u8>, axum::Error>> = body.chunks(64 * 1024).map(|chunk| Ok(chunk.to_vec())).collect();
+        let error = check(session, multipart_request(Body::from_stream(stream::iter(chunks)))).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,236 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        multipart::MultipartError,
+        rejection::{JsonRejection, QueryRejection},
+        Request, State,
+    },
+    http::StatusCode,
+    middleware::Next,
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+use crate::{payment::PaymentError, schema::FieldErrors, templates::Templates, AppState};
+
+//Every error a handler can return, turned into a status code and a JSON body.
+//Browser routes go through html_error_pages which swaps the JSON for pages/not_found.html
+#[derive(Debug)]
+pub enum AppError {
+    NotFound(String),
+    BadRequest(String),
+    //not logged in
+    Unauthorized(String),
+    //logged in without the role for it
+    Forbidden(String),
+    Validation(FieldErrors),
+    Conflict(String),
+    Database(sqlx::Error),
+    Multipart(MultipartError),
+    Io(std::io::Error),
+    Template(tera::Error),
+    Payment(PaymentError),
+}
+
+//attached to error responses so html_error_pages knows what to render
+#[derive(Clone, Debug)]
+struct ErrorPage {
+    status: StatusCode,
+    message: String,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::BadRequest(_) | AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::Database(sqlx::Error::RowNotFound) => StatusCode::NOT_FOUND,
+            AppError::Database(sqlx::Error::Database(e)) if e.is_unique_violation() => StatusCode::CONFLICT,
+            AppError::Database(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => StatusCode::CONFLICT,
+            AppError::Database(sqlx::Error::Database(e)) if e.is_check_violation() => StatusCode::BAD_REQUEST,
+            AppError::Multipart(e) => e.status(),
+            AppError::Payment(PaymentError::InvalidSignature) => StatusCode::UNAUTHORIZED,
+            AppError::Payment(PaymentError::InvalidTransition(_)) => StatusCode::CONFLICT,
+            AppError::Payment(_) => StatusCode::BAD_REQUEST,
+            AppError::Database(_) | AppError::Io(_) | AppError::Template(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    //the message shown to the client, server side details only go to the log
+    pub fn message(&self) -> String {
+        match self {
+            AppError::NotFound(message)
+            | AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Conflict(message) => message.clone(),
+            AppError::Validation(_) => "Some fields are invalid".to_string(),
+            AppError::Database(sqlx::Error::RowNotFound) => "Record not found".to_string(),
+            AppError::Database(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                "A record with that value already exists".to_string()
+            }
+            AppError::Database(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => {
+                "The record is referenced by, or references, a record that doesn't exist".to_string()
+            }
+            AppError::Database(sqlx::Error::Database(e)) if e.is_check_violation() => {
+                "A value is outside the allowed range".to_string()
+            }
+            AppError::Multipart(e) => e.body_text(),
+            AppError::Payment(e) => e.to_string(),
+            AppError::Database(_) | AppError::Io(_) | AppError::Template(_) => {
+                "Something bad happened on the server".to_string()
+            }
+        }
+    }
+
+    //replaces the generic unique violation message with one that names the resource
+    pub fn on_conflict(self, message: &str) -> Self {
+        if self.status() == StatusCode::CONFLICT {
+            AppError::Conflict(message.to_string())
+        } else {
+            self
+        }
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::NotFound(message)
+            | AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::Conflict(message) => write!(f, "{}", message),
+            AppError::Validation(errors) => write!(f, "invalid fields: {:?}", errors),
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            AppError::Multipart(e) => write!(f, "multipart error: {}", e),
+            AppError::Io(e) => write!(f, "io error: {}", e),
+            AppError::Template(e) => write!(f, "template error: {:?}", e),
+            AppError::Payment(e) => write!(f, "payment error: {}", e),
+        }
+    }
+}
+
+//the JSON body of every error
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// `fail` for errors in the request, `error` for errors of the server.
+    pub status: &'static str,
+    pub message: String,
+    /// The fields that are invalid and what is wrong with each.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<std::collections::BTreeMap<String, String>>)]
+    pub errors: Option<FieldErrors>,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let message = self.message();
+
+        if status.is_server_error() {
+            println!("🔥 {}", self);
+        }
+
+        let body = ErrorBody {
+            status: if status.is_server_error() { "error" } else { "fail" },
+            message: message.clone(),
+            errors: match self {
+                AppError::Validation(errors) => Some(errors),
+                _ => None,
+            },
+        };
+
+        let mut response = (status, Json(body)).into_response();
+        response.extensions_mut().insert(ErrorPage { status, message });
+        response
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        AppError::Database(e)
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(e: MultipartError) -> Self {
+        AppError::Multipart(e)
+    }
+}
+
+//bodies and query strings of the JSON API that don't parse get the same error format as everything else
+impl From<JsonRejection> for AppError {
+    fn from(e: JsonRejection) -> Self {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(e: QueryRejection) -> Self {
+        AppError::BadRequest(e.body_text())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(e: std::io::Error) -> Self {
+        AppError::Io(e)
+    }
+}
+
+impl From<tera::Error> for AppError {
+    fn from(e: tera::Error) -> Self {
+        AppError::Template(e)
+    }
+}
+
+impl From<PaymentError> for AppError {
+    fn from(e: PaymentError) -> Self {
+        AppError::Payment(e)
+    }
+}
+
+impl From<FieldErrors> for AppError {
+    fn from(errors: FieldErrors) -> Self {
+        AppError::Validation(errors)
+    }
+}
+
+//Renders pages/not_found.html with the status and message of the error
+pub fn render_error_page(templates: &Templates, status: StatusCode, message: &str) -> Response {
+    let mut context = tera::Context::new();
+    context.insert("title", "Based.win");
+    context.insert("page_title", status.canonical_reason().unwrap_or("Error"));
+    context.insert("message", message);
+
+    match templates.render("pages/not_found.html", &context) {
+        Ok(html) => (status, Html(html)).into_response(),
+        Err(e) => {
+            println!("🔥 Failed to render the error page: {:?}", e);
+            (status, message.to_string()).into_response()
+        }
+    }
+}
+
+//Middleware for browser routes, replaces the JSON body of an AppError with the HTML error page
+pub async fn html_error_pages(
+    State(data): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let response = next.run(request).await;
+    match response.extensions().get::<ErrorPage>() {
+        Some(page) => render_error_page(&data.templates, page.status, &page.message),
+        None => response,
+    }
+}
+
+//Fallback for paths that don't match any route
+pub async fn not_found_page(State(data): State<Arc<AppState>>) -> Response {
+    render_error_page(&data.templates, StatusCode::NOT_FOUND, "This page doesn't exist")
+}
@@ -1,42 +1,62 @@
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tera::{to_value, Tera};
-use lazy_static::lazy_static;
-use tera::Context;
-use tokio::fs;
-use base64::prelude::*;
 use uuid::Uuid;
 
 
 use axum::{
-    extract::{multipart, Form, Multipart, Path, Query, State},
-    http::StatusCode,
-    response::{Html, IntoResponse},
-    Json,
+    body::Bytes,
+    extract::{multipart::Field, Multipart, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension, Form, Json,
 };
 use serde_json::json;
-use serde::{Deserialize, Serialize};
+use rust_decimal::{Decimal, RoundingStrategy};
+use sqlx::{Pool, Postgres, Transaction};
+
+use axum_extra::extract::cookie::CookieJar;
 
 use crate::{
-    model::{ProductAttributes, ProductCategories, ProductModel, ProductTerms},
-    schema::{CreateProductSchema, FilterOptions},
+    auth::{
+        end_login, hash_password, hash_token, new_api_credentials, new_token, start_login, verify_password, CurrentCustomer, RESET_HOURS,
+    },
+    csrf,
+    error::{AppError, ErrorBody},
+    images::{self, ImageSizes, StoredImage},
+    mail::Email,
+    model::{
+        ApiKeyModel, ApiKeyPermissions, BackorderStatus, CartData, CartItemModel, CartModel, CartResponse, CategoryNode, CustomerAddressModel, CustomerModel, OrderItemModel, OrderModel,
+        OrderStatus, PaymentModel, PaymentStatus, ProductAttributeOptions, ProductAttributes, ProductCategories,
+        HealthResponse, MediaModel, MediaReference, ProductImageModel, ProductModel, ProductReviewModel, ProductSearchResult, ProductTags, ProductTerms, ProductVariationModel, ReviewStatus, Role, SearchData, SearchResponse, TermOrder, VariationDetails, VariationTermModel,
+    },
+    payment::{payment_change, PaymentChange, PaymentDetails, PaymentGateway, WebhookEvent},
+    schema::{
+        AddToCartSchema, AddressSchema, ApiKeySchema, AttributeSchema, CategoryFilters, CategorySchema, CheckoutSchema, CreateProductSchema, CustomerRoleSchema, FieldErrors, DISPLAY_TYPES, MAX_TAG_LENGTH,
+        FilterOptions, Pagination, ProductSort, page_offset, query_string,
+        ForgotPasswordSchema, LoginSchema, MediaFilterOptions, Mock3dsSchema, OrderFilterOptions, OrderStatusSchema, PayOrderSchema, ProductPageOptions,
+        RegisterSchema, ResetPasswordSchema, ReviewFilterOptions, ReviewReplySchema, ReviewSchema, ReviewStatusSchema, ScanMediaSchema, SearchOptions, slugify, TagSchema, TagSearchOptions, TermOrderSchema, TermSchema, UpdateCartItemSchema, UpdateGallerySchema, UpdateProductSchema, UpdateVariationSchema,
+    },
+    session::SessionId,
     AppState,
 };
 
+#[utoipa::path(get, path = "/api/healthchecker", tag = "shop", summary = "Check the server is up",
+    responses((status = 200, body = HealthResponse)))]
 pub async fn health_checker_handler() -> impl IntoResponse {
     const MESSAGE: &str = "Simple CRUD API with Rust, SQLX, Postgres,and Axum";
 
-    let json_response = serde_json::json!({
-        "status": "success",
-        "message": MESSAGE
-    });
-
-    Json(json_response)
+    Json(HealthResponse {
+        status: "success".to_string(),
+        message: MESSAGE.to_string(),
+    })
 }
 
 //TODO fix so that products[0].title can be inserted into the page name
 fn common_context() -> tera::Context {
     let mut context = tera::Context::new();
     context.insert("title", "Based.win");
+    //every form posts this back, see csrf_protect
+    context.insert("csrf_token", &csrf::current_token());
     context
 }
 
@@ -48,312 +68,432 @@ fn common_context() -> tera::Context {
 pub async fn tera_product_handler(
     opts: Option<Query<FilterOptions>>,
     State(data): State<Arc<AppState>>,
-) -> Html<String> {
+) -> Result<Html<String>, AppError> {
     let Query(opts) = opts.unwrap_or_default();
 
-    //the amount of products that will be listed in featured products
-    let limit = opts.limit.unwrap_or(14);
-    let offset = (opts.page.unwrap_or(1) - 1) * limit;
-
-    let query_result = sqlx::query_as!(
+    //the products listed in featured products, a page at a time
+    let products = sqlx::query_as!(
         ProductModel,
-        "SELECT * FROM products ORDER by id LIMIT $1 OFFSET $2",
-        limit as i32,
-        offset as i32
+        "SELECT * FROM products WHERE published
+        ORDER BY
+            CASE WHEN $3 = 'popularity' THEN total_sales END DESC,
+            CASE WHEN $3 = 'rating' THEN average_rating END DESC,
+            CASE WHEN $3 = 'rating' THEN rating_count END DESC,
+            CASE WHEN $3 = 'price' THEN price END ASC,
+            CASE WHEN $3 = 'price-desc' THEN price END DESC,
+            CASE WHEN $3 = 'title' THEN LOWER(title) END ASC,
+            created_at DESC, id
+        LIMIT $1 OFFSET $2",
+        opts.limit() as i64,
+        opts.offset() as i64,
+        opts.sort().as_str(),
     )
     .fetch_all(&data.db)
-    .await;
-
-    if query_result.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching featured products",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
+    .await?;
+    let total = sqlx::query_scalar!(r#"SELECT COUNT(*) AS "count!" FROM products WHERE published"#)
+        .fetch_one(&data.db)
+        .await?;
 
-    let products = query_result.unwrap();
-
-    //tera
-    let tera = Tera::new("frontend/**/*.html").unwrap();
     let mut context = common_context();
 
     context.insert("page_title", "Index");
     context.insert("message", "This is Index page.");
     context.insert("products", &products);
+    let paths: Vec<&str> = products.iter().map(|product| product.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    insert_listing_context(&mut context, "/products", &[], &opts, total);
 
     //Static images used across most pages
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     context.insert("static_img", &static_images);
 
-    // Specify the uploads folder
-    let uploads_dir = std::path::Path::new("frontend/img/products");
-
-    // Vector to store the image paths
-    let mut images = Vec::new(); 
-
-    //Read the contents of the uploads directory
-    //TODO Turn this into a function that can be called when needed
-    if let Ok(entries) = std::fs::read_dir(uploads_dir) {
-        for entry in entries {
-            if let Ok(entry) = entry {
-                let path = entry.path();
-                if let Some(ext) = path.extension() {
-                    // Only include files with valid image extensions (e.g., jpg, png)
-                    if ext =="jpg" || ext == "png" || ext == "jpeg" || ext == "gif" || ext == "webp" {
-                        if let Some(path_str) = path.to_str() {
-                            images.push(path_str.to_string());
-                            println!("image path is {}", path_str.to_string());
-
-                        }
-                    }
-                }
-            }
-        }
-    }
-    
-    context.insert("images", &images);
-
-    let output = tera.render("index.html", &context);
-    Html(output.unwrap())
+    let output = data.templates.render("index.html", &context)?;
+    Ok(Html(output))
 }
 
 //TODO rename this to single product template
 pub async fn single_product_display(
     Path(id): Path<Uuid>,
+    opts: Option<Query<ProductPageOptions>>,
     State(data): State<Arc<AppState>>,
-) -> Html<String> {
+    CurrentCustomer(customer): CurrentCustomer,
+) -> Result<Html<String>, AppError> {
 
-    //TODO turn this query into a function
-    let query_result = sqlx::query_as!(
-        ProductModel,
-        "SELECT * FROM products WHERE id = $1",
-        id as Uuid,
+    let product = find_product(&data.db, id).await?;
+    println!("*****SINGLE PRODUCT PAGE*****");
+    let images = find_product_images(&data.db, id).await?;
+    let categories = find_product_categories(&data.db, id).await?;
+    let tags = find_product_tags(&data.db, id).await?;
+
+    let variations: Vec<VariationDetails> = find_variations(&data.db, id)
+        .await?
+        .into_iter()
+        .filter(|variation| variation.variation.enabled)
+        .collect();
+
+    //one select per attribute of the product, with the terms that are part of at least one variation
+    let attributes = sqlx::query_as!(
+        ProductAttributes,
+        "SELECT a.* FROM product_attributes a
+        JOIN product_attributes_map am ON am.attribute_id = a.id
+        WHERE am.product_id = $1
+        ORDER BY am.position",
+        id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let terms = sqlx::query_as!(
+        ProductTerms,
+        "SELECT DISTINCT t.* FROM product_terms t
+        JOIN product_variation_terms vt ON vt.term_id = t.id
+        JOIN product_variations v ON v.id = vt.variation_id
+        WHERE v.product_id = $1 AND v.enabled",
+        id,
     )
     .fetch_all(&data.db)
-    .await;
+    .await?;
+    let options: Vec<_> = attributes
+        .iter()
+        .filter_map(|attribute| {
+            let terms = sorted_terms(attribute, &terms);
+            (!terms.is_empty()).then(|| json!({"id": attribute.id, "name": attribute.name, "terms": terms}))
+        })
+        .collect();
+    //read by the option selects on the page, < is escaped so it can't close the script tag
+    let variations_json = serde_json::to_string(&variations)
+        .map_err(|e| AppError::Io(e.into()))?
+        .replace('<', "\\u003c");
 
-    if query_result.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all note items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
+    //approved reviews, oldest first, with the shop's replies under the review they answer
+    let (reviews, replies): (Vec<ProductReviewModel>, Vec<ProductReviewModel>) = sqlx::query_as!(
+        ProductReviewModel,
+        "SELECT * FROM product_reviews WHERE product_id = $1 AND status = 'approved' ORDER BY created_at",
+        id,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .partition(|review| review.parent_id.is_none());
+    let mut review_replies: HashMap<Uuid, Vec<ProductReviewModel>> = HashMap::new();
+    for reply in replies {
+        if let Some(parent_id) = reply.parent_id {
+            review_replies.entry(parent_id).or_default().push(reply);
+        }
     }
+    let Query(opts) = opts.unwrap_or_default();
 
-    let products = query_result.unwrap();
-    println!("*****SINGLE PRODUCT PAGE*****");
-
-    //tera
-    let tera = Tera::new("frontend/**/*.html").unwrap();
     let mut context = common_context();
 
-    context.insert("page_title", &products[0].title);
+    context.insert("page_title", &product.title);
 
-    context.insert("products", &products);
+    context.insert("product", &product);
+    context.insert("images", &images);
+    let mut paths: Vec<&str> = images.iter().map(|image| image.path.as_str()).collect();
+    paths.push(&product.product_gallery);
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    context.insert("categories", &categories);
+    context.insert("tags", &tags);
+    context.insert("variations", &variations);
+    context.insert("variation_options", &options);
+    context.insert("variations_json", &variations_json);
+    context.insert("reviews", &reviews);
+    context.insert("review_replies", &review_replies);
+    context.insert("logged_in", &customer.is_some());
+    context.insert("can_review", &(customer.is_some() || reviews_allow_guests()));
+    context.insert("review_posted", &(opts.review.as_deref() == Some("posted")));
 
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     //let static_images = vec!["frontend/img/logo_small.webp", "frontend/img/button.png"];
     context.insert("static_img", &static_images);
 
-    let output = tera.render("single_product.html", &context);
-    Html(output.unwrap())
+    let output = data.templates.render("single_product.html", &context)?;
+    Ok(Html(output))
+
+}
+
+//Looks up a single attribute, answering 404 when it doesn't exist
+pub async fn find_attribute(db: &Pool<Postgres>, id: Uuid) -> Result<ProductAttributes, AppError> {
+    sqlx::query_as!(
+        ProductAttributes,
+        "SELECT * FROM product_attributes WHERE id = $1",
+        id,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product attribute with ID: {} not found", id)))
+}
+
+//The terms of an attribute in the attribute's order_by
+pub async fn find_terms(db: &Pool<Postgres>, attribute: &ProductAttributes) -> Result<Vec<ProductTerms>, AppError> {
+    let terms = sqlx::query_as!(
+        ProductTerms,
+        "SELECT * FROM product_terms WHERE product_id = $1",
+        attribute.id,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(sorted_terms(attribute, &terms))
+}
+
+//picks the terms of the attribute out of terms of any attribute, in the attribute's order_by
+fn sorted_terms(attribute: &ProductAttributes, terms: &[ProductTerms]) -> Vec<ProductTerms> {
+    let mut terms: Vec<ProductTerms> = terms.iter().filter(|term| term.product_id == attribute.id).cloned().collect();
+    attribute.order_by.sort(&mut terms);
+    terms
+}
+
+//sort select and page links of the product listings, params are the listing's filters
+fn insert_listing_context(context: &mut tera::Context, path: &str, params: &[(String, String)], opts: &FilterOptions, total: i64) {
+    let sort_options: Vec<_> = ProductSort::ALL.iter().map(|sort| (sort.as_str(), sort.label())).collect();
+    //the sort select starts again from the first page
+    let mut sort_params = params.to_vec();
+    sort_params.extend(opts.params().into_iter().filter(|(name, _)| name != "orderby"));
+    let mut page_params = params.to_vec();
+    page_params.extend(opts.params());
+
+    context.insert("sort_options", &sort_options);
+    context.insert("orderby", opts.sort().as_str());
+    context.insert("sort_params", &sort_params);
+    context.insert("pagination", &Pagination::new(path, &page_params, opts, total));
+}
 
+//value and label of every order_by, for the sort order selects
+fn term_order_options() -> Vec<(&'static str, &'static str)> {
+    TermOrder::ALL.iter().map(|order| (order.as_str(), order.label())).collect()
 }
 
 pub async fn product_attributes_template(
     State(data): State<Arc<AppState>>
-) -> Html<String> {
+) -> Result<Html<String>, AppError> {
     //TODO make this into a function that can be reused in product catalog and single product
 
 
-    let query_result = sqlx::query_as!(
+    let attributes = sqlx::query_as!(
         ProductAttributes,
         "SELECT * FROM product_attributes ORDER by id",
     )
     .fetch_all(&data.db)
-    .await;
-
-    if query_result.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all product attribute items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
+    .await?;
 
     //TODO you probably only need to select the term names
-    let terms_query = sqlx::query_as!(
+    let terms = sqlx::query_as!(
         ProductTerms,
         "SELECT * FROM product_terms ORDER by product_id"
     )
     .fetch_all(&data.db)
-    .await;
-
-    if terms_query.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all product attribute items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
+    .await?;
+    let terms: Vec<ProductTerms> = attributes
+        .iter()
+        .flat_map(|attribute| sorted_terms(attribute, &terms))
+        .collect();
 
-    let attributes = query_result.unwrap();
-    let terms = terms_query.unwrap();
-
-    //tera
-    let tera = Tera::new("frontend/**/*.html").unwrap();
     let mut context = common_context();
 
     context.insert("page_title", "Product Attributes Page");
     context.insert("attributes", &attributes);
     context.insert("terms", &terms);
+    context.insert("order_options", &term_order_options());
 
     //Static images used across most pages
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     context.insert("static_img", &static_images);
 
-    let output = tera.render("product_attributes/product_attributes.html", &context);
-    Html(output.unwrap())
+    let output = data.templates.render("product_attributes/product_attributes.html", &context)?;
+    Ok(Html(output))
 }
 
 pub async fn product_terms_template(
     Path(id): Path<Uuid>,
     State(data): State<Arc<AppState>>
-) -> Html<String> {
-
-    //TODO turn this query into a function
-    let terms_query = sqlx::query_as!(
-        ProductTerms,
-        "SELECT * FROM product_terms WHERE product_id = $1",
-        id as Uuid,
-    )
-    .fetch_all(&data.db)
-    .await;
-
-    if terms_query.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all product attribute items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
+) -> Result<Html<String>, AppError> {
 
-    let attributes_query = sqlx::query_as!(
-        ProductAttributes,
-        "SELECT * FROM product_attributes WHERE id = $1",
-        id as Uuid,
-    )
-    .fetch_all(&data.db)
-    .await;
+    let attribute = find_attribute(&data.db, id).await?;
+    let terms = find_terms(&data.db, &attribute).await?;
 
-    if attributes_query.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching product terms items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
-
-    let terms = terms_query.unwrap();
-    let attributes = attributes_query.unwrap();
-    
-    //tera
-    //let tera = Tera::new("frontend/**/*.html").unwrap();
-    //let tera = Tera::new("frontend/**/*.html,frontend/product_attributes/**/*.html").unwrap();
-    let tera = Tera::new("frontend/**/*.html").unwrap();
-    //let tera = Tera::new("frontend/*.html,frontend/**/*.html").unwrap();
-    //let tera = Tera::new("frontend/product_attributes/**/*.html").unwrap();
     let mut context = common_context();
 
     context.insert("page_title", "Product Attributes Page");
     context.insert("terms", &terms);
-    context.insert("attributes", &attributes);
+    context.insert("attributes", &[&attribute]);
+    context.insert("order_options", &term_order_options());
 
     //Static images used across most pages
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     context.insert("static_img", &static_images);
 
     //let output = tera.render("product_app_control_attribute_edit.html", &context);
-    let output = tera.render("product_attributes/product_app_control_attribute_edit.html", &context);
+    let output = data.templates.render("product_attributes/product_app_control_attribute_edit.html", &context)?;
 
-    Html(output.unwrap())
+    Ok(Html(output))
 }
 
-//TODO finish implementing this function, based on product_attributes_template
+//Admin page at 127.0.0.1:8000/categories with the whole category tree
 pub async fn product_categories_template(
     State(data): State<Arc<AppState>>
-) -> Html<String> {
-    //TODO make this into a function that can be reused in product catalog and single product
-
-
-    let query_result = sqlx::query_as!(
-        ProductCategories,
-        "SELECT * FROM product_categories ORDER by id",
-    )
-    .fetch_all(&data.db)
-    .await;
-
-    if query_result.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all product categories",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
-    }
+) -> Result<Html<String>, AppError> {
+    let categories = category_tree(&data.db).await?;
 
-    let categories = query_result.unwrap();
-    println!("all categories are {:?}", categories);
-
-    //tera
-    let tera = Tera::new("frontend/**/*.html").unwrap();
     let mut context = common_context();
 
     context.insert("page_title", "Product Categories Page");
     context.insert("categories", &categories);
+    context.insert("display_types", &DISPLAY_TYPES);
+    //thumbnails with sizes were uploaded, so they are all in the media library
+    let media = find_media(&data.db).await?;
+    context.insert("image_sizes", &media_sizes(&media));
+    context.insert("media", &media);
 
     //Static images used across most pages
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     context.insert("static_img", &static_images);
 
-    let output = tera.render("product_categories/product_categories.html", &context);
-    Html(output.unwrap())
+    let output = data.templates.render("product_categories/product_categories.html", &context)?;
+    Ok(Html(output))
+}
+
+//Every category, each one followed by its subcategories. Siblings are sorted by name with Uncategorized first
+pub async fn category_tree(db: &Pool<Postgres>) -> Result<Vec<CategoryNode>, AppError> {
+    let categories = sqlx::query_as!(
+        ProductCategories,
+        r#"WITH RECURSIVE tree AS (
+            SELECT id, ARRAY[CASE WHEN slug = 'uncategorized' THEN '0' ELSE '1' END, LOWER(name), id::TEXT] AS path
+            FROM product_categories WHERE parent_id IS NULL
+            UNION ALL
+            SELECT c.id, tree.path || ARRAY[LOWER(c.name), c.id::TEXT]
+            FROM product_categories c JOIN tree ON c.parent_id = tree.id
+        )
+        SELECT c.* FROM product_categories c JOIN tree ON tree.id = c.id ORDER BY tree.path"#,
+    )
+    .fetch_all(db)
+    .await?;
+
+    //parents always come before their children
+    let mut depths: HashMap<Uuid, i32> = HashMap::new();
+    Ok(categories
+        .into_iter()
+        .map(|category| {
+            let depth = category.parent_id.and_then(|parent_id| depths.get(&parent_id)).map_or(0, |depth| depth + 1);
+            depths.insert(category.id, depth);
+            CategoryNode { category, depth }
+        })
+        .collect())
+}
+
+//the category and all of its subcategories, in tree order
+fn category_subtree(tree: &[CategoryNode], id: Uuid) -> &[CategoryNode] {
+    let Some(start) = tree.iter().position(|node| node.category.id == id) else {
+        return &[];
+    };
+    let depth = tree[start].depth;
+    let len = tree[start + 1..].iter().take_while(|node| node.depth > depth).count();
+    &tree[start..=start + len]
+}
+
+pub async fn find_category(db: &Pool<Postgres>, id: Uuid) -> Result<ProductCategories, AppError> {
+    sqlx::query_as!(
+        ProductCategories,
+        "SELECT * FROM product_categories WHERE id = $1",
+        id,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product category with ID: {} not found", id)))
+}
+
+//new categories without an image, it is never added to the media library
+pub const CATEGORY_PLACEHOLDER: &str = "frontend/img/categories/woocommerce-placeholder-300x300.png";
+
+//Reads the add and edit category forms, the image is saved as soon as it is read.
+//An image picked from the media library is used when none was uploaded
+async fn read_category_form(
+    data: &AppState,
+    multipart: &mut Multipart,
+) -> Result<(HashMap<String, String>, Option<String>), AppError> {
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut thumbnail: Option<String> = None;
+    let mut picked: Option<String> = None;
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name == "image" {
+            //browsers send an empty file part when no image was picked
+            if field.file_name().is_none_or(|name| name.is_empty()) {
+                continue;
+            }
+            thumbnail = Some(save_image(data, field, "categories").await?);
+        } else if field_name == "media_id" {
+            picked = Some(field.text().await?).filter(|id| !id.is_empty());
+        } else {
+            fields.insert(field_name, field.text().await?);
+        }
+    }
+    if let (None, Some(id)) = (&thumbnail, picked) {
+        thumbnail = find_media_paths(&data.db, &[id]).await?.pop();
+    }
+    Ok((fields, thumbnail))
+}
+
+//Parents have to exist, and a category can't be moved under itself or one of its subcategories.
+//Called with product_categories locked so the tree can't change in between
+async fn check_category_parent(
+    tx: &mut Transaction<'_, Postgres>,
+    id: Option<Uuid>,
+    parent_id: Option<Uuid>,
+) -> Result<(), AppError> {
+    let Some(parent_id) = parent_id else {
+        return Ok(());
+    };
+
+    let parent = sqlx::query!("SELECT name FROM product_categories WHERE id = $1", parent_id)
+        .fetch_optional(&mut **tx)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("The parent category doesn't exist".to_string()))?;
+
+    if let Some(id) = id {
+        let cycle = sqlx::query_scalar!(
+            r#"WITH RECURSIVE subtree AS (
+                SELECT id FROM product_categories WHERE id = $1
+                UNION
+                SELECT c.id FROM product_categories c JOIN subtree ON c.parent_id = subtree.id
+            )
+            SELECT EXISTS(SELECT 1 FROM subtree WHERE id = $2) AS "cycle!""#,
+            id,
+            parent_id,
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        if cycle {
+            return Err(AppError::BadRequest(format!(
+                "A category can't be moved under itself or one of its subcategories like {}",
+                parent.name
+            )));
+        }
+    }
+    Ok(())
 }
 
 pub async fn create_product_terms_handler(
+    Path(id): Path<Uuid>,
     State(data): State<Arc<AppState>>,
     mut multipart: Multipart,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let mut term_name = String::new();
-    let mut term_slug = String::new();
-    let mut term_desc = String::new();
-    let mut attribute_id = Uuid::new_v4();
-
+) -> Result<impl IntoResponse, AppError> {
+    let mut term = TermSchema::default();
 
-    // Iterate over multipart fields to collect name, slug, and terms
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    // Iterate over multipart fields to collect name, slug, and description
+    while let Some(field) = multipart.next_field().await? {
         if let Some(field_name) = field.name() {
             match field_name {
-                "attribute_id" => {
-                    let id_str = field.text().await.unwrap();
-                    attribute_id = Uuid::parse_str(&id_str).unwrap();
-                }
                 "term_name" => {
-                    term_name = field.text().await.unwrap();
+                    term.term_name = field.text().await?;
                 }
                 "term_slug" => {
-                    term_slug = field.text().await.unwrap();
+                    term.term_slug = field.text().await?;
                 }
-                "term_desc" => {
-                    term_desc = field.text().await.unwrap();
+                //the form used to send term_desc
+                "description" | "term_desc" => {
+                    term.description = field.text().await?;
                 }
+                "attribute_id" | "csrf_token" => {}
                 _ => {
                     println!("Unexpected field: {}", field_name);
                 }
@@ -361,93 +501,310 @@ pub async fn create_product_terms_handler(
         }
     }
 
-    // Validate that required fields are populated
-    if term_name.is_empty() || term_slug.is_empty() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Name and slug are required fields.",
-        });
-        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    let errors = term.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
     }
 
-    // Now insert into the database after fields are collected
+    // Now insert into the database after fields are collected, new terms go last in the custom order
     println!("inserting product term into the database");
-    let query_result = sqlx::query_as!(
+    let attribute = insert_term(&data.db, id, &term).await?;
+
+    //TODO verify if this is what prints in the browser and update it accordingly
+    let response = json!({
+        "status": "success",
+        "data": {
+            "attribute": attribute
+        }
+    });
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+//Adds a term to the attribute id, new terms go last in the custom order
+pub async fn insert_term(db: &Pool<Postgres>, id: Uuid, term: &TermSchema) -> Result<ProductTerms, AppError> {
+    sqlx::query_as!(
         ProductTerms,
-        "INSERT INTO product_terms (product_id, name, slug, description) VALUES ($1, $2, $3, $4) RETURNING *",
-        attribute_id,
-        term_name,
-        term_slug,
-        term_desc,
+        "INSERT INTO product_terms (product_id, name, slug, description, position)
+        VALUES ($1, $2, $3, $4, (SELECT COALESCE(MAX(position) + 1, 0) FROM product_terms WHERE product_id = $1))
+        RETURNING *",
+        id,
+        term.term_name,
+        term.term_slug,
+        term.description,
     )
-    .fetch_one(&data.db)
-    .await;
+    .fetch_one(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product term with that name or slug already exists"))
+}
 
-    // Handle the result of the database operation
-    //TODO verify if this is what prints in the browser and update it accordingly
-    match query_result {
-        Ok(attribute) => {
-            let response = json!({
-                "status": "success",
-                "data": {
-                    "attribute": attribute
+//Admin page at 127.0.0.1:8000/product-attribute/:id/terms/:term_id for editing a term
+pub async fn edit_term_template(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let attribute = find_attribute(&data.db, id).await?;
+    let term = find_term(&data.db, id, term_id).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Edit {}", term.name));
+    context.insert("attribute", &attribute);
+    context.insert("term", &term);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("product_attributes/edit_term.html", &context)?;
+    Ok(Html(output))
+}
+
+pub async fn find_term(db: &Pool<Postgres>, id: Uuid, term_id: Uuid) -> Result<ProductTerms, AppError> {
+    sqlx::query_as!(
+        ProductTerms,
+        "SELECT * FROM product_terms WHERE id = $1 AND product_id = $2",
+        term_id,
+        id,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product term with ID: {} not found", term_id)))
+}
+
+//Renames an attribute or changes how its terms are ordered
+pub async fn update_attribute_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<AttributeSchema>,
+) -> Result<Redirect, AppError> {
+    let errors = body.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    let order_by = body.order_by.parse::<TermOrder>().map_err(AppError::BadRequest)?;
+
+    update_attribute(&data.db, id, &body, order_by).await?;
+    Ok(Redirect::to(&format!("/product-attribute/{}", id)))
+}
+
+pub async fn update_attribute(
+    db: &Pool<Postgres>,
+    id: Uuid,
+    changes: &AttributeSchema,
+    order_by: TermOrder,
+) -> Result<ProductAttributes, AppError> {
+    sqlx::query_as!(
+        ProductAttributes,
+        "UPDATE product_attributes SET name = $1, slug = $2, order_by = $3, updated_at = NOW() WHERE id = $4 RETURNING *",
+        changes.attribute_name,
+        changes.attribute_slug,
+        order_by.as_str(),
+        id,
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product attribute with that slug already exists"))?
+    .ok_or_else(|| AppError::NotFound(format!("Product attribute with ID: {} not found", id)))
+}
+
+//Deletes an attribute with its terms. Products lose it and the variations made with it are disabled
+pub async fn delete_attribute(db: &Pool<Postgres>, id: Uuid) -> Result<ProductAttributes, AppError> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE product_variations SET enabled = FALSE, updated_at = NOW()
+        WHERE id IN (SELECT variation_id FROM product_variation_terms WHERE attribute_id = $1)",
+        id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let attribute = sqlx::query_as!(ProductAttributes, "DELETE FROM product_attributes WHERE id = $1 RETURNING *", id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Product attribute with ID: {} not found", id)))?;
+    tx.commit().await?;
+    Ok(attribute)
+}
+
+pub async fn delete_attribute_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    delete_attribute(&data.db, id).await?;
+    Ok(Redirect::to("/attributes"))
+}
+
+pub async fn update_term_handler(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<TermSchema>,
+) -> Result<Redirect, AppError> {
+    let errors = body.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    update_term(&data.db, id, term_id, &body).await?;
+    Ok(Redirect::to(&format!("/product-attribute/{}", id)))
+}
+
+pub async fn update_term(db: &Pool<Postgres>, id: Uuid, term_id: Uuid, changes: &TermSchema) -> Result<ProductTerms, AppError> {
+    sqlx::query_as!(
+        ProductTerms,
+        "UPDATE product_terms SET name = $1, slug = $2, description = $3 WHERE id = $4 AND product_id = $5 RETURNING *",
+        changes.term_name,
+        changes.term_slug,
+        changes.description,
+        term_id,
+        id,
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product term with that name or slug already exists"))?
+    .ok_or_else(|| AppError::NotFound(format!("Product term with ID: {} not found", term_id)))
+}
+
+//Deletes terms of an attribute, they are taken off every product and the variations made with them are disabled.
+//Returns how many were deleted
+pub async fn delete_terms(db: &Pool<Postgres>, id: Uuid, term_ids: &[Uuid]) -> Result<usize, AppError> {
+    let mut tx = db.begin().await?;
+    sqlx::query!(
+        "UPDATE product_variations SET enabled = FALSE, updated_at = NOW()
+        WHERE id IN (SELECT variation_id FROM product_variation_terms WHERE attribute_id = $1 AND term_id = ANY($2))",
+        id,
+        term_ids,
+    )
+    .execute(&mut *tx)
+    .await?;
+    let deleted = sqlx::query_scalar!(
+        "DELETE FROM product_terms WHERE product_id = $1 AND id = ANY($2) RETURNING id",
+        id,
+        term_ids,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(deleted.len())
+}
+
+pub async fn delete_term_handler(
+    Path((id, term_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    if delete_terms(&data.db, id, &[term_id]).await? == 0 {
+        return Err(AppError::NotFound(format!("Product term with ID: {} not found", term_id)));
+    }
+    Ok(Redirect::to(&format!("/product-attribute/{}", id)))
+}
+
+//Bulk actions form of the attribute's terms page, delete is the only action
+pub async fn bulk_delete_terms_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut term_ids: Vec<Uuid> = Vec::new();
+    let mut action = String::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "term_ids" => {
+                let term_id = field.text().await?;
+                term_ids.push(
+                    term_id
+                        .parse()
+                        .map_err(|_| AppError::BadRequest(format!("{} is not a term", term_id)))?,
+                );
+            }
+            //the same select is at the top and the bottom of the table
+            "action" | "action2" => {
+                let value = field.text().await?;
+                if value != "-1" {
+                    action = value;
                 }
-            });
-            Ok((StatusCode::CREATED, Json(response)))
-        }
-        Err(e) => {
-            if e.to_string()
-                .contains("duplicate key value violates unique constraint")
-            {
-                let error_response = serde_json::json!({
-                    "status": "fail",
-                    "message": "Product attribute with that name already exists",
-                });
-                return Err((StatusCode::CONFLICT, Json(error_response)));
             }
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error", "message": format!("{:?}", e)})),
-            ));
+            "csrf_token" => {}
+            _ => println!("Unexpected field: {}", field_name),
         }
     }
+
+    match action.as_str() {
+        "delete" => {
+            delete_terms(&data.db, id, &term_ids).await?;
+        }
+        "" => return Err(AppError::BadRequest("Choose a bulk action".to_string())),
+        _ => return Err(AppError::BadRequest(format!("Unknown bulk action {}", action))),
+    }
+    Ok(Redirect::to(&format!("/product-attribute/{}", id)))
 }
 
-pub async fn create_product_template(
-    State(data): State<Arc<AppState>>
-) -> Html<String> {
+//Saves the drag and drop order of the terms, used when the attribute's order_by is custom ordering
+pub async fn reorder_terms_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<TermOrderSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    let attribute = find_attribute(&data.db, id).await?;
 
-    let query_result = sqlx::query_as!(
-        ProductCategories,
-        "SELECT * FROM product_categories ORDER by id",
+    let mut tx = data.db.begin().await?;
+    let mut known = sqlx::query_scalar!(
+        "SELECT id FROM product_terms WHERE product_id = $1 FOR UPDATE",
+        attribute.id,
     )
-    .fetch_all(&data.db)
-    .await;
-
-    if query_result.is_err() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Something bad happened while fetching all product attribute items",
-        });
-        //TODO create function to handle errors
-        //return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(error_response)));
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut sent = body.term_ids.clone();
+    known.sort();
+    sent.sort();
+    if known != sent {
+        return Err(AppError::BadRequest(format!(
+            "The order has to list every term of {} once, reload the page and try again",
+            attribute.name
+        )));
     }
 
-    let categories = query_result.unwrap();
+    let positions: Vec<i32> = (0..body.term_ids.len() as i32).collect();
+    sqlx::query!(
+        "UPDATE product_terms t SET position = ordered.position
+        FROM UNNEST($1::UUID[], $2::INTEGER[]) AS ordered(id, position)
+        WHERE t.id = ordered.id",
+        &body.term_ids,
+        &positions,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let terms = find_terms(&data.db, &attribute).await?;
+    let response = json!({
+        "status": "success",
+        "data": {
+            "terms": terms
+        }
+    });
+    Ok(Json(response))
+}
+
+pub async fn create_product_template(
+    State(data): State<Arc<AppState>>
+) -> Result<Html<String>, AppError> {
+
+    let categories = category_tree(&data.db).await?;
 
-    //tera
-    let tera = Tera::new("frontend/**/*.html").unwrap();
     let mut context = common_context();
 
     context.insert("page_title", "Add new Product");
     context.insert("categories", &categories);
+    let media = find_media(&data.db).await?;
+    context.insert("image_sizes", &media_sizes(&media));
+    context.insert("media", &media);
 
     //Static images used across most pages
     let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
     context.insert("static_img", &static_images);
 
-    let output = tera.render("products/create_product_form.html", &context);
-    Html(output.unwrap())
+    let output = data.templates.render("products/create_product_form.html", &context)?;
+    Ok(Html(output))
 }
 
 //pub async fn create_product_form() -> Html<&'static str> {
@@ -467,24 +824,23 @@ pub async fn create_product_template(
 pub async fn create_product_attribute_handler(
     State(data): State<Arc<AppState>>,
     mut multipart: Multipart,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let mut attribute_name = String::new();
-    let mut attribute_slug = String::new();
-    let mut order_by = String::new();
+) -> Result<impl IntoResponse, AppError> {
+    let mut attribute = AttributeSchema::default();
 
-    // Iterate over multipart fields to collect name, slug, and terms
-    while let Some(field) = multipart.next_field().await.unwrap() {
+    // Iterate over multipart fields to collect name, slug, and order_by
+    while let Some(field) = multipart.next_field().await? {
         if let Some(field_name) = field.name() {
             match field_name {
                 "attribute_name" => {
-                    attribute_name = field.text().await.unwrap();
+                    attribute.attribute_name = field.text().await?;
                 }
                 "attribute_slug" => {
-                    attribute_slug = field.text().await.unwrap();
+                    attribute.attribute_slug = field.text().await?;
                 }
                 "order_by" => {
-                    order_by = field.text().await.unwrap();
+                    attribute.order_by = field.text().await?;
                 }
+                "csrf_token" => {}
                 _ => {
                     println!("Unexpected field: {}", field_name);
                 }
@@ -492,311 +848,4696 @@ pub async fn create_product_attribute_handler(
         }
     }
 
-    // Validate that required fields are populated
-    if attribute_name.is_empty() || attribute_slug.is_empty() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Name, slug, and terms are required fields.",
-        });
-        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
+    let errors = attribute.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
     }
+    let order_by = attribute.order_by.parse::<TermOrder>().map_err(AppError::BadRequest)?;
 
     // Now insert into the database after fields are collected
-    let query_result = sqlx::query_as!(
+    let attribute = insert_attribute(&data.db, &attribute, order_by).await?;
+
+    let response = json!({
+        "status": "success",
+        "data": {
+            "attribute": attribute
+        }
+    });
+    Ok((StatusCode::CREATED, Json(response)))
+}
+
+//Adds an attribute, used by the attributes page and the REST API
+pub async fn insert_attribute(
+    db: &Pool<Postgres>,
+    attribute: &AttributeSchema,
+    order_by: TermOrder,
+) -> Result<ProductAttributes, AppError> {
+    sqlx::query_as!(
         ProductAttributes,
         "INSERT INTO product_attributes (name, slug, order_by) VALUES ($1, $2, $3) RETURNING *",
-        attribute_name,
-        attribute_slug,
-        order_by,
+        attribute.attribute_name,
+        attribute.attribute_slug,
+        order_by.as_str(),
     )
-    .fetch_one(&data.db)
-    .await;
-
-    // Handle the result of the database operation
-    match query_result {
-        Ok(attribute) => {
-            let response = json!({
-                "status": "success",
-                "data": {
-                    "attribute": attribute
-                }
-            });
-            Ok((StatusCode::CREATED, Json(response)))
-        }
-        Err(e) => {
-            if e.to_string()
-                .contains("duplicate key value violates unique constraint")
-            {
-                let error_response = serde_json::json!({
-                    "status": "fail",
-                    "message": "Product attribute with that name already exists",
-                });
-                return Err((StatusCode::CONFLICT, Json(error_response)));
-            }
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error", "message": format!("{:?}", e)})),
-            ));
-        }
-    }
+    .fetch_one(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product attribute with that slug already exists"))
 }
 
 pub async fn create_product_category_handler(
     State(data): State<Arc<AppState>>,
     mut multipart: Multipart,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let mut name = String::new();
-    let mut lvl = String::new();
-    let mut slug = String::new();
-    let mut parent = String::new();
-    let mut description = String::new();
-    let mut display_type = String::new();
-    let mut thumbnail = String::new();
-    //let child_categories: Vec<String> = Vec::new();
-
-    // Iterate over multipart fields to collect name, slug, parent category, description and image
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        if let Some(field_name) = field.name() {
-            match field_name {
-                "name" => {
-                    name = field.text().await.unwrap();
-                    println!("listing this name {name}");
-                }
-                "slug" => {
-                    slug = field.text().await.unwrap();
-                }
-                "parent" => {
-                    //parent = field.text().await.unwrap();
-                    //let fullfield: Vec<&str> = field.text().await.unwrap().split("|").collect();
-                    let fullfield = field.text().await.unwrap();
-                    if fullfield == "-1" {
-                        //TODO simplify this like on line 545
-                        let tmplvl = fullfield.parse::<i32>().unwrap() + 1;
-                        lvl = tmplvl.to_string();
-                    }
-                    else {
-                        let splitter: Vec<&str> = fullfield.split("|").collect();
-                        //sets lvl to 0 if there's no parent category
-                        //let lvlcheck = "-1";
-                        //if parent == lvlcheck {
-                        println!("THIS IS fullfield: {}", fullfield);
-                        println!("THIS IS A PROBLEM: {}", splitter[0].to_string());
-                        parent = splitter[0].to_string();
-                        let tmplvl = splitter[1].parse::<i32>().unwrap();
-                        lvl = (tmplvl + 1).to_string();
-                        //lvl = (splitter[1]).to_string();
-                        //println!("This is the second part of splitter: {}", splitter[1].to_string());
-                        //TODO figure this out
-                        //lvl should be the parents lvl + 1
-                        //parent will = the UUID of the parent category
-                        //lookup parent with UUID then find parent category and set lvl to +1?
-                        //else {
-                        //    lvl = parent.clone();
-                        //}
-                    }
-                }
-                "description" => {
-                    description = field.text().await.unwrap();
-                }
-                "image" => {
-                    // File upload handling
-                    let file_name = field.file_name().unwrap().to_string();
-                    let content_type = field.content_type().unwrap().to_string();
-                    let data = field.bytes().await.unwrap();
-
-                    let Some(file_type) = content_type.split('/').nth(1) else {
-                        return Err((StatusCode::BAD_REQUEST, Json(json!({"error": "Invalid file type"}))));
-                    };
-
-                    //let upload_path = format!("frontend/img/categories/{}", BASE64_STANDARD.encode(&file_name));
-                    thumbnail = format!("frontend/img/categories/{}", BASE64_STANDARD.encode(&file_name));
-                    println!("Uploading file to {:?}", thumbnail);
-                    fs::write(&thumbnail, data).await.unwrap();
-                    //thumbnail = upload_path;
-                    //order_by = field.text().await.unwrap();
-                }
-                _ => {
-                    println!("Unexpected field: {}", field_name);
-                }
-            }
-        }
-    }
-    // Validate that required fields are populated
-    if name.is_empty() || slug.is_empty() {
-        let error_response = serde_json::json!({
-            "status": "fail",
-            "message": "Name, slug, and terms are required fields.",
-        });
-        return Err((StatusCode::BAD_REQUEST, Json(error_response)));
-    }
+) -> Result<impl IntoResponse, AppError> {
+    let (fields, thumbnail) = read_category_form(&data, &mut multipart).await?;
 
-    // Now insert into the database after fields are collected
-    let query_result = sqlx::query_as!(
-        ProductCategories,
-        "INSERT INTO product_categories (name, slug, parent, description, display_type, lvl, thumbnail) VALUES ($1, $2, $3, $4, $5, $6, $7) RETURNING *",
-        name,
-        slug,
-        parent,
-        description,
-        display_type,
-        lvl,
-        thumbnail,
-    )
-    .fetch_one(&data.db)
-    .await;
-    println!("inserted name {name} successfully");
-    println!("inserted slug {slug} successfully");
-    println!("inserted parent {parent} successfully");
-    println!("inserted description {description} successfully");
-    println!("inserted display_type {display_type} successfully");
-    println!("inserted lvl {lvl} successfully");
-    println!("inserted thumbnail {thumbnail} successfully");
-
-    // Handle the result of the database operation
-    match query_result {
-        Ok(note) => {
-            let note_response = json!({"status": "success","data": json!({
-                "note": note
-            })});
-
-            return Ok((StatusCode::CREATED, Json(note_response)));
-        }
-        Err(e) => {
-            if e.to_string()
-                .contains("duplicate key value violates unique constraint")
-            {
-                let error_response = serde_json::json!({
-                    "status": "fail",
-                    "message": "Product with that name already exists",
-                });
-                return Err((StatusCode::CONFLICT, Json(error_response)));
-            }
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
-            ));
-        }
-    }
+    let category = CategorySchema::from_fields(&fields).map_err(AppError::Validation)?;
+    let note = insert_category(&data.db, &category, thumbnail.as_deref().unwrap_or(CATEGORY_PLACEHOLDER)).await?;
 
+    let note_response = json!({"status": "success","data": json!({
+        "note": note
+    })});
+
+    Ok((StatusCode::CREATED, Json(note_response)))
 }
 
-//TODO get a function working that can accept product parameters and images
-//TODO images with a space in the name aren't being displayed properly, the src="" ends at the space
-///Some of this was written by ChatGPT
-///creates products
-pub async fn multipart_create_product_handler(
+//Admin page at 127.0.0.1:8000/categories/:id for editing and moving a category
+pub async fn edit_category_template(
+    Path(id): Path<Uuid>,
     State(data): State<Arc<AppState>>,
-    mut multipart: Multipart,
-) -> Result<impl IntoResponse, (StatusCode, Json<serde_json::Value>)> {
-    let mut text_inputs = vec![String::new(); 17]; // Store text inputs
-
-    //maps variables to tag names in create_product_form.html
-    let field_mapping = [
-        "title", "description", "category", "price", "sku", "product_type", "stock",
-        "allow_backorders", "low_stock_threshold", "shipping_weight", "product_gallery", "attributes",
-        "variations", "shipping_dimensions", "shipping_class", "tax_status", "tax_class"
-    ];
-    
-    //TODO Remove this, it's unnecessary
-    //tera
-    //let tera = Tera::new("frontend/**/*.html").unwrap();
-    //let mut context = common_context();
-
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        if let Some(field_name) = field.name() {
-            print!("{:?} = ", field_name);
-            //TODO refactor this so that the product_gallery case is handled first and its related
-            //text_inputs[index] is equal to the file path after the image is uploaded
-            //if let Some(index) = field_mapping.iter().position(|&name| name == field_name) {
-            if field_name == "product_gallery" {
-                // File upload handling
-                let file_name = field.file_name().unwrap().to_string();
-                let content_type = field.content_type().unwrap().to_string();
-                let data = field.bytes().await.unwrap();
-
-                let Some(file_type) = content_type.split('/').nth(1) else {
-                    return Err((StatusCode::BAD_REQUEST, Json(json!({"error": "Invalid file type"}))));
-                };
-
-                //let upload_path = format!("/home/kenny/code/Rust/rust-axum-postgres-api/uploads/{}", file_name);
-                let upload_path = format!("frontend/img/products/{}", BASE64_STANDARD.encode(&file_name));
-                println!("Uploading file to {:?}", upload_path);
-                fs::write(&upload_path, data).await.unwrap();
-                text_inputs[10] = upload_path;
-            } else if let Some(index) = field_mapping.iter().position(|&name| name == field_name) {
-                text_inputs[index] = field.text().await.unwrap();
-                //println!("{:?} is {:?}", field_name, text_inputs[index]);
-                println!("{:?}", text_inputs[index]);
-            } 
-        }
-    }
-
-    let query_result = sqlx::query_as!(
-        ProductModel,
-        "INSERT INTO products (
-        title,
-        description,
-        category,
-        price,
-        sku,
-        product_type,
-        stock,
+) -> Result<Html<String>, AppError> {
+    let category = find_category(&data.db, id).await?;
+    let tree = category_tree(&data.db).await?;
+
+    //it can't be moved under itself or its subcategories
+    let subtree: Vec<Uuid> = category_subtree(&tree, id).iter().map(|node| node.category.id).collect();
+    let parents: Vec<&CategoryNode> = tree.iter().filter(|node| !subtree.contains(&node.category.id)).collect();
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Edit {}", category.name));
+    context.insert("category", &category);
+    context.insert("parents", &parents);
+    context.insert("display_types", &DISPLAY_TYPES);
+    //the thumbnail too, uploads are all in the media library
+    let media = find_media(&data.db).await?;
+    context.insert("image_sizes", &media_sizes(&media));
+    context.insert("media", &media);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("product_categories/edit_category.html", &context)?;
+    Ok(Html(output))
+}
+
+//Updates or moves a category, the counts of its old and new ancestors are refreshed by the database
+pub async fn update_category_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let (fields, thumbnail) = read_category_form(&data, &mut multipart).await?;
+
+    let changes = CategorySchema::from_fields(&fields).map_err(AppError::Validation)?;
+    update_category(&data.db, id, &changes, thumbnail.as_deref()).await?;
+    Ok(Redirect::to("/categories"))
+}
+
+//Adds a category, used by the categories page and the REST API
+pub async fn insert_category(
+    db: &Pool<Postgres>,
+    category: &CategorySchema,
+    thumbnail: &str,
+) -> Result<ProductCategories, AppError> {
+    let mut tx = db.begin().await?;
+    sqlx::query!("LOCK TABLE product_categories IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    check_category_parent(&mut tx, None, category.parent_id).await?;
+
+    let category = sqlx::query_as!(
+        ProductCategories,
+        "INSERT INTO product_categories (name, slug, parent_id, description, display_type, thumbnail)
+        VALUES ($1, $2, $3, $4, $5, $6) RETURNING *",
+        category.name,
+        category.slug,
+        category.parent_id,
+        category.description,
+        category.display_type,
+        thumbnail,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product category with that slug already exists"))?;
+    tx.commit().await?;
+    Ok(category)
+}
+
+//Updates or moves a category, the old thumbnail stays in the media library
+pub async fn update_category(
+    db: &Pool<Postgres>,
+    id: Uuid,
+    changes: &CategorySchema,
+    thumbnail: Option<&str>,
+) -> Result<ProductCategories, AppError> {
+    let mut tx = db.begin().await?;
+    sqlx::query!("LOCK TABLE product_categories IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("SELECT id FROM product_categories WHERE id = $1", id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Product category with ID: {} not found", id)))?;
+    check_category_parent(&mut tx, Some(id), changes.parent_id).await?;
+
+    let category = sqlx::query_as!(
+        ProductCategories,
+        "UPDATE product_categories SET
+        name = $1,
+        slug = $2,
+        parent_id = $3,
+        description = $4,
+        display_type = $5,
+        thumbnail = COALESCE($6, thumbnail),
+        updated_at = NOW()
+        WHERE id = $7 RETURNING *",
+        changes.name,
+        changes.slug,
+        changes.parent_id,
+        changes.description,
+        changes.display_type,
+        thumbnail,
+        id,
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product category with that slug already exists"))?;
+    tx.commit().await?;
+    Ok(category)
+}
+
+//Deletes a category. Its subcategories move up to its parent. Products that were only in this
+//category go to the parent too, or to Uncategorized for top level categories. Returns the deleted category
+async fn delete_category(tx: &mut Transaction<'_, Postgres>, id: Uuid) -> Result<ProductCategories, AppError> {
+    let category = sqlx::query_as!(
+        ProductCategories,
+        "SELECT * FROM product_categories WHERE id = $1",
+        id,
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product category with ID: {} not found", id)))?;
+    if category.slug == "uncategorized" {
+        return Err(AppError::BadRequest(format!("{} can't be deleted", category.name)));
+    }
+
+    sqlx::query!(
+        "UPDATE product_categories SET parent_id = $1, updated_at = NOW() WHERE parent_id = $2",
+        category.parent_id,
+        category.id,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO product_categories_map (product_id, category_id)
+        SELECT m.product_id, f.id FROM product_categories_map m
+        JOIN product_categories f ON f.id = $1 OR ($1 IS NULL AND f.slug = 'uncategorized')
+        WHERE m.category_id = $2 AND NOT EXISTS (
+            SELECT 1 FROM product_categories_map o WHERE o.product_id = m.product_id AND o.category_id <> $2
+        )",
+        category.parent_id,
+        category.id,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    //the category's rows in product_categories_map go with it
+    sqlx::query!("DELETE FROM product_categories WHERE id = $1", category.id)
+        .execute(&mut **tx)
+        .await?;
+    Ok(category)
+}
+
+//Deletes a single category, see delete_category. Its thumbnail stays in the media library
+pub async fn remove_category(db: &Pool<Postgres>, id: Uuid) -> Result<ProductCategories, AppError> {
+    let mut tx = db.begin().await?;
+    sqlx::query!("LOCK TABLE product_categories IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    let category = delete_category(&mut tx, id).await?;
+    tx.commit().await?;
+    Ok(category)
+}
+
+pub async fn delete_category_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    remove_category(&data.db, id).await?;
+    Ok(Redirect::to("/categories"))
+}
+
+//Bulk actions form of the categories page, delete is the only action
+pub async fn bulk_delete_categories_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut category_ids: Vec<Uuid> = Vec::new();
+    let mut action = String::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "category_ids" => {
+                let category_id = field.text().await?;
+                category_ids.push(
+                    category_id
+                        .parse()
+                        .map_err(|_| AppError::BadRequest(format!("{} is not a category", category_id)))?,
+                );
+            }
+            //the same select is at the top and the bottom of the table
+            "action" | "action2" => {
+                let value = field.text().await?;
+                if value != "-1" {
+                    action = value;
+                }
+            }
+            "csrf_token" => {}
+            _ => println!("Unexpected field: {}", field_name),
+        }
+    }
+
+    match action.as_str() {
+        "delete" => {}
+        "" => return Err(AppError::BadRequest("Choose a bulk action".to_string())),
+        _ => return Err(AppError::BadRequest(format!("Unknown bulk action {}", action))),
+    }
+
+    let mut tx = data.db.begin().await?;
+    sqlx::query!("LOCK TABLE product_categories IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await?;
+    for id in category_ids {
+        delete_category(&mut tx, id).await?;
+    }
+    tx.commit().await?;
+    Ok(Redirect::to("/categories"))
+}
+
+//Admin page at 127.0.0.1:8000/tags
+pub async fn product_tags_template(
+    State(data): State<Arc<AppState>>
+) -> Result<Html<String>, AppError> {
+    let tags = sqlx::query_as!(
+        ProductTags,
+        "SELECT * FROM product_tags ORDER BY LOWER(name), id",
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Product Tags Page");
+    context.insert("tags", &tags);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("product_tags/product_tags.html", &context)?;
+    Ok(Html(output))
+}
+
+pub async fn find_tag(db: &Pool<Postgres>, id: Uuid) -> Result<ProductTags, AppError> {
+    sqlx::query_as!(
+        ProductTags,
+        "SELECT * FROM product_tags WHERE id = $1",
+        id,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product tag with ID: {} not found", id)))
+}
+
+pub async fn create_product_tag_handler(
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<TagSchema>,
+) -> Result<Redirect, AppError> {
+    let errors = body.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    insert_tag(&data.db, &body).await?;
+    Ok(Redirect::to("/tags"))
+}
+
+pub async fn insert_tag(db: &Pool<Postgres>, tag: &TagSchema) -> Result<ProductTags, AppError> {
+    sqlx::query_as!(
+        ProductTags,
+        "INSERT INTO product_tags (name, slug, description) VALUES ($1, $2, $3) RETURNING *",
+        tag.name,
+        tag.slug,
+        tag.description,
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product tag with that name or slug already exists"))
+}
+
+pub async fn edit_tag_template(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let tag = find_tag(&data.db, id).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Edit {}", tag.name));
+    context.insert("tag", &tag);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("product_tags/edit_tag.html", &context)?;
+    Ok(Html(output))
+}
+
+pub async fn update_tag_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<TagSchema>,
+) -> Result<Redirect, AppError> {
+    let errors = body.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    update_tag(&data.db, id, &body).await?;
+    Ok(Redirect::to("/tags"))
+}
+
+pub async fn update_tag(db: &Pool<Postgres>, id: Uuid, changes: &TagSchema) -> Result<ProductTags, AppError> {
+    sqlx::query_as!(
+        ProductTags,
+        "UPDATE product_tags SET name = $1, slug = $2, description = $3, updated_at = NOW() WHERE id = $4 RETURNING *",
+        changes.name,
+        changes.slug,
+        changes.description,
+        id,
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product tag with that name or slug already exists"))?
+    .ok_or_else(|| AppError::NotFound(format!("Product tag with ID: {} not found", id)))
+}
+
+//Deleting a tag takes it off its products, the products themselves are kept
+pub async fn delete_tag(db: &Pool<Postgres>, id: Uuid) -> Result<ProductTags, AppError> {
+    sqlx::query_as!(ProductTags, "DELETE FROM product_tags WHERE id = $1 RETURNING *", id)
+        .fetch_optional(db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Product tag with ID: {} not found", id)))
+}
+
+pub async fn delete_tag_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    delete_tag(&data.db, id).await?;
+    Ok(Redirect::to("/tags"))
+}
+
+//Bulk actions form of the tags page, delete is the only action
+pub async fn bulk_delete_tags_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut tag_ids: Vec<Uuid> = Vec::new();
+    let mut action = String::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "tag_ids" => {
+                let tag_id = field.text().await?;
+                tag_ids.push(
+                    tag_id
+                        .parse()
+                        .map_err(|_| AppError::BadRequest(format!("{} is not a tag", tag_id)))?,
+                );
+            }
+            //the same select is at the top and the bottom of the table
+            "action" | "action2" => {
+                let value = field.text().await?;
+                if value != "-1" {
+                    action = value;
+                }
+            }
+            "csrf_token" => {}
+            _ => println!("Unexpected field: {}", field_name),
+        }
+    }
+
+    match action.as_str() {
+        "delete" => {}
+        "" => return Err(AppError::BadRequest("Choose a bulk action".to_string())),
+        _ => return Err(AppError::BadRequest(format!("Unknown bulk action {}", action))),
+    }
+
+    sqlx::query!("DELETE FROM product_tags WHERE id = ANY($1)", &tag_ids)
+        .execute(&data.db)
+        .await?;
+    Ok(Redirect::to("/tags"))
+}
+
+//how many names the tags autocomplete suggests
+const TAG_SEARCH_LIMIT: i64 = 10;
+
+//Autocomplete of the tags field on the product forms, the tags starting with q, most used first
+pub async fn tag_search_handler(
+    opts: Option<Query<TagSearchOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let q = opts.q.trim();
+
+    let tags = if q.is_empty() {
+        Vec::new()
+    } else {
+        //% and _ typed by the user are matched literally
+        let pattern = format!("{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_"));
+        sqlx::query_scalar!(
+            "SELECT name FROM product_tags WHERE LOWER(name) LIKE LOWER($1) ORDER BY count DESC, LOWER(name) LIMIT $2",
+            pattern,
+            TAG_SEARCH_LIMIT,
+        )
+        .fetch_all(&data.db)
+        .await?
+    };
+
+    let response = json!({"status": "success","data": json!({
+        "tags": tags
+    })});
+
+    Ok(Json(response))
+}
+
+//Storefront page at /tag/:slug with the published products that have the tag
+pub async fn tag_products_template(
+    Path(slug): Path<String>,
+    opts: Option<Query<FilterOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let tag = sqlx::query_as!(
+        ProductTags,
+        "SELECT * FROM product_tags WHERE slug = $1",
+        slug,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product tag {} not found", slug)))?;
+
+    let products = sqlx::query_as!(
+        ProductModel,
+        "SELECT p.* FROM products p
+        JOIN product_tags_map m ON m.product_id = p.id
+        WHERE m.tag_id = $1 AND p.published
+        ORDER BY
+            CASE WHEN $4 = 'popularity' THEN p.total_sales END DESC,
+            CASE WHEN $4 = 'rating' THEN p.average_rating END DESC,
+            CASE WHEN $4 = 'rating' THEN p.rating_count END DESC,
+            CASE WHEN $4 = 'price' THEN p.price END ASC,
+            CASE WHEN $4 = 'price-desc' THEN p.price END DESC,
+            CASE WHEN $4 = 'title' THEN LOWER(p.title) END ASC,
+            p.created_at DESC, p.id
+        LIMIT $2 OFFSET $3",
+        tag.id,
+        opts.limit() as i64,
+        opts.offset() as i64,
+        opts.sort().as_str(),
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let total = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM products p
+        JOIN product_tags_map m ON m.product_id = p.id
+        WHERE m.tag_id = $1 AND p.published"#,
+        tag.id,
+    )
+    .fetch_one(&data.db)
+    .await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &tag.name);
+    context.insert("tag", &tag);
+    context.insert("products", &products);
+    let paths: Vec<&str> = products.iter().map(|product| product.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    insert_listing_context(&mut context, &format!("/tag/{}", tag.slug), &[], &opts, total);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("tag.html", &context)?;
+    Ok(Html(output))
+}
+
+//The number of products found, and the price range and the in stock count, each leaving out its own filter
+struct CategoryFilterSummary {
+    total: i64,
+    min_price: Option<Decimal>,
+    max_price: Option<Decimal>,
+    in_stock_count: i64,
+}
+
+async fn category_filter_summary(
+    db: &Pool<Postgres>,
+    category_id: Uuid,
+    filters: &CategoryFilters,
+    term_ids: &[Uuid],
+) -> Result<CategoryFilterSummary, AppError> {
+    let summary = sqlx::query_as!(
+        CategoryFilterSummary,
+        r#"SELECT
+            COUNT(*) FILTER (WHERE price_ok AND stock_ok AND terms_ok) AS "total!",
+            MIN(min_price) FILTER (WHERE stock_ok AND terms_ok) AS min_price,
+            MAX(max_price) FILTER (WHERE stock_ok AND terms_ok) AS max_price,
+            COUNT(*) FILTER (WHERE in_stock AND price_ok AND terms_ok) AS "in_stock_count!"
+        FROM category_filter_matches($1, $2, $3, $4, $5)"#,
+        category_id,
+        filters.min_price,
+        filters.max_price,
+        filters.in_stock,
+        term_ids,
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(summary)
+}
+
+//Products each term would leave, counted with the filters of the other attributes so
+//more terms of an attribute that is already filtered on can still be picked
+async fn category_term_counts(
+    db: &Pool<Postgres>,
+    category_id: Uuid,
+    filters: &CategoryFilters,
+    term_ids: &[Uuid],
+) -> Result<HashMap<Uuid, i64>, AppError> {
+    let counts = sqlx::query!(
+        r#"SELECT tm.term_id, COUNT(*) AS "count!"
+        FROM category_filter_matches($1, $2, $3, $4, $5) m
+        JOIN product_terms_map tm ON tm.product_id = m.id
+        JOIN product_terms t ON t.id = tm.term_id
+        WHERE m.price_ok AND m.stock_ok AND NOT EXISTS (
+            SELECT 1 FROM product_terms ft WHERE ft.id = ANY($5) AND ft.product_id <> t.product_id AND NOT EXISTS (
+                SELECT 1 FROM product_terms_map otm JOIN product_terms ot ON ot.id = otm.term_id
+                WHERE otm.product_id = m.id AND ot.product_id = ft.product_id AND ot.id = ANY($5)
+            )
+        )
+        GROUP BY tm.term_id"#,
+        category_id,
+        filters.min_price,
+        filters.max_price,
+        filters.in_stock,
+        term_ids,
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| (row.term_id, row.count))
+    .collect();
+    Ok(counts)
+}
+
+//Storefront page of a category at 127.0.0.1:8000/category/:slug, with the published products of the category
+//and its subcategories. They can be narrowed down by price, attribute terms and stock (see CategoryFilters),
+//and every filter value shows how many products it would leave when picked.
+//A variable product's price and stock come from its enabled variations
+pub async fn category_products_template(
+    Path(slug): Path<String>,
+    opts: Option<Query<FilterOptions>>,
+    Query(params): Query<HashMap<String, String>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let category = sqlx::query_as!(
+        ProductCategories,
+        "SELECT * FROM product_categories WHERE slug = $1",
+        slug,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product category {} not found", slug)))?;
+    let mut filters = CategoryFilters::from_query(&params).map_err(AppError::Validation)?;
+
+    let attributes = sqlx::query_as!(
+        ProductAttributes,
+        "SELECT * FROM product_attributes ORDER BY name",
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let terms = sqlx::query_as!(ProductTerms, "SELECT * FROM product_terms")
+        .fetch_all(&data.db)
+        .await?;
+
+    //attributes and terms that don't exist are dropped from the filters
+    let mut term_ids: Vec<Uuid> = Vec::new();
+    filters.terms.retain(|attribute_slug, slugs| {
+        let Some(attribute) = attributes.iter().find(|attribute| &attribute.slug == attribute_slug) else {
+            return false;
+        };
+        slugs.retain(|slug| {
+            match terms.iter().find(|term| term.product_id == attribute.id && &term.slug == slug) {
+                Some(term) => {
+                    term_ids.push(term.id);
+                    true
+                }
+                None => false,
+            }
+        });
+        !slugs.is_empty()
+    });
+
+    //category_filter_matches has the price range and stock of every published product in the category tree,
+    //and whether it passes the price, stock and term filters
+    let products = sqlx::query_as!(
+        ProductModel,
+        "SELECT p.* FROM products p JOIN category_filter_matches($1, $2, $3, $4, $5) m ON m.id = p.id
+        WHERE m.price_ok AND m.stock_ok AND m.terms_ok
+        ORDER BY
+            CASE WHEN $8 = 'popularity' THEN p.total_sales END DESC,
+            CASE WHEN $8 = 'rating' THEN p.average_rating END DESC,
+            CASE WHEN $8 = 'rating' THEN p.rating_count END DESC,
+            CASE WHEN $8 = 'price' THEN m.min_price END ASC,
+            CASE WHEN $8 = 'price-desc' THEN m.max_price END DESC,
+            CASE WHEN $8 = 'title' THEN LOWER(p.title) END ASC,
+            p.created_at DESC, p.id
+        LIMIT $6 OFFSET $7",
+        category.id,
+        filters.min_price,
+        filters.max_price,
+        filters.in_stock,
+        &term_ids,
+        opts.limit() as i64,
+        opts.offset() as i64,
+        opts.sort().as_str(),
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let summary = category_filter_summary(&data.db, category.id, &filters, &term_ids).await?;
+    let term_counts = category_term_counts(&data.db, category.id, &filters, &term_ids).await?;
+
+    let base_url = format!("/category/{}", category.slug);
+    //changing a filter keeps the sort order and starts again from the first page
+    let filter_url = |filters: &CategoryFilters| {
+        let mut params = filters.params();
+        params.extend(opts.params());
+        format!("{}{}", base_url, query_string(&params))
+    };
+    //one list per attribute with terms left to pick, a picked term links to the page without it
+    let facets: Vec<_> = attributes
+        .iter()
+        .filter_map(|attribute| {
+            let picked = filters.terms.get(&attribute.slug);
+            let options: Vec<_> = sorted_terms(attribute, &terms)
+                .into_iter()
+                .filter_map(|term| {
+                    let selected = picked.is_some_and(|slugs| slugs.contains(&term.slug));
+                    let count = term_counts.get(&term.id).copied().unwrap_or(0);
+                    (selected || count > 0).then(|| json!({
+                        "name": term.name,
+                        "count": count,
+                        "selected": selected,
+                        "url": filter_url(&filters.toggle_term(&attribute.slug, &term.slug)),
+                    }))
+                })
+                .collect();
+            (!options.is_empty()).then(|| json!({"name": attribute.name, "slug": attribute.slug, "terms": options}))
+        })
+        .collect();
+
+    let stock_filters = CategoryFilters { in_stock: !filters.in_stock, ..filters.clone() };
+
+    let subcategories = sqlx::query_as!(
+        ProductCategories,
+        "SELECT * FROM product_categories WHERE parent_id = $1 AND total_count > 0 ORDER BY name",
+        category.id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    //breadcrumbs, top level category first
+    let ancestors = sqlx::query_as!(
+        ProductCategories,
+        r#"WITH RECURSIVE ancestors AS (
+            SELECT c.*, 1 AS depth FROM product_categories c
+            WHERE c.id = (SELECT parent_id FROM product_categories WHERE id = $1)
+            UNION ALL
+            SELECT c.*, a.depth + 1 FROM product_categories c JOIN ancestors a ON c.id = a.parent_id
+        )
+        SELECT id AS "id!", parent_id, name AS "name!", slug AS "slug!", description AS "description!",
+            display_type AS "display_type!", thumbnail AS "thumbnail!", count AS "count!", total_count AS "total_count!",
+            created_at, updated_at
+        FROM ancestors ORDER BY depth DESC"#,
+        category.id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &category.name);
+    context.insert("category", &category);
+    context.insert("ancestors", &ancestors);
+    context.insert("subcategories", &subcategories);
+    context.insert("products", &products);
+    let paths: Vec<&str> = products.iter().map(|product| product.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    context.insert("facets", &facets);
+    context.insert("filtered", &!filters.is_empty());
+    context.insert("min_price", &filters.min_price);
+    context.insert("max_price", &filters.max_price);
+    context.insert("price_bounds", &(summary.min_price, summary.max_price));
+    let mut price_form_params = filters.without_price().params();
+    price_form_params.extend(opts.params());
+    context.insert("price_form_params", &price_form_params);
+    context.insert("in_stock", &filters.in_stock);
+    context.insert("in_stock_count", &summary.in_stock_count);
+    context.insert("stock_url", &filter_url(&stock_filters));
+    context.insert("clear_url", &filter_url(&CategoryFilters::default()));
+    context.insert("base_url", &base_url);
+    insert_listing_context(&mut context, &base_url, &filters.params(), &opts, summary.total);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("category.html", &context)?;
+    Ok(Html(output))
+}
+
+//results on the search page and in the search box suggestions
+const SEARCH_LIMIT: i64 = 48;
+const SEARCH_SUGGESTION_LIMIT: i64 = 8;
+
+//ts_headline marks the matched words with <mark>, everything else in it is escaped
+fn highlight_html(headline: &str) -> String {
+    tera::escape_html(headline)
+        .replace("&lt;mark&gt;", "<mark>")
+        .replace("&lt;&#x2F;mark&gt;", "</mark>")
+}
+
+//Searches the published products, ranked by where the words are found (see the product_search migration).
+//When nothing matches, typos are forgiven by comparing trigrams of the title instead, that's the bool
+async fn search_products(
+    db: &Pool<Postgres>,
+    opts: &SearchOptions,
+    limit: i64,
+) -> Result<(Vec<ProductSearchResult>, bool), AppError> {
+    let Some(tsquery) = opts.tsquery() else {
+        return Ok((Vec::new(), false));
+    };
+
+    //headlines are only made for the page of results, they are slow on long descriptions
+    let results: Vec<ProductSearchResult> = sqlx::query!(
+        r#"SELECT r.id, r.title, r.price, r.product_gallery,
+            ts_headline('simple', r.title, q.query, 'HighlightAll=true, StartSel=<mark>, StopSel=</mark>') AS "title_html!",
+            ts_headline('simple', r.description, q.query,
+                'StartSel=<mark>, StopSel=</mark>, MaxWords=30, MinWords=15, MaxFragments=2, FragmentDelimiter=" … "') AS "snippet_html!"
+        FROM to_tsquery('english', $1) AS q(query), LATERAL (
+            SELECT p.*, ts_rank_cd(s.document, q.query) AS rank
+            FROM products p JOIN product_search s ON s.product_id = p.id
+            WHERE p.published AND s.document @@ q.query
+            ORDER BY rank DESC, p.title
+            LIMIT $2
+        ) r
+        ORDER BY r.rank DESC, r.title"#,
+        tsquery,
+        limit,
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| ProductSearchResult {
+        id: row.id,
+        title: row.title,
+        price: row.price,
+        product_gallery: row.product_gallery,
+        title_html: highlight_html(&row.title_html),
+        snippet_html: highlight_html(&row.snippet_html),
+    })
+    .collect();
+    if !results.is_empty() {
+        return Ok((results, false));
+    }
+
+    //<% is word_similarity, which compares q with the closest part of the title. The threshold is
+    //lowered from 0.6 for short words, where one wrong letter changes most of the trigrams
+    let mut tx = db.begin().await?;
+    sqlx::query!("SET LOCAL pg_trgm.word_similarity_threshold = 0.3")
+        .execute(&mut *tx)
+        .await?;
+    let results = sqlx::query!(
+        "SELECT id, title, price, product_gallery, description FROM products
+        WHERE published AND $1 <% title
+        ORDER BY word_similarity($1, title) DESC, title
+        LIMIT $2",
+        opts.q.trim(),
+        limit,
+    )
+    .fetch_all(&mut *tx)
+    .await?
+    .into_iter()
+    .map(|row| {
+        let snippet: Vec<&str> = row.description.split_whitespace().take(30).collect();
+        ProductSearchResult {
+            id: row.id,
+            title_html: tera::escape_html(&row.title),
+            title: row.title,
+            price: row.price,
+            product_gallery: row.product_gallery,
+            snippet_html: tera::escape_html(&snippet.join(" ")),
+        }
+    })
+    .collect();
+    tx.commit().await?;
+    Ok((results, true))
+}
+
+//Search page at 127.0.0.1:8000/search?q=
+pub async fn search_template(
+    opts: Option<Query<SearchOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let (results, fuzzy) = search_products(&data.db, &opts, SEARCH_LIMIT).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Search");
+    context.insert("q", opts.q.trim());
+    context.insert("results", &results);
+    let paths: Vec<&str> = results.iter().map(|product| product.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    context.insert("fuzzy", &fuzzy);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("search.html", &context)?;
+    Ok(Html(output))
+}
+
+//GET /api/search?q= for the search box, the best few matches for what has been typed so far
+#[utoipa::path(get, path = "/api/search", tag = "shop", summary = "Suggest products for the search box",
+    params(SearchOptions), responses((status = 200, body = SearchResponse)))]
+pub async fn search_handler(
+    opts: Option<Query<SearchOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    let (results, fuzzy) = search_products(&data.db, &opts, SEARCH_SUGGESTION_LIMIT).await?;
+
+    Ok(Json(SearchResponse {
+        status: "success".to_string(),
+        data: SearchData {
+            query: opts.q.trim().to_string(),
+            fuzzy,
+            products: results,
+        },
+    }))
+}
+
+//TODO get a function working that can accept product parameters and images
+///Some of this was written by ChatGPT
+///creates products
+pub async fn multipart_create_product_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let mut fields: HashMap<String, String> = HashMap::new(); // Store text inputs
+    let mut category_ids: Vec<String> = Vec::new();
+    let mut uploads: Vec<String> = Vec::new();
+    let mut media_ids: Vec<String> = Vec::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        if let Some(field_name) = field.name() {
+            if field_name == "category_ids" {
+                category_ids.push(field.text().await?);
+            } else if field_name == "media_ids" {
+                media_ids.push(field.text().await?);
+            } else if field_name == "product_gallery" {
+                //browsers send an empty file part when no image was picked
+                if field.file_name().is_none_or(|name| name.is_empty()) {
+                    continue;
+                }
+                uploads.push(save_image(&data, field, "products").await?);
+            } else if PRODUCT_FIELDS.contains(&field_name) {
+                let field_name = field_name.to_string();
+                fields.insert(field_name, field.text().await?);
+            } else if field_name != "csrf_token" {
+                println!("Unexpected field: {}", field_name);
+            }
+        }
+    }
+
+    //images picked from the media library go after the uploaded ones
+    uploads.extend(find_media_paths(&data.db, &media_ids).await?);
+    let product = CreateProductSchema::from_fields(&fields, &category_ids).map_err(AppError::Validation)?;
+    let product = CreateProductSchema { images: uploads, ..product };
+
+    let (note, categories, tags) = insert_product(&data.db, &product).await?;
+    let note_response = json!({"status": "success","data": json!({
+        "note": note,
+        "categories": categories,
+        "tags": tags
+    })});
+
+    Ok((StatusCode::CREATED, Json(note_response)))
+}
+
+//Inserts a product with its categories and tags, used by the product form and the REST API
+pub async fn insert_product(
+    db: &Pool<Postgres>,
+    product: &CreateProductSchema,
+) -> Result<(ProductModel, Vec<Uuid>, Vec<ProductTags>), AppError> {
+    let mut tx = db.begin().await?;
+    let note = sqlx::query_as!(
+        ProductModel,
+        "INSERT INTO products (
+        title,
+        description,
+        price,
+        sku,
+        product_type,
+        stock,
         allow_backorders,
         low_stock_threshold,
         shipping_weight,
-        product_gallery,
-        attributes,
-        variations,
-        shipping_dimensions,
+        shipping_length,
+        shipping_width,
+        shipping_height,
         shipping_class,
         tax_status,
-        tax_class) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17) RETURNING *",
-        text_inputs[0],
-        text_inputs[1],
-        text_inputs[2],
-        text_inputs[3],
-        text_inputs[4],
-        text_inputs[5],
-        text_inputs[6],
-        text_inputs[7],
-        text_inputs[8],
-        text_inputs[9],
-        text_inputs[10],
-        text_inputs[11],
-        text_inputs[12],
-        text_inputs[13],
-        text_inputs[14],
-        text_inputs[15],
-        text_inputs[16]
+        tax_class,
+        published) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING *",
+        product.title,
+        product.description,
+        product.price,
+        product.sku,
+        product.product_type,
+        product.stock,
+        product.allow_backorders.as_str(),
+        product.low_stock_threshold,
+        product.shipping_weight,
+        product.shipping_length,
+        product.shipping_width,
+        product.shipping_height,
+        product.shipping_class,
+        product.tax_status,
+        product.tax_class,
+        product.published.unwrap_or(false),
     )
-    .fetch_one(&data.db)
-    .await;
-
-    match query_result {
-        Ok(note) => {
-            let note_response = json!({"status": "success","data": json!({
-                "note": note
-            })});
-
-            return Ok((StatusCode::CREATED, Json(note_response)));
-        }
-        Err(e) => {
-            if e.to_string()
-                .contains("duplicate key value violates unique constraint")
-            {
-                let error_response = serde_json::json!({
-                    "status": "fail",
-                    "message": "Product with that name already exists",
-                });
-                return Err((StatusCode::CONFLICT, Json(error_response)));
-            }
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({"status": "error","message": format!("{:?}", e)})),
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product with that name already exists"))?;
+    let categories = set_product_categories(&mut tx, &[note.id], &product.category_ids).await?;
+    let tags = set_product_tags(&mut tx, note.id, &product.tags).await?;
+    add_product_images(&mut tx, note.id, &product.images).await?;
+    //with the featured image the database copied to product_gallery
+    let note = sqlx::query_as!(ProductModel, "SELECT * FROM products WHERE id = $1", note.id)
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok((note, categories, tags))
+}
+
+//maps variables to tag names in create_product_form.html and edit_product_form.html
+const PRODUCT_FIELDS: [&str; 17] = [
+    "title", "description", "tags", "price", "sku", "product_type", "stock",
+    "allow_backorders", "low_stock_threshold", "shipping_weight",
+    "shipping_length", "shipping_width", "shipping_height", "shipping_class",
+    "tax_status", "tax_class", "published"
+];
+
+//Replaces the categories of the products with category_ids, or Uncategorized when it's empty.
+//Returns the ids the products ended up in. Counts are kept up to date by the database
+async fn set_product_categories(
+    tx: &mut Transaction<'_, Postgres>,
+    product_ids: &[Uuid],
+    category_ids: &[Uuid],
+) -> Result<Vec<Uuid>, AppError> {
+    let category_ids = if category_ids.is_empty() {
+        sqlx::query_scalar!("SELECT id FROM product_categories WHERE slug = 'uncategorized'")
+            .fetch_all(&mut **tx)
+            .await?
+    } else {
+        let found = sqlx::query_scalar!(
+            "SELECT id FROM product_categories WHERE id = ANY($1)",
+            category_ids,
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+        if found.len() != category_ids.len() {
+            return Err(AppError::BadRequest("One of the categories doesn't exist".to_string()));
+        }
+        category_ids.to_vec()
+    };
+
+    sqlx::query!(
+        "DELETE FROM product_categories_map WHERE product_id = ANY($1) AND category_id <> ALL($2)",
+        product_ids,
+        &category_ids,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO product_categories_map (product_id, category_id)
+        SELECT p.id, c.id FROM UNNEST($1::UUID[]) AS p(id) CROSS JOIN UNNEST($2::UUID[]) AS c(id)
+        ON CONFLICT DO NOTHING",
+        product_ids,
+        &category_ids,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(category_ids)
+}
+
+//Replaces the tags of a product with the named ones. Names are matched whatever their case and
+//missing tags are created with a slug made from their name. Counts are kept up to date by the database
+async fn set_product_tags(
+    tx: &mut Transaction<'_, Postgres>,
+    product_id: Uuid,
+    names: &[String],
+) -> Result<Vec<ProductTags>, AppError> {
+    //two forms adding the same new tag at once would otherwise both create it
+    sqlx::query!("LOCK TABLE product_tags IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut **tx)
+        .await?;
+
+    let mut tags = Vec::new();
+    for name in names {
+        let tag = sqlx::query_as!(
+            ProductTags,
+            "SELECT * FROM product_tags WHERE LOWER(name) = LOWER($1)",
+            name,
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+        let tag = match tag {
+            Some(tag) => tag,
+            None => {
+                //another tag can have the slug already, "C++" and "C" are both "c"
+                let base = slugify(name);
+                let mut slug = base.clone();
+                let mut suffix = 1;
+                while sqlx::query_scalar!("SELECT EXISTS(SELECT 1 FROM product_tags WHERE slug = $1)", slug)
+                    .fetch_one(&mut **tx)
+                    .await?
+                    .unwrap_or(false)
+                {
+                    suffix += 1;
+                    slug = format!("{}-{}", base.chars().take(MAX_TAG_LENGTH - 10).collect::<String>(), suffix);
+                }
+                sqlx::query_as!(
+                    ProductTags,
+                    "INSERT INTO product_tags (name, slug) VALUES ($1, $2) RETURNING *",
+                    name,
+                    slug,
+                )
+                .fetch_one(&mut **tx)
+                .await?
+            }
+        };
+        tags.push(tag);
+    }
+
+    let tag_ids: Vec<Uuid> = tags.iter().map(|tag| tag.id).collect();
+    sqlx::query!(
+        "DELETE FROM product_tags_map WHERE product_id = $1 AND tag_id <> ALL($2)",
+        product_id,
+        &tag_ids,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO product_tags_map (product_id, tag_id) SELECT $1, UNNEST($2::UUID[]) ON CONFLICT DO NOTHING",
+        product_id,
+        &tag_ids,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(tags)
+}
+
+//The tags of a product sorted by name
+pub async fn find_product_tags(db: &Pool<Postgres>, id: Uuid) -> Result<Vec<ProductTags>, AppError> {
+    Ok(sqlx::query_as!(
+        ProductTags,
+        "SELECT t.* FROM product_tags t
+        JOIN product_tags_map m ON m.tag_id = t.id
+        WHERE m.product_id = $1
+        ORDER BY LOWER(t.name), t.id",
+        id,
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+//The categories of a product sorted by name
+pub async fn find_product_categories(db: &Pool<Postgres>, id: Uuid) -> Result<Vec<ProductCategories>, AppError> {
+    Ok(sqlx::query_as!(
+        ProductCategories,
+        "SELECT c.* FROM product_categories c
+        JOIN product_categories_map m ON m.category_id = c.id
+        WHERE m.product_id = $1
+        ORDER BY c.name, c.id",
+        id,
+    )
+    .fetch_all(db)
+    .await?)
+}
+
+//Looks up a single product, answering 404 when it doesn't exist
+pub async fn find_product(db: &Pool<Postgres>, id: Uuid) -> Result<ProductModel, AppError> {
+    sqlx::query_as!(
+        ProductModel,
+        "SELECT * FROM products WHERE id = $1",
+        id as Uuid,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Product with ID: {} not found", id)))
+}
+
+//Every variation of a product with its terms, in the order they were created
+pub async fn find_variations(db: &Pool<Postgres>, product_id: Uuid) -> Result<Vec<VariationDetails>, AppError> {
+    let variations = sqlx::query_as!(
+        ProductVariationModel,
+        "SELECT * FROM product_variations WHERE product_id = $1 ORDER BY created_at, id",
+        product_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    //terms follow the attribute positions of the product, attributes it no longer has go last
+    let terms = sqlx::query_as!(
+        VariationTermModel,
+        r#"SELECT
+        vt.variation_id AS "variation_id!",
+        vt.attribute_id AS "attribute_id!",
+        a.name AS "attribute_name!",
+        vt.term_id AS "term_id!",
+        t.name AS "term_name!"
+        FROM product_variation_terms vt
+        JOIN product_variations v ON v.id = vt.variation_id
+        JOIN product_attributes a ON a.id = vt.attribute_id
+        JOIN product_terms t ON t.id = vt.term_id
+        LEFT JOIN product_attributes_map am ON am.product_id = v.product_id AND am.attribute_id = vt.attribute_id
+        WHERE v.product_id = $1
+        ORDER BY am.position NULLS LAST, a.name"#,
+        product_id,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut terms_by_variation: HashMap<Uuid, Vec<VariationTermModel>> = HashMap::new();
+    for term in terms {
+        terms_by_variation.entry(term.variation_id).or_default().push(term);
+    }
+
+    Ok(variations
+        .into_iter()
+        .map(|variation| {
+            let terms = terms_by_variation.remove(&variation.id).unwrap_or_default();
+            VariationDetails::new(variation, terms)
+        })
+        .collect())
+}
+
+//The gallery of a product in order
+pub async fn find_product_images(db: &Pool<Postgres>, id: Uuid) -> Result<Vec<ProductImageModel>, AppError> {
+    let images = sqlx::query_as!(
+        ProductImageModel,
+        "SELECT * FROM product_images WHERE product_id = $1 ORDER BY position, created_at, id",
+        id,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(images)
+}
+
+//Adds images to the end of a product's gallery. The first image is featured when none is,
+//the database copies the featured image to product_gallery
+async fn add_product_images(tx: &mut Transaction<'_, Postgres>, product_id: Uuid, paths: &[String]) -> Result<(), AppError> {
+    sqlx::query!(
+        "INSERT INTO product_images (product_id, path, position)
+        SELECT $1, added.path, added.n + COALESCE((SELECT MAX(position) FROM product_images WHERE product_id = $1), -1)
+        FROM UNNEST($2::TEXT[]) WITH ORDINALITY AS added(path, n)",
+        product_id,
+        paths,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE product_images SET featured = TRUE
+        WHERE id = (SELECT id FROM product_images WHERE product_id = $1 ORDER BY position, created_at, id LIMIT 1)
+        AND NOT EXISTS(SELECT 1 FROM product_images WHERE product_id = $1 AND featured)",
+        product_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+//Applies the gallery changes of the edit form, removed images stay in the media library
+async fn update_product_images(
+    tx: &mut Transaction<'_, Postgres>,
+    product_id: Uuid,
+    changes: &UpdateGallerySchema,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        "DELETE FROM product_images WHERE product_id = $1 AND id = ANY($2)",
+        product_id,
+        &changes.remove,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    //images that aren't on the form, e.g. added since it was loaded, keep their order after the listed ones
+    let ids: Vec<Uuid> = changes.order.iter().map(|image| image.id).collect();
+    let alt_texts: Vec<String> = changes.order.iter().map(|image| image.alt_text.clone()).collect();
+    sqlx::query!(
+        "UPDATE product_images i SET position = ordered.n - 1, alt_text = ordered.alt_text
+        FROM UNNEST($2::UUID[], $3::TEXT[]) WITH ORDINALITY AS ordered(id, alt_text, n)
+        WHERE i.id = ordered.id AND i.product_id = $1",
+        product_id,
+        &ids,
+        &alt_texts,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE product_images i SET position = ordered.position
+        FROM (
+            SELECT id, $3 + ROW_NUMBER() OVER (ORDER BY position, created_at, id) - 1 AS position
+            FROM product_images WHERE product_id = $1 AND id <> ALL($2)
+        ) ordered
+        WHERE i.id = ordered.id",
+        product_id,
+        &ids,
+        ids.len() as i64,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    //one image is featured at a time, the old one is unmarked first for the unique index
+    if let Some(featured) = changes.featured.filter(|id| !changes.remove.contains(id)) {
+        sqlx::query!(
+            "UPDATE product_images SET featured = FALSE WHERE product_id = $1 AND featured AND id <> $2",
+            product_id,
+            featured,
+        )
+        .execute(&mut **tx)
+        .await?;
+        sqlx::query!(
+            "UPDATE product_images SET featured = TRUE WHERE product_id = $1 AND id = $2",
+            product_id,
+            featured,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    add_product_images(tx, product_id, &changes.uploads).await?;
+    Ok(())
+}
+
+//Admin list of every product at 127.0.0.1:8000/all_products
+pub async fn all_products_template(
+    State(data): State<Arc<AppState>>
+) -> Result<Html<String>, AppError> {
+    let products = sqlx::query_as!(
+        ProductModel,
+        "SELECT * FROM products ORDER by created_at DESC",
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let categories = category_tree(&data.db).await?;
+
+    //category names of every product keyed by product id
+    let mut product_categories: HashMap<Uuid, Vec<String>> = HashMap::new();
+    let rows = sqlx::query!(
+        "SELECT m.product_id, c.name FROM product_categories_map m
+        JOIN product_categories c ON c.id = m.category_id
+        ORDER BY c.name",
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        product_categories.entry(row.product_id).or_default().push(row.name);
+    }
+    let mut product_tags: HashMap<Uuid, Vec<String>> = HashMap::new();
+    let rows = sqlx::query!(
+        "SELECT m.product_id, t.name FROM product_tags_map m
+        JOIN product_tags t ON t.id = m.tag_id
+        ORDER BY LOWER(t.name)",
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for row in rows {
+        product_tags.entry(row.product_id).or_default().push(row.name);
+    }
+
+    let mut context = common_context();
+
+    context.insert("page_title", "All Products");
+    context.insert("products", &products);
+    let paths: Vec<&str> = products.iter().map(|product| product.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    context.insert("categories", &categories);
+    context.insert("product_categories", &product_categories);
+    context.insert("product_tags", &product_tags);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("products/all_products.html", &context)?;
+    Ok(Html(output))
+}
+
+pub async fn edit_product_template(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>
+) -> Result<Html<String>, AppError> {
+    let product = find_product(&data.db, id).await?;
+
+    let categories = category_tree(&data.db).await?;
+    let selected: Vec<Uuid> = find_product_categories(&data.db, id)
+        .await?
+        .iter()
+        .map(|category| category.id)
+        .collect();
+    let tag_names = find_product_tags(&data.db, id)
+        .await?
+        .iter()
+        .map(|tag| tag.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Edit {}", product.title));
+    context.insert("product", &product);
+    context.insert("images", &find_product_images(&data.db, id).await?);
+    context.insert("categories", &categories);
+    context.insert("selected_categories", &selected);
+    context.insert("tag_names", &tag_names);
+    let media = find_media(&data.db).await?;
+    context.insert("image_sizes", &media_sizes(&media));
+    context.insert("media", &media);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("products/edit_product_form.html", &context)?;
+    Ok(Html(output))
+}
+
+///updates a product, only the fields that are submitted are changed
+///product_gallery uploads and media_ids picked from the media library are added to the end of the gallery
+pub async fn update_product_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    //before anything is uploaded for it
+    find_product(&data.db, id).await?;
+
+    let mut fields: HashMap<String, String> = HashMap::new();
+    //the edit form always sends an empty category_ids so unchecking every category is noticed
+    let mut category_ids: Option<Vec<String>> = None;
+    //the gallery, image_ids and image_alts are sent once per image in the order they are shown
+    let mut image_ids: Vec<String> = Vec::new();
+    let mut image_alts: Vec<String> = Vec::new();
+    let mut remove_image_ids: Vec<String> = Vec::new();
+    let mut featured_image: Option<String> = None;
+    let mut uploads: Vec<String> = Vec::new();
+    let mut media_ids: Vec<String> = Vec::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "category_ids" => category_ids.get_or_insert_with(Vec::new).push(field.text().await?),
+            "media_ids" => media_ids.push(field.text().await?),
+            "image_ids" => image_ids.push(field.text().await?),
+            "image_alts" => image_alts.push(field.text().await?),
+            "remove_image_ids" => remove_image_ids.push(field.text().await?),
+            "featured_image" => featured_image = Some(field.text().await?),
+            "csrf_token" => {}
+            "product_gallery" => {
+                //browsers send an empty file part when no new image was picked
+                if field.file_name().is_none_or(|name| name.is_empty()) {
+                    continue;
+                }
+                uploads.push(save_image(&data, field, "products").await?);
+            }
+            name if PRODUCT_FIELDS.contains(&name) => {
+                fields.insert(field_name, field.text().await?);
+            }
+            _ => println!("Unexpected field: {}", field_name),
+        }
+    }
+
+    let changes = UpdateProductSchema::from_fields(&fields, category_ids.as_deref());
+    let gallery = UpdateGallerySchema::from_fields(&image_ids, &image_alts, featured_image.as_deref(), &remove_image_ids);
+    let changes = match (changes, gallery) {
+        (Ok(changes), Ok(gallery)) => {
+            uploads.extend(find_media_paths(&data.db, &media_ids).await?);
+            UpdateProductSchema {
+                images: Some(UpdateGallerySchema { uploads, ..gallery }),
+                ..changes
+            }
+        }
+        (changes, gallery) => {
+            let mut errors = changes.err().unwrap_or_default();
+            errors.extend(gallery.err().unwrap_or_default());
+            return Err(AppError::Validation(errors));
+        }
+    };
+
+    let product = update_product(&data.db, id, &changes).await?;
+
+    let product_response = json!({"status": "success","data": json!({
+        "product": product
+    })});
+
+    Ok((StatusCode::OK, Json(product_response)))
+}
+
+//Applies the changes to a product, used by the product form and the REST API
+pub async fn update_product(db: &Pool<Postgres>, id: Uuid, changes: &UpdateProductSchema) -> Result<ProductModel, AppError> {
+    let mut tx = db.begin().await?;
+    let product = sqlx::query_as!(
+        ProductModel,
+        "UPDATE products SET
+        title = COALESCE($1, title),
+        description = COALESCE($2, description),
+        price = COALESCE($3, price),
+        sku = COALESCE($4, sku),
+        product_type = COALESCE($5, product_type),
+        stock = COALESCE($6, stock),
+        allow_backorders = COALESCE($7, allow_backorders),
+        low_stock_threshold = COALESCE($8, low_stock_threshold),
+        shipping_weight = COALESCE($9, shipping_weight),
+        shipping_length = COALESCE($10, shipping_length),
+        shipping_width = COALESCE($11, shipping_width),
+        shipping_height = COALESCE($12, shipping_height),
+        shipping_class = COALESCE($13, shipping_class),
+        tax_status = COALESCE($14, tax_status),
+        tax_class = COALESCE($15, tax_class),
+        published = COALESCE($16, published),
+        updated_at = NOW()
+        WHERE id = $17 RETURNING *",
+        changes.title,
+        changes.description,
+        changes.price,
+        changes.sku,
+        changes.product_type,
+        changes.stock,
+        changes.allow_backorders.map(|status| status.as_str()),
+        changes.low_stock_threshold,
+        changes.shipping_weight,
+        changes.shipping_length,
+        changes.shipping_width,
+        changes.shipping_height,
+        changes.shipping_class,
+        changes.tax_status,
+        changes.tax_class,
+        changes.published,
+        id as Uuid,
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("Product with that name already exists"))?
+    .ok_or_else(|| AppError::NotFound(format!("Product with ID: {} not found", id)))?;
+    if let Some(category_ids) = &changes.category_ids {
+        set_product_categories(&mut tx, &[id], category_ids).await?;
+    }
+    if let Some(tags) = &changes.tags {
+        set_product_tags(&mut tx, id, tags).await?;
+    }
+    if let Some(images) = &changes.images {
+        update_product_images(&mut tx, id, images).await?;
+    }
+    tx.commit().await?;
+
+    //the gallery changes the featured image after the UPDATE returned the product
+    if changes.images.is_some() {
+        return find_product(db, id).await;
+    }
+    Ok(product)
+}
+
+//Deletes a product, returns the deleted product. Its images are deleted too unless something else uses them
+pub async fn delete_product(data: &AppState, id: Uuid) -> Result<ProductModel, AppError> {
+    delete_products(data, &[id])
+        .await?
+        .pop()
+        .ok_or_else(|| AppError::NotFound(format!("Product with ID: {} not found", id)))
+}
+
+//Deletes the products and the images of them and their variations that nothing else uses,
+//from the media library and the storage
+async fn delete_products(data: &AppState, ids: &[Uuid]) -> Result<Vec<ProductModel>, AppError> {
+    let mut tx = data.db.begin().await?;
+    let paths = sqlx::query_scalar!(
+        r#"SELECT DISTINCT path AS "path!" FROM media_references WHERE product_id = ANY($1)"#,
+        ids,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let products = sqlx::query_as!(
+        ProductModel,
+        "DELETE FROM products WHERE id = ANY($1) RETURNING *",
+        ids,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    //the same image uploaded for two products is only stored once
+    let unused = sqlx::query_scalar!(
+        r#"SELECT u.path AS "path!" FROM UNNEST($1::TEXT[]) AS u(path)
+        WHERE NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = u.path)"#,
+        &paths,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM media WHERE path = ANY($1)", &unused)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    for path in &unused {
+        images::remove(data.storage.as_ref(), path).await;
+    }
+    Ok(products)
+}
+
+///deletes a product and the images nothing else uses
+pub async fn delete_product_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    delete_product(&data, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+///applies a bulk action from the all products page to every selected product
+///actions are publish, unpublish, delete and change_category, which replaces all of their categories
+pub async fn bulk_product_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<impl IntoResponse, AppError> {
+    let mut action = String::new();
+    let mut category = String::new();
+    let mut product_ids: Vec<Uuid> = Vec::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        if let Some(field_name) = field.name() {
+            match field_name {
+                "action" => {
+                    action = field.text().await?;
+                }
+                "category" => {
+                    category = field.text().await?;
+                }
+                "product_ids" => {
+                    let id_str = field.text().await?;
+                    let id = Uuid::parse_str(&id_str)
+                        .map_err(|_| AppError::BadRequest(format!("Invalid product ID: {}", id_str)))?;
+                    product_ids.push(id);
+                }
+                "csrf_token" => {}
+                _ => {
+                    println!("Unexpected field: {}", field_name);
+                }
+            }
+        }
+    }
+
+    if product_ids.is_empty() {
+        return Err(AppError::BadRequest("Select at least one product.".to_string()));
+    }
+
+    let products = match action.as_str() {
+        "publish" | "unpublish" => {
+            sqlx::query_as!(
+                ProductModel,
+                "UPDATE products SET published = $1, updated_at = NOW() WHERE id = ANY($2) RETURNING *",
+                action == "publish",
+                &product_ids,
+            )
+            .fetch_all(&data.db)
+            .await?
+        }
+        "change_category" => {
+            if category.is_empty() {
+                return Err(AppError::BadRequest("Pick a category to move the products to.".to_string()));
+            }
+            let category_id: Uuid = category
+                .parse()
+                .map_err(|_| AppError::BadRequest(format!("{} is not a category", category)))?;
+
+            let mut tx = data.db.begin().await?;
+            let products = sqlx::query_as!(
+                ProductModel,
+                "UPDATE products SET updated_at = NOW() WHERE id = ANY($1) RETURNING *",
+                &product_ids,
+            )
+            .fetch_all(&mut *tx)
+            .await?;
+            let ids: Vec<Uuid> = products.iter().map(|product| product.id).collect();
+            set_product_categories(&mut tx, &ids, &[category_id]).await?;
+            tx.commit().await?;
+            products
+        }
+        "delete" => delete_products(&data, &product_ids).await?,
+        _ => {
+            return Err(AppError::BadRequest(format!("Unknown bulk action: {}", action)));
+        }
+    };
+
+    let response = json!({"status": "success","data": json!({
+        "action": action,
+        "products": products
+    })});
+
+    Ok((StatusCode::OK, Json(response)))
+}
+
+//Every item in the visitor's cart, empty when they don't have a cart yet
+async fn load_cart(db: &Pool<Postgres>, session: SessionId) -> Result<CartModel, AppError> {
+    let items = sqlx::query_as!(
+        CartItemModel,
+        r#"SELECT
+        ci.id,
+        ci.product_id,
+        ci.variation_id,
+        ci.variation,
+        ci.quantity,
+        p.title,
+        COALESCE(v.price, p.price) AS "price!",
+        COALESCE(NULLIF(v.image, ''), p.product_gallery) AS "product_gallery!",
+        COALESCE(v.stock, p.stock) AS "stock!",
+        p.allow_backorders,
+        COALESCE(v.price, p.price) * ci.quantity AS "line_total!"
+        FROM cart_items ci
+        JOIN carts c ON c.id = ci.cart_id
+        JOIN products p ON p.id = ci.product_id
+        LEFT JOIN product_variations v ON v.id = ci.variation_id
+        WHERE c.session_id = $1
+        ORDER BY ci.created_at"#,
+        session.0,
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(CartModel::new(items))
+}
+
+//products with variations are bought as one of their enabled variations, other products without one
+pub fn check_variation<'a>(
+    product: &ProductModel,
+    variations: &'a [VariationDetails],
+    variation_id: Option<Uuid>,
+) -> Result<Option<&'a VariationDetails>, AppError> {
+    match variation_id {
+        None if variations.is_empty() => Ok(None),
+        None => Err(AppError::BadRequest(format!("Choose an option for {}", product.title))),
+        Some(id) => variations
+            .iter()
+            .find(|variation| variation.variation.id == id && variation.variation.enabled)
+            .map(Some)
+            .ok_or_else(|| AppError::BadRequest(format!("That option of {} isn't available", product.title))),
+    }
+}
+
+//quantity is everything of the product, or of the variation, that would be in the cart.
+//Variations have their own stock and follow the product's allow_backorders
+fn check_stock(product: &ProductModel, variation: Option<&ProductVariationModel>, quantity: i32) -> Result<(), AppError> {
+    let stock = variation.map_or(product.stock, |variation| variation.stock);
+    if product.allow_backorders != BackorderStatus::No || quantity <= stock {
+        Ok(())
+    } else if stock <= 0 {
+        Err(AppError::Conflict(format!("{} is out of stock", product.title)))
+    } else {
+        Err(AppError::Conflict(format!("Only {} of {} left in stock", stock, product.title)))
+    }
+}
+
+async fn add_to_cart(db: &Pool<Postgres>, session: SessionId, item: AddToCartSchema) -> Result<(), AppError> {
+    if item.quantity < 1 {
+        return Err(AppError::BadRequest("Quantity must be at least 1".to_string()));
+    }
+    let product = find_product(db, item.product_id).await?;
+    let variations = find_variations(db, product.id).await?;
+    let variation = check_variation(&product, &variations, item.variation_id)?;
+
+    let cart_id = sqlx::query_scalar!(
+        "INSERT INTO carts (session_id) VALUES ($1)
+        ON CONFLICT (session_id) DO UPDATE SET updated_at = NOW() RETURNING id",
+        session.0,
+    )
+    .fetch_one(db)
+    .await?;
+
+    let in_cart = sqlx::query_scalar!(
+        r#"SELECT COALESCE(SUM(quantity), 0)::INTEGER AS "quantity!" FROM cart_items
+        WHERE cart_id = $1 AND product_id = $2 AND variation_id IS NOT DISTINCT FROM $3"#,
+        cart_id,
+        product.id,
+        item.variation_id,
+    )
+    .fetch_one(db)
+    .await?;
+    check_stock(&product, variation.map(|variation| &variation.variation), in_cart.saturating_add(item.quantity))?;
+
+    sqlx::query!(
+        "INSERT INTO cart_items (cart_id, product_id, variation_id, variation, quantity) VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (cart_id, product_id, variation_id)
+        DO UPDATE SET quantity = cart_items.quantity + EXCLUDED.quantity, variation = EXCLUDED.variation, updated_at = NOW()",
+        cart_id,
+        product.id,
+        item.variation_id,
+        variation.map_or("", |variation| variation.label.as_str()),
+        item.quantity,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+async fn remove_cart_item(db: &Pool<Postgres>, session: SessionId, item_id: Uuid) -> Result<(), AppError> {
+    let removed = sqlx::query!(
+        "DELETE FROM cart_items ci USING carts c
+        WHERE ci.id = $1 AND c.id = ci.cart_id AND c.session_id = $2",
+        item_id,
+        session.0,
+    )
+    .execute(db)
+    .await?;
+
+    if removed.rows_affected() == 0 {
+        return Err(AppError::NotFound("That item isn't in your cart".to_string()));
+    }
+    Ok(())
+}
+
+//a quantity of 0 removes the item
+async fn set_cart_item_quantity(
+    db: &Pool<Postgres>,
+    session: SessionId,
+    item_id: Uuid,
+    quantity: i32,
+) -> Result<(), AppError> {
+    if quantity < 0 {
+        return Err(AppError::BadRequest("Quantity must not be negative".to_string()));
+    }
+    if quantity == 0 {
+        return remove_cart_item(db, session, item_id).await;
+    }
+
+    let item = sqlx::query!(
+        "SELECT ci.product_id, ci.variation_id FROM cart_items ci
+        JOIN carts c ON c.id = ci.cart_id
+        WHERE ci.id = $1 AND c.session_id = $2",
+        item_id,
+        session.0,
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound("That item isn't in your cart".to_string()))?;
+
+    let product = find_product(db, item.product_id).await?;
+    let variation = match item.variation_id {
+        Some(variation_id) => sqlx::query_as!(
+            ProductVariationModel,
+            "SELECT * FROM product_variations WHERE id = $1",
+            variation_id,
+        )
+        .fetch_optional(db)
+        .await?,
+        None => None,
+    };
+    //a product, or one of its variations, is a single line of the cart so the line is all that needs stock
+    check_stock(&product, variation.as_ref(), quantity)?;
+
+    sqlx::query!(
+        "UPDATE cart_items SET quantity = $1, updated_at = NOW() WHERE id = $2",
+        quantity,
+        item_id,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+//Cart page at 127.0.0.1:8000/cart
+pub async fn cart_template(
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let cart = load_cart(&data.db, session).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Cart");
+    context.insert("cart", &cart);
+    let paths: Vec<&str> = cart.items.iter().map(|item| item.product_gallery.as_str()).collect();
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("cart/cart.html", &context)?;
+    Ok(Html(output))
+}
+
+//Add to cart form on the product page
+pub async fn add_to_cart_form_handler(
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(item): Form<AddToCartSchema>,
+) -> Result<Redirect, AppError> {
+    add_to_cart(&data.db, session, item).await?;
+    Ok(Redirect::to("/cart"))
+}
+
+//Quantity form on the cart page
+pub async fn update_cart_item_form_handler(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<UpdateCartItemSchema>,
+) -> Result<Redirect, AppError> {
+    set_cart_item_quantity(&data.db, session, id, body.quantity).await?;
+    Ok(Redirect::to("/cart"))
+}
+
+//Remove button on the cart page
+pub async fn remove_cart_item_form_handler(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    remove_cart_item(&data.db, session, id).await?;
+    Ok(Redirect::to("/cart"))
+}
+
+fn cart_response(cart: CartModel) -> Json<CartResponse> {
+    Json(CartResponse {
+        status: "success".to_string(),
+        data: CartData { cart },
+    })
+}
+
+#[utoipa::path(get, path = "/api/cart", tag = "shop", summary = "Show the cart",
+    responses((status = 200, body = CartResponse)))]
+pub async fn get_cart_handler(
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let cart = load_cart(&data.db, session).await?;
+    Ok(cart_response(cart))
+}
+
+#[utoipa::path(post, path = "/api/cart/items", tag = "shop", summary = "Add a product to the cart",
+    request_body = AddToCartSchema,
+    responses(
+        (status = 201, body = CartResponse),
+        (status = 404, description = "No such product or variation", body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+    ))]
+pub async fn add_cart_item_handler(
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Json(item): Json<AddToCartSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    add_to_cart(&data.db, session, item).await?;
+    let cart = load_cart(&data.db, session).await?;
+    Ok((StatusCode::CREATED, cart_response(cart)))
+}
+
+#[utoipa::path(patch, path = "/api/cart/items/{id}", tag = "shop", summary = "Change the quantity of a cart item, 0 removes it",
+    params(("id" = Uuid, Path, description = "Cart item id")),
+    request_body = UpdateCartItemSchema,
+    responses(
+        (status = 200, body = CartResponse),
+        (status = 404, body = ErrorBody),
+        (status = 409, description = "Not enough in stock", body = ErrorBody),
+    ))]
+pub async fn update_cart_item_handler(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Json(body): Json<UpdateCartItemSchema>,
+) -> Result<impl IntoResponse, AppError> {
+    set_cart_item_quantity(&data.db, session, id, body.quantity).await?;
+    let cart = load_cart(&data.db, session).await?;
+    Ok(cart_response(cart))
+}
+
+#[utoipa::path(delete, path = "/api/cart/items/{id}", tag = "shop", summary = "Remove an item from the cart",
+    params(("id" = Uuid, Path, description = "Cart item id")),
+    responses((status = 200, body = CartResponse), (status = 404, body = ErrorBody)))]
+pub async fn delete_cart_item_handler(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    remove_cart_item(&data.db, session, id).await?;
+    let cart = load_cart(&data.db, session).await?;
+    Ok(cart_response(cart))
+}
+
+//tax_status holds the tax percentage of the product, anything that isn't a percentage is untaxed
+fn tax_rate(tax_status: &str) -> Decimal {
+    tax_status
+        .trim()
+        .trim_end_matches('%')
+        .trim()
+        .parse::<Decimal>()
+        .ok()
+        .filter(|rate| !rate.is_sign_negative() && *rate < Decimal::ONE_HUNDRED)
+        .map(|rate| rate.round_dp(2))
+        .unwrap_or(Decimal::ZERO)
+}
+
+//tax of an order line, half a cent rounds up like shops round it on receipts
+fn line_tax(line_total: Decimal, tax_rate: Decimal) -> Decimal {
+    (line_total * tax_rate / Decimal::ONE_HUNDRED).round_dp_with_strategy(2, RoundingStrategy::MidpointAwayFromZero)
+}
+
+//What stock is taken from: a product, or one of its variations
+type StockKey = (Uuid, Option<Uuid>);
+
+//Locks the products and variations, checks there is enough of each and takes the quantities out of stock.
+//Returns the locked products and variations so the caller can snapshot them
+async fn reserve_stock(
+    tx: &mut Transaction<'_, Postgres>,
+    quantities: &HashMap<StockKey, i32>,
+) -> Result<(HashMap<Uuid, ProductModel>, HashMap<Uuid, ProductVariationModel>), AppError> {
+    let product_ids: Vec<Uuid> = quantities.keys().map(|key| key.0).collect();
+    let variation_ids: Vec<Uuid> = quantities.keys().filter_map(|key| key.1).collect();
+
+    let products: HashMap<Uuid, ProductModel> = sqlx::query_as!(
+        ProductModel,
+        "SELECT * FROM products WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+        &product_ids,
+    )
+    .fetch_all(&mut **tx)
+    .await?
+    .into_iter()
+    .map(|product| (product.id, product))
+    .collect();
+    let variations: HashMap<Uuid, ProductVariationModel> = sqlx::query_as!(
+        ProductVariationModel,
+        "SELECT * FROM product_variations WHERE id = ANY($1) ORDER BY id FOR UPDATE",
+        &variation_ids,
+    )
+    .fetch_all(&mut **tx)
+    .await?
+    .into_iter()
+    .map(|variation| (variation.id, variation))
+    .collect();
+
+    for (&(product_id, variation_id), &quantity) in quantities {
+        let Some(product) = products.get(&product_id) else { continue };
+        let variation = variation_id.and_then(|id| variations.get(&id));
+        if variation.is_some_and(|variation| !variation.enabled) {
+            return Err(AppError::Conflict(format!("That option of {} isn't available anymore", product.title)));
+        }
+        check_stock(product, variation, quantity)?;
+    }
+    for (&(product_id, variation_id), &quantity) in quantities {
+        match variation_id {
+            Some(variation_id) => sqlx::query!(
+                "UPDATE product_variations SET stock = stock - $1, updated_at = NOW() WHERE id = $2",
+                quantity,
+                variation_id,
+            ),
+            None => sqlx::query!(
+                "UPDATE products SET stock = stock - $1, updated_at = NOW() WHERE id = $2",
+                quantity,
+                product_id,
+            ),
+        }
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    Ok((products, variations))
+}
+
+//Puts the items of an order back in stock, variations go back to the variation and not the product
+async fn release_stock(tx: &mut Transaction<'_, Postgres>, order_id: Uuid) -> Result<(), AppError> {
+    sqlx::query!(
+        "UPDATE products p SET stock = p.stock + oi.quantity, updated_at = NOW()
+        FROM (
+            SELECT product_id, SUM(quantity)::INTEGER AS quantity FROM order_items
+            WHERE order_id = $1 AND variation_id IS NULL GROUP BY product_id
+        ) oi
+        WHERE p.id = oi.product_id",
+        order_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE product_variations v SET stock = v.stock + oi.quantity, updated_at = NOW()
+        FROM (
+            SELECT variation_id, SUM(quantity)::INTEGER AS quantity FROM order_items
+            WHERE order_id = $1 GROUP BY variation_id
+        ) oi
+        WHERE v.id = oi.variation_id",
+        order_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+//Turns the visitor's cart into a pending order and empties the cart
+async fn place_order(
+    db: &Pool<Postgres>,
+    session: SessionId,
+    customer_id: Option<Uuid>,
+    checkout: &CheckoutSchema,
+) -> Result<OrderModel, AppError> {
+    let mut tx = db.begin().await?;
+
+    let cart_items = sqlx::query_as!(
+        NewOrderItem,
+        "SELECT ci.product_id, ci.variation_id, ci.variation, ci.quantity FROM cart_items ci
+        JOIN carts c ON c.id = ci.cart_id
+        WHERE c.session_id = $1
+        ORDER BY ci.created_at",
+        session.0,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    if cart_items.is_empty() {
+        return Err(AppError::BadRequest("Your cart is empty".to_string()));
+    }
+    let order = insert_order(&mut tx, session.0, customer_id, checkout, &cart_items).await?;
+
+    sqlx::query!(
+        "DELETE FROM cart_items ci USING carts c WHERE c.id = ci.cart_id AND c.session_id = $1",
+        session.0,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(order)
+}
+
+//a line of a new order, from the cart or the REST API
+pub struct NewOrderItem {
+    pub product_id: Uuid,
+    pub variation_id: Option<Uuid>,
+    //"Color: Red, Size: M", empty for simple products
+    pub variation: String,
+    pub quantity: i32,
+}
+
+//Takes the items out of stock and inserts a pending order with a copy of their products.
+//session_id is the visitor who can see the order without logging in
+pub async fn insert_order(
+    tx: &mut Transaction<'_, Postgres>,
+    session_id: Uuid,
+    customer_id: Option<Uuid>,
+    checkout: &CheckoutSchema,
+    cart_items: &[NewOrderItem],
+) -> Result<OrderModel, AppError> {
+    let mut quantities: HashMap<StockKey, i32> = HashMap::new();
+    for item in cart_items {
+        *quantities.entry((item.product_id, item.variation_id)).or_default() += item.quantity;
+    }
+    let (products, variations) = reserve_stock(tx, &quantities).await?;
+    if let Some(item) = cart_items.iter().find(|item| !products.contains_key(&item.product_id)) {
+        return Err(AppError::BadRequest(format!("Product with ID: {} not found", item.product_id)));
+    }
+
+    //price, sku, tax and line total of every cart item, taken from the product or variation as it is now
+    let lines: Vec<_> = cart_items
+        .iter()
+        .map(|item| {
+            let product = &products[&item.product_id];
+            let variation = item.variation_id.and_then(|id| variations.get(&id));
+            let price = variation.map_or(product.price, |variation| variation.price);
+            let sku = variation
+                .map(|variation| variation.sku.as_str())
+                .filter(|sku| !sku.is_empty())
+                .unwrap_or(&product.sku);
+            let line_total = price * Decimal::from(item.quantity);
+            let tax_rate = tax_rate(&product.tax_status);
+            let tax = line_tax(line_total, tax_rate);
+            (item, product, sku, price, tax_rate, tax, line_total)
+        })
+        .collect();
+    let subtotal: Decimal = lines.iter().map(|line| line.6).sum();
+    let tax_total: Decimal = lines.iter().map(|line| line.5).sum();
+
+    let order = sqlx::query_as!(
+        OrderModel,
+        "INSERT INTO orders (
+        session_id,
+        billing_name,
+        billing_email,
+        billing_phone,
+        billing_address,
+        billing_city,
+        billing_postcode,
+        billing_country,
+        customer_note,
+        payment_method,
+        subtotal,
+        tax_total,
+        total,
+        customer_id,
+        stock_reduced) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, TRUE) RETURNING *",
+        session_id,
+        checkout.billing_name,
+        checkout.billing_email,
+        checkout.billing_phone,
+        checkout.billing_address,
+        checkout.billing_city,
+        checkout.billing_postcode,
+        checkout.billing_country,
+        checkout.customer_note,
+        checkout.payment_method,
+        subtotal,
+        tax_total,
+        subtotal + tax_total,
+        customer_id,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    for (item, product, sku, price, tax_rate, tax, line_total) in lines {
+        sqlx::query!(
+            "INSERT INTO order_items (order_id, product_id, variation_id, title, sku, variation, price, quantity, tax_rate, tax, line_total)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+            order.id,
+            product.id,
+            item.variation_id,
+            product.title,
+            sku,
+            item.variation,
+            price,
+            item.quantity,
+            tax_rate,
+            tax,
+            line_total,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(order)
+}
+
+//Moves an order to another status if the state machine allows it.
+//Stock goes back when the order is cancelled, refunded or fails, and is taken again if it comes back
+pub async fn set_order_status(db: &Pool<Postgres>, id: Uuid, next: OrderStatus) -> Result<OrderModel, AppError> {
+    let mut tx = db.begin().await?;
+    let order = lock_order(&mut tx, id).await?;
+    let order = move_order(&mut tx, &order, next).await?;
+    tx.commit().await?;
+    Ok(order)
+}
+
+pub async fn lock_order(tx: &mut Transaction<'_, Postgres>, id: Uuid) -> Result<OrderModel, AppError> {
+    sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE id = $1 FOR UPDATE",
+        id,
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Order with ID: {} not found", id)))
+}
+
+//set_order_status for an order already locked in tx
+pub async fn move_order(tx: &mut Transaction<'_, Postgres>, order: &OrderModel, next: OrderStatus) -> Result<OrderModel, AppError> {
+    if !order.status.can_transition_to(next) {
+        return Err(AppError::Conflict(format!(
+            "Order #{} can't be moved from {} to {}",
+            order.number,
+            order.status.as_str(),
+            next.as_str()
+        )));
+    }
+
+    let stock_reduced = if next.releases_stock() && order.stock_reduced {
+        release_stock(tx, order.id).await?;
+        false
+    } else if !next.releases_stock() && !order.stock_reduced {
+        let quantities: HashMap<StockKey, i32> = sqlx::query!(
+            r#"SELECT product_id AS "product_id!", variation_id, SUM(quantity)::INTEGER AS "quantity!" FROM order_items
+            WHERE order_id = $1 AND product_id IS NOT NULL GROUP BY product_id, variation_id"#,
+            order.id,
+        )
+        .fetch_all(&mut **tx)
+        .await?
+        .into_iter()
+        .map(|row| ((row.product_id, row.variation_id), row.quantity))
+        .collect();
+        reserve_stock(tx, &quantities).await?;
+        true
+    } else {
+        order.stock_reduced
+    };
+
+    let order = sqlx::query_as!(
+        OrderModel,
+        "UPDATE orders SET status = $1, stock_reduced = $2, updated_at = NOW() WHERE id = $3 RETURNING *",
+        next.as_str(),
+        stock_reduced,
+        order.id,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(order)
+}
+
+//Deletes an order for good, the stock it holds goes back first
+pub async fn delete_order(db: &Pool<Postgres>, id: Uuid) -> Result<OrderModel, AppError> {
+    let mut tx = db.begin().await?;
+
+    let order = sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE id = $1 FOR UPDATE",
+        id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Order with ID: {} not found", id)))?;
+
+    if order.stock_reduced {
+        release_stock(&mut tx, order.id).await?;
+    }
+    //items and payments go with it
+    sqlx::query!("DELETE FROM orders WHERE id = $1", order.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(order)
+}
+
+pub async fn find_order_items(db: &Pool<Postgres>, order_id: Uuid) -> Result<Vec<OrderItemModel>, AppError> {
+    let items = sqlx::query_as!(
+        OrderItemModel,
+        "SELECT * FROM order_items WHERE order_id = $1 ORDER BY title, variation",
+        order_id,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(items)
+}
+
+fn checkout_context(
+    data: &AppState,
+    cart: &CartModel,
+    checkout: &CheckoutSchema,
+    errors: &FieldErrors,
+) -> tera::Context {
+    let mut context = common_context();
+
+    context.insert("page_title", "Checkout");
+    context.insert("cart", cart);
+    context.insert("checkout", checkout);
+    context.insert("errors", errors);
+    context.insert("payment_methods", &data.payments.options());
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+    context
+}
+
+//Checkout page at 127.0.0.1:8000/checkout
+pub async fn checkout_template(
+    Extension(session): Extension<SessionId>,
+    CurrentCustomer(customer): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let cart = load_cart(&data.db, session).await?;
+    if cart.items.is_empty() {
+        return Ok(Redirect::to("/cart").into_response());
+    }
+
+    let mut checkout = CheckoutSchema {
+        payment_method: data.payments.all().next().map(|gateway| gateway.id().to_string()).unwrap_or_default(),
+        ..Default::default()
+    };
+    //logged in customers start with their saved billing address
+    if let Some(customer) = customer {
+        let billing = sqlx::query_as!(
+            CustomerAddressModel,
+            "SELECT kind, name, phone, address, city, postcode, country FROM customer_addresses
+            WHERE customer_id = $1 AND kind = 'billing'",
+            customer.id,
+        )
+        .fetch_optional(&data.db)
+        .await?
+        .unwrap_or_default();
+        let full_name = format!("{} {}", customer.first_name, customer.last_name).trim().to_string();
+        checkout.billing_name = if billing.name.is_empty() { full_name } else { billing.name };
+        checkout.billing_email = customer.email;
+        checkout.billing_phone = billing.phone;
+        checkout.billing_address = billing.address;
+        checkout.billing_city = billing.city;
+        checkout.billing_postcode = billing.postcode;
+        checkout.billing_country = billing.country;
+    }
+    let context = checkout_context(&data, &cart, &checkout, &FieldErrors::new());
+    let output = data.templates.render("checkout/checkout.html", &context)?;
+    Ok(Html(output).into_response())
+}
+
+//Places the order and pays for it, invalid billing details show the checkout page again with the errors
+pub async fn checkout_handler(
+    Extension(session): Extension<SessionId>,
+    CurrentCustomer(customer): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+    Form(mut checkout): Form<CheckoutSchema>,
+) -> Result<Response, AppError> {
+    let mut errors = checkout.validate();
+    match data.payments.get(&checkout.payment_method) {
+        None => {
+            errors.insert("payment_method", "must be one of the payment methods below".to_string());
+        }
+        Some(gateway) if gateway.needs_card() && checkout.card_number.trim().is_empty() => {
+            errors.insert("card_number", "is required".to_string());
+        }
+        Some(_) => {}
+    }
+    if !errors.is_empty() {
+        let cart = load_cart(&data.db, session).await?;
+        let context = checkout_context(&data, &cart, &checkout, &errors);
+        let output = data.templates.render("checkout/checkout.html", &context)?;
+        return Ok((StatusCode::BAD_REQUEST, Html(output)).into_response());
+    }
+
+    let customer_id = customer.map(|customer| customer.id);
+    let order = place_order(&data.db, session, customer_id, &checkout).await?;
+    let details = PaymentDetails {
+        card_number: checkout.card_number,
+    };
+    pay_for_order(&data, &order, &details).await
+}
+
+//An order placed by this visitor or the logged in customer, anyone else gets a 404
+async fn find_customer_order(
+    db: &Pool<Postgres>,
+    session: SessionId,
+    customer: &CurrentCustomer,
+    id: Uuid,
+) -> Result<OrderModel, AppError> {
+    sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE id = $1 AND (session_id = $2 OR customer_id = $3)",
+        id,
+        session.0,
+        customer.0.as_ref().map(|customer| customer.id),
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Order with ID: {} not found", id)))
+}
+
+pub async fn find_payments(db: &Pool<Postgres>, order_id: Uuid) -> Result<Vec<PaymentModel>, AppError> {
+    let payments = sqlx::query_as!(
+        PaymentModel,
+        "SELECT * FROM payments WHERE order_id = $1 ORDER BY created_at",
+        order_id,
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(payments)
+}
+
+//Moves the order along after its payment changed, e.g. processing once it is captured.
+//Payments can arrive after the order was already moved by hand, those leave the order as it is
+async fn update_order_for_payment(
+    db: &Pool<Postgres>,
+    gateway: &dyn PaymentGateway,
+    order_id: Uuid,
+    status: PaymentStatus,
+) -> Result<(), AppError> {
+    let Some(next) = gateway.order_status(status) else {
+        return Ok(());
+    };
+    match set_order_status(db, order_id, next).await {
+        Err(AppError::Conflict(message)) => {
+            println!("Payment {} didn't change order {}: {}", status.as_str(), order_id, message);
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+//Asks the gateway for the money, then sends the customer to 3D Secure or the order received page
+async fn pay_for_order(data: &AppState, order: &OrderModel, details: &PaymentDetails) -> Result<Response, AppError> {
+    let gateway = data
+        .payments
+        .get(&order.payment_method)
+        .ok_or_else(|| AppError::BadRequest(format!("Unknown payment method: {}", order.payment_method)))?;
+
+    let result = gateway.authorize(order, details).await?;
+    sqlx::query!(
+        "INSERT INTO payments (order_id, gateway, status, amount, transaction_id, message)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        order.id,
+        gateway.id(),
+        result.status.as_str(),
+        order.total,
+        result.transaction_id,
+        result.message,
+    )
+    .execute(&data.db)
+    .await?;
+    update_order_for_payment(&data.db, gateway, order.id, result.status).await?;
+
+    let redirect = result
+        .redirect
+        .unwrap_or_else(|| format!("/checkout/order-received/{}", order.id));
+    Ok(Redirect::to(&redirect).into_response())
+}
+
+//Records what the gateway said about a payment and moves the order along. A replayed update changes nothing,
+//one the payment or order state machine doesn't allow is a 409
+async fn apply_payment_event(
+    db: &Pool<Postgres>,
+    gateway: &dyn PaymentGateway,
+    event: &WebhookEvent,
+) -> Result<PaymentModel, AppError> {
+    let mut tx = db.begin().await?;
+    let payment = sqlx::query_as!(
+        PaymentModel,
+        "SELECT * FROM payments WHERE gateway = $1 AND transaction_id = $2 FOR UPDATE",
+        gateway.id(),
+        event.transaction_id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Payment {} not found", event.transaction_id)))?;
+    let order = lock_order(&mut tx, payment.order_id).await?;
+
+    let order_status = match payment_change(gateway, payment.status, order.status, event.status)? {
+        PaymentChange::Replayed => return Ok(payment),
+        PaymentChange::Apply(order_status) => order_status,
+    };
+    let payment = sqlx::query_as!(
+        PaymentModel,
+        "UPDATE payments SET status = $1, message = $2, updated_at = NOW() WHERE id = $3 RETURNING *",
+        event.status.as_str(),
+        event.message,
+        payment.id,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if let Some(order_status) = order_status {
+        move_order(&mut tx, &order, order_status).await?;
+    }
+
+    tx.commit().await?;
+    Ok(payment)
+}
+
+//Thank you page shown after checkout, only to the visitor who placed the order.
+//Failed and unpaid orders can be paid again from here
+pub async fn order_received_template(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    customer: CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let order = find_customer_order(&data.db, session, &customer, id).await?;
+    let items = find_order_items(&data.db, order.id).await?;
+    let payment = find_payments(&data.db, order.id).await?.pop();
+    let gateway = data.payments.get(&order.payment_method);
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Order received");
+    context.insert("order", &order);
+    context.insert("items", &items);
+    context.insert("payment", &payment);
+    context.insert("payment_title", &gateway.map(|gateway| gateway.title()));
+    context.insert("instructions", &gateway.map(|gateway| gateway.instructions()));
+    context.insert("needs_payment", &matches!(order.status, OrderStatus::Pending | OrderStatus::Failed));
+    context.insert("payment_methods", &data.payments.options());
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("checkout/order_received.html", &context)?;
+    Ok(Html(output))
+}
+
+//Pays for a pending or failed order again, failed orders go back to pending and take their stock again
+pub async fn pay_order_handler(
+    Path(id): Path<Uuid>,
+    Extension(session): Extension<SessionId>,
+    customer: CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<PayOrderSchema>,
+) -> Result<Response, AppError> {
+    let order = find_customer_order(&data.db, session, &customer, id).await?;
+    let gateway = data
+        .payments
+        .get(&body.payment_method)
+        .ok_or_else(|| AppError::BadRequest("Choose a payment method".to_string()))?;
+    if gateway.needs_card() && body.card_number.trim().is_empty() {
+        return Err(AppError::BadRequest("Enter a card number".to_string()));
+    }
+
+    let order = match order.status {
+        OrderStatus::Failed => set_order_status(&data.db, order.id, OrderStatus::Pending).await?,
+        OrderStatus::Pending => order,
+        _ => return Err(AppError::Conflict(format!("Order #{} doesn't need to be paid", order.number))),
+    };
+    let order = sqlx::query_as!(
+        OrderModel,
+        "UPDATE orders SET payment_method = $1, updated_at = NOW() WHERE id = $2 RETURNING *",
+        gateway.id(),
+        order.id,
+    )
+    .fetch_one(&data.db)
+    .await?;
+
+    let details = PaymentDetails {
+        card_number: body.card_number,
+    };
+    pay_for_order(&data, &order, &details).await
+}
+
+//The payment waiting for 3D Secure on the mock gateway, only for the visitor who placed the order
+async fn find_mock_3ds_payment(
+    data: &AppState,
+    session: SessionId,
+    transaction_id: &str,
+) -> Result<(PaymentModel, OrderModel), AppError> {
+    let not_found = || AppError::NotFound("That payment doesn't need authentication".to_string());
+    let payment = sqlx::query_as!(
+        PaymentModel,
+        "SELECT * FROM payments WHERE gateway = 'mock' AND transaction_id = $1 AND status = 'requires-action'",
+        transaction_id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(not_found)?;
+    let order = find_customer_order(&data.db, session, &CurrentCustomer(None), payment.order_id)
+        .await
+        .map_err(|_| not_found())?;
+    Ok((payment, order))
+}
+
+//Stands in for the bank's 3D Secure page when paying with the mock gateway
+pub async fn mock_3ds_template(
+    Path(transaction_id): Path<String>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let (payment, order) = find_mock_3ds_payment(&data, session, &transaction_id).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "3D Secure");
+    context.insert("payment", &payment);
+    context.insert("order", &order);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("checkout/mock_3ds.html", &context)?;
+    Ok(Html(output))
+}
+
+//Approve or fail on the mock 3D Secure page, handled the same way as a webhook from the gateway
+pub async fn mock_3ds_handler(
+    Path(transaction_id): Path<String>,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<Mock3dsSchema>,
+) -> Result<Redirect, AppError> {
+    let gateway = data
+        .payments
+        .get("mock")
+        .ok_or_else(|| AppError::NotFound("The mock gateway is only available in development".to_string()))?;
+    let (payment, order) = find_mock_3ds_payment(&data, session, &transaction_id).await?;
+
+    let event = match body.outcome.as_str() {
+        "approve" => WebhookEvent {
+            transaction_id: payment.transaction_id,
+            status: PaymentStatus::Captured,
+            message: "Payment captured after 3D Secure".to_string(),
+        },
+        "fail" => WebhookEvent {
+            transaction_id: payment.transaction_id,
+            status: PaymentStatus::Declined,
+            message: "3D Secure authentication failed".to_string(),
+        },
+        _ => return Err(AppError::BadRequest(format!("Unknown outcome: {}", body.outcome))),
+    };
+    apply_payment_event(&data.db, gateway, &event).await?;
+
+    Ok(Redirect::to(&format!("/checkout/order-received/{}", order.id)))
+}
+
+//Payment updates sent by gateways to /payments/webhook/:gateway
+pub async fn payment_webhook_handler(
+    Path(gateway_id): Path<String>,
+    State(data): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<impl IntoResponse, AppError> {
+    let gateway = data
+        .payments
+        .get(&gateway_id)
+        .ok_or_else(|| AppError::NotFound(format!("Unknown payment gateway: {}", gateway_id)))?;
+
+    let event = gateway.verify_webhook(&headers, &body)?;
+    let payment = apply_payment_event(&data.db, gateway, &event).await?;
+
+    Ok(Json(json!({"status": "success","data": json!({
+        "payment": payment
+    })})))
+}
+
+//Capture and refund buttons on the admin order page
+async fn update_payment(
+    data: &AppState,
+    order_id: Uuid,
+    payment_id: Uuid,
+    refund: bool,
+) -> Result<Redirect, AppError> {
+    let payment = sqlx::query_as!(
+        PaymentModel,
+        "SELECT * FROM payments WHERE id = $1 AND order_id = $2",
+        payment_id,
+        order_id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Payment with ID: {} not found", payment_id)))?;
+    let gateway = data
+        .payments
+        .get(&payment.gateway)
+        .ok_or_else(|| AppError::BadRequest(format!("The {} payment method is no longer available", payment.gateway)))?;
+
+    let result = if refund {
+        gateway.refund(&payment).await?
+    } else {
+        gateway.capture(&payment).await?
+    };
+    let event = WebhookEvent {
+        transaction_id: result.transaction_id,
+        status: result.status,
+        message: result.message,
+    };
+    apply_payment_event(&data.db, gateway, &event).await?;
+
+    Ok(Redirect::to(&format!("/orders/{}", order_id)))
+}
+
+pub async fn capture_payment_handler(
+    Path((order_id, payment_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    update_payment(&data, order_id, payment_id, false).await
+}
+
+pub async fn refund_payment_handler(
+    Path((order_id, payment_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    update_payment(&data, order_id, payment_id, true).await
+}
+
+//Admin list of orders at 127.0.0.1:8000/orders, ?status= shows a single status
+pub async fn orders_template(
+    opts: Option<Query<OrderFilterOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+
+    let status = match opts.status.as_deref() {
+        None | Some("") | Some("all") => None,
+        Some(status) => Some(status.parse::<OrderStatus>().map_err(AppError::BadRequest)?),
+    };
+    let limit = opts.limit.unwrap_or(20).clamp(1, 100);
+    let page = opts.page.unwrap_or(1).max(1);
+    let offset = page_offset(page, limit);
+
+    let orders = sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE ($1::TEXT IS NULL OR status = $1)
+        ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+        status.map(|status| status.as_str()),
+        limit as i64,
+        offset as i64,
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    //number of orders in each status for the filter links
+    let counts: HashMap<String, i64> = sqlx::query!(
+        r#"SELECT status, COUNT(*) AS "count!" FROM orders GROUP BY status"#,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .map(|row| (row.status, row.count))
+    .collect();
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Orders");
+    context.insert("orders", &orders);
+    context.insert("counts", &counts);
+    context.insert("total", &counts.values().sum::<i64>());
+    context.insert("statuses", &OrderStatus::ALL);
+    context.insert("status", &status);
+    context.insert("page", &page);
+    context.insert("limit", &limit);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("orders/orders.html", &context)?;
+    Ok(Html(output))
+}
+
+//Admin order page with the line items and the status change form
+pub async fn order_template(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let order = sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE id = $1",
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Order with ID: {} not found", id)))?;
+    let items = find_order_items(&data.db, order.id).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Order #{}", order.number));
+    context.insert("order", &order);
+    context.insert("items", &items);
+    context.insert("transitions", order.status.transitions());
+    context.insert("payments", &find_payments(&data.db, order.id).await?);
+    context.insert("payment_title", &data.payments.get(&order.payment_method).map(|gateway| gateway.title()));
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("orders/order.html", &context)?;
+    Ok(Html(output))
+}
+
+//Status change form on the admin order page
+pub async fn update_order_status_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<OrderStatusSchema>,
+) -> Result<Redirect, AppError> {
+    let next = body.status.parse::<OrderStatus>().map_err(AppError::BadRequest)?;
+    set_order_status(&data.db, id, next).await?;
+    Ok(Redirect::to(&format!("/orders/{}", id)))
+}
+
+//Customer accounts, logins are kept in customer_sessions and the cookie only holds the token
+
+//only paths on this site, so ?next= can't send anyone somewhere else after logging in
+fn local_redirect(next: &str) -> &str {
+    if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') {
+        next
+    } else {
+        "/account"
+    }
+}
+
+const ADDRESS_KINDS: [&str; 2] = ["billing", "shipping"];
+
+fn account_context(page_title: &str, errors: &FieldErrors) -> tera::Context {
+    let mut context = common_context();
+
+    context.insert("page_title", page_title);
+    context.insert("errors", errors);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+    context
+}
+
+//Registration page at 127.0.0.1:8000/register
+pub async fn register_template(
+    CurrentCustomer(customer): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    if customer.is_some() {
+        return Ok(Redirect::to("/account").into_response());
+    }
+    let mut context = account_context("Register", &FieldErrors::new());
+    context.insert("form", &RegisterSchema::default());
+    let output = data.templates.render("account/register.html", &context)?;
+    Ok(Html(output).into_response())
+}
+
+//Creates the account and logs it in, invalid fields show the form again with the errors
+pub async fn register_handler(
+    jar: CookieJar,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<RegisterSchema>,
+) -> Result<Response, AppError> {
+    let mut errors = body.validate();
+    if !errors.contains_key("email") {
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM customers WHERE LOWER(email) = $1) AS "taken!""#,
+            body.email,
+        )
+        .fetch_one(&data.db)
+        .await?;
+        if taken {
+            errors.insert("email", "is already registered, log in instead".to_string());
+        }
+    }
+    if !errors.is_empty() {
+        let mut context = account_context("Register", &errors);
+        context.insert("form", &body);
+        let output = data.templates.render("account/register.html", &context)?;
+        return Ok((StatusCode::BAD_REQUEST, Html(output)).into_response());
+    }
+
+    let password_hash = hash_password(body.password).await?;
+    let customer_id = sqlx::query_scalar!(
+        "INSERT INTO customers (email, password_hash, first_name, last_name) VALUES ($1, $2, $3, $4) RETURNING id",
+        body.email,
+        password_hash,
+        body.first_name,
+        body.last_name,
+    )
+    .fetch_one(&data.db)
+    .await
+    .map_err(|e| AppError::from(e).on_conflict("An account with that email already exists"))?;
+
+    let jar = start_login(&data.db, jar, session, customer_id).await?;
+    Ok((jar, Redirect::to("/account")).into_response())
+}
+
+//Login page at 127.0.0.1:8000/login, ?next= is where to go afterwards
+pub async fn login_template(
+    CurrentCustomer(customer): CurrentCustomer,
+    Query(form): Query<LoginSchema>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    if customer.is_some() {
+        return Ok(Redirect::to(local_redirect(&form.next)).into_response());
+    }
+    let mut context = account_context("Log in", &FieldErrors::new());
+    context.insert("form", &form);
+    let output = data.templates.render("account/login.html", &context)?;
+    Ok(Html(output).into_response())
+}
+
+//A wrong email and a wrong password get the same message so accounts can't be discovered from here
+pub async fn login_handler(
+    jar: CookieJar,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<LoginSchema>,
+) -> Result<Response, AppError> {
+    body.email = body.email.trim().to_lowercase();
+    let customer = sqlx::query_as!(
+        CustomerModel,
+        "SELECT * FROM customers WHERE LOWER(email) = $1",
+        body.email,
+    )
+    .fetch_optional(&data.db)
+    .await?;
+
+    let hash = customer.as_ref().map(|customer| customer.password_hash.clone());
+    let customer = match customer {
+        Some(customer) if verify_password(std::mem::take(&mut body.password), hash).await => customer,
+        _ => {
+            let mut errors = FieldErrors::new();
+            errors.insert("password", "Incorrect email or password".to_string());
+            let mut context = account_context("Log in", &errors);
+            context.insert("form", &body);
+            let output = data.templates.render("account/login.html", &context)?;
+            return Ok((StatusCode::UNAUTHORIZED, Html(output)).into_response());
+        }
+    };
+
+    let jar = start_login(&data.db, jar, session, customer.id).await?;
+    Ok((jar, Redirect::to(local_redirect(&body.next))).into_response())
+}
+
+pub async fn logout_handler(jar: CookieJar, State(data): State<Arc<AppState>>) -> Result<Response, AppError> {
+    let jar = end_login(&data.db, jar).await?;
+    Ok((jar, Redirect::to("/")).into_response())
+}
+
+//Account page at 127.0.0.1:8000/account with the saved addresses and past orders
+pub async fn account_template(
+    CurrentCustomer(customer): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let Some(customer) = customer else {
+        return Ok(Redirect::to("/login?next=/account").into_response());
+    };
+
+    //both kinds are always shown, empty until the customer saves them
+    let mut addresses: HashMap<String, CustomerAddressModel> = ADDRESS_KINDS
+        .iter()
+        .map(|kind| {
+            let address = CustomerAddressModel {
+                kind: kind.to_string(),
+                ..Default::default()
+            };
+            (kind.to_string(), address)
+        })
+        .collect();
+    let saved = sqlx::query_as!(
+        CustomerAddressModel,
+        "SELECT kind, name, phone, address, city, postcode, country FROM customer_addresses WHERE customer_id = $1",
+        customer.id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    for address in saved {
+        addresses.insert(address.kind.clone(), address);
+    }
+
+    let orders = sqlx::query_as!(
+        OrderModel,
+        "SELECT * FROM orders WHERE customer_id = $1 ORDER BY created_at DESC",
+        customer.id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let mut context = account_context("My account", &FieldErrors::new());
+    context.insert("customer", &customer);
+    context.insert("addresses", &addresses);
+    context.insert("address_kinds", &ADDRESS_KINDS);
+    context.insert("orders", &orders);
+    let output = data.templates.render("account/account.html", &context)?;
+    Ok(Html(output).into_response())
+}
+
+//Saves the billing or shipping address from the account page
+pub async fn update_address_handler(
+    Path(kind): Path<String>,
+    CurrentCustomer(customer): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<AddressSchema>,
+) -> Result<Response, AppError> {
+    let Some(customer) = customer else {
+        return Ok(Redirect::to("/login?next=/account").into_response());
+    };
+    if !ADDRESS_KINDS.contains(&kind.as_str()) {
+        return Err(AppError::NotFound(format!("There is no {} address", kind)));
+    }
+
+    body.trim();
+    sqlx::query!(
+        "INSERT INTO customer_addresses (customer_id, kind, name, phone, address, city, postcode, country)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (customer_id, kind) DO UPDATE SET
+        name = EXCLUDED.name,
+        phone = EXCLUDED.phone,
+        address = EXCLUDED.address,
+        city = EXCLUDED.city,
+        postcode = EXCLUDED.postcode,
+        country = EXCLUDED.country,
+        updated_at = NOW()",
+        customer.id,
+        kind,
+        body.name,
+        body.phone,
+        body.address,
+        body.city,
+        body.postcode,
+        body.country,
+    )
+    .execute(&data.db)
+    .await?;
+
+    Ok(Redirect::to("/account").into_response())
+}
+
+//Forgot password page at 127.0.0.1:8000/forgot-password
+pub async fn forgot_password_template(State(data): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+    let mut context = account_context("Forgot password", &FieldErrors::new());
+    context.insert("form", &ForgotPasswordSchema::default());
+    context.insert("sent", &false);
+    let output = data.templates.render("account/forgot_password.html", &context)?;
+    Ok(Html(output))
+}
+
+//Emails a reset link if the account exists, the page looks the same either way
+pub async fn forgot_password_handler(
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<ForgotPasswordSchema>,
+) -> Result<Html<String>, AppError> {
+    body.email = body.email.trim().to_lowercase();
+    let customer_id = sqlx::query_scalar!("SELECT id FROM customers WHERE LOWER(email) = $1", body.email)
+        .fetch_optional(&data.db)
+        .await?;
+
+    if let Some(customer_id) = customer_id {
+        let token = new_token();
+        sqlx::query!(
+            "INSERT INTO password_reset_tokens (token_hash, customer_id, expires_at)
+            VALUES ($1, $2, NOW() + make_interval(hours => $3))",
+            hash_token(&token),
+            customer_id,
+            RESET_HOURS as i32,
+        )
+        .execute(&data.db)
+        .await?;
+
+        let email = Email {
+            to: body.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Someone asked to reset the password of your account.\n\n\
+                Open this link within {} hours to choose a new password:\n{}/reset-password/{}\n\n\
+                If it wasn't you, ignore this email and your password stays the same.",
+                RESET_HOURS,
+                site_url(),
+                token
+            ),
+        };
+        //a failure is only logged, an error page would tell who has an account
+        if let Err(e) = data.mailer.send(email).await {
+            println!("🔥 Failed to send the password reset email: {}", e);
+        }
+    }
+
+    let mut context = account_context("Forgot password", &FieldErrors::new());
+    context.insert("form", &body);
+    context.insert("sent", &true);
+    let output = data.templates.render("account/forgot_password.html", &context)?;
+    Ok(Html(output))
+}
+
+//SITE_URL is used for links in emails, the Host header can be set to anything by the client
+pub fn site_url() -> String {
+    std::env::var("SITE_URL")
+        .unwrap_or_else(|_| "http://127.0.0.1:8000".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+async fn check_reset_token(db: &Pool<Postgres>, token: &str) -> Result<(), AppError> {
+    sqlx::query_scalar!(
+        "SELECT customer_id FROM password_reset_tokens
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()",
+        hash_token(token),
+    )
+    .fetch_optional(db)
+    .await?
+    .map(|_| ())
+    .ok_or_else(|| AppError::NotFound("This password reset link is invalid or has expired".to_string()))
+}
+
+//Reset password page linked from the email
+pub async fn reset_password_template(
+    Path(token): Path<String>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    check_reset_token(&data.db, &token).await?;
+    let mut context = account_context("Reset password", &FieldErrors::new());
+    context.insert("token", &token);
+    let output = data.templates.render("account/reset_password.html", &context)?;
+    Ok(Html(output))
+}
+
+//Sets the new password, uses up every reset link of the customer, logs out everywhere and logs in here
+pub async fn reset_password_handler(
+    Path(token): Path<String>,
+    jar: CookieJar,
+    Extension(session): Extension<SessionId>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<ResetPasswordSchema>,
+) -> Result<Response, AppError> {
+    check_reset_token(&data.db, &token).await?;
+    let errors = body.validate();
+    if !errors.is_empty() {
+        let mut context = account_context("Reset password", &errors);
+        context.insert("token", &token);
+        let output = data.templates.render("account/reset_password.html", &context)?;
+        return Ok((StatusCode::BAD_REQUEST, Html(output)).into_response());
+    }
+
+    let password_hash = hash_password(body.password).await?;
+    let mut tx = data.db.begin().await?;
+    //checked again here so the same link can't be used twice at once
+    let customer_id = sqlx::query_scalar!(
+        "UPDATE password_reset_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW() RETURNING customer_id",
+        hash_token(&token),
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("This password reset link is invalid or has expired".to_string()))?;
+
+    sqlx::query!(
+        "UPDATE customers SET password_hash = $1, updated_at = NOW() WHERE id = $2",
+        password_hash,
+        customer_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE password_reset_tokens SET used_at = NOW() WHERE customer_id = $1 AND used_at IS NULL",
+        customer_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM customer_sessions WHERE customer_id = $1", customer_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    let jar = start_login(&data.db, jar, session, customer_id).await?;
+    Ok((jar, Redirect::to("/account")).into_response())
+}
+
+//Admin page at 127.0.0.1:8000/customers for changing the roles of accounts
+pub async fn customers_template(
+    CurrentCustomer(current): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let customers = sqlx::query_as!(CustomerModel, "SELECT * FROM customers ORDER BY created_at")
+        .fetch_all(&data.db)
+        .await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Customers");
+    context.insert("customers", &customers);
+    context.insert("roles", &Role::ALL);
+    context.insert("current_id", &current.map(|customer| customer.id));
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("customers/customers.html", &context)?;
+    Ok(Html(output))
+}
+
+//Admins can't change their own role, so there is always at least one admin left
+pub async fn update_customer_role_handler(
+    Path(id): Path<Uuid>,
+    CurrentCustomer(current): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<CustomerRoleSchema>,
+) -> Result<Redirect, AppError> {
+    let role = body.role.parse::<Role>().map_err(AppError::BadRequest)?;
+    if current.is_some_and(|customer| customer.id == id) {
+        return Err(AppError::BadRequest("You can't change your own role".to_string()));
+    }
+
+    sqlx::query!(
+        "UPDATE customers SET role = $1, updated_at = NOW() WHERE id = $2 RETURNING id",
+        role.as_str(),
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Customer with ID: {} not found", id)))?;
+
+    Ok(Redirect::to("/customers"))
+}
+
+//Admin page at 127.0.0.1:8000/api-keys for consumer keys and secrets of /api/v1.
+//Shop managers see their own keys and admins everyone's, new_key is shown once after it's made
+async fn api_keys_page(data: &AppState, current: &CustomerModel, new_key: Option<(String, String)>) -> Result<Html<String>, AppError> {
+    let owner = (current.role != Role::Admin).then_some(current.id);
+    let api_keys = sqlx::query_as!(
+        ApiKeyModel,
+        "SELECT * FROM api_keys WHERE ($1::UUID IS NULL OR customer_id = $1) ORDER BY revoked_at IS NOT NULL, created_at DESC",
+        owner,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let customer_ids: Vec<Uuid> = api_keys.iter().map(|api_key| api_key.customer_id).collect();
+    let owners: HashMap<String, String> = sqlx::query!("SELECT id, email FROM customers WHERE id = ANY($1)", &customer_ids)
+        .fetch_all(&data.db)
+        .await?
+        .into_iter()
+        .map(|row| (row.id.to_string(), row.email))
+        .collect();
+
+    let mut context = common_context();
+
+    context.insert("page_title", "API keys");
+    context.insert("api_keys", &api_keys);
+    context.insert("owners", &owners);
+    context.insert("permissions", &ApiKeyPermissions::ALL);
+    context.insert("show_owner", &owner.is_none());
+    if let Some((consumer_key, consumer_secret)) = new_key {
+        context.insert("new_key", &json!({"consumer_key": consumer_key, "consumer_secret": consumer_secret}));
+    }
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("api_keys/api_keys.html", &context)?;
+    Ok(Html(output))
+}
+
+pub async fn api_keys_template(
+    CurrentCustomer(current): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let current = current.ok_or_else(|| AppError::Unauthorized("Log in to do that".to_string()))?;
+    api_keys_page(&data, &current, None).await
+}
+
+//Only the hashes of the key and secret are stored, so the page this answers with is the only time the secret is shown
+pub async fn create_api_key_handler(
+    CurrentCustomer(current): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+    Form(mut body): Form<ApiKeySchema>,
+) -> Result<Html<String>, AppError> {
+    let current = current.ok_or_else(|| AppError::Unauthorized("Log in to do that".to_string()))?;
+    let mut errors = body.validate();
+    let permissions = body.permissions.parse::<ApiKeyPermissions>().unwrap_or_else(|e| {
+        errors.insert("permissions", e);
+        ApiKeyPermissions::default()
+    });
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    let (consumer_key, consumer_secret) = new_api_credentials();
+    sqlx::query!(
+        "INSERT INTO api_keys (customer_id, description, permissions, consumer_key_hash, consumer_secret_hash, truncated_key)
+        VALUES ($1, $2, $3, $4, $5, $6)",
+        current.id,
+        body.description,
+        permissions.as_str(),
+        hash_token(&consumer_key),
+        hash_token(&consumer_secret),
+        &consumer_key[consumer_key.len() - 7..],
+    )
+    .execute(&data.db)
+    .await?;
+
+    api_keys_page(&data, &current, Some((consumer_key, consumer_secret))).await
+}
+
+//Revoked keys stop working straight away and stay listed. Shop managers can revoke their own keys, admins anyone's
+pub async fn revoke_api_key_handler(
+    Path(id): Path<Uuid>,
+    CurrentCustomer(current): CurrentCustomer,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    let current = current.ok_or_else(|| AppError::Unauthorized("Log in to do that".to_string()))?;
+    let owner = (current.role != Role::Admin).then_some(current.id);
+    sqlx::query!(
+        "UPDATE api_keys SET revoked_at = COALESCE(revoked_at, NOW())
+        WHERE id = $1 AND ($2::UUID IS NULL OR customer_id = $2) RETURNING id",
+        id,
+        owner,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("API key with ID: {} not found", id)))?;
+
+    Ok(Redirect::to("/api-keys"))
+}
+
+//most variations the generator makes at once, every combination of the picked terms is one variation
+const MAX_GENERATED_VARIATIONS: usize = 100;
+
+//Admin page at 127.0.0.1:8000/product/:id/variations for picking the product's attributes and terms
+//and editing the variations made of them
+pub async fn product_variations_template(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let product = find_product(&data.db, id).await?;
+
+    //attributes the product already has come first, in their order
+    let attributes = sqlx::query_as!(
+        ProductAttributes,
+        "SELECT a.* FROM product_attributes a
+        LEFT JOIN product_attributes_map am ON am.attribute_id = a.id AND am.product_id = $1
+        ORDER BY am.position NULLS LAST, a.name",
+        id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let terms = sqlx::query_as!(
+        ProductTerms,
+        "SELECT * FROM product_terms",
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let selected = sqlx::query_scalar!(
+        "SELECT term_id FROM product_terms_map WHERE product_id = $1",
+        id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    let attributes: Vec<ProductAttributeOptions> = attributes
+        .into_iter()
+        .map(|attribute| {
+            let terms = sorted_terms(&attribute, &terms);
+            let selected = terms.iter().map(|term| term.id).filter(|id| selected.contains(id)).collect();
+            ProductAttributeOptions { attribute, terms, selected }
+        })
+        .collect();
+    let variations = find_variations(&data.db, id).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", &format!("Variations of {}", product.title));
+    context.insert("product", &product);
+    context.insert("attributes", &attributes);
+    context.insert("variations", &variations);
+    let mut paths: Vec<&str> = variations.iter().map(|variation| variation.variation.image.as_str()).collect();
+    paths.push(&product.product_gallery);
+    context.insert("image_sizes", &find_image_sizes(&data.db, &paths).await?);
+    context.insert("max_variations", &MAX_GENERATED_VARIATIONS);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("products/variations.html", &context)?;
+    Ok(Html(output))
+}
+
+//Replaces the attributes and terms of a product with the checked ones.
+//Variations using a term or attribute the product no longer has are disabled, not deleted
+pub async fn update_product_attributes_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut term_ids: Vec<Uuid> = Vec::new();
+    let mut positions: HashMap<Uuid, i32> = HashMap::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name == "term_ids" {
+            let term_id = field.text().await?;
+            term_ids.push(
+                term_id
+                    .parse()
+                    .map_err(|_| AppError::BadRequest(format!("{} is not a term", term_id)))?,
+            );
+        } else if let Some(attribute_id) = field_name.strip_prefix("position_") {
+            let attribute_id = attribute_id
+                .parse()
+                .map_err(|_| AppError::BadRequest(format!("{} is not an attribute", attribute_id)))?;
+            let position = field.text().await?;
+            //a blank position goes after the numbered ones
+            positions.insert(attribute_id, position.trim().parse().unwrap_or(i32::MAX));
+        } else if field_name != "csrf_token" {
+            println!("Unexpected field: {}", field_name);
+        }
+    }
+
+    let product = find_product(&data.db, id).await?;
+    let mut tx = data.db.begin().await?;
+
+    let terms = sqlx::query!(
+        "SELECT id, product_id AS attribute_id FROM product_terms WHERE id = ANY($1)",
+        &term_ids,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if terms.len() != term_ids.len() {
+        return Err(AppError::BadRequest("One of the terms doesn't exist".to_string()));
+    }
+
+    let mut attribute_ids: Vec<Uuid> = terms.iter().map(|term| term.attribute_id).collect();
+    attribute_ids.sort_by_key(|attribute_id| (positions.get(attribute_id).copied().unwrap_or(i32::MAX), *attribute_id));
+    attribute_ids.dedup();
+    let attribute_positions: Vec<i32> = (0..attribute_ids.len() as i32).collect();
+
+    sqlx::query!("DELETE FROM product_terms_map WHERE product_id = $1", product.id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO product_terms_map (product_id, term_id) SELECT $1, UNNEST($2::UUID[])",
+        product.id,
+        &term_ids,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM product_attributes_map WHERE product_id = $1", product.id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO product_attributes_map (product_id, attribute_id, position)
+        SELECT $1, * FROM UNNEST($2::UUID[], $3::INTEGER[])",
+        product.id,
+        &attribute_ids,
+        &attribute_positions,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE product_variations v SET enabled = FALSE, updated_at = NOW()
+        WHERE v.product_id = $1 AND v.enabled AND (
+            EXISTS(SELECT 1 FROM product_variation_terms vt WHERE vt.variation_id = v.id AND NOT vt.term_id = ANY($2))
+            OR (SELECT COUNT(*) FROM product_variation_terms vt WHERE vt.variation_id = v.id) <> $3
+        )",
+        product.id,
+        &term_ids,
+        attribute_ids.len() as i64,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Redirect::to(&format!("/product/{}/variations", product.id)))
+}
+
+//Makes a variation for every combination of the product's terms that doesn't have one yet.
+//New variations start with the product's price and weight and no stock
+pub async fn generate_variations_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    let product = find_product(&data.db, id).await?;
+
+    let terms = sqlx::query!(
+        r#"SELECT am.attribute_id, tm.term_id AS "term_id!" FROM product_attributes_map am
+        JOIN product_terms t ON t.product_id = am.attribute_id
+        JOIN product_terms_map tm ON tm.term_id = t.id AND tm.product_id = am.product_id
+        WHERE am.product_id = $1
+        ORDER BY am.position, t.name"#,
+        product.id,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    if terms.is_empty() {
+        return Err(AppError::BadRequest(format!("Pick the attributes of {} first", product.title)));
+    }
+
+    //one list of terms per attribute, then every combination of them
+    let mut attributes: Vec<(Uuid, Vec<Uuid>)> = Vec::new();
+    for term in terms {
+        match attributes.last_mut() {
+            Some((attribute_id, term_ids)) if *attribute_id == term.attribute_id => term_ids.push(term.term_id),
+            _ => attributes.push((term.attribute_id, vec![term.term_id])),
+        }
+    }
+    let count = attributes.iter().map(|(_, term_ids)| term_ids.len()).product::<usize>();
+    if count > MAX_GENERATED_VARIATIONS {
+        return Err(AppError::BadRequest(format!(
+            "Those terms make {} variations, pick fewer so there are at most {}",
+            count, MAX_GENERATED_VARIATIONS
+        )));
+    }
+    let mut combinations: Vec<Vec<Uuid>> = vec![Vec::new()];
+    for (_, term_ids) in &attributes {
+        combinations = combinations
+            .iter()
+            .flat_map(|combination| {
+                term_ids.iter().map(move |term_id| {
+                    let mut combination = combination.clone();
+                    combination.push(*term_id);
+                    combination
+                })
+            })
+            .collect();
+    }
+
+    let mut existing: Vec<Vec<Uuid>> = find_variations(&data.db, product.id)
+        .await?
+        .into_iter()
+        .map(|variation| variation.terms.iter().map(|term| term.term_id).collect())
+        .collect();
+    for terms in existing.iter_mut() {
+        terms.sort();
+    }
+
+    let attribute_ids: Vec<Uuid> = attributes.iter().map(|(attribute_id, _)| *attribute_id).collect();
+    let mut tx = data.db.begin().await?;
+    for term_ids in combinations {
+        let mut sorted = term_ids.clone();
+        sorted.sort();
+        if existing.contains(&sorted) {
+            continue;
+        }
+
+        let variation_id = sqlx::query_scalar!(
+            "INSERT INTO product_variations (product_id, price, shipping_weight) VALUES ($1, $2, $3) RETURNING id",
+            product.id,
+            product.price,
+            product.shipping_weight,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO product_variation_terms (variation_id, attribute_id, term_id)
+            SELECT $1, * FROM UNNEST($2::UUID[], $3::UUID[])",
+            variation_id,
+            &attribute_ids,
+            &term_ids,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    Ok(Redirect::to(&format!("/product/{}/variations", product.id)))
+}
+
+//Updates a variation from its form on the variations page, the old image stays in the media library
+pub async fn update_variation_handler(
+    Path((id, variation_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut fields: HashMap<String, String> = HashMap::new();
+    let mut image: Option<String> = None;
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        if field_name == "image" {
+            //browsers send an empty file part when no new image was picked
+            if field.file_name().is_none_or(|name| name.is_empty()) {
+                continue;
+            }
+            image = Some(save_image(&data, field, "products").await?);
+        } else {
+            fields.insert(field_name, field.text().await?);
+        }
+    }
+
+    let changes = UpdateVariationSchema::from_fields(&fields).map_err(AppError::Validation)?;
+    sqlx::query_as!(
+        ProductVariationModel,
+        "UPDATE product_variations SET
+        sku = $1,
+        price = $2,
+        stock = $3,
+        shipping_weight = $4,
+        enabled = $5,
+        image = COALESCE($6, image),
+        updated_at = NOW()
+        WHERE id = $7 AND product_id = $8 RETURNING *",
+        changes.sku,
+        changes.price,
+        changes.stock,
+        changes.shipping_weight,
+        changes.enabled,
+        image,
+        variation_id,
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Variation with ID: {} not found", variation_id)))?;
+
+    Ok(Redirect::to(&format!("/product/{}/variations", id)))
+}
+
+//Deletes a variation, it drops out of carts and orders keep their copy of it
+pub async fn delete_variation_handler(
+    Path((id, variation_id)): Path<(Uuid, Uuid)>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    sqlx::query!(
+        "DELETE FROM product_variations WHERE id = $1 AND product_id = $2 RETURNING id",
+        variation_id,
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Variation with ID: {} not found", variation_id)))?;
+
+    Ok(Redirect::to(&format!("/product/{}/variations", id)))
+}
+
+//Product reviews, posted on the product page and moderated on /reviews
+
+//REVIEWS_ALLOW_GUESTS=true lets visitors review products without an account
+fn reviews_allow_guests() -> bool {
+    std::env::var("REVIEWS_ALLOW_GUESTS").is_ok_and(|allow| allow == "true")
+}
+
+//the name reviews and replies are posted under for a logged in customer
+fn review_author_name(customer: &CustomerModel) -> String {
+    let full_name = format!("{} {}", customer.first_name, customer.last_name).trim().to_string();
+    if full_name.is_empty() {
+        customer.email.split('@').next().unwrap_or_default().to_string()
+    } else {
+        full_name
+    }
+}
+
+//Posts a review from the product page. Shop managers' reviews are approved straight away,
+//everyone else's wait in pending. Only logged in customers can be verified owners, since
+//a guest could type in anybody's email
+pub async fn create_review_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    CurrentCustomer(customer): CurrentCustomer,
+    Form(mut body): Form<ReviewSchema>,
+) -> Result<Redirect, AppError> {
+    let product = find_product(&data.db, id).await?;
+    if customer.is_none() && !reviews_allow_guests() {
+        let next = format!("/product/{}#reviews", product.id);
+        return Ok(Redirect::to(&format!("/login?next={}", urlencoding::encode(&next))));
+    }
+
+    let errors = body.validate(customer.is_none());
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    let (author_name, author_email, verified, status) = match &customer {
+        Some(customer) => {
+            let verified = sqlx::query_scalar!(
+                "SELECT EXISTS(
+                    SELECT 1 FROM order_items i JOIN orders o ON o.id = i.order_id
+                    WHERE i.product_id = $1 AND o.status IN ('processing', 'completed')
+                    AND (o.customer_id = $2 OR LOWER(o.billing_email) = LOWER($3))
+                )",
+                product.id,
+                customer.id,
+                customer.email,
+            )
+            .fetch_one(&data.db)
+            .await?
+            .unwrap_or(false);
+            let status = if customer.role.can_manage_shop() { ReviewStatus::Approved } else { ReviewStatus::Pending };
+            (review_author_name(customer), customer.email.clone(), verified, status)
+        }
+        None => (body.author_name.clone(), body.author_email.clone(), false, ReviewStatus::Pending),
+    };
+
+    sqlx::query!(
+        "INSERT INTO product_reviews (product_id, customer_id, author_name, author_email, rating, content, status, verified)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        product.id,
+        customer.as_ref().map(|customer| customer.id),
+        author_name,
+        author_email,
+        body.stars(),
+        body.content,
+        status.as_str(),
+        verified,
+    )
+    .execute(&data.db)
+    .await?;
+
+    Ok(Redirect::to(&format!("/product/{}?review=posted#reviews", product.id)))
+}
+
+//the reviews page to go back to, filter is the status it was showing
+fn reviews_url(filter: &str) -> String {
+    match filter.parse::<ReviewStatus>() {
+        Ok(status) => format!("/reviews?status={}", status.as_str()),
+        Err(_) => "/reviews".to_string(),
+    }
+}
+
+//Admin page at 127.0.0.1:8000/reviews, all is the pending and approved reviews like in WordPress
+pub async fn reviews_template(
+    opts: Option<Query<ReviewFilterOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+
+    let status = match opts.status.as_deref() {
+        None | Some("") | Some("all") => None,
+        Some(status) => Some(status.parse::<ReviewStatus>().map_err(AppError::BadRequest)?),
+    };
+    let limit = opts.limit.unwrap_or(20).clamp(1, 100);
+    let page = opts.page.unwrap_or(1).max(1);
+    let offset = page_offset(page, limit);
+
+    let reviews = sqlx::query_as!(
+        ProductReviewModel,
+        "SELECT * FROM product_reviews
+        WHERE ($1::TEXT IS NULL AND status IN ('pending', 'approved')) OR status = $1
+        ORDER BY created_at DESC LIMIT $2 OFFSET $3",
+        status.map(|status| status.as_str()),
+        limit as i64,
+        offset as i64,
+    )
+    .fetch_all(&data.db)
+    .await?;
+
+    //number of reviews in each status for the filter links
+    let counts: HashMap<String, i64> = sqlx::query!(
+        r#"SELECT status, COUNT(*) AS "count!" FROM product_reviews GROUP BY status"#,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .map(|row| (row.status, row.count))
+    .collect();
+    let total = [ReviewStatus::Pending, ReviewStatus::Approved]
+        .iter()
+        .filter_map(|status| counts.get(status.as_str()))
+        .sum::<i64>();
+
+    let product_ids: Vec<Uuid> = reviews.iter().map(|review| review.product_id).collect();
+    let product_titles: HashMap<Uuid, String> = sqlx::query!(
+        "SELECT id, title FROM products WHERE id = ANY($1)",
+        &product_ids,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, row.title))
+    .collect();
+    //"in reply to" of the replies
+    let parent_ids: Vec<Uuid> = reviews.iter().filter_map(|review| review.parent_id).collect();
+    let parent_authors: HashMap<Uuid, String> = sqlx::query!(
+        "SELECT id, author_name FROM product_reviews WHERE id = ANY($1)",
+        &parent_ids,
+    )
+    .fetch_all(&data.db)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, row.author_name))
+    .collect();
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Reviews");
+    context.insert("reviews", &reviews);
+    context.insert("counts", &counts);
+    context.insert("total", &total);
+    context.insert("statuses", &ReviewStatus::ALL);
+    context.insert("status", &status);
+    context.insert("page", &page);
+    context.insert("limit", &limit);
+    context.insert("product_titles", &product_titles);
+    context.insert("parent_authors", &parent_authors);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("reviews/reviews.html", &context)?;
+    Ok(Html(output))
+}
+
+//Approve, unapprove, spam, trash or restore a review
+pub async fn update_review_status_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<ReviewStatusSchema>,
+) -> Result<Redirect, AppError> {
+    let status = body.status.parse::<ReviewStatus>().map_err(AppError::BadRequest)?;
+
+    sqlx::query!(
+        "UPDATE product_reviews SET status = $1, updated_at = NOW() WHERE id = $2 RETURNING id",
+        status.as_str(),
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Review with ID: {} not found", id)))?;
+
+    Ok(Redirect::to(&reviews_url(&body.filter)))
+}
+
+//Deletes a review for good, it has to be in the spam or the trash first.
+//Replies to it are deleted with it
+pub async fn delete_review_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<ReviewStatusSchema>,
+) -> Result<Redirect, AppError> {
+    let review = sqlx::query_as!(
+        ProductReviewModel,
+        "SELECT * FROM product_reviews WHERE id = $1",
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Review with ID: {} not found", id)))?;
+    if !matches!(review.status, ReviewStatus::Spam | ReviewStatus::Trash) {
+        return Err(AppError::BadRequest("Move the review to the trash before deleting it".to_string()));
+    }
+
+    sqlx::query!("DELETE FROM product_reviews WHERE id = $1", id)
+        .execute(&data.db)
+        .await?;
+    Ok(Redirect::to(&reviews_url(&body.filter)))
+}
+
+//Replies to a review as the shop. Replying to a pending review approves it, and
+//replies to a reply are added to the review it belongs to
+pub async fn reply_review_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+    CurrentCustomer(customer): CurrentCustomer,
+    Form(mut body): Form<ReviewReplySchema>,
+) -> Result<Redirect, AppError> {
+    let customer = customer.ok_or_else(|| AppError::Unauthorized("Log in to do that".to_string()))?;
+    let errors = body.validate();
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+
+    let mut tx = data.db.begin().await?;
+    let review = sqlx::query_as!(
+        ProductReviewModel,
+        "SELECT * FROM product_reviews WHERE id = $1 FOR UPDATE",
+        id,
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("Review with ID: {} not found", id)))?;
+    if review.status == ReviewStatus::Pending {
+        sqlx::query!(
+            "UPDATE product_reviews SET status = 'approved', updated_at = NOW() WHERE id = $1",
+            review.id,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    sqlx::query!(
+        "INSERT INTO product_reviews (product_id, parent_id, customer_id, author_name, author_email, content, status)
+        VALUES ($1, $2, $3, $4, $5, $6, 'approved')",
+        review.product_id,
+        review.parent_id.unwrap_or(review.id),
+        customer.id,
+        review_author_name(&customer),
+        customer.email,
+        body.content,
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Redirect::to(&reviews_url(&body.filter)))
+}
+
+//Bulk actions form of the reviews page
+pub async fn bulk_reviews_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, AppError> {
+    let mut review_ids: Vec<Uuid> = Vec::new();
+    let mut action = String::new();
+    let mut filter = String::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "review_ids" => {
+                let review_id = field.text().await?;
+                review_ids.push(
+                    review_id
+                        .parse()
+                        .map_err(|_| AppError::BadRequest(format!("{} is not a review", review_id)))?,
+                );
+            }
+            //the same select is at the top and the bottom of the table
+            "action" | "action2" => {
+                let value = field.text().await?;
+                if value != "-1" {
+                    action = value;
+                }
+            }
+            "filter" => filter = field.text().await?,
+            "csrf_token" => {}
+            _ => println!("Unexpected field: {}", field_name),
+        }
+    }
+
+    let status = match action.as_str() {
+        "approve" => ReviewStatus::Approved,
+        "unapprove" => ReviewStatus::Pending,
+        "spam" => ReviewStatus::Spam,
+        "trash" => ReviewStatus::Trash,
+        "delete" => {
+            //only the ones already in the spam or the trash, like the single delete
+            sqlx::query!(
+                "DELETE FROM product_reviews WHERE id = ANY($1) AND status IN ('spam', 'trash')",
+                &review_ids,
+            )
+            .execute(&data.db)
+            .await?;
+            return Ok(Redirect::to(&reviews_url(&filter)));
+        }
+        "" => return Err(AppError::BadRequest("Choose a bulk action".to_string())),
+        _ => return Err(AppError::BadRequest(format!("Unknown bulk action {}", action))),
+    };
+
+    sqlx::query!(
+        "UPDATE product_reviews SET status = $1, updated_at = NOW() WHERE id = ANY($2)",
+        status.as_str(),
+        &review_ids,
+    )
+    .execute(&data.db)
+    .await?;
+    Ok(Redirect::to(&reviews_url(&filter)))
+}
+
+//Media library at /media, every uploaded image with what uses it. Images stay in it when the products and
+//categories using them change or are deleted, so they can be picked again, and unused ones are deleted from here
+
+//folders of the storage uploads go in, the media library uploads to media
+const MEDIA_FOLDERS: [&str; 3] = ["products", "categories", "media"];
+
+//Stores an uploaded image and its sizes under folder and adds it to the media library, returns its url
+async fn save_image(data: &AppState, field: Field<'_>, folder: &str) -> Result<String, AppError> {
+    let image = images::save_upload(data.storage.as_ref(), field, folder).await?;
+    add_media(&data.db, &image).await?;
+    Ok(image.url)
+}
+
+//Adds a stored image to the media library, returns false when it was already in it
+async fn add_media(db: &Pool<Postgres>, image: &StoredImage) -> Result<bool, AppError> {
+    let file_name: String = image.file_name.chars().take(255).collect();
+    let result = sqlx::query!(
+        "INSERT INTO media (path, file_name, mime_type, size, width, height, widths, webp) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (path) DO NOTHING",
+        image.url,
+        file_name,
+        image.mime_type,
+        image.size,
+        image.width,
+        image.height,
+        &image.sizes.widths,
+        image.sizes.webp,
+    )
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+//Every image in the media library, newest first, for the pickers of the product and category forms
+async fn find_media(db: &Pool<Postgres>) -> Result<Vec<MediaModel>, AppError> {
+    let media = sqlx::query_as!(MediaModel, "SELECT * FROM media ORDER BY created_at DESC, id")
+        .fetch_all(db)
+        .await?;
+    Ok(media)
+}
+
+//The sizes of the images on a page, what the srcset filter builds from.
+//Images that aren't in the media library have none and only get src
+async fn find_image_sizes(db: &Pool<Postgres>, paths: &[&str]) -> Result<HashMap<String, ImageSizes>, AppError> {
+    let paths: Vec<String> = paths.iter().filter(|path| !path.is_empty()).map(|path| path.to_string()).collect();
+    let rows = sqlx::query!("SELECT path, widths, webp FROM media WHERE path = ANY($1)", &paths)
+        .fetch_all(db)
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.path, ImageSizes { widths: row.widths, webp: row.webp }))
+        .collect())
+}
+
+//find_image_sizes of media library images, which have theirs
+fn media_sizes(media: &[MediaModel]) -> HashMap<String, ImageSizes> {
+    media
+        .iter()
+        .map(|item| (item.path.clone(), ImageSizes { widths: item.widths.clone(), webp: item.webp }))
+        .collect()
+}
+
+//The paths of the images picked from the media library, in the order they were picked
+async fn find_media_paths(db: &Pool<Postgres>, media_ids: &[String]) -> Result<Vec<String>, AppError> {
+    let ids = media_ids
+        .iter()
+        .map(|id| id.parse::<Uuid>().map_err(|_| AppError::BadRequest(format!("{} is not in the media library", id))))
+        .collect::<Result<Vec<Uuid>, AppError>>()?;
+    let paths = sqlx::query_scalar!(
+        "SELECT m.path FROM UNNEST($1::UUID[]) WITH ORDINALITY AS picked(id, n)
+        JOIN media m ON m.id = picked.id ORDER BY picked.n",
+        &ids,
+    )
+    .fetch_all(db)
+    .await?;
+    if paths.len() != ids.len() {
+        return Err(AppError::BadRequest("An image picked from the media library has been deleted".to_string()));
+    }
+    Ok(paths)
+}
+
+//The products and categories using each of the paths
+async fn find_media_references(db: &Pool<Postgres>, paths: &[String]) -> Result<HashMap<String, Vec<MediaReference>>, AppError> {
+    let rows = sqlx::query!(
+        r#"SELECT r.path AS "path!", p.id AS "product_id?", p.title AS "product_title?", c.id AS "category_id?", c.name AS "category_name?"
+        FROM media_references r
+        LEFT JOIN products p ON p.id = r.product_id
+        LEFT JOIN product_categories c ON c.id = r.category_id
+        WHERE r.path = ANY($1)
+        ORDER BY p.title, c.name"#,
+        paths,
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut references: HashMap<String, Vec<MediaReference>> = HashMap::new();
+    for row in rows {
+        let reference = match (row.product_id, row.product_title, row.category_id, row.category_name) {
+            (Some(id), Some(title), _, _) => MediaReference {
+                name: title,
+                url: format!("/product/{}/edit", id),
+            },
+            (_, _, Some(id), Some(name)) => MediaReference {
+                name,
+                url: format!("/categories/{}", id),
+            },
+            _ => continue,
+        };
+        references.entry(row.path).or_default().push(reference);
+    }
+    Ok(references)
+}
+
+//scan_after is where Scan storage goes on from when it stopped after a batch
+async fn media_page(
+    data: &AppState,
+    opts: &MediaFilterOptions,
+    notice: Option<String>,
+    scan_after: Option<String>,
+) -> Result<Html<String>, AppError> {
+    let unused = opts.unused.unwrap_or(false);
+    let limit = opts.limit.unwrap_or(40).clamp(1, 100);
+    let page = opts.page.unwrap_or(1).max(1);
+    let offset = page_offset(page, limit);
+
+    let media = sqlx::query_as!(
+        MediaModel,
+        "SELECT * FROM media m
+        WHERE NOT $1 OR NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = m.path)
+        ORDER BY created_at DESC, id LIMIT $2 OFFSET $3",
+        unused,
+        limit as i64,
+        offset as i64,
+    )
+    .fetch_all(&data.db)
+    .await?;
+    let counts = sqlx::query!(
+        r#"SELECT COUNT(*) AS "total!",
+        COUNT(*) FILTER (WHERE NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = m.path)) AS "unused!",
+        COUNT(*) FILTER (WHERE created_at < NOW() - INTERVAL '1 hour'
+            AND NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = m.path)) AS "deletable!"
+        FROM media m"#,
+    )
+    .fetch_one(&data.db)
+    .await?;
+    let paths: Vec<String> = media.iter().map(|item| item.path.clone()).collect();
+    let references = find_media_references(&data.db, &paths).await?;
+
+    let mut context = common_context();
+
+    context.insert("page_title", "Media library");
+    context.insert("media", &media);
+    context.insert("image_sizes", &media_sizes(&media));
+    context.insert("references", &references);
+    context.insert("total", &counts.total);
+    context.insert("unused_count", &counts.unused);
+    context.insert("deletable_count", &counts.deletable);
+    context.insert("unused", &unused);
+    context.insert("page", &page);
+    context.insert("limit", &limit);
+    context.insert("notice", &notice);
+    context.insert("scan_after", &scan_after);
+
+    //Static images used across most pages
+    let static_images = vec!["frontend/static/logo_small.webp", "frontend/static/button.png"];
+    context.insert("static_img", &static_images);
+
+    let output = data.templates.render("media/media.html", &context)?;
+    Ok(Html(output))
+}
+
+//Admin page at 127.0.0.1:8000/media, ?unused=true lists the images nothing uses
+pub async fn media_template(
+    opts: Option<Query<MediaFilterOptions>>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Html<String>, AppError> {
+    let Query(opts) = opts.unwrap_or_default();
+    media_page(&data, &opts, None, None).await
+}
+
+//Uploads images to the media library without using them anywhere yet
+pub async fn upload_media_handler(
+    State(data): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Html<String>, AppError> {
+    let mut uploaded = 0;
+    while let Some(field) = multipart.next_field().await? {
+        let field_name = field.name().unwrap_or_default().to_string();
+        match field_name.as_str() {
+            "files" => {
+                //browsers send an empty file part when no image was picked
+                if field.file_name().is_none_or(|name| name.is_empty()) {
+                    continue;
+                }
+                save_image(&data, field, "media").await?;
+                uploaded += 1;
+            }
+            "csrf_token" => {}
+            _ => println!("Unexpected field: {}", field_name),
+        }
+    }
+
+    let notice = format!("Uploaded {} image{}.", uploaded, if uploaded == 1 { "" } else { "s" });
+    media_page(&data, &MediaFilterOptions::default(), Some(notice), None).await
+}
+
+//Deletes an image from the media library and the storage, only when nothing uses it
+pub async fn delete_media_handler(
+    Path(id): Path<Uuid>,
+    State(data): State<Arc<AppState>>,
+) -> Result<Redirect, AppError> {
+    let media = sqlx::query_as!(MediaModel, "SELECT * FROM media WHERE id = $1", id)
+        .fetch_optional(&data.db)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Media with ID: {} not found", id)))?;
+    let deleted = sqlx::query!(
+        "DELETE FROM media m WHERE id = $1 AND created_at < NOW() - INTERVAL '1 hour'
+        AND NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = m.path)
+        RETURNING id",
+        id,
+    )
+    .fetch_optional(&data.db)
+    .await?;
+    if deleted.is_none() {
+        let references = find_media_references(&data.db, &[media.path]).await?;
+        let names: Vec<String> = references.into_values().flatten().map(|reference| reference.name).collect();
+        if names.is_empty() {
+            return Err(AppError::Conflict(
+                "The image was uploaded less than an hour ago, it may be for a product or category being saved".to_string(),
             ));
         }
+        return Err(AppError::Conflict(format!("The image is still used by {}", names.join(", "))));
+    }
+
+    images::remove(data.storage.as_ref(), &media.path).await;
+    Ok(Redirect::to("/media"))
+}
+
+//Images uploaded in the last hour are never deleted as unused: save_image adds them to the media library
+//before the product or category using them is saved, which can still fail or be waiting on a lock
+async fn delete_unused_media(db: &Pool<Postgres>) -> Result<Vec<String>, AppError> {
+    let paths = sqlx::query_scalar!(
+        "DELETE FROM media m WHERE created_at < NOW() - INTERVAL '1 hour'
+        AND NOT EXISTS(SELECT 1 FROM media_references r WHERE r.path = m.path)
+        RETURNING path",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(paths)
+}
+
+//Deletes every image nothing uses from the media library and the storage
+pub async fn delete_unused_media_handler(State(data): State<Arc<AppState>>) -> Result<Html<String>, AppError> {
+    let paths = delete_unused_media(&data.db).await?;
+    for path in &paths {
+        images::remove(data.storage.as_ref(), path).await;
+    }
+
+    let notice = format!("Deleted {} unused image{}.", paths.len(), if paths.len() == 1 { "" } else { "s" });
+    media_page(&data, &MediaFilterOptions::default(), Some(notice), None).await
+}
+
+//images Scan storage reads at a time, each one is downloaded to find its type and dimensions
+const MEDIA_SCAN_BATCH: usize = 50;
+
+//Adds the images in the storage that aren't in the media library yet, e.g. uploaded before it existed.
+//Sizes and WebP versions belong to their original and aren't added, neither is anything that isn't an image.
+//Reads MEDIA_SCAN_BATCH images in key order and the page offers to go on after the last one
+pub async fn scan_media_handler(
+    State(data): State<Arc<AppState>>,
+    Form(body): Form<ScanMediaSchema>,
+) -> Result<Html<String>, AppError> {
+    let known: HashSet<String> = sqlx::query_scalar!("SELECT path FROM media")
+        .fetch_all(&data.db)
+        .await?
+        .into_iter()
+        .collect();
+
+    let mut keys = Vec::new();
+    for folder in MEDIA_FOLDERS {
+        let listed = data.storage.list(folder).await?;
+        keys.extend(images::originals(&listed).into_iter().cloned());
+    }
+    keys.sort();
+    let mut unknown = keys.into_iter().filter(|key| *key > body.after).filter(|key| {
+        let url = data.storage.url(key);
+        url != CATEGORY_PLACEHOLDER && !known.contains(&url)
+    });
+    let batch: Vec<String> = unknown.by_ref().take(MEDIA_SCAN_BATCH).collect();
+    let left = unknown.count();
+
+    let (mut added, mut skipped) = (0, 0);
+    for key in &batch {
+        let bytes = data.storage.get(key).await?;
+        match images::describe(data.storage.url(key), images::stored_file_name(key), &bytes) {
+            Ok(mut image) => {
+                image.sizes = images::find_sizes(data.storage.as_ref(), key, image.width).await?;
+                if add_media(&data.db, &image).await? {
+                    added += 1;
+                }
+            }
+            Err(e) => {
+                println!("Skipped {:?}: {}", key, e);
+                skipped += 1;
+            }
+        }
+    }
+
+    let mut notice = format!("Added {} image{} found in the storage.", added, if added == 1 { "" } else { "s" });
+    if skipped > 0 {
+        notice += &format!(" Skipped {} file{} that couldn't be read as an image.", skipped, if skipped == 1 { "" } else { "s" });
+    }
+    let scan_after = if left > 0 {
+        notice += &format!(" {} more to read, continue scanning to add them.", left);
+        batch.last().cloned()
+    } else {
+        None
+    };
+    media_page(&data, &MediaFilterOptions::default(), Some(notice), scan_after).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::payment::MockGateway;
+
+    async fn insert_order(db: &Pool<Postgres>, status: OrderStatus) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO orders (session_id, status, billing_name, billing_email, billing_address, billing_city,
+            billing_country, payment_method, subtotal, tax_total, total)
+            VALUES ($1, $2, 'Test', 'test@example.com', 'Street 1', 'City', 'NL', 'mock', 10, 0, 10) RETURNING id",
+        )
+        .bind(Uuid::new_v4())
+        .bind(status.as_str())
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn insert_payment(db: &Pool<Postgres>, order_id: Uuid, status: PaymentStatus, transaction_id: &str) {
+        sqlx::query("INSERT INTO payments (order_id, gateway, status, amount, transaction_id) VALUES ($1, 'mock', $2, 10, $3)")
+            .bind(order_id)
+            .bind(status.as_str())
+            .bind(transaction_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    async fn insert_product(db: &Pool<Postgres>, stock: i32) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO products (title, description, sku, product_type, shipping_class, tax_status, tax_class, price, stock, allow_backorders)
+            VALUES ('Last one', '', $1, 'simple', '', '21%', 'standard', 10, $2, 'no') RETURNING id",
+        )
+        .bind(format!("test-{}", Uuid::new_v4().simple()))
+        .bind(stock)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn fill_cart(db: &Pool<Postgres>, product_id: Uuid, quantity: i32) -> SessionId {
+        let session = SessionId(Uuid::new_v4());
+        let cart_id: Uuid = sqlx::query_scalar("INSERT INTO carts (session_id) VALUES ($1) RETURNING id")
+            .bind(session.0)
+            .fetch_one(db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO cart_items (cart_id, product_id, quantity) VALUES ($1, $2, $3)")
+            .bind(cart_id)
+            .bind(product_id)
+            .bind(quantity)
+            .execute(db)
+            .await
+            .unwrap();
+        session
+    }
+
+    fn checkout() -> CheckoutSchema {
+        CheckoutSchema {
+            billing_name: "Test".to_string(),
+            billing_email: "test@example.com".to_string(),
+            billing_address: "Street 1".to_string(),
+            billing_city: "City".to_string(),
+            billing_country: "NL".to_string(),
+            payment_method: "cod".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tax_rates() {
+        assert_eq!(tax_rate("21%"), Decimal::from(21));
+        assert_eq!(tax_rate(" 9 % "), Decimal::from(9));
+        assert_eq!(tax_rate("8.875"), Decimal::new(888, 2));
+        assert_eq!(tax_rate("taxable"), Decimal::ZERO);
+        assert_eq!(tax_rate("-5%"), Decimal::ZERO);
+        assert_eq!(tax_rate("100%"), Decimal::ZERO);
+    }
+
+    #[test]
+    fn tax_rounds_half_a_cent_up() {
+        //0.125
+        assert_eq!(line_tax(Decimal::new(50, 2), Decimal::from(25)), Decimal::new(13, 2));
+        //0.105
+        assert_eq!(line_tax(Decimal::new(105, 2), Decimal::from(10)), Decimal::new(11, 2));
+        //4.1979
+        assert_eq!(line_tax(Decimal::new(1999, 2), Decimal::from(21)), Decimal::new(420, 2));
+        //0.0049
+        assert_eq!(line_tax(Decimal::new(7, 2), Decimal::from(7)), Decimal::ZERO);
+        assert_eq!(line_tax(Decimal::from(100), Decimal::ZERO), Decimal::ZERO);
+    }
+
+    #[sqlx::test]
+    async fn checkout_totals(db: Pool<Postgres>) {
+        let product_id = insert_product(&db, 5).await;
+        let session = fill_cart(&db, product_id, 3).await;
+
+        let order = place_order(&db, session, None, &checkout()).await.unwrap();
+        assert_eq!(order.subtotal, Decimal::from(30));
+        assert_eq!(order.tax_total, Decimal::new(630, 2));
+        assert_eq!(order.total, Decimal::new(3630, 2));
+        assert!(order.stock_reduced);
+        let stock: i32 = sqlx::query_scalar("SELECT stock FROM products WHERE id = $1").bind(product_id).fetch_one(&db).await.unwrap();
+        assert_eq!(stock, 2);
+    }
+
+    #[sqlx::test]
+    async fn checkouts_for_the_last_one_do_not_oversell(db: Pool<Postgres>) {
+        let product_id = insert_product(&db, 1).await;
+        let first = fill_cart(&db, product_id, 1).await;
+        let second = fill_cart(&db, product_id, 1).await;
+
+        let checkout = checkout();
+        let (a, b) = tokio::join!(
+            place_order(&db, first, None, &checkout),
+            place_order(&db, second, None, &checkout),
+        );
+        assert_eq!([&a, &b].iter().filter(|result| result.is_ok()).count(), 1);
+        let error = a.err().or(b.err()).unwrap();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+
+        let stock: i32 = sqlx::query_scalar("SELECT stock FROM products WHERE id = $1").bind(product_id).fetch_one(&db).await.unwrap();
+        assert_eq!(stock, 0);
+        let orders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM orders").fetch_one(&db).await.unwrap();
+        assert_eq!(orders, 1);
+    }
+
+    #[sqlx::test]
+    async fn cancelled_order_gives_stock_back(db: Pool<Postgres>) {
+        let product_id = insert_product(&db, 1).await;
+        let session = fill_cart(&db, product_id, 1).await;
+        let order = place_order(&db, session, None, &checkout()).await.unwrap();
+
+        let order = set_order_status(&db, order.id, OrderStatus::Cancelled).await.unwrap();
+        assert!(!order.stock_reduced);
+        let stock: i32 = sqlx::query_scalar("SELECT stock FROM products WHERE id = $1").bind(product_id).fetch_one(&db).await.unwrap();
+        assert_eq!(stock, 1);
+        assert_eq!(set_order_status(&db, order.id, OrderStatus::Processing).await.unwrap_err().status(), StatusCode::CONFLICT);
+    }
+
+    fn event(transaction_id: &str, status: PaymentStatus) -> WebhookEvent {
+        WebhookEvent {
+            transaction_id: transaction_id.to_string(),
+            status,
+            message: String::new(),
+        }
+    }
+
+    async fn order_status(db: &Pool<Postgres>, id: Uuid) -> OrderStatus {
+        let status: String = sqlx::query_scalar("SELECT status FROM orders WHERE id = $1").bind(id).fetch_one(db).await.unwrap();
+        status.parse().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn replayed_webhook_changes_nothing(db: Pool<Postgres>) {
+        let gateway = MockGateway::from_env();
+        let order_id = insert_order(&db, OrderStatus::Pending).await;
+        insert_payment(&db, order_id, PaymentStatus::RequiresAction, "mock_replay").await;
+
+        let payment = apply_payment_event(&db, &gateway, &event("mock_replay", PaymentStatus::Captured)).await.unwrap();
+        assert_eq!(payment.status, PaymentStatus::Captured);
+        assert_eq!(order_status(&db, order_id).await, OrderStatus::Processing);
+
+        set_order_status(&db, order_id, OrderStatus::Completed).await.unwrap();
+        let payment = apply_payment_event(&db, &gateway, &event("mock_replay", PaymentStatus::Captured)).await.unwrap();
+        assert_eq!(payment.status, PaymentStatus::Captured);
+        assert_eq!(order_status(&db, order_id).await, OrderStatus::Completed);
+    }
+
+    #[sqlx::test]
+    async fn webhook_after_refund_is_rejected(db: Pool<Postgres>) {
+        let gateway = MockGateway::from_env();
+        let order_id = insert_order(&db, OrderStatus::Processing).await;
+        insert_payment(&db, order_id, PaymentStatus::Captured, "mock_refunded").await;
+
+        apply_payment_event(&db, &gateway, &event("mock_refunded", PaymentStatus::Refunded)).await.unwrap();
+        assert_eq!(order_status(&db, order_id).await, OrderStatus::Refunded);
+
+        let result = apply_payment_event(&db, &gateway, &event("mock_refunded", PaymentStatus::Captured)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::CONFLICT);
+        assert_eq!(order_status(&db, order_id).await, OrderStatus::Refunded);
+    }
+
+    #[sqlx::test]
+    async fn webhook_for_cancelled_order_is_rejected(db: Pool<Postgres>) {
+        let gateway = MockGateway::from_env();
+        let order_id = insert_order(&db, OrderStatus::Cancelled).await;
+        insert_payment(&db, order_id, PaymentStatus::RequiresAction, "mock_cancelled").await;
+
+        let result = apply_payment_event(&db, &gateway, &event("mock_cancelled", PaymentStatus::Captured)).await;
+        assert_eq!(result.unwrap_err().status(), StatusCode::CONFLICT);
+        assert_eq!(order_status(&db, order_id).await, OrderStatus::Cancelled);
+        let status: String = sqlx::query_scalar("SELECT status FROM payments WHERE transaction_id = 'mock_cancelled'")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(status, "requires-action");
+    }
+
+    async fn insert_media(db: &Pool<Postgres>, path: &str, age: &str) {
+        sqlx::query(
+            "INSERT INTO media (path, mime_type, size, width, height, created_at)
+            VALUES ($1, 'image/jpeg', 1, 1, 1, NOW() - $2::INTERVAL)",
+        )
+        .bind(path)
+        .bind(age)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn unused_media_is_kept_for_an_hour(db: Pool<Postgres>) {
+        insert_media(&db, "/img/media/old.jpg", "2 hours").await;
+        insert_media(&db, "/img/media/new.jpg", "0 hours").await;
+        insert_media(&db, "/img/products/used.jpg", "2 hours").await;
+        let product_id = insert_product(&db, 1).await;
+        sqlx::query("INSERT INTO product_images (product_id, path) VALUES ($1, '/img/products/used.jpg')")
+            .bind(product_id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        assert_eq!(delete_unused_media(&db).await.unwrap(), vec!["/img/media/old.jpg"]);
+        let mut left: Vec<String> = sqlx::query_scalar("SELECT path FROM media").fetch_all(&db).await.unwrap();
+        left.sort();
+        assert_eq!(left, vec!["/img/media/new.jpg", "/img/products/used.jpg"]);
+    }
+
+    fn session_in(jar: &CookieJar) -> Uuid {
+        jar.get(crate::session::SESSION_COOKIE).unwrap().value().parse().unwrap()
+    }
+
+    #[sqlx::test]
+    async fn login_and_logout_renew_the_session(db: Pool<Postgres>) {
+        let product_id = insert_product(&db, 5).await;
+        let session = fill_cart(&db, product_id, 2).await;
+        let customer_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO customers (email, password_hash, first_name, last_name) VALUES ('a@example.com', '', 'A', 'B') RETURNING id",
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+
+        //the cart goes along to the new session_id
+        let jar = start_login(&db, CookieJar::new(), session, customer_id).await.unwrap();
+        let renewed = SessionId(session_in(&jar));
+        assert_ne!(renewed.0, session.0);
+        assert!(jar.get(crate::auth::LOGIN_COOKIE).is_some());
+        assert_eq!(load_cart(&db, renewed).await.unwrap().item_count, 2);
+        assert_eq!(load_cart(&db, session).await.unwrap().item_count, 0);
+
+        //and stays behind with the old one on logout
+        let jar = end_login(&db, jar).await.unwrap();
+        let after_logout = SessionId(session_in(&jar));
+        assert_ne!(after_logout.0, renewed.0);
+        assert!(jar.get(crate::auth::LOGIN_COOKIE).is_none());
+        assert_eq!(load_cart(&db, after_logout).await.unwrap().item_count, 0);
+        let sessions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM customer_sessions").fetch_one(&db).await.unwrap();
+        assert_eq!(sessions, 0);
+    }
+
+    async fn insert_category(db: &Pool<Postgres>, slug: &str, parent_id: Option<Uuid>) -> Uuid {
+        sqlx::query_scalar(
+            "INSERT INTO product_categories (name, description, slug, display_type, thumbnail, parent_id)
+            VALUES ($1, '', $1, '', '', $2) RETURNING id",
+        )
+        .bind(slug)
+        .bind(parent_id)
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn insert_term(db: &Pool<Postgres>, attribute_id: Uuid, slug: &str) -> Uuid {
+        sqlx::query_scalar("INSERT INTO product_terms (product_id, name, slug) VALUES ($1, $2, $2) RETURNING id")
+            .bind(attribute_id)
+            .bind(slug)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn insert_listed_product(db: &Pool<Postgres>, title: &str, price: i32, stock: i32, category_id: Uuid, term_ids: &[Uuid]) {
+        let product_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO products (title, description, sku, product_type, shipping_class, tax_status, tax_class, price, stock, allow_backorders, published)
+            VALUES ($1, '', $1, 'simple', '', '21%', 'standard', $2, $3, 'no', TRUE) RETURNING id",
+        )
+        .bind(title)
+        .bind(Decimal::from(price))
+        .bind(stock)
+        .fetch_one(db)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO product_categories_map (product_id, category_id) VALUES ($1, $2)")
+            .bind(product_id)
+            .bind(category_id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO product_terms_map (product_id, term_id) SELECT $1, UNNEST($2::UUID[])")
+            .bind(product_id)
+            .bind(term_ids)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn category_filters_count_the_subcategories(db: Pool<Postgres>) {
+        let clothing = insert_category(&db, "clothing", None).await;
+        let shirts = insert_category(&db, "shirts", Some(clothing)).await;
+        let shoes = insert_category(&db, "shoes", None).await;
+        let color: Uuid = sqlx::query_scalar("INSERT INTO product_attributes (name, slug) VALUES ('Color', 'color') RETURNING id")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let size: Uuid = sqlx::query_scalar("INSERT INTO product_attributes (name, slug) VALUES ('Size', 'size') RETURNING id")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let (red, blue) = (insert_term(&db, color, "red").await, insert_term(&db, color, "blue").await);
+        let (small, large) = (insert_term(&db, size, "s").await, insert_term(&db, size, "l").await);
+
+        insert_listed_product(&db, "Red scarf", 10, 5, clothing, &[red, small]).await;
+        insert_listed_product(&db, "Blue shirt", 20, 0, shirts, &[blue, small]).await;
+        insert_listed_product(&db, "Red shirt", 30, 2, shirts, &[red, large]).await;
+        //not in the tree
+        insert_listed_product(&db, "Red shoes", 40, 1, shoes, &[red, small]).await;
+
+        let filters = CategoryFilters::default();
+        let summary = category_filter_summary(&db, clothing, &filters, &[]).await.unwrap();
+        assert_eq!((summary.total, summary.in_stock_count), (3, 2));
+        assert_eq!((summary.min_price, summary.max_price), (Some(Decimal::from(10)), Some(Decimal::from(30))));
+        let counts = category_term_counts(&db, clothing, &filters, &[]).await.unwrap();
+        assert_eq!(counts, HashMap::from([(red, 2), (blue, 1), (small, 2), (large, 1)]));
+        assert_eq!(category_filter_summary(&db, shirts, &filters, &[]).await.unwrap().total, 2);
+
+        //red leaves the red products, and its own attribute keeps the counts without it
+        let summary = category_filter_summary(&db, clothing, &filters, &[red]).await.unwrap();
+        assert_eq!((summary.total, summary.in_stock_count), (2, 2));
+        let counts = category_term_counts(&db, clothing, &filters, &[red]).await.unwrap();
+        assert_eq!(counts, HashMap::from([(red, 2), (blue, 1), (small, 1), (large, 1)]));
+
+        //picking small as well counts the colors among the small products
+        let counts = category_term_counts(&db, clothing, &filters, &[red, small]).await.unwrap();
+        assert_eq!(counts, HashMap::from([(red, 1), (blue, 1), (small, 1), (large, 1)]));
+        assert_eq!(category_filter_summary(&db, clothing, &filters, &[red, small]).await.unwrap().total, 1);
+
+        //the price range leaves out the price filter, the in stock count leaves out the stock filter
+        let filters = CategoryFilters { max_price: Some(Decimal::from(15)), in_stock: true, ..CategoryFilters::default() };
+        let summary = category_filter_summary(&db, clothing, &filters, &[]).await.unwrap();
+        assert_eq!((summary.total, summary.in_stock_count), (1, 1));
+        assert_eq!((summary.min_price, summary.max_price), (Some(Decimal::from(10)), Some(Decimal::from(30))));
+        let counts = category_term_counts(&db, clothing, &filters, &[]).await.unwrap();
+        assert_eq!(counts, HashMap::from([(red, 1), (small, 1)]));
+    }
+
+    fn titles(results: &[ProductSearchResult]) -> Vec<&str> {
+        results.iter().map(|result| result.title.as_str()).collect()
+    }
+
+    #[sqlx::test]
+    async fn search_matches_prefixes_and_typos(db: Pool<Postgres>) {
+        let clothing = insert_category(&db, "clothing", None).await;
+        insert_listed_product(&db, "Woollen scarf", 10, 1, clothing, &[]).await;
+        insert_listed_product(&db, "Leather boots", 20, 1, clothing, &[]).await;
+        insert_listed_product(&db, "Wool socks", 5, 1, clothing, &[]).await;
+        sqlx::query("UPDATE products SET published = FALSE WHERE title = 'Wool socks'")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let search = |q: &str| SearchOptions { q: q.to_string() };
+        //the last word can be half typed
+        let (results, typos) = search_products(&db, &search("wool"), 10).await.unwrap();
+        assert_eq!((titles(&results), typos), (vec!["Woollen scarf"], false));
+        assert_eq!(results[0].title_html, "<mark>Woollen</mark> scarf");
+        let (results, typos) = search_products(&db, &search("Leath boo"), 10).await.unwrap();
+        assert_eq!((titles(&results), typos), (vec!["Leather boots"], false));
+
+        //nothing starts with these, the trigrams of the titles still match
+        let (results, typos) = search_products(&db, &search("lether"), 10).await.unwrap();
+        assert_eq!((titles(&results), typos), (vec!["Leather boots"], true));
+        let (results, typos) = search_products(&db, &search("scraf"), 10).await.unwrap();
+        assert_eq!((titles(&results), typos), (vec!["Woollen scarf"], true));
+        let (results, typos) = search_products(&db, &search("umbrella"), 10).await.unwrap();
+        assert_eq!((titles(&results), typos), (Vec::<&str>::new(), true));
+
+        let (results, _) = search_products(&db, &search("!!"), 10).await.unwrap();
+        assert!(results.is_empty());
     }
-    // Process `text_inputs` as necessary
-    
-    //Ok(())
 }
@@ -0,0 +1,442 @@
+//Uploaded images are never stored as they were sent. The format is sniffed from the bytes and anything that
+//isn't a JPEG, PNG, GIF or WebP is rejected, then the image is decoded and encoded again, which drops EXIF and
+//other metadata once the orientation in it has been applied. Each size in ImageSize is written next to the
+//original along with lossy WebP versions when they are smaller, the media library keeps which ones were stored
+//and the templates pick between them with the srcset filter. Images are named by the SHA-256 of the upload,
+//so uploading the same image again stores nothing new
+use std::{
+    collections::{HashMap, HashSet},
+    io::Cursor,
+};
+
+use axum::extract::multipart::Field;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use image::{
+    codecs::{jpeg::JpegEncoder, png::PngEncoder},
+    imageops::FilterType,
+    DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{error::AppError, handler::site_url, storage::Storage};
+
+//of one uploaded image
+pub const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+//of a whole form, the product forms upload several images at once
+pub const MAX_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+//width and height, a small file can decode to gigabytes of pixels
+const MAX_IMAGE_DIMENSION: u32 = 8000;
+const JPEG_QUALITY: u8 = 85;
+const WEBP_QUALITY: f32 = 80.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageSize {
+    Thumbnail,
+    Medium,
+    Large,
+}
+
+impl ImageSize {
+    pub const ALL: [ImageSize; 3] = [ImageSize::Thumbnail, ImageSize::Medium, ImageSize::Large];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ImageSize::Thumbnail => "thumbnail",
+            ImageSize::Medium => "medium",
+            ImageSize::Large => "large",
+        }
+    }
+
+    //images are scaled down to this width keeping their aspect ratio, smaller ones keep their own size
+    pub fn width(self) -> u32 {
+        match self {
+            ImageSize::Thumbnail => 150,
+            ImageSize::Medium => 300,
+            ImageSize::Large => 1024,
+        }
+    }
+}
+
+//Path of a size of a stored image, None is the original. photo.jpg is photo-medium.jpg at the medium size
+//and photo-medium.webp for its WebP version
+pub fn derivative_path(path: &str, size: Option<ImageSize>, webp: bool) -> String {
+    let (stem, extension) = split_extension(path);
+    let extension = if webp { "webp" } else { extension };
+    match size {
+        Some(size) => format!("{}-{}.{}", stem, size.as_str(), extension),
+        None => format!("{}.{}", stem, extension),
+    }
+}
+
+//The sizes stored next to an original, what srcset is built from. widths is in ImageSize order and empty
+//for originals stored before they had sizes, webp is whether they have WebP versions
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImageSizes {
+    pub widths: Vec<i32>,
+    pub webp: bool,
+}
+
+//A stored original with what the media library shows about it
+#[derive(Debug, Clone)]
+pub struct StoredImage {
+    pub url: String,
+    //as it was uploaded, empty for files found in the storage
+    pub file_name: String,
+    pub mime_type: &'static str,
+    pub size: i64,
+    pub width: i32,
+    pub height: i32,
+    pub sizes: ImageSizes,
+}
+
+//Reads the format and dimensions of a stored original from its header, sizes are left empty
+pub fn describe(url: String, file_name: String, bytes: &[u8]) -> Result<StoredImage, AppError> {
+    let name = if file_name.is_empty() { url.as_str() } else { file_name.as_str() };
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let format = reader
+        .format()
+        .ok_or_else(|| AppError::BadRequest(format!("{} isn't an image", name)))?;
+    let (width, height) = reader.into_dimensions().map_err(|e| decode_error(name, e))?;
+    Ok(StoredImage {
+        mime_type: format.to_mime_type(),
+        size: bytes.len() as i64,
+        width: width as i32,
+        height: height as i32,
+        url,
+        file_name,
+        sizes: ImageSizes::default(),
+    })
+}
+
+//The sizes of an original found in the storage. They were scaled down to the width of each size,
+//smaller images kept their own
+pub async fn find_sizes(storage: &dyn Storage, key: &str, width: i32) -> Result<ImageSizes, AppError> {
+    if !storage.exists(&derivative_path(key, Some(ImageSize::Thumbnail), false)).await? {
+        return Ok(ImageSizes::default());
+    }
+    Ok(ImageSizes {
+        widths: ImageSize::ALL.iter().map(|size| width.min(size.width() as i32)).collect(),
+        webp: storage.exists(&derivative_path(key, Some(ImageSize::Thumbnail), true)).await?,
+    })
+}
+
+//The file name of a key found in the storage. Images stored before the upload pipeline are named
+//with the base64 of the name they were uploaded with
+pub fn stored_file_name(key: &str) -> String {
+    let name = key.rsplit('/').next().unwrap_or(key);
+    BASE64_STANDARD
+        .decode(name)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .unwrap_or_else(|| name.to_string())
+}
+
+//images stored before the upload pipeline have names without an extension
+fn split_extension(path: &str) -> (&str, &str) {
+    path.rsplit_once('.').unwrap_or((path, ""))
+}
+
+//The keys of the originals among the keys of a folder, leaving out the sizes and WebP versions stored next to them
+pub fn originals(keys: &[String]) -> Vec<&String> {
+    let stems: HashSet<&str> = keys
+        .iter()
+        .map(|key| split_extension(key))
+        .filter(|(_, extension)| *extension != "webp")
+        .map(|(stem, _)| stem)
+        .collect();
+    keys.iter()
+        .filter(|key| {
+            let (stem, extension) = split_extension(key);
+            let sized = ImageSize::ALL.iter().any(|size| stem.ends_with(&format!("-{}", size.as_str())));
+            let webp_version = extension == "webp" && stems.contains(stem);
+            !sized && !webp_version
+        })
+        .collect()
+}
+
+//Reads an uploaded image, cleans it and stores it with its sizes under folder, e.g. products.
+//An image that is already stored is described from the stored original instead
+pub async fn save_upload(storage: &dyn Storage, mut field: Field<'_>, folder: &str) -> Result<StoredImage, AppError> {
+    let file_name = field.file_name().unwrap_or_default().to_string();
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = field.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() > MAX_IMAGE_BYTES {
+            return Err(AppError::BadRequest(format!(
+                "{} is larger than {} MB",
+                file_name,
+                MAX_IMAGE_BYTES / 1024 / 1024
+            )));
+        }
+    }
+
+    let key = format!("{}/{}.{}", folder, hex::encode(Sha256::digest(&bytes)), extension(&bytes, &file_name)?);
+    if storage.exists(&key).await? {
+        println!("Image {:?} is already stored", key);
+        let mut image = describe(storage.url(&key), file_name, &storage.get(&key).await?)?;
+        image.sizes = find_sizes(storage, &key, image.width).await?;
+        return Ok(image);
+    }
+
+    //decoding and resizing are slow, so they run on the blocking thread pool like argon2
+    let name = file_name.clone();
+    let (files, sizes) = tokio::task::spawn_blocking(move || process(&bytes, &name))
+        .await
+        .map_err(|e| AppError::Io(std::io::Error::other(e)))??;
+
+    println!("Uploading image to {:?}", key);
+    let mut original = Vec::new();
+    for (size, webp, data) in files {
+        let path = derivative_path(&key, size, webp);
+        if size.is_none() && !webp {
+            original = data.clone();
+        }
+        if let Err(e) = storage.put(&path, data, content_type(&path)).await {
+            remove(storage, &storage.url(&key)).await;
+            return Err(e.into());
+        }
+    }
+    let mut image = describe(storage.url(&key), file_name, &original)?;
+    image.sizes = sizes;
+    Ok(image)
+}
+
+//Deletes a stored image and all of its sizes, images from before they had sizes only have the original
+pub async fn remove(storage: &dyn Storage, url: &str) {
+    let Some(key) = storage.key(url) else {
+        return;
+    };
+    let mut keys = vec![key.clone()];
+    for webp in [false, true] {
+        keys.extend(ImageSize::ALL.iter().map(|size| derivative_path(&key, Some(*size), webp)));
+    }
+    keys.push(derivative_path(&key, None, true));
+
+    for key in keys {
+        match storage.delete(&key).await {
+            Ok(()) => println!("Removed image {:?}", key),
+            Err(e) => println!("Could not remove {:?}: {}", key, e),
+        }
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.') {
+        Some((_, "jpg")) => "image/jpeg",
+        Some((_, "webp")) => "image/webp",
+        _ => "image/png",
+    }
+}
+
+//The file extension an upload is stored with, from the format sniffed from its bytes
+fn extension(bytes: &[u8], file_name: &str) -> Result<&'static str, AppError> {
+    let format = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?.format();
+    match format {
+        Some(ImageFormat::Jpeg) => Ok("jpg"),
+        Some(ImageFormat::Png) => Ok("png"),
+        //re-encoding a GIF would only keep its first frame
+        Some(ImageFormat::Gif) => Ok("png"),
+        Some(ImageFormat::WebP) => Ok("webp"),
+        _ => Err(AppError::BadRequest(format!("{} isn't a JPEG, PNG, GIF or WebP image", file_name))),
+    }
+}
+
+type ImageFiles = Vec<(Option<ImageSize>, bool, Vec<u8>)>;
+
+//Decodes an upload and encodes each size and the original again, as (size, webp, bytes).
+//The original comes last, so once it is stored all of its sizes are too. WebP versions are
+//left out when together they aren't smaller than the files they are versions of
+fn process(bytes: &[u8], file_name: &str) -> Result<(ImageFiles, ImageSizes), AppError> {
+    let extension = extension(bytes, file_name)?;
+    let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder().map_err(|e| decode_error(file_name, e))?;
+    let orientation = decoder.orientation().map_err(|e| decode_error(file_name, e))?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| decode_error(file_name, e))?;
+    image.apply_orientation(orientation);
+
+    let mut files = Vec::new();
+    let mut webp_files = Vec::new();
+    let mut widths = Vec::new();
+    for size in ImageSize::ALL {
+        let resized = if image.width() > size.width() {
+            image.resize(size.width(), MAX_IMAGE_DIMENSION, FilterType::Lanczos3)
+        } else {
+            image.clone()
+        };
+        widths.push(resized.width() as i32);
+        files.push((Some(size), false, encode(&resized, extension)?));
+        if extension != "webp" {
+            webp_files.push((Some(size), true, encode(&resized, "webp")?));
+        }
+    }
+    let original = encode(&image, extension)?;
+    if extension != "webp" {
+        webp_files.push((None, true, encode(&image, "webp")?));
+    }
+
+    //a WebP original already is its own WebP version
+    let webp = extension == "webp" || smaller(&webp_files, &files, &original);
+    if webp {
+        files.extend(webp_files);
+    }
+    files.push((None, false, original));
+    Ok((files, ImageSizes { widths, webp }))
+}
+
+fn smaller(webp_files: &ImageFiles, files: &ImageFiles, original: &[u8]) -> bool {
+    let bytes = |files: &ImageFiles| files.iter().map(|(_, _, data)| data.len()).sum::<usize>();
+    bytes(webp_files) < bytes(files) + original.len()
+}
+
+fn decode_error(file_name: &str, e: image::ImageError) -> AppError {
+    match e {
+        image::ImageError::Limits(_) => AppError::BadRequest(format!(
+            "{} is larger than {} by {} pixels",
+            file_name, MAX_IMAGE_DIMENSION, MAX_IMAGE_DIMENSION
+        )),
+        e => AppError::BadRequest(format!("{} could not be read: {}", file_name, e)),
+    }
+}
+
+//Encodes without any of the metadata of the upload. WebP is lossy like JPEG,
+//JPEG has no alpha channel so transparent images are flattened
+fn encode(image: &DynamicImage, extension: &str) -> Result<Vec<u8>, AppError> {
+    let mut data = Vec::new();
+    let result = match extension {
+        "jpg" => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY)),
+        "webp" => return encode_webp(image),
+        _ => image.write_with_encoder(PngEncoder::new(&mut data)),
+    };
+    result.map_err(|e| AppError::Io(std::io::Error::other(e)))?;
+    Ok(data)
+}
+
+fn encode_webp(image: &DynamicImage) -> Result<Vec<u8>, AppError> {
+    let (width, height) = (image.width(), image.height());
+    let encoded = if image.color().has_alpha() {
+        let pixels = image.to_rgba8();
+        webp::Encoder::from_rgba(&pixels, width, height).encode_simple(false, WEBP_QUALITY)
+    } else {
+        let pixels = image.to_rgb8();
+        webp::Encoder::from_rgb(&pixels, width, height).encode_simple(false, WEBP_QUALITY)
+    };
+    let encoded = encoded.map_err(|e| AppError::Io(std::io::Error::other(format!("WebP encoding failed: {:?}", e))))?;
+    Ok(encoded.to_vec())
+}
+
+//Where pages load a stored image from, local images are saved as a path from the site root
+pub fn public_url(url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("/{}", url)
+    }
+}
+
+//public_url with the site in front, for the JSON API
+pub fn absolute_url(url: &str) -> String {
+    if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("{}/{}", site_url(), url)
+    }
+}
+
+//Tera filter for the src of a stored image, {{ path | media_url }}
+pub fn media_url(value: &tera::Value, _: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let url = tera::from_value::<String>(value.clone())?;
+    Ok(tera::Value::String(public_url(&url)))
+}
+
+//Tera filter for the srcset of an <img> or <source>, {{ path | srcset(images=image_sizes) }} or with webp=true.
+//images maps the paths on the page to their ImageSizes from the media library, see find_image_sizes.
+//Empty for images without sizes or WebP versions, which only have src
+pub fn srcset(value: &tera::Value, args: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let path = tera::from_value::<String>(value.clone())?;
+    let webp = args.get("webp").and_then(|webp| webp.as_bool()).unwrap_or(false);
+    let sizes = match args.get("images").and_then(|images| images.get(&path)) {
+        Some(sizes) => tera::from_value::<ImageSizes>(sizes.clone())?,
+        None => ImageSizes::default(),
+    };
+    Ok(tera::Value::String(srcset_of(&path, &sizes, webp)))
+}
+
+fn srcset_of(path: &str, sizes: &ImageSizes, webp: bool) -> String {
+    if webp && !sizes.webp {
+        return String::new();
+    }
+    let mut candidates: Vec<String> = Vec::new();
+    let mut widths: Vec<i32> = Vec::new();
+    for (size, width) in ImageSize::ALL.iter().zip(&sizes.widths) {
+        //sizes of a smaller image are copies of it, one of them is enough
+        if widths.contains(width) {
+            continue;
+        }
+        widths.push(*width);
+        candidates.push(format!("{} {}w", public_url(&derivative_path(path, Some(*size), webp)), width));
+    }
+    candidates.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn encoded(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8]));
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut bytes, format).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn sizes_keep_their_own_width() {
+        let (files, sizes) = process(&encoded(400, 200, ImageFormat::Jpeg), "photo.jpg").unwrap();
+        assert_eq!(sizes.widths, vec![150, 300, 400]);
+
+        //the original comes last and WebP versions are only there when they are smaller
+        assert_eq!(files.last().map(|(size, webp, _)| (*size, *webp)), Some((None, false)));
+        let bytes = |webp: bool| files.iter().filter(|file| file.1 == webp).map(|file| file.2.len()).sum::<usize>();
+        assert_eq!(files.iter().any(|file| file.1), sizes.webp);
+        if sizes.webp {
+            assert_eq!(files.len(), 8);
+            assert!(bytes(true) < bytes(false));
+        } else {
+            assert_eq!(files.len(), 4);
+        }
+    }
+
+    #[test]
+    fn webp_uploads_are_their_own_webp_version() {
+        let webp = encode_webp(&image::load_from_memory(&encoded(200, 100, ImageFormat::Png)).unwrap()).unwrap();
+        let (files, sizes) = process(&webp, "photo.webp").unwrap();
+        assert_eq!(sizes, ImageSizes { widths: vec![150, 200, 200], webp: true });
+        assert!(files.iter().all(|(_, webp, _)| !webp));
+    }
+
+    #[test]
+    fn srcset_from_sizes() {
+        let sizes = ImageSizes { widths: vec![150, 300, 1024], webp: false };
+        assert_eq!(
+            srcset_of("frontend/img/products/a.jpg", &sizes, false),
+            "/frontend/img/products/a-thumbnail.jpg 150w, /frontend/img/products/a-medium.jpg 300w, /frontend/img/products/a-large.jpg 1024w"
+        );
+        assert_eq!(srcset_of("frontend/img/products/a.jpg", &sizes, true), "");
+
+        //a small image is the same at the larger sizes
+        let sizes = ImageSizes { widths: vec![150, 200, 200], webp: true };
+        assert_eq!(
+            srcset_of("https://cdn.example.com/products/a.png", &sizes, true),
+            "https://cdn.example.com/products/a-thumbnail.webp 150w, https://cdn.example.com/products/a-medium.webp 200w"
+        );
+        assert_eq!(srcset_of("frontend/img/products/old", &ImageSizes::default(), false), "");
+    }
+}
@@ -0,0 +1,56 @@
+use async_trait::async_trait;
+use std::path::PathBuf;
+use tokio::fs;
+
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+//Sends emails to customers, e.g. password reset links.
+//There is no SMTP sender yet, the log and file sinks are for development and tests
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> std::io::Result<()>;
+}
+
+//MAIL_SINK=file writes every email to MAIL_DIR (default mail/), anything else prints them to the log
+pub fn mailer_from_env() -> Box<dyn Mailer> {
+    match std::env::var("MAIL_SINK").as_deref() {
+        Ok("file") => Box::new(FileMailer {
+            dir: PathBuf::from(std::env::var("MAIL_DIR").unwrap_or_else(|_| "mail".to_string())),
+        }),
+        _ => Box::new(LogMailer),
+    }
+}
+
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: Email) -> std::io::Result<()> {
+        println!("📧 To: {}\nSubject: {}\n\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+//one .eml file per email, named by the time it was sent so they sort in order
+pub struct FileMailer {
+    pub dir: PathBuf,
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> std::io::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+        let name = format!(
+            "{}_{}.eml",
+            chrono::Utc::now().format("%Y%m%d%H%M%S%.6f"),
+            uuid::Uuid::new_v4().simple()
+        );
+        let message = format!("To: {}\r\nSubject: {}\r\n\r\n{}\r\n", email.to, email.subject, email.body);
+        fs::write(self.dir.join(name), message).await
+    }
+}
@@ -1,7 +1,18 @@
+mod api;
+mod auth;
+mod csrf;
+mod error;
 mod handler;
+mod images;
+mod mail;
 mod model;
+mod openapi;
+mod payment;
 mod route;
 mod schema;
+mod session;
+mod storage;
+mod templates;
 
 use std::sync::Arc;
 
@@ -10,13 +21,21 @@ use axum::http::{
     HeaderValue, Method,
 };
 use dotenv::dotenv;
+use mail::Mailer;
+use payment::PaymentGateways;
 use route::create_router;
 use tower_http::cors::CorsLayer;
 
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
+use storage::Storage;
+use templates::Templates;
 
 pub struct AppState {
     db: Pool<Postgres>,
+    templates: Templates,
+    payments: PaymentGateways,
+    mailer: Box<dyn Mailer>,
+    storage: Box<dyn Storage>,
 }
 
 #[tokio::main]
@@ -39,13 +58,44 @@ async fn main() {
         }
     };
 
+    let templates = match Templates::load() {
+        Ok(templates) => {
+            println!("✅Templates loaded successfully!");
+            templates
+        }
+        Err(err) => {
+            println!("🔥 Failed to load the templates in frontend/:{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    //APP_ENV=development in .env reloads the templates when they change, no restart needed
+    let development = std::env::var("APP_ENV").is_ok_and(|env| env == "development");
+    let _watcher = if development {
+        match templates.watch() {
+            Ok(watcher) => Some(watcher),
+            Err(err) => {
+                println!("🔥 Failed to watch the templates, hot reload is off: {:?}", err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
     let cors = CorsLayer::new()
         .allow_origin("http://localhost:3000".parse::<HeaderValue>().unwrap())
-        .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
         .allow_credentials(true)
         .allow_headers([AUTHORIZATION, ACCEPT, CONTENT_TYPE]);
 
-    let app = create_router(Arc::new(AppState { db: pool.clone() })).layer(cors);
+    let app = create_router(Arc::new(AppState {
+        db: pool.clone(),
+        templates,
+        payments: PaymentGateways::from_env(),
+        mailer: mail::mailer_from_env(),
+        storage: storage::storage_from_env(),
+    })).layer(cors);
 
     println!("🚀 Server started successfully");
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8000").await.unwrap();
@@ -1,5 +1,7 @@
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 //stores attributes for products
@@ -8,7 +10,7 @@ pub struct ProductAttributes {
     pub id: Uuid,
     pub name: String,
     pub slug: String,
-    pub order_by: String,
+    pub order_by: TermOrder,
     //TODO turn this into a vector of string arrays Vec<[T; N]>
     //pub terms: Vec<[String; 3]>, // New field: vector of arrays, each with 3 strings
     #[serde(rename = "createdAt")]
@@ -17,20 +19,111 @@ pub struct ProductAttributes {
     pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+//a category in the category tree, depth is 0 for top level categories
+#[derive(Debug, Serialize, Clone)]
+pub struct CategoryNode {
+    #[serde(flatten)]
+    pub category: ProductCategories,
+    pub depth: i32,
+}
+
+//how the terms of an attribute are listed, stored as TEXT with a CHECK constraint in the product_attributes table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+pub enum TermOrder {
+    //the drag and drop order on the attribute's page
+    #[default]
+    MenuOrder,
+    Name,
+    //by the number the name starts with, so 2 comes before 10
+    NameNum,
+    //in the order the terms were added
+    Id,
+}
+
+impl TermOrder {
+    pub const ALL: [TermOrder; 4] = [TermOrder::MenuOrder, TermOrder::Name, TermOrder::NameNum, TermOrder::Id];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TermOrder::MenuOrder => "menu_order",
+            TermOrder::Name => "name",
+            TermOrder::NameNum => "name_num",
+            TermOrder::Id => "id",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TermOrder::MenuOrder => "Custom ordering",
+            TermOrder::Name => "Name",
+            TermOrder::NameNum => "Name (numeric)",
+            TermOrder::Id => "Term ID",
+        }
+    }
+
+    pub fn sort(&self, terms: &mut [ProductTerms]) {
+        match self {
+            TermOrder::MenuOrder => terms.sort_by(|a, b| (a.position, &a.name).cmp(&(b.position, &b.name))),
+            TermOrder::Name => terms.sort_by_key(|term| term.name.to_lowercase()),
+            //names without a number go last
+            TermOrder::NameNum => terms.sort_by(|a, b| {
+                let number = |term: &ProductTerms| {
+                    let digits: String = term
+                        .name
+                        .trim()
+                        .chars()
+                        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
+                        .collect();
+                    digits.parse::<Decimal>().ok()
+                };
+                match (number(a), number(b)) {
+                    (Some(x), Some(y)) => x.cmp(&y),
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                }
+                .then_with(|| a.name.cmp(&b.name))
+            }),
+            TermOrder::Id => terms.sort_by_key(|term| (term.created_at, term.id)),
+        }
+    }
+}
+
+impl std::str::FromStr for TermOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TermOrder::ALL
+            .into_iter()
+            .find(|order| order.as_str() == s)
+            .ok_or_else(|| format!("unknown sort order \"{}\"", s))
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for TermOrder {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
 //stores categories for products
 #[derive(Eq, Hash, PartialEq, Clone, Debug, FromRow, Deserialize, Serialize)]
 pub struct ProductCategories {
-    pub lvl: String,
     pub id: Uuid,
+    //None for top level categories
     pub parent_id: Option<Uuid>,
-    pub parent: String,
     pub name: String,
     pub slug: String,
     pub description: String,
+    //empty, products, subcategories or both
     pub display_type: String,
     pub thumbnail: String,
-    pub count: i32, // Count of products in this category
-    pub child_categories: Option<Vec<Uuid>>, // Array of UUIDs for child categories
+    pub count: i32, // Count of published products in this category
+    //published products in this category or any of its subcategories, each counted once
+    pub total_count: i32,
     //TODO turn this into a vector of string arrays Vec<[T; N]>
     //pub terms: Vec<[String; 3]>, // New field: vector of arrays, each with 3 strings
     #[serde(rename = "createdAt")]
@@ -39,15 +132,133 @@ pub struct ProductCategories {
     pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+//free-form product tags, typed on the product form
+#[derive(Eq, Hash, PartialEq, Clone, Debug, FromRow, Deserialize, Serialize)]
+pub struct ProductTags {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub description: String,
+    pub count: i32, // Count of published products with this tag
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 #[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
 pub struct ProductTerms {
+    pub id: Uuid,
     pub product_id: Uuid,       // Foreign key to ProductAttributes
     pub name: String,         // term Name First term (NOT NULL)
     pub slug: String,         // term Slug Second term (NOT NULL)
     pub description: Option<String>, // term Description Third term (can be NULL)
+    //drag and drop order, used when the attribute's order_by is menu_order
+    pub position: i32,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
 }
 
-//TODO create a mapping of ProduductTerms to Product Attributes
+//A variation of a variable product, bought instead of the product itself.
+//It is made of one term of each of the product's attributes, see VariationTermModel
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct ProductVariationModel {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub sku: String,
+    pub price: Decimal,
+    pub stock: i32,
+    //kg
+    pub shipping_weight: Decimal,
+    //empty uses the product's image
+    pub image: String,
+    pub enabled: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//the term of one attribute in a variation, e.g. Color: Red
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct VariationTermModel {
+    pub variation_id: Uuid,
+    pub attribute_id: Uuid,
+    pub attribute_name: String,
+    pub term_id: Uuid,
+    pub term_name: String,
+}
+
+//a variation with its terms in the order of the product's attributes
+#[derive(Debug, Serialize, Clone)]
+pub struct VariationDetails {
+    #[serde(flatten)]
+    pub variation: ProductVariationModel,
+    pub terms: Vec<VariationTermModel>,
+    //"Color: Red, Size: M", kept on cart and order items
+    pub label: String,
+}
+
+impl VariationDetails {
+    pub fn new(variation: ProductVariationModel, terms: Vec<VariationTermModel>) -> Self {
+        let label = terms
+            .iter()
+            .map(|term| format!("{}: {}", term.attribute_name, term.term_name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        VariationDetails { variation, terms, label }
+    }
+}
+
+//an attribute with every term of it, selected holds the terms the product comes in
+#[derive(Debug, Serialize, Clone)]
+pub struct ProductAttributeOptions {
+    pub attribute: ProductAttributes,
+    pub terms: Vec<ProductTerms>,
+    pub selected: Vec<Uuid>,
+}
+
+//whether a product can still be ordered once its stock runs out
+//stored as TEXT with a CHECK constraint in the products table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum BackorderStatus {
+    #[default]
+    No,
+    Notify,
+    Yes,
+}
+
+impl BackorderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BackorderStatus::No => "no",
+            BackorderStatus::Notify => "notify",
+            BackorderStatus::Yes => "yes",
+        }
+    }
+}
+
+impl std::str::FromStr for BackorderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "no" => Ok(BackorderStatus::No),
+            "notify" => Ok(BackorderStatus::Notify),
+            "yes" => Ok(BackorderStatus::Yes),
+            _ => Err(format!("must be one of no, notify or yes, got \"{}\"", s)),
+        }
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for BackorderStatus {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
 
 //this is the sqlx database model
 #[derive(Debug, FromRow, Deserialize, Serialize)]
@@ -56,24 +267,821 @@ pub struct ProductModel {
     pub id: Uuid,
     pub title: String,
     pub description: String,
-    pub category: String,
-    pub price: String,
+    pub price: Decimal,
     pub sku: String,
     pub product_type: String,
-    pub stock: String,
-    pub allow_backorders: String,
-    pub low_stock_threshold: String,
-    pub shipping_weight: String,
+    pub stock: i32,
+    pub allow_backorders: BackorderStatus,
+    pub low_stock_threshold: i32,
+    //kg
+    pub shipping_weight: Decimal,
+    //path of the featured image, kept up to date by the database from product_images
     pub product_gallery: String,
-    pub attributes: String,
-    pub variations: String,
-    pub shipping_dimensions: String,
+    //cm, measured in the box ready to ship
+    pub shipping_length: Decimal,
+    pub shipping_width: Decimal,
+    pub shipping_height: Decimal,
     pub shipping_class: String,
     pub tax_status: String,
     pub tax_class: String,
     pub published: Option<bool>,
+    //of the approved reviews, kept up to date by the database
+    pub average_rating: Decimal,
+    pub rating_count: i32,
+    //items sold in processing, on-hold and completed orders, kept up to date by the database
+    pub total_sales: i32,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//An image in a product's gallery, ordered by position
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct ProductImageModel {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub path: String,
+    pub alt_text: String,
+    pub position: i32,
+    //the main image on the product page and the one used in listings
+    pub featured: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//An uploaded image in the media library, path is what products and categories store
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct MediaModel {
+    pub id: Uuid,
+    pub path: String,
+    pub file_name: String,
+    pub mime_type: String,
+    //bytes of the original
+    pub size: i64,
+    pub width: i32,
+    pub height: i32,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    //of its sizes, see images::ImageSizes
+    pub widths: Vec<i32>,
+    pub webp: bool,
+}
+
+//a product or category using a media library image, linked to its edit page
+#[derive(Debug, Serialize, Clone)]
+pub struct MediaReference {
+    pub name: String,
+    pub url: String,
+}
+
+//a line in the visitor's cart joined with the product it points to
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone, ToSchema)]
+pub struct CartItemModel {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub variation_id: Option<Uuid>,
+    pub variation: String,
+    pub quantity: i32,
+    pub title: String,
+    pub price: Decimal,
+    pub product_gallery: String,
+    pub stock: i32,
+    pub allow_backorders: BackorderStatus,
+    pub line_total: Decimal,
+}
+
+impl CartItemModel {
+    //more is in the cart than in stock, only possible when the product allows backorders
+    pub fn backordered(&self) -> bool {
+        self.quantity > self.stock
+    }
+}
+
+//the cart page and /api/cart both show this
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CartModel {
+    pub items: Vec<CartItemModel>,
+    pub item_count: i32,
+    pub subtotal: Decimal,
+    //products that will be backordered, shown to the customer when allow_backorders is notify
+    pub backordered: Vec<Uuid>,
+}
+
+//body of the /api/cart responses
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CartResponse {
+    pub status: String,
+    pub data: CartData,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CartData {
+    pub cart: CartModel,
+}
+
+impl CartModel {
+    pub fn new(items: Vec<CartItemModel>) -> Self {
+        let item_count = items.iter().map(|item| item.quantity).sum();
+        let subtotal = items.iter().map(|item| item.line_total).sum();
+        let backordered = items
+            .iter()
+            .filter(|item| item.backordered() && item.allow_backorders == BackorderStatus::Notify)
+            .map(|item| item.id)
+            .collect();
+        CartModel {
+            items,
+            item_count,
+            subtotal,
+            backordered,
+        }
+    }
+}
+
+//WooCommerce order statuses, stored as TEXT with a CHECK constraint in the orders table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum OrderStatus {
+    //placed, waiting for payment
+    #[default]
+    Pending,
+    //paid, waiting to be shipped
+    Processing,
+    //waiting for the shop to confirm payment, e.g. a bank transfer
+    OnHold,
+    Completed,
+    Cancelled,
+    Refunded,
+    //payment failed or was declined
+    Failed,
+}
+
+impl OrderStatus {
+    pub const ALL: [OrderStatus; 7] = [
+        OrderStatus::Pending,
+        OrderStatus::Processing,
+        OrderStatus::OnHold,
+        OrderStatus::Completed,
+        OrderStatus::Cancelled,
+        OrderStatus::Refunded,
+        OrderStatus::Failed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Processing => "processing",
+            OrderStatus::OnHold => "on-hold",
+            OrderStatus::Completed => "completed",
+            OrderStatus::Cancelled => "cancelled",
+            OrderStatus::Refunded => "refunded",
+            OrderStatus::Failed => "failed",
+        }
+    }
+
+    //the statuses an order in this status can be moved to
+    pub fn transitions(&self) -> &'static [OrderStatus] {
+        use OrderStatus::*;
+        match self {
+            Pending => &[Processing, OnHold, Cancelled, Failed],
+            OnHold => &[Processing, Cancelled, Failed],
+            Processing => &[Completed, OnHold, Cancelled, Refunded],
+            Completed => &[Refunded],
+            Failed => &[Pending, Processing, Cancelled],
+            Cancelled | Refunded => &[],
+        }
+    }
+
+    pub fn can_transition_to(&self, next: OrderStatus) -> bool {
+        self.transitions().contains(&next)
+    }
+
+    //stock is held for the order in every status except these
+    pub fn releases_stock(&self) -> bool {
+        matches!(self, OrderStatus::Cancelled | OrderStatus::Refunded | OrderStatus::Failed)
+    }
+}
+
+impl std::str::FromStr for OrderStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OrderStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown order status \"{}\"", s))
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for OrderStatus {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone, Default)]
+pub struct OrderModel {
+    pub id: Uuid,
+    pub number: i64,
+    pub session_id: Uuid,
+    pub status: OrderStatus,
+    pub billing_name: String,
+    pub billing_email: String,
+    pub billing_phone: String,
+    pub billing_address: String,
+    pub billing_city: String,
+    pub billing_postcode: String,
+    pub billing_country: String,
+    pub customer_note: String,
+    pub subtotal: Decimal,
+    pub tax_total: Decimal,
+    pub total: Decimal,
+    pub stock_reduced: bool,
+    //id of the PaymentGateway picked at checkout
+    pub payment_method: String,
+    //None for guest checkout
+    pub customer_id: Option<Uuid>,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//a copy of the product as it was when the order was placed
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct OrderItemModel {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    //None once the product has been deleted
+    pub product_id: Option<Uuid>,
+    pub title: String,
+    pub sku: String,
+    //None for simple products and once the variation has been deleted
+    pub variation_id: Option<Uuid>,
+    pub variation: String,
+    pub price: Decimal,
+    pub quantity: i32,
+    //percent
+    pub tax_rate: Decimal,
+    pub tax: Decimal,
+    pub line_total: Decimal,
+}
+
+//stored as TEXT with a CHECK constraint in the payments table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum PaymentStatus {
+    //waiting for money that is paid outside the shop, e.g. a bank transfer
+    #[default]
+    Pending,
+    //the customer has to do something first, e.g. 3D Secure
+    RequiresAction,
+    //the money is reserved but not taken yet
+    Authorized,
+    Captured,
+    Declined,
+    Refunded,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Pending => "pending",
+            PaymentStatus::RequiresAction => "requires-action",
+            PaymentStatus::Authorized => "authorized",
+            PaymentStatus::Captured => "captured",
+            PaymentStatus::Declined => "declined",
+            PaymentStatus::Refunded => "refunded",
+        }
+    }
+
+    //the statuses a payment in this status can be moved to
+    pub fn transitions(&self) -> &'static [PaymentStatus] {
+        use PaymentStatus::*;
+        match self {
+            Pending => &[RequiresAction, Authorized, Captured, Declined],
+            RequiresAction => &[Authorized, Captured, Declined],
+            Authorized => &[Captured, Declined],
+            Captured => &[Refunded],
+            Declined | Refunded => &[],
+        }
+    }
+
+    pub fn can_transition_to(&self, next: PaymentStatus) -> bool {
+        self.transitions().contains(&next)
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for PaymentStatus {
+    fn from(s: String) -> Self {
+        match s.as_str() {
+            "requires-action" => PaymentStatus::RequiresAction,
+            "authorized" => PaymentStatus::Authorized,
+            "captured" => PaymentStatus::Captured,
+            "declined" => PaymentStatus::Declined,
+            "refunded" => PaymentStatus::Refunded,
+            _ => PaymentStatus::Pending,
+        }
+    }
+}
+
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone, Default)]
+pub struct PaymentModel {
+    pub id: Uuid,
+    pub order_id: Uuid,
+    //id of the PaymentGateway that took the payment
+    pub gateway: String,
+    pub status: PaymentStatus,
+    pub amount: Decimal,
+    pub transaction_id: String,
+    pub message: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//Roles of a customer account, stored as TEXT with a CHECK constraint in the customers table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum Role {
+    //everything a shop manager can do, and changing the roles of other accounts
+    Admin,
+    //products, categories, attributes and orders
+    ShopManager,
+    #[default]
+    Customer,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Admin, Role::ShopManager, Role::Customer];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::ShopManager => "shop-manager",
+            Role::Customer => "customer",
+        }
+    }
+
+    pub fn can_manage_shop(&self) -> bool {
+        matches!(self, Role::Admin | Role::ShopManager)
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| role.as_str() == s)
+            .ok_or_else(|| format!("unknown role \"{}\"", s))
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for Role {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+//What an API key may do, stored as TEXT with a CHECK constraint in the api_keys table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum ApiKeyPermissions {
+    //GET requests only
+    #[default]
+    Read,
+    //POST, PUT, PATCH and DELETE requests only
+    Write,
+    ReadWrite,
+}
+
+impl ApiKeyPermissions {
+    pub const ALL: [ApiKeyPermissions; 3] = [ApiKeyPermissions::Read, ApiKeyPermissions::Write, ApiKeyPermissions::ReadWrite];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyPermissions::Read => "read",
+            ApiKeyPermissions::Write => "write",
+            ApiKeyPermissions::ReadWrite => "read-write",
+        }
+    }
+
+    pub fn can_read(&self) -> bool {
+        matches!(self, ApiKeyPermissions::Read | ApiKeyPermissions::ReadWrite)
+    }
+
+    pub fn can_write(&self) -> bool {
+        matches!(self, ApiKeyPermissions::Write | ApiKeyPermissions::ReadWrite)
+    }
+}
+
+impl std::str::FromStr for ApiKeyPermissions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ApiKeyPermissions::ALL
+            .into_iter()
+            .find(|permissions| permissions.as_str() == s)
+            .ok_or_else(|| format!("unknown permissions \"{}\"", s))
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for ApiKeyPermissions {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+//a consumer key and secret pair of a shop manager, for calling /api/v1 without a browser
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct ApiKeyModel {
+    pub id: Uuid,
+    pub customer_id: Uuid,
+    pub description: String,
+    pub permissions: ApiKeyPermissions,
+    pub consumer_key_hash: String,
+    pub consumer_secret_hash: String,
+    pub truncated_key: String,
+    pub last_access: Option<chrono::DateTime<chrono::Utc>>,
+    //revoked keys stay listed but can't be used any more
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct CustomerModel {
+    pub id: Uuid,
+    pub email: String,
+    #[serde(skip_serializing)]
+    pub password_hash: String,
+    pub first_name: String,
+    pub last_name: String,
+    pub role: Role,
     #[serde(rename = "createdAt")]
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
     #[serde(rename = "updatedAt")]
     pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
 }
+
+//kind is billing or shipping
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone, Default)]
+pub struct CustomerAddressModel {
+    pub kind: String,
+    pub name: String,
+    pub phone: String,
+    pub address: String,
+    pub city: String,
+    pub postcode: String,
+    pub country: String,
+}
+
+//WordPress comment statuses, stored as TEXT with a CHECK constraint in the product_reviews table
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "kebab-case")]
+#[sqlx(type_name = "text", rename_all = "kebab-case")]
+pub enum ReviewStatus {
+    //waiting for a shop manager, not shown on the product page
+    #[default]
+    Pending,
+    Approved,
+    Spam,
+    Trash,
+}
+
+impl ReviewStatus {
+    pub const ALL: [ReviewStatus; 4] = [ReviewStatus::Pending, ReviewStatus::Approved, ReviewStatus::Spam, ReviewStatus::Trash];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReviewStatus::Pending => "pending",
+            ReviewStatus::Approved => "approved",
+            ReviewStatus::Spam => "spam",
+            ReviewStatus::Trash => "trash",
+        }
+    }
+}
+
+impl std::str::FromStr for ReviewStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ReviewStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == s)
+            .ok_or_else(|| format!("unknown review status \"{}\"", s))
+    }
+}
+
+//query_as! decodes the column as a String, the CHECK constraint keeps it to known values
+impl From<String> for ReviewStatus {
+    fn from(s: String) -> Self {
+        s.parse().unwrap_or_default()
+    }
+}
+
+//a review of a product, or a reply from the shop when parent_id is set
+#[derive(Debug, FromRow, Deserialize, Serialize, Clone)]
+pub struct ProductReviewModel {
+    pub id: Uuid,
+    pub product_id: Uuid,
+    pub parent_id: Option<Uuid>,
+    //None for guests
+    pub customer_id: Option<Uuid>,
+    pub author_name: String,
+    pub author_email: String,
+    //1 to 5 stars, None for replies
+    pub rating: Option<i32>,
+    pub content: String,
+    pub status: ReviewStatus,
+    //verified owner, the author had bought the product
+    pub verified: bool,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(rename = "updatedAt")]
+    pub updated_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+//a product found by the search, title_html and snippet_html are escaped with the matched words in <mark>
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ProductSearchResult {
+    pub id: Uuid,
+    pub title: String,
+    pub price: Decimal,
+    pub product_gallery: String,
+    pub title_html: String,
+    pub snippet_html: String,
+}
+
+//body of /api/search
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchResponse {
+    pub status: String,
+    pub data: SearchData,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchData {
+    /// The search as it was matched, trimmed.
+    pub query: String,
+    /// Whether nothing matched and these are products with similarly spelled titles instead.
+    pub fuzzy: bool,
+    pub products: Vec<ProductSearchResult>,
+}
+
+//body of /api/healthchecker
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthResponse {
+    pub status: String,
+    pub message: String,
+}
+
+//Resources of the /api/v1 REST API, shaped like the WooCommerce REST API ones. The doc comments end up in /api/openapi.json
+
+/// A category or tag of a product.
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ApiTermLink {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+}
+
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ApiImage {
+    pub src: String,
+    pub alt: String,
+}
+
+/// Shipping dimensions in cm.
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ApiProductDimensions {
+    pub length: Decimal,
+    pub width: Decimal,
+    pub height: Decimal,
+}
+
+/// An attribute the product comes in.
+#[derive(Debug, Serialize, Clone, ToSchema)]
+pub struct ApiProductAttribute {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub position: i32,
+    /// Whether the product has variations.
+    pub variation: bool,
+    /// Names of the attribute's terms the product comes in.
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiProduct {
+    pub id: Uuid,
+    pub name: String,
+    pub permalink: String,
+    pub date_created: Option<chrono::DateTime<chrono::Utc>>,
+    pub date_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// `variable` when the product has variations, `simple` otherwise.
+    #[serde(rename = "type")]
+    pub product_type: String,
+    /// `publish` or `draft`.
+    pub status: String,
+    pub description: String,
+    pub sku: String,
+    pub price: Decimal,
+    pub regular_price: Decimal,
+    /// Digital products.
+    #[serde(rename = "virtual")]
+    pub is_virtual: bool,
+    /// Items sold in processing, on-hold and completed orders.
+    pub total_sales: i32,
+    pub tax_status: String,
+    pub tax_class: String,
+    pub manage_stock: bool,
+    pub stock_quantity: i32,
+    /// `instock`, `outofstock` or `onbackorder`, variable products are in stock when one of their enabled variations is.
+    pub stock_status: String,
+    pub backorders: BackorderStatus,
+    pub low_stock_amount: i32,
+    /// kg
+    pub weight: Decimal,
+    pub dimensions: ApiProductDimensions,
+    pub shipping_class: String,
+    /// Of the approved reviews.
+    pub average_rating: Decimal,
+    pub rating_count: i32,
+    pub categories: Vec<ApiTermLink>,
+    pub tags: Vec<ApiTermLink>,
+    /// The gallery, the featured image first.
+    pub images: Vec<ApiImage>,
+    pub attributes: Vec<ApiProductAttribute>,
+    /// Ids of the product's variations.
+    pub variations: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiCategory {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    /// None for top level categories.
+    pub parent: Option<Uuid>,
+    pub description: String,
+    /// `default`, `products`, `subcategories` or `both`.
+    pub display: String,
+    pub image: Option<ApiImage>,
+    /// Published products in the category.
+    pub count: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiAttribute {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    /// Always `select`.
+    #[serde(rename = "type")]
+    pub attribute_type: String,
+    pub order_by: TermOrder,
+    pub has_archives: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiTerm {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub description: String,
+    /// Position in the custom ordering of the attribute.
+    pub menu_order: i32,
+    /// Products that come in the term.
+    pub count: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiTag {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub description: String,
+    /// Published products with the tag.
+    pub count: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiBilling {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub phone: String,
+    pub address_1: String,
+    pub city: String,
+    pub postcode: String,
+    pub country: String,
+}
+
+/// A copy of the product as it was when the order was placed.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiOrderLine {
+    pub id: Uuid,
+    pub name: String,
+    /// None once the product has been deleted.
+    pub product_id: Option<Uuid>,
+    pub variation_id: Option<Uuid>,
+    /// The variation's terms, e.g. `Color: Red, Size: S`.
+    pub variation: String,
+    pub quantity: i32,
+    pub sku: String,
+    pub price: Decimal,
+    pub subtotal: Decimal,
+    pub total: Decimal,
+    pub total_tax: Decimal,
+    /// Percent.
+    pub tax_rate: Decimal,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiOrder {
+    pub id: Uuid,
+    pub number: String,
+    pub status: OrderStatus,
+    pub date_created: Option<chrono::DateTime<chrono::Utc>>,
+    pub date_modified: Option<chrono::DateTime<chrono::Utc>>,
+    /// None for guest orders.
+    pub customer_id: Option<Uuid>,
+    pub customer_note: String,
+    pub billing: ApiBilling,
+    /// Id of the payment gateway, empty for orders paid outside the shop.
+    pub payment_method: String,
+    pub payment_method_title: String,
+    pub total: Decimal,
+    pub total_tax: Decimal,
+    pub line_items: Vec<ApiOrderLine>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OrderStatus::*;
+
+    #[test]
+    fn order_status_transitions() {
+        let allowed = [
+            (Pending, vec![Processing, OnHold, Cancelled, Failed]),
+            (OnHold, vec![Processing, Cancelled, Failed]),
+            (Processing, vec![Completed, OnHold, Cancelled, Refunded]),
+            (Completed, vec![Refunded]),
+            (Failed, vec![Pending, Processing, Cancelled]),
+            (Cancelled, vec![]),
+            (Refunded, vec![]),
+        ];
+        for (from, to) in allowed {
+            for next in OrderStatus::ALL {
+                assert_eq!(
+                    from.can_transition_to(next),
+                    to.contains(&next),
+                    "{} to {}",
+                    from.as_str(),
+                    next.as_str()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn order_status_illegal_moves() {
+        //nothing moves to the status it is already in
+        for status in OrderStatus::ALL {
+            assert!(!status.can_transition_to(status), "{}", status.as_str());
+        }
+        assert!(!Completed.can_transition_to(Processing));
+        assert!(!Refunded.can_transition_to(Processing));
+        assert!(!Cancelled.can_transition_to(Pending));
+        assert!(!Pending.can_transition_to(Completed));
+        assert!(!Pending.can_transition_to(Refunded));
+    }
+
+    #[test]
+    fn order_status_stock() {
+        let released: Vec<OrderStatus> = OrderStatus::ALL.into_iter().filter(|status| status.releases_stock()).collect();
+        assert_eq!(released, vec![Cancelled, Refunded, Failed]);
+    }
+
+    #[test]
+    fn order_status_names() {
+        for status in OrderStatus::ALL {
+            assert_eq!(status.as_str().parse::<OrderStatus>(), Ok(status));
+        }
+        assert!("shipped".parse::<OrderStatus>().is_err());
+    }
+}
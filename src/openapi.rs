@@ -0,0 +1,129 @@
+//OpenAPI 3 document of the JSON API, built from the schema.rs and model.rs types and the #[utoipa::path] of each handler.
+//Served at /api/openapi.json with Swagger UI at /api/docs, both bundled into the binary so the docs work offline
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityRequirement, SecurityScheme},
+        RefOr, Response,
+    },
+    Modify, OpenApi,
+};
+
+use crate::{
+    api,
+    auth::LOGIN_COOKIE,
+    error::ErrorBody,
+    handler,
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Shop API",
+        description = "The cart and search endpoints used by the shop's pages, and the /api/v1 REST API for shop managers, \
+            shaped like the WooCommerce REST API. Lists are paged with page and per_page and send the X-WP-Total, \
+            X-WP-TotalPages and Link headers."
+    ),
+    paths(
+        handler::health_checker_handler,
+        handler::search_handler,
+        handler::get_cart_handler,
+        handler::add_cart_item_handler,
+        handler::update_cart_item_handler,
+        handler::delete_cart_item_handler,
+        api::list_products_handler,
+        api::create_product_handler,
+        api::get_product_handler,
+        api::update_product_handler,
+        api::delete_product_handler,
+        api::list_categories_handler,
+        api::create_category_handler,
+        api::get_category_handler,
+        api::update_category_handler,
+        api::delete_category_handler,
+        api::list_attributes_handler,
+        api::create_attribute_handler,
+        api::get_attribute_handler,
+        api::update_attribute_handler,
+        api::delete_attribute_handler,
+        api::list_terms_handler,
+        api::create_term_handler,
+        api::get_term_handler,
+        api::update_term_handler,
+        api::delete_term_handler,
+        api::list_tags_handler,
+        api::create_tag_handler,
+        api::get_tag_handler,
+        api::update_tag_handler,
+        api::delete_tag_handler,
+        api::list_orders_handler,
+        api::create_order_handler,
+        api::get_order_handler,
+        api::update_order_handler,
+        api::delete_order_handler,
+    ),
+    components(schemas(ErrorBody)),
+    modifiers(&ShopManagerOnly),
+    tags(
+        (name = "shop", description = "Cart and search for visitors, the cart belongs to the session_id cookie"),
+        (name = "products"),
+        (name = "categories"),
+        (name = "attributes"),
+        (name = "terms"),
+        (name = "tags"),
+        (name = "orders"),
+    )
+)]
+pub struct ApiDoc;
+
+//Everything under /api/v1 needs a logged in shop manager or admin, or an API key of one
+struct ShopManagerOnly;
+
+impl Modify for ShopManagerOnly {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "login",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::with_description(
+                LOGIN_COOKIE,
+                "Set by logging in at /login",
+            ))),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::Http(
+                Http::builder()
+                    .scheme(HttpAuthScheme::Basic)
+                    .description(Some("The consumer key and secret of an API key from /api-keys as the username and password"))
+                    .build(),
+            ),
+        );
+
+        let error = |description: &str| -> RefOr<Response> {
+            Response::builder()
+                .description(description)
+                .content(
+                    "application/json",
+                    utoipa::openapi::Content::new(Some(utoipa::openapi::Ref::from_schema_name("ErrorBody"))),
+                )
+                .build()
+                .into()
+        };
+        for (path, item) in openapi.paths.paths.iter_mut() {
+            if !path.starts_with("/api/v1/") {
+                continue;
+            }
+            let operations = [&mut item.get, &mut item.post, &mut item.put, &mut item.patch, &mut item.delete];
+            for operation in operations.into_iter().flatten() {
+                operation.security = Some(vec![
+                    SecurityRequirement::new("login", Vec::<String>::new()),
+                    SecurityRequirement::new("api_key", Vec::<String>::new()),
+                ]);
+                operation.responses.responses.insert("401".to_string(), error("Not logged in, or an invalid or revoked API key"));
+                operation.responses.responses.insert(
+                    "403".to_string(),
+                    error("Not a shop manager, or an API key without the read or write permission the method needs"),
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,523 @@
+use async_trait::async_trait;
+use axum::http::HeaderMap;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::model::{OrderModel, OrderStatus, PaymentModel, PaymentStatus};
+
+//What a gateway answered for an authorize, capture or refund
+#[derive(Debug, Clone)]
+pub struct PaymentResult {
+    pub status: PaymentStatus,
+    //id of the payment at the gateway, used to match webhooks to payments
+    pub transaction_id: String,
+    pub message: String,
+    //where to send the customer when status is RequiresAction, e.g. a 3DS challenge
+    pub redirect: Option<String>,
+}
+
+//A payment update sent by the gateway, already verified
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookEvent {
+    pub transaction_id: String,
+    pub status: PaymentStatus,
+    #[serde(default)]
+    pub message: String,
+}
+
+//Fields from the checkout form that only some gateways use, they are never stored
+#[derive(Debug, Clone, Default)]
+pub struct PaymentDetails {
+    pub card_number: String,
+}
+
+#[derive(Debug)]
+pub enum PaymentError {
+    //the gateway can't do this, e.g. refund a cash on delivery payment automatically
+    Unsupported(&'static str),
+    InvalidRequest(String),
+    InvalidSignature,
+    //a webhook that would move the payment or its order somewhere the state machines don't allow
+    InvalidTransition(String),
+}
+
+impl std::fmt::Display for PaymentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentError::Unsupported(action) => write!(f, "This payment method doesn't support {}", action),
+            PaymentError::InvalidRequest(message) => write!(f, "{}", message),
+            PaymentError::InvalidSignature => write!(f, "The webhook signature is invalid"),
+            PaymentError::InvalidTransition(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+//A payment provider. Every method gets the payment as it is stored so gateways don't keep state
+#[async_trait]
+pub trait PaymentGateway: Send + Sync {
+    //stored in orders.payment_method and used in the webhook url /payments/webhook/:id
+    fn id(&self) -> &'static str;
+    fn title(&self) -> &'static str;
+    //shown under the payment method at checkout
+    fn description(&self) -> String;
+    //shown on the order received page
+    fn instructions(&self) -> String {
+        String::new()
+    }
+    //whether the checkout form needs to show the card fields
+    fn needs_card(&self) -> bool {
+        false
+    }
+
+    async fn authorize(&self, order: &OrderModel, details: &PaymentDetails) -> Result<PaymentResult, PaymentError>;
+    async fn capture(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError>;
+    async fn refund(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError>;
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> Result<WebhookEvent, PaymentError>;
+
+    //the status the order moves to after a payment ends up in this status, None leaves it as is
+    fn order_status(&self, payment: PaymentStatus) -> Option<OrderStatus> {
+        match payment {
+            PaymentStatus::Captured => Some(OrderStatus::Processing),
+            PaymentStatus::Authorized => Some(OrderStatus::OnHold),
+            PaymentStatus::Declined => Some(OrderStatus::Failed),
+            PaymentStatus::Refunded => Some(OrderStatus::Refunded),
+            PaymentStatus::Pending | PaymentStatus::RequiresAction => None,
+        }
+    }
+}
+
+//What a payment update does to a payment and its order
+#[derive(Debug, PartialEq)]
+pub enum PaymentChange {
+    //the payment is already in the status, e.g. the gateway sent the same webhook again
+    Replayed,
+    //the payment moves to the status, and the order to this status when there is one
+    Apply(Option<OrderStatus>),
+}
+
+//Checks a payment update against the payment and order state machines, so an old or replayed webhook
+//can't take a refunded or cancelled order back to processing
+pub fn payment_change(
+    gateway: &dyn PaymentGateway,
+    payment: PaymentStatus,
+    order: OrderStatus,
+    next: PaymentStatus,
+) -> Result<PaymentChange, PaymentError> {
+    if payment == next {
+        return Ok(PaymentChange::Replayed);
+    }
+    if !payment.can_transition_to(next) {
+        return Err(PaymentError::InvalidTransition(format!(
+            "The payment can't be moved from {} to {}",
+            payment.as_str(),
+            next.as_str()
+        )));
+    }
+    match gateway.order_status(next) {
+        Some(status) if status != order && !order.can_transition_to(status) => Err(PaymentError::InvalidTransition(format!(
+            "A {} payment can't move the order from {} to {}",
+            next.as_str(),
+            order.as_str(),
+            status.as_str()
+        ))),
+        Some(status) if status != order => Ok(PaymentChange::Apply(Some(status))),
+        _ => Ok(PaymentChange::Apply(None)),
+    }
+}
+
+//The gateways customers can pick from at checkout
+pub struct PaymentGateways {
+    gateways: Vec<Box<dyn PaymentGateway>>,
+}
+
+impl PaymentGateways {
+    //Bank transfer and cash on delivery are always there, the mock card gateway only with APP_ENV=development
+    pub fn from_env() -> Self {
+        let mut gateways: Vec<Box<dyn PaymentGateway>> = vec![
+            Box::new(OfflineGateway::BankTransfer {
+                account_details: std::env::var("BACS_ACCOUNT_DETAILS").unwrap_or_default(),
+            }),
+            Box::new(OfflineGateway::CashOnDelivery),
+        ];
+        if std::env::var("APP_ENV").is_ok_and(|env| env == "development") {
+            gateways.push(Box::new(MockGateway::from_env()));
+        }
+        PaymentGateways { gateways }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&dyn PaymentGateway> {
+        self.gateways
+            .iter()
+            .find(|gateway| gateway.id() == id)
+            .map(|gateway| gateway.as_ref())
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &dyn PaymentGateway> {
+        self.gateways.iter().map(|gateway| gateway.as_ref())
+    }
+
+    //the payment methods as they are listed on the checkout page
+    pub fn options(&self) -> Vec<serde_json::Value> {
+        self.gateways
+            .iter()
+            .map(|gateway| {
+                json!({
+                    "id": gateway.id(),
+                    "title": gateway.title(),
+                    "description": gateway.description(),
+                    "needs_card": gateway.needs_card(),
+                })
+            })
+            .collect()
+    }
+}
+
+//Payments made outside the shop, the admin marks them as received on the order page
+pub enum OfflineGateway {
+    BankTransfer { account_details: String },
+    CashOnDelivery,
+}
+
+#[async_trait]
+impl PaymentGateway for OfflineGateway {
+    fn id(&self) -> &'static str {
+        match self {
+            OfflineGateway::BankTransfer { .. } => "bacs",
+            OfflineGateway::CashOnDelivery => "cod",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            OfflineGateway::BankTransfer { .. } => "Direct bank transfer",
+            OfflineGateway::CashOnDelivery => "Cash on delivery",
+        }
+    }
+
+    fn description(&self) -> String {
+        match self {
+            OfflineGateway::BankTransfer { .. } => "Make your payment directly into our bank account. Please use your order number as the payment reference. Your order will not be shipped until the funds have cleared in our account.".to_string(),
+            OfflineGateway::CashOnDelivery => "Pay with cash upon delivery.".to_string(),
+        }
+    }
+
+    fn instructions(&self) -> String {
+        match self {
+            OfflineGateway::BankTransfer { account_details } => account_details.clone(),
+            OfflineGateway::CashOnDelivery => "Pay with cash upon delivery.".to_string(),
+        }
+    }
+
+    async fn authorize(&self, order: &OrderModel, _details: &PaymentDetails) -> Result<PaymentResult, PaymentError> {
+        Ok(PaymentResult {
+            status: PaymentStatus::Pending,
+            transaction_id: format!("{}_{}", self.id(), order.number),
+            message: format!("Awaiting {}", self.title().to_lowercase()),
+            redirect: None,
+        })
+    }
+
+    async fn capture(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError> {
+        if payment.status != PaymentStatus::Pending {
+            return Err(PaymentError::InvalidRequest("Only pending payments can be marked as received".to_string()));
+        }
+        Ok(PaymentResult {
+            status: PaymentStatus::Captured,
+            transaction_id: payment.transaction_id.clone(),
+            message: "Payment received".to_string(),
+            redirect: None,
+        })
+    }
+
+    //the money has to be sent back by hand, this only records it
+    async fn refund(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError> {
+        if payment.status != PaymentStatus::Captured {
+            return Err(PaymentError::InvalidRequest("Only received payments can be refunded".to_string()));
+        }
+        Ok(PaymentResult {
+            status: PaymentStatus::Refunded,
+            transaction_id: payment.transaction_id.clone(),
+            message: "Refunded manually".to_string(),
+            redirect: None,
+        })
+    }
+
+    fn verify_webhook(&self, _headers: &HeaderMap, _body: &[u8]) -> Result<WebhookEvent, PaymentError> {
+        Err(PaymentError::Unsupported("webhooks"))
+    }
+
+    //bank transfers wait on-hold until the money arrives, cash on delivery orders can be shipped right away
+    fn order_status(&self, payment: PaymentStatus) -> Option<OrderStatus> {
+        match (self, payment) {
+            (OfflineGateway::BankTransfer { .. }, PaymentStatus::Pending) => Some(OrderStatus::OnHold),
+            (OfflineGateway::CashOnDelivery, PaymentStatus::Pending) => Some(OrderStatus::Processing),
+            (OfflineGateway::CashOnDelivery, PaymentStatus::Captured) => None,
+            (_, PaymentStatus::Captured) => Some(OrderStatus::Processing),
+            (_, PaymentStatus::Refunded) => Some(OrderStatus::Refunded),
+            _ => None,
+        }
+    }
+}
+
+//Test card numbers for the mock gateway, same as Stripe's
+pub const MOCK_CARD_SUCCESS: &str = "4242424242424242";
+pub const MOCK_CARD_AUTHORIZE_ONLY: &str = "4000000000000077";
+pub const MOCK_CARD_DECLINE: &str = "4000000000000002";
+pub const MOCK_CARD_3DS: &str = "4000000000003220";
+
+pub const MOCK_SIGNATURE_HEADER: &str = "x-mock-signature";
+
+//Card gateway that never leaves the server, the card number picks the outcome.
+//3DS payments are finished on the page at /checkout/mock-3ds/:transaction_id.
+//Webhooks go to POST /payments/webhook/mock with the hex HMAC-SHA256 of the body, keyed with
+//MOCK_GATEWAY_SECRET, in the x-mock-signature header
+pub struct MockGateway {
+    webhook_secret: String,
+}
+
+impl MockGateway {
+    //a random secret is used when MOCK_GATEWAY_SECRET isn't set, so only the 3DS page can finish payments
+    pub fn from_env() -> Self {
+        let webhook_secret = std::env::var("MOCK_GATEWAY_SECRET").unwrap_or_else(|_| Uuid::new_v4().to_string());
+        MockGateway { webhook_secret }
+    }
+
+    fn result(status: PaymentStatus, transaction_id: String, message: &str) -> PaymentResult {
+        PaymentResult {
+            status,
+            transaction_id,
+            message: message.to_string(),
+            redirect: None,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentGateway for MockGateway {
+    fn id(&self) -> &'static str {
+        "mock"
+    }
+
+    fn title(&self) -> &'static str {
+        "Test card"
+    }
+
+    fn description(&self) -> String {
+        format!(
+            "Development only, no money is taken. Use {} to pay, {} to only authorize, {} to be declined or {} for 3D Secure.",
+            MOCK_CARD_SUCCESS, MOCK_CARD_AUTHORIZE_ONLY, MOCK_CARD_DECLINE, MOCK_CARD_3DS
+        )
+    }
+
+    fn needs_card(&self) -> bool {
+        true
+    }
+
+    async fn authorize(&self, _order: &OrderModel, details: &PaymentDetails) -> Result<PaymentResult, PaymentError> {
+        let card_number: String = details.card_number.chars().filter(|c| c.is_ascii_digit()).collect();
+        let transaction_id = format!("mock_{}", Uuid::new_v4().simple());
+
+        Ok(match card_number.as_str() {
+            MOCK_CARD_SUCCESS => Self::result(PaymentStatus::Captured, transaction_id, "Payment captured"),
+            MOCK_CARD_AUTHORIZE_ONLY => Self::result(PaymentStatus::Authorized, transaction_id, "Payment authorized, capture it from the order page"),
+            MOCK_CARD_DECLINE => Self::result(PaymentStatus::Declined, transaction_id, "Your card was declined"),
+            MOCK_CARD_3DS => PaymentResult {
+                redirect: Some(format!("/checkout/mock-3ds/{}", transaction_id)),
+                ..Self::result(PaymentStatus::RequiresAction, transaction_id, "Waiting for 3D Secure authentication")
+            },
+            "" => return Err(PaymentError::InvalidRequest("Enter a card number".to_string())),
+            _ => Self::result(PaymentStatus::Declined, transaction_id, "Unknown test card"),
+        })
+    }
+
+    async fn capture(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError> {
+        if payment.status != PaymentStatus::Authorized {
+            return Err(PaymentError::InvalidRequest("Only authorized payments can be captured".to_string()));
+        }
+        Ok(Self::result(PaymentStatus::Captured, payment.transaction_id.clone(), "Payment captured"))
+    }
+
+    async fn refund(&self, payment: &PaymentModel) -> Result<PaymentResult, PaymentError> {
+        if payment.status != PaymentStatus::Captured {
+            return Err(PaymentError::InvalidRequest("Only captured payments can be refunded".to_string()));
+        }
+        Ok(Self::result(PaymentStatus::Refunded, payment.transaction_id.clone(), "Payment refunded"))
+    }
+
+    fn verify_webhook(&self, headers: &HeaderMap, body: &[u8]) -> Result<WebhookEvent, PaymentError> {
+        let signature = headers
+            .get(MOCK_SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| hex::decode(value).ok())
+            .ok_or(PaymentError::InvalidSignature)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.webhook_secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(body);
+        mac.verify_slice(&signature).map_err(|_| PaymentError::InvalidSignature)?;
+
+        serde_json::from_slice(body).map_err(|e| PaymentError::InvalidRequest(format!("Invalid webhook body: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+
+    fn mock() -> MockGateway {
+        MockGateway {
+            webhook_secret: SECRET.to_string(),
+        }
+    }
+
+    async fn pay_with(card_number: &str) -> Result<PaymentResult, PaymentError> {
+        let details = PaymentDetails {
+            card_number: card_number.to_string(),
+        };
+        mock().authorize(&OrderModel::default(), &details).await
+    }
+
+    fn signed(body: &[u8], secret: &str) -> HeaderMap {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let mut headers = HeaderMap::new();
+        headers.insert(MOCK_SIGNATURE_HEADER, hex::encode(mac.finalize().into_bytes()).parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn mock_card_captures() {
+        let result = pay_with(MOCK_CARD_SUCCESS).await.unwrap();
+        assert_eq!(result.status, PaymentStatus::Captured);
+        assert!(result.transaction_id.starts_with("mock_"));
+        assert_eq!(result.redirect, None);
+        //spaces typed in the card number are ignored
+        assert_eq!(pay_with("4242 4242 4242 4242").await.unwrap().status, PaymentStatus::Captured);
+    }
+
+    #[tokio::test]
+    async fn mock_card_authorizes_only() {
+        let result = pay_with(MOCK_CARD_AUTHORIZE_ONLY).await.unwrap();
+        assert_eq!(result.status, PaymentStatus::Authorized);
+        assert_eq!(result.redirect, None);
+    }
+
+    #[tokio::test]
+    async fn mock_card_declines() {
+        assert_eq!(pay_with(MOCK_CARD_DECLINE).await.unwrap().status, PaymentStatus::Declined);
+        assert_eq!(pay_with("4111111111111111").await.unwrap().status, PaymentStatus::Declined);
+        assert!(matches!(pay_with("").await, Err(PaymentError::InvalidRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn mock_card_waits_for_3ds() {
+        let result = pay_with(MOCK_CARD_3DS).await.unwrap();
+        assert_eq!(result.status, PaymentStatus::RequiresAction);
+        assert_eq!(result.redirect, Some(format!("/checkout/mock-3ds/{}", result.transaction_id)));
+    }
+
+    #[tokio::test]
+    async fn mock_captures_and_refunds_in_order() {
+        let gateway = mock();
+        let mut payment = PaymentModel {
+            status: PaymentStatus::Authorized,
+            transaction_id: "mock_1".to_string(),
+            ..Default::default()
+        };
+        assert!(gateway.refund(&payment).await.is_err());
+        let captured = gateway.capture(&payment).await.unwrap();
+        assert_eq!(captured.status, PaymentStatus::Captured);
+        assert_eq!(captured.transaction_id, "mock_1");
+
+        payment.status = PaymentStatus::Captured;
+        assert!(gateway.capture(&payment).await.is_err());
+        assert_eq!(gateway.refund(&payment).await.unwrap().status, PaymentStatus::Refunded);
+    }
+
+    #[test]
+    fn webhook_with_valid_signature() {
+        let body = br#"{"transaction_id":"mock_1","status":"captured","message":"paid"}"#;
+        let event = mock().verify_webhook(&signed(body, SECRET), body).unwrap();
+        assert_eq!(event.transaction_id, "mock_1");
+        assert_eq!(event.status, PaymentStatus::Captured);
+        assert_eq!(event.message, "paid");
+    }
+
+    #[test]
+    fn webhook_with_bad_signature() {
+        let body = br#"{"transaction_id":"mock_1","status":"captured"}"#;
+        let gateway = mock();
+        assert!(matches!(
+            gateway.verify_webhook(&signed(body, "another-secret"), body),
+            Err(PaymentError::InvalidSignature)
+        ));
+        //signed for another body
+        let tampered = br#"{"transaction_id":"mock_2","status":"captured"}"#;
+        assert!(matches!(
+            gateway.verify_webhook(&signed(body, SECRET), tampered),
+            Err(PaymentError::InvalidSignature)
+        ));
+        assert!(matches!(gateway.verify_webhook(&HeaderMap::new(), body), Err(PaymentError::InvalidSignature)));
+        let mut headers = HeaderMap::new();
+        headers.insert(MOCK_SIGNATURE_HEADER, "not hex".parse().unwrap());
+        assert!(matches!(gateway.verify_webhook(&headers, body), Err(PaymentError::InvalidSignature)));
+    }
+
+    #[test]
+    fn webhook_moves_payment_and_order() {
+        let gateway = mock();
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::RequiresAction, OrderStatus::Pending, PaymentStatus::Captured).unwrap(),
+            PaymentChange::Apply(Some(OrderStatus::Processing))
+        );
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::RequiresAction, OrderStatus::Pending, PaymentStatus::Declined).unwrap(),
+            PaymentChange::Apply(Some(OrderStatus::Failed))
+        );
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::Authorized, OrderStatus::OnHold, PaymentStatus::Captured).unwrap(),
+            PaymentChange::Apply(Some(OrderStatus::Processing))
+        );
+        //the order is already where the payment would move it
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::Pending, OrderStatus::Processing, PaymentStatus::Captured).unwrap(),
+            PaymentChange::Apply(None)
+        );
+    }
+
+    #[test]
+    fn replayed_webhook_changes_nothing() {
+        let gateway = mock();
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::Captured, OrderStatus::Processing, PaymentStatus::Captured).unwrap(),
+            PaymentChange::Replayed
+        );
+        assert_eq!(
+            payment_change(&gateway, PaymentStatus::Refunded, OrderStatus::Refunded, PaymentStatus::Refunded).unwrap(),
+            PaymentChange::Replayed
+        );
+    }
+
+    #[test]
+    fn out_of_order_webhook_is_rejected() {
+        let gateway = mock();
+        //a captured webhook arriving again after the refund
+        assert!(matches!(
+            payment_change(&gateway, PaymentStatus::Refunded, OrderStatus::Refunded, PaymentStatus::Captured),
+            Err(PaymentError::InvalidTransition(_))
+        ));
+        assert!(matches!(
+            payment_change(&gateway, PaymentStatus::Declined, OrderStatus::Failed, PaymentStatus::Captured),
+            Err(PaymentError::InvalidTransition(_))
+        ));
+        //the payment may move, the cancelled order may not
+        assert!(matches!(
+            payment_change(&gateway, PaymentStatus::RequiresAction, OrderStatus::Cancelled, PaymentStatus::Captured),
+            Err(PaymentError::InvalidTransition(_))
+        ));
+    }
+}
@@ -1,16 +1,47 @@
 use std::sync::Arc;
 
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{get, patch, post},
     Router,
 };
 use axum::routing::method_routing::get_service;
 use tower_http::services::ServeDir;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
 use crate::{
+    api,
+    auth::{require_admin, require_api_access, require_shop_manager},
+    csrf::csrf_protect,
+    error::{html_error_pages, not_found_page},
+    images::MAX_UPLOAD_BYTES,
     handler::{
-        create_product_template, health_checker_handler, multipart_create_product_handler, product_attributes_template, single_product_display, tera_product_handler, create_product_attribute_handler, product_terms_template, create_product_terms_handler, product_categories_template, create_product_category_handler
+        create_product_template, health_checker_handler, multipart_create_product_handler, product_attributes_template, single_product_display, tera_product_handler, create_product_attribute_handler, product_terms_template, create_product_terms_handler, product_categories_template, create_product_category_handler,
+        all_products_template, bulk_product_handler, edit_product_template, update_product_handler, delete_product_handler,
+        cart_template, add_to_cart_form_handler, update_cart_item_form_handler, remove_cart_item_form_handler,
+        get_cart_handler, add_cart_item_handler, update_cart_item_handler, delete_cart_item_handler,
+        checkout_template, checkout_handler, order_received_template, orders_template, order_template, update_order_status_handler,
+        pay_order_handler, mock_3ds_template, mock_3ds_handler, payment_webhook_handler, capture_payment_handler, refund_payment_handler,
+        register_template, register_handler, login_template, login_handler, logout_handler, account_template, update_address_handler,
+        forgot_password_template, forgot_password_handler, reset_password_template, reset_password_handler,
+        customers_template, update_customer_role_handler,
+        api_keys_template, create_api_key_handler, revoke_api_key_handler,
+        media_template, upload_media_handler, delete_media_handler, delete_unused_media_handler, scan_media_handler,
+        product_variations_template, update_product_attributes_handler, generate_variations_handler,
+        update_variation_handler, delete_variation_handler,
+        edit_term_template, update_attribute_handler, delete_attribute_handler, update_term_handler, delete_term_handler,
+        bulk_delete_terms_handler, reorder_terms_handler,
+        edit_category_template, update_category_handler, delete_category_handler, bulk_delete_categories_handler,
+        product_tags_template, create_product_tag_handler, edit_tag_template, update_tag_handler, delete_tag_handler,
+        bulk_delete_tags_handler, tag_search_handler, tag_products_template, category_products_template,
+        search_template, search_handler,
+        create_review_handler, reviews_template, bulk_reviews_handler, update_review_status_handler, reply_review_handler,
+        delete_review_handler
     },
+    openapi::ApiDoc,
+    session::session_cookie,
     AppState,
 };
 
@@ -19,16 +50,160 @@ pub fn create_router(app_state: Arc<AppState>) -> Router {
     let path = "/frontend";
     //let path = "/frontend/img";
     //let path = "/frontend/static";
-    Router::new()
+    //Pages for the browser, errors are rendered as pages/not_found.html instead of JSON
+    let pages = Router::new()
+        .route("/products", get(tera_product_handler))
+        .route("/product/:id", get(single_product_display))
+        .route("/product/:id/reviews", post(create_review_handler))
+        .route("/tag/:slug", get(tag_products_template))
+        .route("/category/:slug", get(category_products_template))
+        .route("/search", get(search_template))
+        .route("/cart", get(cart_template).post(add_to_cart_form_handler))
+        .route("/cart/items/:id", post(update_cart_item_form_handler))
+        .route("/cart/items/:id/remove", post(remove_cart_item_form_handler))
+        .route("/checkout", get(checkout_template).post(checkout_handler))
+        .route("/checkout/order-received/:id", get(order_received_template))
+        .route("/checkout/order-pay/:id", post(pay_order_handler))
+        .route("/checkout/mock-3ds/:transaction_id", get(mock_3ds_template).post(mock_3ds_handler))
+        .route("/register", get(register_template).post(register_handler))
+        .route("/login", get(login_template).post(login_handler))
+        .route("/logout", post(logout_handler))
+        .route("/account", get(account_template))
+        .route("/account/addresses/:kind", post(update_address_handler))
+        .route("/forgot-password", get(forgot_password_template).post(forgot_password_handler))
+        .route("/reset-password/:token", get(reset_password_template).post(reset_password_handler))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), html_error_pages));
+
+    //Admin pages, guests are sent to the login page and customers get a 403
+    let admin_pages = Router::new()
         .route("/multipart_create_product", get(create_product_template).post(multipart_create_product_handler))
         .route("/categories", get(product_categories_template).post(create_product_category_handler))
-        .route("/api/healthchecker", get(health_checker_handler))
-        .route("/products", get(tera_product_handler))
+        .route("/categories/delete", post(bulk_delete_categories_handler))
+        .route("/categories/:id", get(edit_category_template).post(update_category_handler))
+        .route("/categories/:id/delete", post(delete_category_handler))
+        .route("/tags", get(product_tags_template).post(create_product_tag_handler))
+        .route("/tags/delete", post(bulk_delete_tags_handler))
+        .route("/tags/:id", get(edit_tag_template).post(update_tag_handler))
+        .route("/tags/:id/delete", post(delete_tag_handler))
+        .route("/reviews", get(reviews_template).post(bulk_reviews_handler))
+        .route("/reviews/:id/status", post(update_review_status_handler))
+        .route("/reviews/:id/reply", post(reply_review_handler))
+        .route("/reviews/:id/delete", post(delete_review_handler))
         .route("/attributes", get(product_attributes_template).post(create_product_attribute_handler))
-        .route("/product/:id", get(single_product_display))
+        .route("/all_products", get(all_products_template).post(bulk_product_handler))
+        .route("/product/:id/edit", get(edit_product_template).post(update_product_handler))
+        .route("/product/:id/variations", get(product_variations_template))
+        .route("/product/:id/attributes", post(update_product_attributes_handler))
+        .route("/product/:id/variations/generate", post(generate_variations_handler))
+        .route("/product/:id/variations/:variation_id", post(update_variation_handler))
+        .route("/product/:id/variations/:variation_id/delete", post(delete_variation_handler))
         .route("/product-attribute/:id", get(product_terms_template).post(create_product_terms_handler))
+        .route("/attributes/:id", post(update_attribute_handler))
+        .route("/attributes/:id/delete", post(delete_attribute_handler))
+        .route("/product-attribute/:id/terms/delete", post(bulk_delete_terms_handler))
+        .route("/product-attribute/:id/terms/:term_id", get(edit_term_template).post(update_term_handler))
+        .route("/product-attribute/:id/terms/:term_id/delete", post(delete_term_handler))
+        .route("/orders", get(orders_template))
+        .route("/orders/:id", get(order_template))
+        .route("/orders/:id/status", post(update_order_status_handler))
+        .route("/orders/:id/payments/:payment_id/capture", post(capture_payment_handler))
+        .route("/orders/:id/payments/:payment_id/refund", post(refund_payment_handler))
+        .route("/api-keys", get(api_keys_template).post(create_api_key_handler))
+        .route("/api-keys/:id/revoke", post(revoke_api_key_handler))
+        .route("/media", get(media_template).post(upload_media_handler))
+        .route("/media/delete-unused", post(delete_unused_media_handler))
+        .route("/media/scan", post(scan_media_handler))
+        .route("/media/:id/delete", post(delete_media_handler))
+        //the product, category, variation and media library forms upload images
+        .route_layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), require_shop_manager))
+        .merge(
+            Router::new()
+                .route("/customers", get(customers_template))
+                .route("/customers/:id/role", post(update_customer_role_handler))
+                .route_layer(middleware::from_fn_with_state(app_state.clone(), require_admin)),
+        )
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), html_error_pages));
+
+    //Admin routes the pages call from scripts, errors stay JSON
+    let admin = Router::new()
+        .route("/tags/search", get(tag_search_handler))
+        .route("/product/:id", patch(update_product_handler).delete(delete_product_handler))
+        .route("/product-attribute/:id/terms/order", post(reorder_terms_handler))
+        //uploads are only read once the shop manager is known
+        .route_layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), require_shop_manager));
+
+    //The REST API for shop managers and their API keys, errors stay JSON
+    let api = Router::new()
+        .route("/api/v1/products", get(api::list_products_handler).post(api::create_product_handler))
+        .route(
+            "/api/v1/products/:id",
+            get(api::get_product_handler)
+                .put(api::update_product_handler)
+                .patch(api::update_product_handler)
+                .delete(api::delete_product_handler),
+        )
+        .route("/api/v1/products/categories", get(api::list_categories_handler).post(api::create_category_handler))
+        .route(
+            "/api/v1/products/categories/:id",
+            get(api::get_category_handler)
+                .put(api::update_category_handler)
+                .patch(api::update_category_handler)
+                .delete(api::delete_category_handler),
+        )
+        .route("/api/v1/products/attributes", get(api::list_attributes_handler).post(api::create_attribute_handler))
+        .route(
+            "/api/v1/products/attributes/:id",
+            get(api::get_attribute_handler)
+                .put(api::update_attribute_handler)
+                .patch(api::update_attribute_handler)
+                .delete(api::delete_attribute_handler),
+        )
+        .route("/api/v1/products/attributes/:id/terms", get(api::list_terms_handler).post(api::create_term_handler))
+        .route(
+            "/api/v1/products/attributes/:id/terms/:term_id",
+            get(api::get_term_handler)
+                .put(api::update_term_handler)
+                .patch(api::update_term_handler)
+                .delete(api::delete_term_handler),
+        )
+        .route("/api/v1/products/tags", get(api::list_tags_handler).post(api::create_tag_handler))
+        .route(
+            "/api/v1/products/tags/:id",
+            get(api::get_tag_handler)
+                .put(api::update_tag_handler)
+                .patch(api::update_tag_handler)
+                .delete(api::delete_tag_handler),
+        )
+        .route("/api/v1/orders", get(api::list_orders_handler).post(api::create_order_handler))
+        .route(
+            "/api/v1/orders/:id",
+            get(api::get_order_handler)
+                .put(api::update_order_handler)
+                .patch(api::update_order_handler)
+                .delete(api::delete_order_handler),
+        )
+        .route_layer(middleware::from_fn_with_state(app_state.clone(), require_api_access));
+
+    Router::new()
+        .merge(pages)
+        .merge(admin_pages)
+        .merge(admin)
+        .merge(api)
+        //the OpenAPI document of the JSON API and Swagger UI to try it out
+        .merge(SwaggerUi::new("/api/docs").url("/api/openapi.json", ApiDoc::openapi()))
+        .route("/api/healthchecker", get(health_checker_handler))
+        .route("/payments/webhook/:gateway", post(payment_webhook_handler))
+        .route("/api/search", get(search_handler))
+        .route("/api/cart", get(get_cart_handler))
+        .route("/api/cart/items", post(add_cart_item_handler))
+        .route("/api/cart/items/:id", patch(update_cart_item_handler).delete(delete_cart_item_handler))
         //.route("/:id", get(single_product_display))
         //.nest_service(path, get_service(ServeDir::new("./frontend/")).handle_error(|_| async { (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Error") }))
         .nest_service(path, get_service(ServeDir::new("./frontend/")).handle_error(|_| async { (axum::http::StatusCode::INTERNAL_SERVER_ERROR, "Error") }))
+        .fallback(not_found_page)
+        .layer(middleware::from_fn_with_state(app_state.clone(), csrf_protect))
+        .layer(middleware::from_fn(session_cookie))
         .with_state(app_state)
 }
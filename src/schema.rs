@@ -1,45 +1,326 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::model::BackorderStatus;
+
+//page, page size and sort order of the product listings
 #[derive(Deserialize, Debug, Default)]
 pub struct FilterOptions {
     pub page: Option<usize>,
     pub limit: Option<usize>,
+    pub orderby: Option<String>,
 }
 
-#[derive(Deserialize, Debug)]
-pub struct ParamOptions {
-    pub id: String,
+pub const PRODUCTS_PER_PAGE: usize = 12;
+pub const MAX_PRODUCTS_PER_PAGE: usize = 100;
+
+//rows before the page, a page far past the end stays a valid OFFSET instead of overflowing
+pub fn page_offset(page: usize, limit: usize) -> usize {
+    page.saturating_sub(1).saturating_mul(limit).min(i64::MAX as usize)
 }
 
-//stores attributes for products
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ProductAttributes {
-    pub name: String,
-    pub slug: String,
-    //pub terms: Vec<String>,
-    //pub terms: Vec<[String; 3]>,
-    pub order_by: String,
+impl FilterOptions {
+    //pages start at 1, page=0 is the first page too
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(PRODUCTS_PER_PAGE).clamp(1, MAX_PRODUCTS_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> usize {
+        page_offset(self.page(), self.limit())
+    }
+
+    //an unknown orderby falls back to the newest products first
+    pub fn sort(&self) -> ProductSort {
+        self.orderby.as_deref().and_then(|orderby| orderby.parse().ok()).unwrap_or_default()
+    }
+
+    //orderby and limit when they aren't the defaults, kept by the page links and filters
+    pub fn params(&self) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        if self.sort() != ProductSort::default() {
+            params.push(("orderby".to_string(), self.sort().as_str().to_string()));
+        }
+        if self.limit() != PRODUCTS_PER_PAGE {
+            params.push(("limit".to_string(), self.limit().to_string()));
+        }
+        params
+    }
 }
 
-//stores categories for products
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ProductCategories {
-    pub parent: String,
-    pub name: String,
-    pub slug: String,
-    //pub terms: Vec<String>,
-    //pub terms: Vec<[String; 3]>,
-    pub order_by: String,
-    pub description: String,
-    pub display_type: String,
-    pub thumbnail: String,
+//sort orders of the product listings, the orderby parameter takes the same values as WooCommerce
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProductSort {
+    #[default]
+    Date,
+    Popularity,
+    Rating,
+    Price,
+    PriceDesc,
+    Title,
+}
+
+impl ProductSort {
+    pub const ALL: [ProductSort; 6] = [
+        ProductSort::Date,
+        ProductSort::Popularity,
+        ProductSort::Rating,
+        ProductSort::Price,
+        ProductSort::PriceDesc,
+        ProductSort::Title,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProductSort::Date => "date",
+            ProductSort::Popularity => "popularity",
+            ProductSort::Rating => "rating",
+            ProductSort::Price => "price",
+            ProductSort::PriceDesc => "price-desc",
+            ProductSort::Title => "title",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ProductSort::Date => "Sort by latest",
+            ProductSort::Popularity => "Sort by popularity",
+            ProductSort::Rating => "Sort by average rating",
+            ProductSort::Price => "Sort by price: low to high",
+            ProductSort::PriceDesc => "Sort by price: high to low",
+            ProductSort::Title => "Sort by name",
+        }
+    }
+}
+
+impl std::str::FromStr for ProductSort {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProductSort::ALL
+            .into_iter()
+            .find(|sort| sort.as_str() == s)
+            .ok_or_else(|| format!("{} is not a sort order", s))
+    }
+}
+
+//"?name=value&..." or "" without parameters
+pub fn query_string(params: &[(String, String)]) -> String {
+    let params: Vec<String> = params
+        .iter()
+        //the commas between term slugs are kept readable
+        .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value).replace("%2C", ",")))
+        .collect();
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+//a link in the page numbers under a listing, None is a gap between numbers
+#[derive(Serialize, Debug)]
+pub struct PageLink {
+    pub number: Option<usize>,
+    pub url: String,
+    pub current: bool,
+}
+
+//page links under a product listing, the first and last pages and two on each side of the current one
+#[derive(Serialize, Debug)]
+pub struct Pagination {
+    pub page: usize,
+    pub pages: usize,
+    pub total: i64,
+    //the range of products shown, "Showing 13–24 of 40 results"
+    pub first: usize,
+    pub last: usize,
+    pub prev_url: Option<String>,
+    pub next_url: Option<String>,
+    pub links: Vec<PageLink>,
+}
+
+impl Pagination {
+    //params are the listing's other parameters, path is where the links go
+    pub fn new(path: &str, params: &[(String, String)], opts: &FilterOptions, total: i64) -> Self {
+        let page = opts.page();
+        let limit = opts.limit();
+        let pages = (total.max(0) as usize).div_ceil(limit).max(1);
+        let url = |number: usize| {
+            let mut params = params.to_vec();
+            if number > 1 {
+                params.push(("page".to_string(), number.to_string()));
+            }
+            format!("{}{}", path, query_string(&params))
+        };
+
+        let mut links = Vec::new();
+        for number in 1..=pages {
+            if number == 1 || number == pages || number.abs_diff(page) <= 2 {
+                links.push(PageLink { number: Some(number), url: url(number), current: number == page });
+            } else if links.last().is_some_and(|link| link.number.is_some()) {
+                links.push(PageLink { number: None, url: String::new(), current: false });
+            }
+        }
+
+        let offset = opts.offset();
+        let shown = (total.max(0) as usize).saturating_sub(offset).min(limit);
+        Pagination {
+            page,
+            pages,
+            total,
+            first: if shown > 0 { offset + 1 } else { 0 },
+            last: if shown > 0 { offset + shown } else { 0 },
+            prev_url: (page > 1).then(|| url((page - 1).min(pages))),
+            next_url: (page < pages).then(|| url(page + 1)),
+            links,
+        }
+    }
+}
+
+//Layered navigation of the category pages, e.g. ?min_price=10&max_price=50&filter_color=red,blue&stock_status=instock.
+//A product matches an attribute when it has any of the picked terms, and it has to match every attribute
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryFilters {
+    pub min_price: Option<Decimal>,
+    pub max_price: Option<Decimal>,
+    pub in_stock: bool,
+    //attribute slug -> term slugs, from the filter_<attribute slug> parameters
+    pub terms: BTreeMap<String, Vec<String>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ProductTerms {
-    pub name: String,         // First term (NOT NULL)
-    pub slug: String,         // Second term (NOT NULL)
-    pub description: Option<String>, // Third term (can be NULL)
+fn parse_price_filter(errors: &mut FieldErrors, name: &'static str, value: Option<&String>) -> Option<Decimal> {
+    let value = value.map(|value| value.trim()).filter(|value| !value.is_empty())?;
+    match value.parse::<Decimal>() {
+        Ok(price) if price >= Decimal::ZERO => Some(price),
+        _ => {
+            errors.insert(name, "must be a price".to_string());
+            None
+        }
+    }
+}
+
+impl CategoryFilters {
+    //other parameters are left alone, so links with tracking parameters still work
+    pub fn from_query(params: &HashMap<String, String>) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let min_price = parse_price_filter(&mut errors, "min_price", params.get("min_price"));
+        let max_price = parse_price_filter(&mut errors, "max_price", params.get("max_price"));
+        if let (Some(min), Some(max)) = (min_price, max_price) {
+            if min > max {
+                errors.insert("max_price", "can't be less than the min price".to_string());
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut terms = BTreeMap::new();
+        for (name, value) in params {
+            let Some(attribute) = name.strip_prefix("filter_") else { continue };
+            let mut slugs: Vec<String> = value
+                .split(',')
+                .map(|slug| slug.trim().to_string())
+                .filter(|slug| !slug.is_empty())
+                .collect();
+            slugs.sort();
+            slugs.dedup();
+            if !slugs.is_empty() {
+                terms.insert(attribute.to_string(), slugs);
+            }
+        }
+
+        Ok(CategoryFilters {
+            min_price,
+            max_price,
+            in_stock: params.get("stock_status").is_some_and(|status| status == "instock"),
+            terms,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == CategoryFilters::default()
+    }
+
+    //name and value of every parameter, for links and the hidden fields of the price form
+    pub fn params(&self) -> Vec<(String, String)> {
+        let mut params = Vec::new();
+        for (attribute, slugs) in &self.terms {
+            params.push((format!("filter_{}", attribute), slugs.join(",")));
+        }
+        if let Some(min_price) = self.min_price {
+            params.push(("min_price".to_string(), min_price.to_string()));
+        }
+        if let Some(max_price) = self.max_price {
+            params.push(("max_price".to_string(), max_price.to_string()));
+        }
+        if self.in_stock {
+            params.push(("stock_status".to_string(), "instock".to_string()));
+        }
+        params
+    }
+
+    //the same filters with the term picked, or unpicked when it already was
+    pub fn toggle_term(&self, attribute: &str, term: &str) -> Self {
+        let mut filters = self.clone();
+        let slugs = filters.terms.entry(attribute.to_string()).or_default();
+        match slugs.iter().position(|slug| slug == term) {
+            Some(index) => {
+                slugs.remove(index);
+            }
+            None => {
+                slugs.push(term.to_string());
+                slugs.sort();
+            }
+        }
+        if slugs.is_empty() {
+            filters.terms.remove(attribute);
+        }
+        filters
+    }
+
+    pub fn without_price(&self) -> Self {
+        CategoryFilters { min_price: None, max_price: None, ..self.clone() }
+    }
+}
+
+//search page and the search box suggestions
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[serde(default)]
+pub struct SearchOptions {
+    pub q: String,
+}
+
+pub const MAX_SEARCH_WORDS: usize = 10;
+
+impl SearchOptions {
+    //the words of q as a to_tsquery that matches them all, the last one can be half typed
+    //so every word is a prefix. None when q has no words
+    pub fn tsquery(&self) -> Option<String> {
+        let words: Vec<String> = self
+            .q
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .take(MAX_SEARCH_WORDS)
+            .map(|word| format!("{}:*", word.to_lowercase()))
+            .collect();
+        (!words.is_empty()).then(|| words.join(" & "))
+    }
+}
+
+//autocomplete of the tags field on the product forms
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TagSearchOptions {
+    pub q: String,
 }
 
 //TODO add the following parameters
@@ -48,19 +329,23 @@ pub struct ProductTerms {
 pub struct CreateProductSchema {
     pub title: String,
     pub description: String,
-    pub category: String,
-    pub price: String,
+    //Uncategorized when empty
+    pub category_ids: Vec<Uuid>,
+    //tag names, tags that don't exist yet are created
+    pub tags: Vec<String>,
+    pub price: Decimal,
     pub sku: String,
     pub product_type: String,
-    pub stock: String,
-    pub allow_backorders: String,
-    pub low_stock_threshold: String,
-    pub shipping_weight: String,
-    pub product_gallery: String,
-    pub attributes: String,
-    pub variations: String,
-    //TODO break this up into length width and height
-    pub shipping_dimensions: String,
+    pub stock: i32,
+    pub allow_backorders: BackorderStatus,
+    pub low_stock_threshold: i32,
+    pub shipping_weight: Decimal,
+    //paths of the uploaded images in gallery order, the first one is featured
+    #[serde(default)]
+    pub images: Vec<String>,
+    pub shipping_length: Decimal,
+    pub shipping_width: Decimal,
+    pub shipping_height: Decimal,
     pub shipping_class: String,
     //this is optional
     pub tax_status: String,
@@ -68,3 +353,1268 @@ pub struct CreateProductSchema {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub published: Option<bool>,
 }
+
+//partial update for products, fields that are None are left unchanged
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UpdateProductSchema {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    //replaces every category of the product, Uncategorized when empty
+    pub category_ids: Option<Vec<Uuid>>,
+    //replaces every tag of the product
+    pub tags: Option<Vec<String>>,
+    pub price: Option<Decimal>,
+    pub sku: Option<String>,
+    pub product_type: Option<String>,
+    pub stock: Option<i32>,
+    pub allow_backorders: Option<BackorderStatus>,
+    pub low_stock_threshold: Option<i32>,
+    pub shipping_weight: Option<Decimal>,
+    //only sent by the edit form
+    pub images: Option<UpdateGallerySchema>,
+    pub shipping_length: Option<Decimal>,
+    pub shipping_width: Option<Decimal>,
+    pub shipping_height: Option<Decimal>,
+    pub shipping_class: Option<String>,
+    pub tax_status: Option<String>,
+    pub tax_class: Option<String>,
+    pub published: Option<bool>,
+}
+
+//validation messages keyed by the form field they belong to
+pub type FieldErrors = BTreeMap<&'static str, String>;
+
+//non-negative decimal with at most `scale` digits after the point, an empty value means zero
+fn parse_decimal(errors: &mut FieldErrors, name: &'static str, raw: &str, scale: u32, max: i64) -> Decimal {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return Decimal::ZERO;
+    }
+    match raw.parse::<Decimal>() {
+        Ok(value) if value.is_sign_negative() => {
+            errors.insert(name, "must not be negative".to_string());
+            Decimal::ZERO
+        }
+        Ok(value) if value.normalize().scale() > scale => {
+            errors.insert(name, format!("must have at most {} decimal places", scale));
+            Decimal::ZERO
+        }
+        Ok(value) if value >= Decimal::from(max) => {
+            errors.insert(name, format!("must be less than {}", max));
+            Decimal::ZERO
+        }
+        Ok(value) => value,
+        Err(_) => {
+            errors.insert(name, "must be a number".to_string());
+            Decimal::ZERO
+        }
+    }
+}
+
+//non-negative whole number, an empty value means zero
+fn parse_count(errors: &mut FieldErrors, name: &'static str, raw: &str) -> i32 {
+    let raw = raw.trim();
+    if raw.is_empty() {
+        return 0;
+    }
+    match raw.parse::<i32>() {
+        Ok(value) if value < 0 => {
+            errors.insert(name, "must not be negative".to_string());
+            0
+        }
+        Ok(value) => value,
+        Err(_) => {
+            errors.insert(name, "must be a whole number".to_string());
+            0
+        }
+    }
+}
+
+fn parse_backorders(errors: &mut FieldErrors, raw: &str) -> BackorderStatus {
+    raw.trim().parse().unwrap_or_else(|e| {
+        errors.insert("allow_backorders", e);
+        BackorderStatus::default()
+    })
+}
+
+//something@domain.tld without spaces, the only way to really check is to send an email
+fn is_email(email: &str) -> bool {
+    !email.contains(char::is_whitespace)
+        && email
+            .split_once('@')
+            .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.') && !domain.starts_with('.') && !domain.ends_with('.'))
+}
+
+//checkboxes send "on", selects send "true"/"false"
+fn parse_bool(raw: &str) -> bool {
+    matches!(raw.trim(), "true" | "on" | "yes" | "1")
+}
+
+//the checked categories of a product form, blank values are skipped
+fn parse_category_ids(errors: &mut FieldErrors, values: &[String]) -> Vec<Uuid> {
+    let mut category_ids: Vec<Uuid> = Vec::new();
+    for value in values.iter().map(|value| value.trim()).filter(|value| !value.is_empty()) {
+        match value.parse() {
+            Ok(category_id) if !category_ids.contains(&category_id) => category_ids.push(category_id),
+            Ok(_) => {}
+            Err(_) => {
+                errors.insert("category_ids", format!("{} isn't a category", value));
+            }
+        }
+    }
+    category_ids
+}
+
+//names and slugs of tags are VARCHAR(200)
+pub const MAX_TAG_LENGTH: usize = 200;
+
+//the slug of a tag typed on the product form, "Summer Sale!" becomes "summer-sale"
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.trim().to_lowercase().chars() {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').chars().take(MAX_TAG_LENGTH).collect()
+}
+
+//the comma separated tags of a product form, blank and repeated names are skipped
+fn parse_tag_names(errors: &mut FieldErrors, raw: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for name in raw.split(',').map(|name| name.trim()).filter(|name| !name.is_empty()) {
+        if name.chars().count() > MAX_TAG_LENGTH {
+            errors.insert("tags", format!("can't be longer than {} characters each", MAX_TAG_LENGTH));
+        } else if slugify(name).is_empty() {
+            errors.insert("tags", format!("{} needs at least one letter or number", name));
+        } else if !names.iter().any(|other| other.to_lowercase() == name.to_lowercase()) {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+impl CreateProductSchema {
+    //builds a product from submitted form fields, collecting every invalid field.
+    //category_ids is a repeated field so it's passed separately
+    pub fn from_fields(fields: &HashMap<String, String>, category_ids: &[String]) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let text = |name: &str| fields.get(name).cloned().unwrap_or_default();
+
+        let title = text("title").trim().to_string();
+        if title.is_empty() {
+            errors.insert("title", "is required".to_string());
+        }
+
+        let product = CreateProductSchema {
+            title,
+            description: text("description"),
+            category_ids: parse_category_ids(&mut errors, category_ids),
+            tags: parse_tag_names(&mut errors, &text("tags")),
+            price: parse_decimal(&mut errors, "price", &text("price"), 2, 10_000_000_000),
+            sku: text("sku"),
+            product_type: text("product_type"),
+            stock: parse_count(&mut errors, "stock", &text("stock")),
+            allow_backorders: parse_backorders(&mut errors, fields.get("allow_backorders").map_or("no", |s| s.as_str())),
+            low_stock_threshold: parse_count(&mut errors, "low_stock_threshold", &text("low_stock_threshold")),
+            shipping_weight: parse_decimal(&mut errors, "shipping_weight", &text("shipping_weight"), 3, 10_000_000),
+            images: Vec::new(),
+            shipping_length: parse_decimal(&mut errors, "shipping_length", &text("shipping_length"), 2, 100_000_000),
+            shipping_width: parse_decimal(&mut errors, "shipping_width", &text("shipping_width"), 2, 100_000_000),
+            shipping_height: parse_decimal(&mut errors, "shipping_height", &text("shipping_height"), 2, 100_000_000),
+            shipping_class: text("shipping_class"),
+            tax_status: text("tax_status"),
+            tax_class: text("tax_class"),
+            published: fields.get("published").map(|s| parse_bool(s)),
+        };
+
+        if errors.is_empty() {
+            Ok(product)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl UpdateProductSchema {
+    //same rules as CreateProductSchema but only for the fields that were submitted,
+    //category_ids is None when the form has no category_ids field at all
+    pub fn from_fields(fields: &HashMap<String, String>, category_ids: Option<&[String]>) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let text = |name: &str| fields.get(name).cloned();
+
+        let title = text("title").map(|t| t.trim().to_string());
+        if title.as_deref() == Some("") {
+            errors.insert("title", "must not be empty".to_string());
+        }
+
+        let product = UpdateProductSchema {
+            title,
+            description: text("description"),
+            category_ids: category_ids.map(|values| parse_category_ids(&mut errors, values)),
+            tags: text("tags").map(|v| parse_tag_names(&mut errors, &v)),
+            price: text("price").map(|v| parse_decimal(&mut errors, "price", &v, 2, 10_000_000_000)),
+            sku: text("sku"),
+            product_type: text("product_type"),
+            stock: text("stock").map(|v| parse_count(&mut errors, "stock", &v)),
+            allow_backorders: text("allow_backorders").map(|v| parse_backorders(&mut errors, &v)),
+            low_stock_threshold: text("low_stock_threshold").map(|v| parse_count(&mut errors, "low_stock_threshold", &v)),
+            shipping_weight: text("shipping_weight").map(|v| parse_decimal(&mut errors, "shipping_weight", &v, 3, 10_000_000)),
+            images: None,
+            shipping_length: text("shipping_length").map(|v| parse_decimal(&mut errors, "shipping_length", &v, 2, 100_000_000)),
+            shipping_width: text("shipping_width").map(|v| parse_decimal(&mut errors, "shipping_width", &v, 2, 100_000_000)),
+            shipping_height: text("shipping_height").map(|v| parse_decimal(&mut errors, "shipping_height", &v, 2, 100_000_000)),
+            shipping_class: text("shipping_class"),
+            tax_status: text("tax_status"),
+            tax_class: text("tax_class"),
+            published: text("published").map(|v| parse_bool(&v)),
+        };
+
+        if errors.is_empty() {
+            Ok(product)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+//alt_text of product images is VARCHAR(255)
+pub const MAX_ALT_TEXT_LENGTH: usize = 255;
+
+fn parse_image_id(errors: &mut FieldErrors, value: &str) -> Option<Uuid> {
+    let id = value.trim().parse().ok();
+    if id.is_none() {
+        errors.insert("images", format!("{} isn't an image", value));
+    }
+    id
+}
+
+//an image kept in the gallery on the edit form, with its alt text
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GalleryImageSchema {
+    pub id: Uuid,
+    pub alt_text: String,
+}
+
+//changes to a product's gallery from the edit form
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct UpdateGallerySchema {
+    //the images in their new order, images that aren't listed keep their place after them
+    pub order: Vec<GalleryImageSchema>,
+    pub featured: Option<Uuid>,
+    pub remove: Vec<Uuid>,
+    //paths of the new uploads, added at the end
+    pub uploads: Vec<String>,
+}
+
+impl UpdateGallerySchema {
+    //image_ids and image_alts are repeated fields, one of each per image in the order they are shown on the form
+    pub fn from_fields(
+        image_ids: &[String],
+        image_alts: &[String],
+        featured: Option<&str>,
+        remove: &[String],
+    ) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+
+        let remove: Vec<Uuid> = remove.iter().filter_map(|value| parse_image_id(&mut errors, value)).collect();
+        let featured = featured
+            .filter(|value| !value.is_empty())
+            .and_then(|value| parse_image_id(&mut errors, value));
+        let mut order = Vec::new();
+        for (i, value) in image_ids.iter().enumerate() {
+            let Some(id) = parse_image_id(&mut errors, value) else {
+                continue;
+            };
+            let alt_text = image_alts.get(i).map_or("", |alt| alt.trim()).to_string();
+            if alt_text.chars().count() > MAX_ALT_TEXT_LENGTH {
+                errors.insert("images", format!("Alt text can't be longer than {} characters", MAX_ALT_TEXT_LENGTH));
+            }
+            if !remove.contains(&id) {
+                order.push(GalleryImageSchema { id, alt_text });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(UpdateGallerySchema {
+                order,
+                featured,
+                remove,
+                uploads: Vec::new(),
+            })
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+//variation form on the product's variations page, the image is uploaded separately
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateVariationSchema {
+    pub sku: String,
+    pub price: Decimal,
+    pub stock: i32,
+    pub shipping_weight: Decimal,
+    pub enabled: bool,
+}
+
+impl UpdateVariationSchema {
+    //same limits as the product fields, an unchecked enabled box isn't sent at all
+    pub fn from_fields(fields: &HashMap<String, String>) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let text = |name: &str| fields.get(name).cloned().unwrap_or_default();
+
+        let variation = UpdateVariationSchema {
+            sku: text("sku").trim().to_string(),
+            price: parse_decimal(&mut errors, "price", &text("price"), 2, 10_000_000_000),
+            stock: parse_count(&mut errors, "stock", &text("stock")),
+            shipping_weight: parse_decimal(&mut errors, "shipping_weight", &text("shipping_weight"), 3, 10_000_000),
+            enabled: fields.get("enabled").is_some_and(|value| parse_bool(value)),
+        };
+
+        if errors.is_empty() {
+            Ok(variation)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn one() -> i32 {
+    1
+}
+
+//forms send an empty string when nothing was picked
+fn empty_as_none<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Uuid>, D::Error> {
+    match Option::<String>::deserialize(deserializer)?.as_deref().map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(id) => Uuid::parse_str(id).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+//adds a product to the cart, from the product page form or POST /api/cart/items
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct AddToCartSchema {
+    pub product_id: Uuid,
+    /// Required for products with variations, None otherwise.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub variation_id: Option<Uuid>,
+    #[serde(default = "one")]
+    pub quantity: i32,
+}
+
+//sets the quantity of a cart item, 0 removes it
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UpdateCartItemSchema {
+    pub quantity: i32,
+}
+
+//billing details from the checkout form
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct CheckoutSchema {
+    pub billing_name: String,
+    pub billing_email: String,
+    pub billing_phone: String,
+    pub billing_address: String,
+    pub billing_city: String,
+    pub billing_postcode: String,
+    pub billing_country: String,
+    pub customer_note: String,
+    //id of a PaymentGateway
+    pub payment_method: String,
+    //only for gateways that take cards, never shown again or stored
+    #[serde(skip_serializing)]
+    pub card_number: String,
+}
+
+impl CheckoutSchema {
+    //trims every field and collects the ones that are missing or invalid
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        for (name, value, required) in [
+            ("billing_name", &mut self.billing_name, true),
+            ("billing_email", &mut self.billing_email, true),
+            ("billing_phone", &mut self.billing_phone, false),
+            ("billing_address", &mut self.billing_address, true),
+            ("billing_city", &mut self.billing_city, true),
+            ("billing_postcode", &mut self.billing_postcode, false),
+            ("billing_country", &mut self.billing_country, true),
+            ("customer_note", &mut self.customer_note, false),
+        ] {
+            *value = value.trim().to_string();
+            if required && value.is_empty() {
+                errors.insert(name, "is required".to_string());
+            }
+        }
+
+        if !self.billing_email.is_empty() && !is_email(&self.billing_email) {
+            errors.insert("billing_email", "must be an email address".to_string());
+        }
+        errors
+    }
+}
+
+//admin order list at /orders
+#[derive(Deserialize, Debug, Default)]
+pub struct OrderFilterOptions {
+    pub status: Option<String>,
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+//media library at /media, unused=true lists the images nothing uses
+#[derive(Deserialize, Debug, Default)]
+pub struct MediaFilterOptions {
+    pub unused: Option<bool>,
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+//scan storage on the media library, after is the last key the previous batch read
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ScanMediaSchema {
+    pub after: String,
+}
+
+//pay for an order again after the payment failed, from the order received page
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct PayOrderSchema {
+    pub payment_method: String,
+    pub card_number: String,
+}
+
+//approve or fail button on the mock 3D Secure page
+#[derive(Deserialize, Debug)]
+pub struct Mock3dsSchema {
+    pub outcome: String,
+}
+
+//status change form on the admin order page
+#[derive(Deserialize, Debug)]
+pub struct OrderStatusSchema {
+    pub status: String,
+}
+
+//role change form on the admin customers page
+#[derive(Deserialize, Debug)]
+pub struct CustomerRoleSchema {
+    pub role: String,
+}
+
+//the new key form on the API keys page, permissions is parsed into ApiKeyPermissions by the handler
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ApiKeySchema {
+    pub description: String,
+    pub permissions: String,
+}
+
+pub const MAX_API_KEY_DESCRIPTION: usize = 200;
+
+impl ApiKeySchema {
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.description = self.description.trim().to_string();
+
+        if self.description.is_empty() {
+            errors.insert("description", "is required".to_string());
+        } else if self.description.chars().count() > MAX_API_KEY_DESCRIPTION {
+            errors.insert("description", format!("can't be longer than {} characters", MAX_API_KEY_DESCRIPTION));
+        }
+        errors
+    }
+}
+
+//add and edit forms of an attribute, order_by is parsed into a TermOrder by the handler
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AttributeSchema {
+    pub attribute_name: String,
+    pub attribute_slug: String,
+    pub order_by: String,
+}
+
+//the add attribute form says slugs can't be longer than this
+pub const MAX_ATTRIBUTE_SLUG_LENGTH: usize = 28;
+
+//slugs end up in urls, so they are lowercased and limited to letters, numbers and hyphens
+fn check_slug(errors: &mut FieldErrors, name: &'static str, slug: &mut String) {
+    *slug = slug.trim().to_lowercase();
+    if slug.is_empty() {
+        errors.insert(name, "is required".to_string());
+    } else if !slug.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        errors.insert(name, "can only contain letters, numbers and hyphens".to_string());
+    }
+}
+
+impl AttributeSchema {
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.attribute_name = self.attribute_name.trim().to_string();
+
+        if self.attribute_name.is_empty() {
+            errors.insert("attribute_name", "is required".to_string());
+        }
+        check_slug(&mut errors, "attribute_slug", &mut self.attribute_slug);
+        if self.attribute_slug.chars().count() > MAX_ATTRIBUTE_SLUG_LENGTH {
+            errors.insert("attribute_slug", format!("can't be longer than {} characters", MAX_ATTRIBUTE_SLUG_LENGTH));
+        }
+        errors
+    }
+}
+
+//add and edit forms of an attribute term
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TermSchema {
+    pub term_name: String,
+    pub term_slug: String,
+    pub description: String,
+}
+
+impl TermSchema {
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.term_name = self.term_name.trim().to_string();
+        self.description = self.description.trim().to_string();
+
+        if self.term_name.is_empty() {
+            errors.insert("term_name", "is required".to_string());
+        }
+        check_slug(&mut errors, "term_slug", &mut self.term_slug);
+        errors
+    }
+}
+
+//add and edit forms of a product category, the thumbnail is uploaded separately
+#[derive(Debug, Default)]
+pub struct CategorySchema {
+    pub name: String,
+    pub slug: String,
+    //None makes it a top level category
+    pub parent_id: Option<Uuid>,
+    pub description: String,
+    pub display_type: String,
+}
+
+pub const DISPLAY_TYPES: [&str; 4] = ["", "products", "subcategories", "both"];
+
+impl CategorySchema {
+    //parent is -1 for no parent, like the parent select of the forms sends
+    pub fn from_fields(fields: &HashMap<String, String>) -> Result<Self, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let text = |name: &str| fields.get(name).map(|value| value.trim().to_string()).unwrap_or_default();
+
+        let mut category = CategorySchema {
+            name: text("name"),
+            slug: text("slug"),
+            parent_id: None,
+            description: text("description"),
+            display_type: text("display_type"),
+        };
+
+        if category.name.is_empty() {
+            errors.insert("name", "is required".to_string());
+        }
+        check_slug(&mut errors, "slug", &mut category.slug);
+        match text("parent").as_str() {
+            "" | "-1" => {}
+            parent => match Uuid::parse_str(parent) {
+                Ok(parent_id) => category.parent_id = Some(parent_id),
+                Err(_) => {
+                    errors.insert("parent", "isn't a category".to_string());
+                }
+            },
+        }
+        if !DISPLAY_TYPES.contains(&category.display_type.as_str()) {
+            errors.insert("display_type", format!("must be one of {}", DISPLAY_TYPES[1..].join(", ")));
+        }
+
+        if errors.is_empty() {
+            Ok(category)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+//drag and drop order of the terms on the attribute's page, every term of the attribute first to last
+#[derive(Deserialize, Debug)]
+pub struct TermOrderSchema {
+    pub term_ids: Vec<Uuid>,
+}
+
+//add and edit forms of a product tag, an empty slug is made from the name
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct TagSchema {
+    pub name: String,
+    pub slug: String,
+    pub description: String,
+}
+
+impl TagSchema {
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.name = self.name.trim().to_string();
+        self.description = self.description.trim().to_string();
+
+        if self.name.is_empty() {
+            errors.insert("name", "is required".to_string());
+        } else if self.name.chars().count() > MAX_TAG_LENGTH {
+            errors.insert("name", format!("can't be longer than {} characters", MAX_TAG_LENGTH));
+        }
+        if self.slug.trim().is_empty() {
+            self.slug = slugify(&self.name);
+        }
+        check_slug(&mut errors, "slug", &mut self.slug);
+        if self.slug.chars().count() > MAX_TAG_LENGTH {
+            errors.insert("slug", format!("can't be longer than {} characters", MAX_TAG_LENGTH));
+        }
+        errors
+    }
+}
+
+//query string of the product page, review=posted after the review form was sent
+#[derive(Deserialize, Debug, Default)]
+pub struct ProductPageOptions {
+    pub review: Option<String>,
+}
+
+//review form on the product page, name and email are only asked from guests
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ReviewSchema {
+    pub rating: String,
+    pub content: String,
+    pub author_name: String,
+    pub author_email: String,
+}
+
+pub const MAX_REVIEW_LENGTH: usize = 5000;
+
+fn check_review_content(errors: &mut FieldErrors, content: &str) {
+    if content.is_empty() {
+        errors.insert("content", "is required".to_string());
+    } else if content.chars().count() > MAX_REVIEW_LENGTH {
+        errors.insert("content", format!("can't be longer than {} characters", MAX_REVIEW_LENGTH));
+    }
+}
+
+impl ReviewSchema {
+    //None when no rating was picked or it isn't 1 to 5 stars
+    pub fn stars(&self) -> Option<i32> {
+        self.rating.trim().parse().ok().filter(|rating| (1..=5).contains(rating))
+    }
+
+    pub fn validate(&mut self, guest: bool) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.content = self.content.trim().to_string();
+        self.author_name = self.author_name.trim().to_string();
+        self.author_email = self.author_email.trim().to_lowercase();
+
+        if self.stars().is_none() {
+            errors.insert("rating", "choose from 1 to 5 stars".to_string());
+        }
+        check_review_content(&mut errors, &self.content);
+        if guest {
+            if self.author_name.is_empty() {
+                errors.insert("author_name", "is required".to_string());
+            }
+            if self.author_email.is_empty() {
+                errors.insert("author_email", "is required".to_string());
+            } else if !is_email(&self.author_email) {
+                errors.insert("author_email", "must be an email address".to_string());
+            }
+        }
+        errors
+    }
+}
+
+//reply form of the reviews page
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ReviewReplySchema {
+    pub content: String,
+    pub filter: String, //status the reviews page was showing
+}
+
+impl ReviewReplySchema {
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.content = self.content.trim().to_string();
+        check_review_content(&mut errors, &self.content);
+        errors
+    }
+}
+
+//approve, unapprove, spam, trash and restore links of the reviews page
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ReviewStatusSchema {
+    pub status: String,
+    pub filter: String, //status the reviews page was showing
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ReviewFilterOptions {
+    pub status: Option<String>,
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+fn check_new_password(errors: &mut FieldErrors, password: &str, password_confirm: &str) {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        errors.insert("password", format!("must be at least {} characters", MIN_PASSWORD_LENGTH));
+    } else if password != password_confirm {
+        errors.insert("password_confirm", "doesn't match the password".to_string());
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct RegisterSchema {
+    pub email: String,
+    pub first_name: String,
+    pub last_name: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    #[serde(skip_serializing)]
+    pub password_confirm: String,
+}
+
+impl RegisterSchema {
+    //lowercases the email, trims the names and collects the invalid fields
+    pub fn validate(&mut self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        self.email = self.email.trim().to_lowercase();
+        self.first_name = self.first_name.trim().to_string();
+        self.last_name = self.last_name.trim().to_string();
+
+        if self.email.is_empty() {
+            errors.insert("email", "is required".to_string());
+        } else if !is_email(&self.email) {
+            errors.insert("email", "must be an email address".to_string());
+        }
+        check_new_password(&mut errors, &self.password, &self.password_confirm);
+        errors
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct LoginSchema {
+    pub email: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    //page to go back to after logging in
+    pub next: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct ForgotPasswordSchema {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ResetPasswordSchema {
+    pub password: String,
+    pub password_confirm: String,
+}
+
+impl ResetPasswordSchema {
+    pub fn validate(&self) -> FieldErrors {
+        let mut errors = FieldErrors::new();
+        check_new_password(&mut errors, &self.password, &self.password_confirm);
+        errors
+    }
+}
+
+//billing or shipping address form on the account page
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(default)]
+pub struct AddressSchema {
+    pub name: String,
+    pub phone: String,
+    pub address: String,
+    pub city: String,
+    pub postcode: String,
+    pub country: String,
+}
+
+impl AddressSchema {
+    pub fn trim(&mut self) {
+        for value in [
+            &mut self.name,
+            &mut self.phone,
+            &mut self.address,
+            &mut self.city,
+            &mut self.postcode,
+            &mut self.country,
+        ] {
+            *value = value.trim().to_string();
+        }
+    }
+}
+
+//page size of the /api/v1 lists when per_page isn't given
+pub const API_PER_PAGE: usize = 10;
+
+//query string of the /api/v1 lists, named like the WooCommerce REST API parameters
+#[derive(Deserialize, Debug, Default, IntoParams)]
+pub struct ApiListOptions {
+    /// Page of the list, from 1.
+    pub page: Option<usize>,
+    /// Items a page, 10 by default and at most 100.
+    pub per_page: Option<usize>,
+    /// Products by name or sku, orders by billing name, email or number, the others by name, slug or description.
+    pub search: Option<String>,
+    /// Products: date, title, price, popularity or rating. Orders: date or id. Categories, terms and tags: name, slug or count.
+    pub orderby: Option<String>,
+    /// `asc` or `desc`.
+    pub order: Option<String>,
+    /// Products: any, publish or draft. Orders: any or an order status.
+    pub status: Option<String>,
+    /// Products in the category.
+    pub category: Option<Uuid>,
+    /// Products with the tag.
+    pub tag: Option<Uuid>,
+    /// Products with exactly this sku.
+    pub sku: Option<String>,
+    /// Categories under this one, 0 for the top level.
+    pub parent: Option<String>,
+    /// Leaves out categories, terms and tags without products.
+    pub hide_empty: Option<bool>,
+    /// Orders of the customer.
+    pub customer: Option<Uuid>,
+}
+
+impl ApiListOptions {
+    pub fn page(&self) -> usize {
+        self.page.unwrap_or(1).max(1)
+    }
+
+    pub fn per_page(&self) -> usize {
+        self.per_page.unwrap_or(API_PER_PAGE).clamp(1, MAX_PRODUCTS_PER_PAGE)
+    }
+
+    pub fn offset(&self) -> usize {
+        page_offset(self.page(), self.per_page())
+    }
+
+    //the same page as FilterOptions, for building page links
+    pub fn filter_options(&self) -> FilterOptions {
+        FilterOptions {
+            page: Some(self.page()),
+            limit: Some(self.per_page()),
+            orderby: None,
+        }
+    }
+
+    pub fn ascending(&self, default: bool) -> Result<bool, String> {
+        match self.order.as_deref() {
+            None | Some("") => Ok(default),
+            Some("asc") => Ok(true),
+            Some("desc") => Ok(false),
+            Some(order) => Err(format!("order must be asc or desc, got \"{}\"", order)),
+        }
+    }
+
+    //search as an ILIKE pattern that matches it anywhere, None when it's blank
+    pub fn search_pattern(&self) -> Option<String> {
+        let search = self.search.as_deref().map(str::trim).filter(|search| !search.is_empty())?;
+        //% and _ are matched literally
+        Some(format!("%{}%", search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")))
+    }
+}
+
+//WooCommerce clients send numbers either as JSON numbers or as strings, both are kept as the text a form would send
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    match Option::<serde_json::Value>::deserialize(deserializer)? {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(value)) => Ok(Some(value)),
+        Some(serde_json::Value::Number(value)) => Ok(Some(value.to_string())),
+        Some(value) => Err(serde::de::Error::custom(format!("expected a string or a number, got {}", value))),
+    }
+}
+
+//a category or tag of a product sent to /api/v1/products, by id or, for tags, by name
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiTermRef {
+    pub id: Option<Uuid>,
+    pub name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiDimensions {
+    #[serde(deserialize_with = "string_or_number")]
+    pub length: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub width: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub height: Option<String>,
+}
+
+//a product sent to POST or PUT /api/v1/products, fields that aren't sent are left as they are
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiProductSchema {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub sku: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub regular_price: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub stock_quantity: Option<String>,
+    pub backorders: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub low_stock_amount: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub weight: Option<String>,
+    pub dimensions: Option<ApiDimensions>,
+    pub shipping_class: Option<String>,
+    #[serde(deserialize_with = "string_or_number")]
+    pub tax_status: Option<String>,
+    pub tax_class: Option<String>,
+    /// `publish`, or `draft`, `pending` and `private` which are all unpublished.
+    pub status: Option<String>,
+    /// Digital products.
+    #[serde(rename = "virtual")]
+    pub is_virtual: Option<bool>,
+    /// Categories by id.
+    pub categories: Option<Vec<ApiTermRef>>,
+    /// Tags by id or by name, new names are created.
+    pub tags: Option<Vec<ApiTermRef>>,
+}
+
+//the product form field of each API field, to name the API field in validation errors
+pub const API_PRODUCT_FIELDS: [(&str, &str); 18] = [
+    ("title", "name"),
+    ("description", "description"),
+    ("price", "regular_price"),
+    ("sku", "sku"),
+    ("product_type", "virtual"),
+    ("stock", "stock_quantity"),
+    ("allow_backorders", "backorders"),
+    ("low_stock_threshold", "low_stock_amount"),
+    ("shipping_weight", "weight"),
+    ("shipping_length", "dimensions.length"),
+    ("shipping_width", "dimensions.width"),
+    ("shipping_height", "dimensions.height"),
+    ("shipping_class", "shipping_class"),
+    ("tax_status", "tax_status"),
+    ("tax_class", "tax_class"),
+    ("published", "status"),
+    ("category_ids", "categories"),
+    ("tags", "tags"),
+];
+
+//renames the fields of validation errors, names are (from, to) pairs
+pub fn rename_errors(errors: FieldErrors, names: &[(&'static str, &'static str)]) -> FieldErrors {
+    errors
+        .into_iter()
+        .map(|(name, message)| {
+            let name = names.iter().find(|(from, _)| *from == name).map_or(name, |(_, to)| *to);
+            (name, message)
+        })
+        .collect()
+}
+
+//product form fields by name and the category ids, when categories was sent
+pub type ProductFields = (HashMap<String, String>, Option<Vec<String>>);
+
+impl ApiProductSchema {
+    //the product form fields that were sent, for CreateProductSchema::from_fields and UpdateProductSchema::from_fields,
+    //and the category ids when categories was sent. Tags can be sent by id so they are looked up by the handler
+    pub fn fields(&self) -> Result<ProductFields, FieldErrors> {
+        let mut errors = FieldErrors::new();
+        let mut fields = HashMap::new();
+        let dimensions = self.dimensions.as_ref();
+
+        for (name, value) in [
+            ("title", self.name.as_ref()),
+            ("description", self.description.as_ref()),
+            ("price", self.regular_price.as_ref()),
+            ("sku", self.sku.as_ref()),
+            ("stock", self.stock_quantity.as_ref()),
+            ("allow_backorders", self.backorders.as_ref()),
+            ("low_stock_threshold", self.low_stock_amount.as_ref()),
+            ("shipping_weight", self.weight.as_ref()),
+            ("shipping_length", dimensions.and_then(|dimensions| dimensions.length.as_ref())),
+            ("shipping_width", dimensions.and_then(|dimensions| dimensions.width.as_ref())),
+            ("shipping_height", dimensions.and_then(|dimensions| dimensions.height.as_ref())),
+            ("shipping_class", self.shipping_class.as_ref()),
+            ("tax_status", self.tax_status.as_ref()),
+            ("tax_class", self.tax_class.as_ref()),
+        ] {
+            if let Some(value) = value {
+                fields.insert(name.to_string(), value.clone());
+            }
+        }
+        if let Some(is_virtual) = self.is_virtual {
+            let product_type = if is_virtual { "digital" } else { "physical" };
+            fields.insert("product_type".to_string(), product_type.to_string());
+        }
+        match self.status.as_deref() {
+            None => {}
+            Some("publish") => {
+                fields.insert("published".to_string(), "true".to_string());
+            }
+            Some("draft" | "pending" | "private") => {
+                fields.insert("published".to_string(), "false".to_string());
+            }
+            Some(_) => {
+                errors.insert("status", "must be one of publish, draft, pending or private".to_string());
+            }
+        }
+
+        let category_ids = self.categories.as_ref().map(|categories| {
+            categories
+                .iter()
+                .filter_map(|category| {
+                    if category.id.is_none() {
+                        errors.insert("categories", "need an id each".to_string());
+                    }
+                    category.id.map(|id| id.to_string())
+                })
+                .collect()
+        });
+
+        if errors.is_empty() {
+            Ok((fields, category_ids))
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+//a category, attribute, term or tag sent to POST or PUT /api/v1, fields that aren't sent are left as they are
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiTermSchema {
+    pub name: Option<String>,
+    /// Made from the name when a new one is added without it.
+    pub slug: Option<String>,
+    pub description: Option<String>,
+    /// Categories: the parent's id, or 0 for a top level category.
+    #[serde(deserialize_with = "string_or_number")]
+    pub parent: Option<String>,
+    /// Categories: `default`, `products`, `subcategories` or `both`.
+    pub display: Option<String>,
+    /// Attributes: how their terms are ordered.
+    pub order_by: Option<String>,
+}
+
+//billing address of an order sent to /api/v1/orders
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiAddress {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub address_1: Option<String>,
+    pub address_2: Option<String>,
+    pub city: Option<String>,
+    pub postcode: Option<String>,
+    pub country: Option<String>,
+}
+
+//a line of an order sent to POST /api/v1/orders
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct ApiLineItem {
+    pub product_id: Uuid,
+    /// Required for products with variations.
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub variation_id: Option<Uuid>,
+    #[serde(default = "one")]
+    pub quantity: i32,
+}
+
+//an order sent to POST or PUT /api/v1/orders, line items can only be sent when it is created
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(default)]
+pub struct ApiOrderSchema {
+    /// New orders start as pending and are then moved to it.
+    pub status: Option<String>,
+    pub customer_id: Option<Uuid>,
+    pub customer_note: Option<String>,
+    pub payment_method: Option<String>,
+    pub billing: Option<ApiAddress>,
+    /// Only when the order is created.
+    pub line_items: Vec<ApiLineItem>,
+}
+
+//the checkout field of each API field, to name the API field in validation errors
+pub const API_ORDER_FIELDS: [(&str, &str); 8] = [
+    ("billing_name", "billing.first_name"),
+    ("billing_email", "billing.email"),
+    ("billing_phone", "billing.phone"),
+    ("billing_address", "billing.address_1"),
+    ("billing_city", "billing.city"),
+    ("billing_postcode", "billing.postcode"),
+    ("billing_country", "billing.country"),
+    ("customer_note", "customer_note"),
+];
+
+impl ApiOrderSchema {
+    //puts the fields that were sent over the billing details of checkout, address_2 goes on the address line
+    pub fn apply_to(&self, checkout: &mut CheckoutSchema) {
+        if let Some(billing) = &self.billing {
+            if billing.first_name.is_some() || billing.last_name.is_some() {
+                let (first_name, last_name) = split_name(&checkout.billing_name);
+                let first_name = billing.first_name.as_deref().unwrap_or(first_name);
+                let last_name = billing.last_name.as_deref().unwrap_or(last_name);
+                checkout.billing_name = format!("{} {}", first_name.trim(), last_name.trim());
+            }
+            if billing.address_1.is_some() || billing.address_2.is_some() {
+                let address_1 = billing.address_1.clone().unwrap_or_else(|| checkout.billing_address.clone());
+                checkout.billing_address = match billing.address_2.as_deref().map(str::trim) {
+                    Some(address_2) if !address_2.is_empty() => format!("{}, {}", address_1.trim(), address_2),
+                    _ => address_1,
+                };
+            }
+            for (value, sent) in [
+                (&mut checkout.billing_email, &billing.email),
+                (&mut checkout.billing_phone, &billing.phone),
+                (&mut checkout.billing_city, &billing.city),
+                (&mut checkout.billing_postcode, &billing.postcode),
+                (&mut checkout.billing_country, &billing.country),
+            ] {
+                if let Some(sent) = sent {
+                    *value = sent.clone();
+                }
+            }
+        }
+        if let Some(customer_note) = &self.customer_note {
+            checkout.customer_note = customer_note.clone();
+        }
+        if let Some(payment_method) = &self.payment_method {
+            checkout.payment_method = payment_method.clone();
+        }
+    }
+}
+
+//"Ada Lovelace" is first name Ada and last name Lovelace, orders only keep the whole name
+pub fn split_name(name: &str) -> (&str, &str) {
+    name.trim().split_once(' ').map_or((name.trim(), ""), |(first, last)| (first, last.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(page: Option<usize>, limit: Option<usize>) -> FilterOptions {
+        FilterOptions { page, limit, orderby: None }
+    }
+
+    #[test]
+    fn page_starts_at_one() {
+        assert_eq!(opts(None, None).page(), 1);
+        assert_eq!(opts(Some(0), None).page(), 1);
+        assert_eq!(opts(Some(3), None).page(), 3);
+    }
+
+    #[test]
+    fn limit_is_clamped() {
+        assert_eq!(opts(None, None).limit(), PRODUCTS_PER_PAGE);
+        assert_eq!(opts(None, Some(0)).limit(), 1);
+        assert_eq!(opts(None, Some(30)).limit(), 30);
+        assert_eq!(opts(None, Some(10_000)).limit(), MAX_PRODUCTS_PER_PAGE);
+    }
+
+    #[test]
+    fn offset_skips_the_earlier_pages() {
+        assert_eq!(opts(None, None).offset(), 0);
+        assert_eq!(opts(Some(1), Some(20)).offset(), 0);
+        assert_eq!(opts(Some(3), Some(20)).offset(), 40);
+    }
+
+    #[test]
+    fn offset_of_a_huge_page_fits_in_i64() {
+        assert_eq!(opts(Some(usize::MAX), Some(MAX_PRODUCTS_PER_PAGE)).offset(), i64::MAX as usize);
+        assert_eq!(page_offset(usize::MAX, usize::MAX), i64::MAX as usize);
+        assert_eq!(page_offset(0, 20), 0);
+    }
+
+    #[test]
+    fn pagination_of_a_middle_page() {
+        let pagination = Pagination::new("/products", &[], &opts(Some(5), Some(10)), 95);
+        assert_eq!((pagination.page, pagination.pages, pagination.first, pagination.last), (5, 10, 41, 50));
+        assert_eq!(pagination.prev_url.as_deref(), Some("/products?page=4"));
+        assert_eq!(pagination.next_url.as_deref(), Some("/products?page=6"));
+        let numbers: Vec<Option<usize>> = pagination.links.iter().map(|link| link.number).collect();
+        assert_eq!(numbers, [Some(1), None, Some(3), Some(4), Some(5), Some(6), Some(7), None, Some(10)]);
+        assert!(pagination.links.iter().filter(|link| link.current).all(|link| link.number == Some(5)));
+    }
+
+    #[test]
+    fn pagination_keeps_the_other_params() {
+        let params = [("orderby".to_string(), "price".to_string())];
+        let pagination = Pagination::new("/products", &params, &opts(Some(2), Some(10)), 25);
+        assert_eq!(pagination.prev_url.as_deref(), Some("/products?orderby=price"));
+        assert_eq!(pagination.next_url.as_deref(), Some("/products?orderby=price&page=3"));
+        assert_eq!((pagination.first, pagination.last), (11, 20));
+    }
+
+    #[test]
+    fn pagination_of_an_empty_listing() {
+        let pagination = Pagination::new("/products", &[], &opts(None, None), 0);
+        assert_eq!((pagination.pages, pagination.first, pagination.last), (1, 0, 0));
+        assert!(pagination.prev_url.is_none() && pagination.next_url.is_none());
+    }
+
+    #[test]
+    fn pagination_past_the_end() {
+        let pagination = Pagination::new("/products", &[], &opts(Some(usize::MAX), Some(10)), 25);
+        assert_eq!((pagination.pages, pagination.first, pagination.last), (3, 0, 0));
+        assert_eq!(pagination.prev_url.as_deref(), Some("/products?page=3"));
+        assert!(pagination.next_url.is_none());
+    }
+
+    fn tsquery(q: &str) -> Option<String> {
+        SearchOptions { q: q.to_string() }.tsquery()
+    }
+
+    #[test]
+    fn tsquery_prefixes_every_word() {
+        assert_eq!(tsquery("Wool").as_deref(), Some("wool:*"));
+        assert_eq!(tsquery("  red   wool sca").as_deref(), Some("red:* & wool:* & sca:*"));
+        assert_eq!(tsquery("Crème brûlée").as_deref(), Some("crème:* & brûlée:*"));
+    }
+
+    #[test]
+    fn tsquery_drops_punctuation() {
+        //none of the to_tsquery operators get through
+        assert_eq!(tsquery("red & !wool | (scarf):*").as_deref(), Some("red:* & wool:* & scarf:*"));
+        assert_eq!(tsquery("t-shirt's").as_deref(), Some("t:* & shirt:* & s:*"));
+        assert_eq!(tsquery("c++").as_deref(), Some("c:*"));
+    }
+
+    #[test]
+    fn tsquery_of_separators_only() {
+        assert_eq!(tsquery(""), None);
+        assert_eq!(tsquery("   "), None);
+        assert_eq!(tsquery("&|!:*'()-"), None);
+    }
+
+    #[test]
+    fn tsquery_keeps_the_first_words() {
+        let q: Vec<String> = (1..=MAX_SEARCH_WORDS + 5).map(|n| format!("w{}", n)).collect();
+        let tsquery = tsquery(&q.join(" ")).unwrap();
+        assert_eq!(tsquery.split(" & ").count(), MAX_SEARCH_WORDS);
+        assert!(tsquery.starts_with("w1:* & w2:*"));
+        assert!(tsquery.ends_with(&format!("w{}:*", MAX_SEARCH_WORDS)));
+    }
+}
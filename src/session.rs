@@ -0,0 +1,44 @@
+use axum::{extract::Request, middleware::Next, response::IntoResponse};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use uuid::Uuid;
+
+use crate::auth::secure_cookies;
+
+pub const SESSION_COOKIE: &str = "session_id";
+
+//Anonymous id for the visitor, handlers get it with Extension<SessionId>
+#[derive(Clone, Copy, Debug)]
+pub struct SessionId(pub Uuid);
+
+//Middleware that gives every visitor a session_id cookie on their first request.
+//Nothing is stored until the session is used, e.g. a cart is created for it
+pub async fn session_cookie(jar: CookieJar, mut request: Request, next: Next) -> impl IntoResponse {
+    let existing = jar
+        .get(SESSION_COOKIE)
+        .and_then(|cookie| Uuid::parse_str(cookie.value()).ok());
+    let session = existing.unwrap_or_else(Uuid::new_v4);
+    request.extensions_mut().insert(SessionId(session));
+
+    let response = next.run(request).await;
+    if existing.is_some() {
+        return (jar, response);
+    }
+    (jar.add(session_cookie_for(session)), response)
+}
+
+fn session_cookie_for(session: Uuid) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, session.to_string()))
+        .path("/")
+        .http_only(true)
+        .secure(secure_cookies())
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(30))
+        .build()
+}
+
+//Gives the visitor a new session_id when they log in or out, so an id that was planted on them or seen
+//before can't be used to get at their cart, orders or CSRF token afterwards
+pub fn renew(jar: CookieJar) -> (CookieJar, SessionId) {
+    let session = Uuid::new_v4();
+    (jar.add(session_cookie_for(session)), SessionId(session))
+}
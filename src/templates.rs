@@ -0,0 +1,94 @@
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tera::{Context, Tera};
+
+use crate::images;
+
+//every template under frontend/ is loaded, pages include each other by their path from there
+const TEMPLATE_DIR: &str = "frontend";
+const TEMPLATE_GLOB: &str = "frontend/**/*.html";
+
+//Tera templates parsed once at startup and shared by every handler through AppState.
+//Clones share the same templates, so a reload from the watcher is seen everywhere
+#[derive(Clone)]
+pub struct Templates {
+    tera: Arc<RwLock<Tera>>,
+}
+
+impl Templates {
+    //Parses every template, the error lists each template that failed and why
+    pub fn load() -> Result<Self, String> {
+        let tera = parse().map_err(|e| describe(&e))?;
+        Ok(Templates {
+            tera: Arc::new(RwLock::new(tera)),
+        })
+    }
+
+    pub fn render(&self, name: &str, context: &Context) -> Result<String, tera::Error> {
+        self.tera
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .render(name, context)
+    }
+
+    //Re-parses the templates from disk, the old ones are kept if any of them fail to parse
+    pub fn reload(&self) {
+        match parse() {
+            Ok(tera) => {
+                *self.tera.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = tera;
+                println!("♻️ Templates reloaded");
+            }
+            Err(e) => println!("🔥 Failed to reload templates, keeping the previous ones:{}", describe(&e)),
+        }
+    }
+
+    //Development only, reloads the templates whenever an html file under frontend/ changes.
+    //The watcher stops when the returned value is dropped
+    pub fn watch(&self) -> notify::Result<RecommendedWatcher> {
+        let templates = self.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            let changed = matches!(
+                event.kind,
+                EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            );
+            let is_template = event
+                .paths
+                .iter()
+                .any(|path| path.extension().is_some_and(|ext| ext == "html"));
+            if changed && is_template {
+                templates.reload();
+            }
+        })?;
+        watcher.watch(Path::new(TEMPLATE_DIR), RecursiveMode::Recursive)?;
+        println!("👀 Watching {}/ for template changes", TEMPLATE_DIR);
+        Ok(watcher)
+    }
+}
+
+//every template with the filters they use
+fn parse() -> tera::Result<Tera> {
+    let mut tera = Tera::new(TEMPLATE_GLOB)?;
+    tera.register_filter("media_url", images::media_url);
+    tera.register_filter("srcset", images::srcset);
+    Ok(tera)
+}
+
+//Tera puts the details of what went wrong in the error's sources, this flattens them into one message
+fn describe(e: &tera::Error) -> String {
+    use std::error::Error;
+
+    let mut message = format!("\n{}", e.to_string().trim_start());
+    let mut cause = e.source();
+    while let Some(e) = cause {
+        message += &format!("\n{}", e);
+        cause = e.source();
+    }
+    message
+}